@@ -6,8 +6,10 @@ fn main() {
 
     {
         let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
-        let mut config = cbindgen::Config::default();
-        config.language = cbindgen::Language::C;
+        let config = cbindgen::Config {
+            language: cbindgen::Language::C,
+            ..Default::default()
+        };
         cbindgen::Builder::new()
             .with_config(config)
             .with_crate(crate_dir)
@@ -15,4 +17,11 @@ fn main() {
             .expect("Unable to generate bindings")
             .write_to_file("../target/include/configmaton.h");
     }
+
+    // Layout of the blobs, for reading them directly from C.
+    {
+        let file = std::fs::File::create("../target/include/configmaton_blob.h")
+            .expect("Unable to create the blob header");
+        configmaton::blob::cheader::write_c_header(std::io::BufWriter::new(file));
+    }
 }
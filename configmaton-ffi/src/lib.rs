@@ -2,6 +2,7 @@ use configmaton::blob::automaton::Automaton;
 use configmaton::keyval_nfa::Msg;
 use configmaton::onion::ThreadUnsafeLocker;
 use configmaton::configmaton::Configmaton;
+use configmaton::embed;
 
 type MyConfigmaton = Configmaton<'static, ThreadUnsafeLocker>;
 pub struct FfiConfigmaton;
@@ -11,23 +12,73 @@ pub struct OwnedConfigmaton {
     configmaton: MyConfigmaton,
 }
 
+// Returns null if the blob is invalid, e.g. truncated, corrupted, of another target or of another
+// format version.
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn new_configmaton_base(buf: *const u8, len: usize) ->
     *mut OwnedConfigmaton
 {
-    let msg = Msg::read(|msgbuf| msgbuf.copy_from(buf, len), len);
-    let aut = msg.get_automaton() as *const _ as *const Automaton<'static>;
+    let Ok(msg) = Msg::try_read(|msgbuf| msgbuf.copy_from(buf, len), len) else {
+        return std::ptr::null_mut();
+    };
+    let Ok(aut) = msg.get_root::<Automaton>() else { return std::ptr::null_mut(); };
+    let aut = aut as *const _ as *const Automaton<'static>;
     let configmaton = Configmaton::new(&*aut);
 
     Box::into_raw(Box::new(OwnedConfigmaton { _msg: msg, configmaton }))
 }
 
+// Compile the JSON config into a blob for `new_configmaton_base`, so that C/C++ users need not run
+// the CLI. Returns true and the blob in `out_blob`, or false if the config is invalid and the UTF-8
+// error message in `out_error`. The other one is set empty (null). Both must be released by
+// `free_blob`.
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn compile_config_json(buf: *const u8, len: usize,
+    out_blob: *mut Bytestring, out_error: *mut Bytestring) -> bool
+{
+    let json = std::slice::from_raw_parts(buf, len);
+    let result = match std::str::from_utf8(json) {
+        Ok(json) => embed::compile_json(json).map_err(|error| error.to_string()),
+        Err(error) => Err(format!("invalid config: {}", error)),
+    };
+    let empty = Bytestring { data: std::ptr::null(), len: 0 };
+    match result {
+        Ok(msg) => {
+            let data = std::slice::from_raw_parts(msg.data, msg.data_len());
+            *out_blob = owned_bytestring(data.into());
+            *out_error = empty;
+            true
+        }
+        Err(error) => {
+            *out_blob = empty;
+            *out_error = owned_bytestring(error.into_bytes().into_boxed_slice());
+            false
+        }
+    }
+}
+
+fn owned_bytestring(data: Box<[u8]>) -> Bytestring {
+    let len = data.len();
+    Bytestring { data: Box::into_raw(data) as *const u8, len }
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn free_blob(blob: Bytestring) {
+    if blob.data.is_null() { return; }
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(blob.data as *mut u8, blob.len)));
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn drop_configmaton_base(base: *mut OwnedConfigmaton) {
     drop(Box::from_raw(base));
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn base_get_configmaton(base: *mut OwnedConfigmaton)
     -> *mut FfiConfigmaton
 {
@@ -35,14 +86,18 @@ pub unsafe extern "C" fn base_get_configmaton(base: *mut OwnedConfigmaton)
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn configmaton_make_child(configmaton: *mut FfiConfigmaton)
     -> *mut FfiConfigmaton
 {
     let configmaton = &mut *(configmaton as *mut MyConfigmaton);
-    configmaton.make_child() as *mut FfiConfigmaton
+    // The child stays alive until the parent is dropped.
+    configmaton.make_child().into_raw() as *mut FfiConfigmaton
 }
 
+// The configmaton keeps pointing to the key and the value, so they must outlive the base.
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn configmaton_set(configmaton: *mut FfiConfigmaton,
     key: *const u8, key_len: usize, value: *const u8, value_len: usize)
 {
@@ -52,7 +107,20 @@ pub unsafe extern "C" fn configmaton_set(configmaton: *mut FfiConfigmaton,
     configmaton.set(key, value);
 }
 
+// Like in `configmaton_set`, the key must outlive the base: a child keeps pointing to it from
+// the tombstone of the key (unless the automaton has interned the key).
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn configmaton_unset(configmaton: *mut FfiConfigmaton,
+    key: *const u8, key_len: usize)
+{
+    let configmaton = &mut *(configmaton as *mut MyConfigmaton);
+    let key = std::slice::from_raw_parts(key, key_len);
+    configmaton.unset(key);
+}
+
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn configmaton_get(configmaton: *const FfiConfigmaton,
     key: *const u8, key_len: usize) -> Bytestring
 {
@@ -61,7 +129,7 @@ pub unsafe extern "C" fn configmaton_get(configmaton: *const FfiConfigmaton,
     let result = configmaton.get(key);
     match result {
         Some(value) => Bytestring { data: value.as_ptr(), len: value.len() },
-        None => Bytestring { data: std::ptr::null(), len: std::usize::MAX },
+        None => Bytestring { data: std::ptr::null(), len: usize::MAX },
     }
 }
 
@@ -72,18 +140,113 @@ pub struct Bytestring {
 }
 
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn configmaton_pop_command(configmaton: *mut FfiConfigmaton)
     -> Bytestring
 {
     let configmaton = &mut *(configmaton as *mut MyConfigmaton);
     match configmaton.pop_command() {
         Some(command) => Bytestring { data: command.as_ptr(), len: command.len() },
-        None => Bytestring { data: std::ptr::null(), len: std::usize::MAX },
+        None => Bytestring { data: std::ptr::null(), len: usize::MAX },
     }
 }
 
+// A cursor over the key-value pairs of a configmaton, taken when the cursor is created. The pairs
+// point to the values passed to `configmaton_set`, so they must outlive the cursor.
+pub struct ConfigmatonIter {
+    pairs: std::vec::IntoIter<(&'static [u8], &'static [u8])>,
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn configmaton_iter_new(configmaton: *const FfiConfigmaton)
+    -> *mut ConfigmatonIter
+{
+    let configmaton = &*(configmaton as *const MyConfigmaton);
+    let pairs = configmaton.iter().collect::<Vec<_>>().into_iter();
+    Box::into_raw(Box::new(ConfigmatonIter { pairs }))
+}
+
+// Returns false (leaving `key` and `value` untouched) once the pairs are exhausted.
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn configmaton_iter_next(iter: *mut ConfigmatonIter,
+    key: *mut Bytestring, value: *mut Bytestring) -> bool
+{
+    let Some((k, v)) = (*iter).pairs.next() else { return false };
+    *key = Bytestring { data: k.as_ptr(), len: k.len() };
+    *value = Bytestring { data: v.as_ptr(), len: v.len() };
+    true
+}
+
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn drop_configmaton_iter(iter: *mut ConfigmatonIter) {
+    drop(Box::from_raw(iter));
+}
+
 #[no_mangle]
+#[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn configmaton_clear_children(configmaton: *mut FfiConfigmaton) {
     let configmaton = &mut *(configmaton as *mut MyConfigmaton);
     configmaton.clear_children();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn compile(json: &str) -> Result<Bytestring, String> {
+        let empty = || Bytestring { data: std::ptr::null(), len: 0 };
+        let (mut blob, mut error) = (empty(), empty());
+        let ok = compile_config_json(json.as_ptr(), json.len(), &mut blob, &mut error);
+        let result = if ok {
+            assert!(error.data.is_null());
+            Ok(blob)
+        } else {
+            assert!(blob.data.is_null());
+            let message = std::slice::from_raw_parts(error.data, error.len);
+            Err(String::from_utf8(message.to_vec()).unwrap())
+        };
+        free_blob(error);
+        result
+    }
+
+    #[test]
+    fn compile_round_trip() {
+        unsafe {
+            let blob = compile(r#"[{"when": {"foo": "a.*"}, "run": ["m1"]}]"#).unwrap();
+            let base = new_configmaton_base(blob.data, blob.len);
+            free_blob(blob);
+            let configmaton = base_get_configmaton(base);
+            configmaton_set(configmaton, b"foo".as_ptr(), 3, b"abc".as_ptr(), 3);
+            let command = configmaton_pop_command(configmaton);
+            assert_eq!(std::slice::from_raw_parts(command.data, command.len), b"m1");
+            assert!(configmaton_pop_command(configmaton).data.is_null());
+            drop_configmaton_base(base);
+
+            let error = compile(r#"[{"when": "#).err().unwrap();
+            assert!(error.starts_with("invalid config"), "{}", error);
+            let error = compile(r#"[{"when": {"foo": "a("}, "run": ["m1"]}]"#).err().unwrap();
+            assert!(error.starts_with("regex \"a(\""), "{}", error);
+        }
+    }
+
+    #[test]
+    fn invalid_blob() {
+        unsafe {
+            let garbage = [0xa5u8; 256];
+            assert!(new_configmaton_base(garbage.as_ptr(), garbage.len()).is_null());
+            assert!(new_configmaton_base(garbage.as_ptr(), 3).is_null());
+
+            let blob = compile(r#"[{"when": {"foo": "a.*"}, "run": ["m1"]}]"#).unwrap();
+            let data = std::slice::from_raw_parts(blob.data, blob.len);
+            assert!(new_configmaton_base(data.as_ptr(), data.len() - 8).is_null());
+            let mut corrupt = data.to_vec();
+            let middle = corrupt.len() / 2;
+            corrupt[middle..].fill(0xff);
+            assert!(new_configmaton_base(corrupt.as_ptr(), corrupt.len()).is_null());
+            free_blob(blob);
+        }
+    }
+}
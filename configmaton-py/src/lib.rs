@@ -0,0 +1,174 @@
+// Python bindings, for the orchestration written in Python which would otherwise go through the
+// C FFI. Unlike the Cython wrapper in `python/`, the keys and values are copied, so the Python
+// objects passed to `set` need not outlive the configmaton.
+//
+// MEMORY: the copies are never freed while the tree of configmatons (the root and all its
+// children) is alive, even if the values are overwritten or unset, see `Interned`. A long-lived
+// tree fed with ever new values (e.g. timestamps or counters) grows without bound, so drop it and
+// build a new one (setting the current values again) from time to time.
+
+// Triggered by the code generated by `#[pyfunction]` for the functions returning `PyResult`.
+#![allow(clippy::useless_conversion)]
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use configmaton::blob::automaton::Automaton;
+use configmaton::configmaton::Configmaton;
+use configmaton::embed;
+use configmaton::holder::Handle;
+use configmaton::keyval_nfa::Msg;
+use configmaton::onion::ThreadUnsafeLocker;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+type MyConfigmaton = Configmaton<'static, ThreadUnsafeLocker>;
+
+// The keys and values set in a tree of configmatons, which borrow them. Each distinct one is kept
+// once, until the whole tree is dropped: the onions and the simulations may still refer to an
+// overwritten value (e.g. as the old value of a key, or in a parent), so none is ever released.
+#[derive(Default)]
+struct Interned(RefCell<HashSet<Box<[u8]>>>);
+
+impl Interned {
+    fn intern(&self, bytes: &[u8]) -> &'static [u8] {
+        let mut set = self.0.borrow_mut();
+        let boxed = match set.get(bytes) {
+            Some(boxed) => boxed,
+            None => { set.insert(bytes.into()); set.get(bytes).unwrap() },
+        };
+        // The boxed slices are never moved or dropped before the tree.
+        unsafe { &*(&**boxed as *const [u8]) }
+    }
+}
+
+enum Node {
+    // The configmaton borrows the blob, so it is dropped first.
+    Root { configmaton: Box<MyConfigmaton>, _msg: Msg },
+    // The parent owns the child, so the child keeps the parent alive.
+    Child { configmaton: Handle<MyConfigmaton>, _parent: Py<PyConfigmaton> },
+}
+
+#[pyclass(name = "Configmaton", unsendable)]
+pub struct PyConfigmaton {
+    node: Node,
+    interned: Rc<Interned>,
+}
+
+impl PyConfigmaton {
+    fn configmaton(&self) -> &MyConfigmaton {
+        match &self.node {
+            Node::Root { configmaton, .. } => configmaton,
+            Node::Child { configmaton, .. } => configmaton,
+        }
+    }
+
+    fn configmaton_mut(&mut self) -> &mut MyConfigmaton {
+        match &mut self.node {
+            Node::Root { configmaton, .. } => configmaton,
+            Node::Child { configmaton, .. } => configmaton,
+        }
+    }
+}
+
+#[pymethods]
+impl PyConfigmaton {
+    // The blob is copied, e.g. from the result of `compile`.
+    #[new]
+    fn new(blob: &[u8]) -> PyResult<Self> {
+        let read = |buf: *mut u8| unsafe { buf.copy_from(blob.as_ptr(), blob.len()) };
+        let msg = unsafe { Msg::try_read(read, blob.len()) }
+            .map_err(|error| PyValueError::new_err(error.to_string()))?;
+        let aut = msg.get_automaton() as *const _ as *const Automaton<'static>;
+        let configmaton = Box::new(Configmaton::new(unsafe { &*aut }));
+        Ok(PyConfigmaton {
+            node: Node::Root { configmaton, _msg: msg },
+            interned: Rc::default(),
+        })
+    }
+
+    // Each distinct key and value is kept until the tree is dropped, see the top of this file.
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        let (key, value) = (self.interned.intern(key), self.interned.intern(value));
+        unsafe { self.configmaton_mut().set(key, value) };
+    }
+
+    fn unset(&mut self, key: &[u8]) {
+        let key = self.interned.intern(key);
+        self.configmaton_mut().unset(key);
+    }
+
+    fn get<'py>(&self, py: Python<'py>, key: &[u8]) -> Option<Bound<'py, PyBytes>> {
+        self.configmaton().get(key).map(|value| PyBytes::new_bound(py, value))
+    }
+
+    fn pop_command<'py>(&mut self, py: Python<'py>) -> Option<Bound<'py, PyBytes>> {
+        self.configmaton_mut().pop_command().map(|command| PyBytes::new_bound(py, command))
+    }
+
+    fn make_child(slf: &Bound<'_, Self>) -> Self {
+        let mut parent = slf.borrow_mut();
+        let configmaton = unsafe { parent.configmaton_mut().make_child() };
+        PyConfigmaton {
+            node: Node::Child { configmaton, _parent: slf.clone().unbind() },
+            interned: parent.interned.clone(),
+        }
+    }
+}
+
+// Compile the JSON config into a blob for `Configmaton`.
+#[pyfunction]
+fn compile<'py>(py: Python<'py>, config_json: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let msg = embed::compile_json(config_json)
+        .map_err(|error| PyValueError::new_err(error.to_string()))?;
+    let data = unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) };
+    Ok(PyBytes::new_bound(py, data))
+}
+
+#[pymodule]
+fn configmaton_py(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyConfigmaton>()?;
+    module.add_function(wrap_pyfunction!(compile, module)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let config = r#"[
+                {"when": {"foo": "bar"}, "run": ["m1"], "then": [
+                    {"when": {"qux": "a.*"}, "run": ["m2"]}
+                ]}
+            ]"#;
+            assert!(compile(py, "[{").is_err());
+            assert!(PyConfigmaton::new(b"garbage").is_err());
+            let blob = compile(py, config).unwrap();
+            let root = Bound::new(py, PyConfigmaton::new(blob.as_bytes()).unwrap()).unwrap();
+            let pop = |x: &Bound<'_, PyConfigmaton>| {
+                x.borrow_mut().pop_command(py).map(|command| command.as_bytes().to_vec())
+            };
+
+            let mut key = b"foo".to_vec();
+            root.borrow_mut().set(&key, b"bar");
+            key.clear();
+            assert_eq!(pop(&root).as_deref(), Some(b"m1".as_ref()));
+            assert_eq!(pop(&root), None);
+            let value = root.borrow().get(py, b"foo").unwrap();
+            assert_eq!(value.as_bytes(), b"bar");
+
+            let child = Bound::new(py, PyConfigmaton::make_child(&root)).unwrap();
+            drop(root);
+            child.borrow_mut().set(b"qux", b"abc");
+            assert_eq!(pop(&child).as_deref(), Some(b"m2".as_ref()));
+            assert_eq!(child.borrow().get(py, b"foo").unwrap().as_bytes(), b"bar");
+            child.borrow_mut().unset(b"foo");
+            assert!(child.borrow().get(py, b"foo").is_none());
+        });
+    }
+}
@@ -0,0 +1,55 @@
+// Matching of long values, where the simulation steps the U8 states once per byte. The same
+// patterns are compiled into each kind of the states: `cargo bench --bench matching`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use configmaton::blob::state::build::U8BuildConfig;
+use configmaton::keyval_nfa::{Cmd, Msg, Parser};
+use configmaton::keyval_simulator::Simulation;
+
+struct StateKindConfig {
+    dense_guard_count: usize,
+    max_ranged_ranges: usize,
+}
+
+impl U8BuildConfig for StateKindConfig {
+    fn guard_size_keep(&self) -> u32 { 10 }
+    fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 3 }
+    fn dense_guard_count(&self) -> usize { self.dense_guard_count }
+    fn max_ranged_ranges(&self) -> usize { self.max_ranged_ranges }
+}
+
+const KINDS: [(&str, StateKindConfig); 3] = [
+    ("sparse", StateKindConfig { dense_guard_count: usize::MAX, max_ranged_ranges: 0 }),
+    ("dense", StateKindConfig { dense_guard_count: 0, max_ranged_ranges: 0 }),
+    ("ranged", StateKindConfig { dense_guard_count: 0, max_ranged_ranges: 16 }),
+];
+
+const CONFIG: &str = r#"[
+    { "when": { "path": "/[a-z0-9/]*/(api|static)/[a-z0-9/]*[0-9]" }, "run": [ "m1" ] },
+    { "when": { "path": "[a-z/]*(ab|cd)[a-z0-9/]*x" }, "run": [ "m2" ] },
+    { "when": { "path": "/v[0-9]/.*" }, "run": [ "m3" ] }
+]"#;
+
+fn matching(c: &mut Criterion) {
+    let value = b"/v1/users/8cf3a/static/img/".repeat(40);
+    let mut group = c.benchmark_group("matching");
+    group.throughput(Throughput::Bytes(value.len() as u64));
+    for (name, cfg) in KINDS.iter() {
+        let config: Vec<Cmd> = serde_json::from_str(CONFIG).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let msg = Msg::serialize(&parser, &init, cfg);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let simulation = Simulation::new(msg.get_automaton(), |_| None);
+        group.bench_function(*name, |b| b.iter_batched_ref(
+            || simulation.clone(),
+            |simulation| simulation.read(b"path", &value, |_| None),
+            BatchSize::SmallInput,
+        ));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, matching);
+criterion_main!(benches);
@@ -1,86 +1,481 @@
+use std::fmt;
+use std::ops::Range;
+
 use regex_syntax::ast;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Ast {
     Range(u8, u8),
     Alternation(Box<Ast>, Box<Ast>),
     Concatenation(Box<Ast>, Box<Ast>),
     Repetition(Box<Ast>),
+    // At least the first count and at most the second one (unbounded if None) repetitions. It is
+    // expanded into copies of the body, see `char_enfa::Nfa::from_ast`.
+    Counted(Box<Ast>, u32, Option<u32>),
     Epsilon,
 }
 
-pub fn parse_regex(regex: &str) -> Ast {
-    let ast = ast::parse::Parser::new().parse(regex).unwrap();
-    parse_ext_ast(&ast)
-}
-
-fn parse_ext_ast(ext: &ast::Ast) -> Ast {
-    match ext {
-        ast::Ast::Literal(lit) => { let c = lit.c as u8; Ast::Range(c, c) },
-        ast::Ast::Dot(_) => { Ast::Range(0, 255) },
-        ast::Ast::Concat(x) => {
-            let mut result = parse_ext_ast(&x.asts[0]);
-            for child in x.asts[1..].iter() {
-                result = Ast::Concatenation(Box::new(result), Box::new(parse_ext_ast(child)));
+// How a pattern matches a value: as a whole, or anywhere in it, like in grep. The anchors `^` and
+// `$` (or `\A` and `\z`) pin an alternative of a substring pattern to the start or to the end of
+// the value, a full pattern is anchored at both ends anyway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum MatchMode {
+    #[default]
+    Full,
+    Substring,
+}
+
+impl MatchMode {
+    pub const NAMES: [(&'static str, MatchMode); 2] =
+        [("full", MatchMode::Full), ("substring", MatchMode::Substring)];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, mode)| *mode)
+    }
+}
+
+// How the regexes of a match are read, see `Match`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RegexOptions {
+    pub mode: MatchMode,
+    // Like a `(?i)` in front of the regex: the letters match in both cases.
+    pub ignore_case: bool,
+}
+
+// The largest count of a counted repetition, and the most nodes (see `Ast::node_count`) a single
+// counted repetition may expand to, so that e.g. `((a{999}){999}){999}` gets rejected instead of
+// exhausting the memory.
+pub const MAX_REPETITION_COUNT: u32 = 1000;
+pub const MAX_REPETITION_NODES: usize = 1 << 16;
+
+// Prints the pattern in a normalized form, from which `parse_regex` builds the same AST.
+impl fmt::Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_prec(0, f)
+    }
+}
+
+impl Ast {
+    // Of the expanded AST, i.e. counting each copy of the body of a counted repetition.
+    pub fn node_count(&self) -> usize {
+        match self {
+            Ast::Range(..) | Ast::Epsilon => 1,
+            Ast::Repetition(a) => 1 + a.node_count(),
+            Ast::Counted(a, min, max) => {
+                let copies = max.unwrap_or(min.saturating_add(1)) as usize;
+                a.node_count().saturating_mul(copies).saturating_add(1)
+            }
+            Ast::Alternation(a, b) | Ast::Concatenation(a, b) =>
+                1 + a.node_count() + b.node_count(),
+        }
+    }
+
+    // Operator precedence: alternation 0, concatenation 1, repetition 2, atoms 3. Both binary
+    // operators are left-associative in the parser, so a right operand of the same kind is
+    // parenthesized to keep the tree shape.
+    fn precedence(&self) -> u8 {
+        match self {
+            Ast::Alternation(..) => 0,
+            Ast::Concatenation(..) => 1,
+            Ast::Repetition(_) | Ast::Counted(..) => 2,
+            Ast::Range(..) | Ast::Epsilon => 3,
+        }
+    }
+
+    fn fmt_prec(&self, ctx: u8, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.precedence() < ctx {
+            write!(f, "(")?;
+            self.fmt_prec(0, f)?;
+            return write!(f, ")");
+        }
+        match self {
+            Ast::Range(0, 255) => write!(f, "."),
+            Ast::Range(a, b) if a == b => write_literal(*a, f),
+            Ast::Range(a, b) => {
+                write!(f, "[")?;
+                write_literal(*a, f)?;
+                write!(f, "-")?;
+                write_literal(*b, f)?;
+                write!(f, "]")
             }
-            result
-        },
-        ast::Ast::Alternation(x) => {
-            let mut result = parse_ext_ast(&x.asts[0]);
-            for child in x.asts[1..].iter() {
-                result = Ast::Alternation(Box::new(result), Box::new(parse_ext_ast(child)));
+            Ast::Alternation(a, b) => {
+                a.fmt_prec(0, f)?;
+                write!(f, "|")?;
+                b.fmt_prec(1, f)
             }
-            result
-        },
-        ast::Ast::Repetition(a) => {
-            Ast::Repetition(Box::new(parse_ext_ast(&a.ast)))
-        },
-        ast::Ast::Group(a) => {
-            parse_ext_ast(&a.ast)
-        },
-        ast::Ast::ClassBracketed(x) => {
-            if x.negated {
-                panic!("negated class bracketed not supported");
+            Ast::Concatenation(a, b) => {
+                a.fmt_prec(1, f)?;
+                b.fmt_prec(2, f)
             }
-            match &x.kind {
-                ast::ClassSet::Item(item) => {
-                    parse_ext_class_set_item(item)
-                },
-                _ => {
-                    panic!("invalid regex command");
+            Ast::Repetition(a) => {
+                a.fmt_prec(3, f)?;
+                write!(f, "*")
+            }
+            Ast::Counted(a, min, max) => {
+                a.fmt_prec(3, f)?;
+                match max {
+                    Some(max) if max == min => write!(f, "{{{}}}", min),
+                    Some(max) => write!(f, "{{{},{}}}", min, max),
+                    None => write!(f, "{{{},}}", min),
                 }
             }
+            // Only a whole pattern or a left alternative may be empty, elsewhere it needs a group.
+            Ast::Epsilon if ctx == 0 => Ok(()),
+            Ast::Epsilon => write!(f, "()"),
         }
-        ast::Ast::Empty(_) => Ast::Epsilon,
-        _ => {
-            panic!("invalid regex command {}", ext);
+    }
+}
+
+fn write_literal(c: u8, f: &mut fmt::Formatter) -> fmt::Result {
+    match c {
+        b' '..=b'~' if regex_syntax::is_meta_character(c as char) => write!(f, "\\{}", c as char),
+        b' '..=b'~' => write!(f, "{}", c as char),
+        _ => write!(f, "\\x{:02X}", c),
+    }
+}
+
+// A problem found in a pattern, located by a byte range of the pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegexError {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}: {}", self.span.start, self.span.end, self.message)
+    }
+}
+
+impl std::error::Error for RegexError {}
+
+pub fn parse_regex(regex: &str) -> Ast {
+    parse_regex_in(regex, RegexOptions::default())
+}
+
+// The AST of a substring pattern matches the same values as a full one, e.g. `a|^b` is parsed
+// like `.*a.*|b.*`. Similarly, the case insensitive letters are parsed like classes of both cases.
+pub fn parse_regex_in(regex: &str, options: RegexOptions) -> Ast {
+    let (ast, errors) = parse_regex_recovering_in(regex, options);
+    if let Some(error) = errors.first() {
+        panic!("invalid regex {:?}: {}", regex, error);
+    }
+    ast
+}
+
+pub fn parse_regex_recovering(regex: &str) -> (Ast, Vec<RegexError>) {
+    parse_regex_recovering_in(regex, RegexOptions::default())
+}
+
+// Collects all the errors of the pattern instead of stopping at the first one. A syntax error
+// drops the offending part of the pattern and the rest is parsed again, an unsupported construct
+// is replaced by Epsilon. The AST is then a best effort, meaningful only if there are no errors.
+pub fn parse_regex_recovering_in(regex: &str, options: RegexOptions) -> (Ast, Vec<RegexError>) {
+    let mut lowering = Lowering {
+        origin: (0..regex.len()).collect(),
+        errors: vec![],
+        mode: options.mode,
+        ignore_case: options.ignore_case,
+    };
+    let mut current = regex.to_owned();
+    loop {
+        match ast::parse::Parser::new().parse(&current) {
+            Ok(ext) => {
+                let ast = lowering.pattern(&ext);
+                lowering.errors.sort_by_key(|error| (error.span.start, error.span.end));
+                return (ast, lowering.errors);
+            }
+            Err(error) => {
+                let span = error.span();
+                let mut start = span.start.offset.min(current.len());
+                let mut end = span.end.offset.min(current.len());
+                // Drop at least one character, so that the loop terminates.
+                if end == start {
+                    if start == current.len() {
+                        start = current.char_indices().last().map_or(0, |(i, _)| i);
+                    }
+                    end = start + current[start..].chars().next().map_or(0, char::len_utf8);
+                }
+                lowering.syntax_error(start..end, error.kind().to_string());
+                if start == end { return (Ast::Epsilon, lowering.errors); }
+                current.replace_range(start..end, "");
+                lowering.origin.drain(start..end);
+            }
         }
     }
 }
 
-fn parse_ext_class_set_item(item: &ast::ClassSetItem) -> Ast {
-    match item {
-        ast::ClassSetItem::Range(range) => {
-            Ast::Range(range.start.c as u8, range.end.c as u8)
-        },
-        ast::ClassSetItem::Literal(c) => {
-            let c = c.c as u8;
-            Ast::Range(c, c)
-        },
-        ast::ClassSetItem::Union(union) => {
-            let mut result = parse_ext_class_set_item(&union.items[0]);
-            for child in union.items[1..].iter() {
-                result = Ast::Alternation(
-                    Box::new(result),
-                    Box::new(parse_ext_class_set_item(child))
-                );
+struct Lowering {
+    // Offsets into the original pattern of the bytes of the currently parsed one.
+    origin: Vec<usize>,
+    errors: Vec<RegexError>,
+    mode: MatchMode,
+    // Set by the `i` flag till the end of the group.
+    ignore_case: bool,
+}
+
+impl Lowering {
+    fn error(&mut self, span: Range<usize>, message: String) {
+        let start = self.origin.get(span.start).copied()
+            .unwrap_or_else(|| self.origin.last().map_or(0, |o| o + 1));
+        let end = if span.end > span.start { self.origin[span.end - 1] + 1 } else { start };
+        self.errors.push(RegexError { span: start..end, message });
+    }
+
+    // An error enclosing an earlier one is likely caused by dropping the earlier one, e.g. the
+    // bracket left unclosed after dropping an invalid class range, so it is not reported.
+    fn syntax_error(&mut self, span: Range<usize>, message: String) {
+        let count = self.errors.len();
+        self.error(span, message);
+        let new = &self.errors[count].span;
+        if self.errors[..count].iter().any(|e| new.start <= e.span.start && e.span.end <= new.end) {
+            self.errors.pop();
+        }
+    }
+
+    fn unsupported(&mut self, span: &ast::Span, what: &str) -> Ast {
+        self.error(span.start.offset..span.end.offset, format!("{} not supported", what));
+        Ast::Epsilon
+    }
+
+    // The anchors may only start or end the alternatives of the whole pattern.
+    fn pattern(&mut self, ext: &ast::Ast) -> Ast {
+        match ext {
+            ast::Ast::Alternation(x) => x.asts.iter().map(|alt| self.alternative(alt))
+                .reduce(|a, b| Ast::Alternation(Box::new(a), Box::new(b)))
+                .unwrap_or(Ast::Epsilon),
+            _ => self.alternative(ext),
+        }
+    }
+
+    fn alternative(&mut self, ext: &ast::Ast) -> Ast {
+        let mut items = match ext {
+            ast::Ast::Concat(x) => &x.asts[..],
+            _ => std::slice::from_ref(ext),
+        };
+        if let [ast::Ast::Empty(_)] = items { items = &[]; }
+        while let [ast::Ast::Flags(x), rest @ ..] = items {
+            self.set_flags(&x.flags);
+            items = rest;
+        }
+        let start = items.first().is_some_and(|x| is_anchor(x, true));
+        if start { items = &items[1..]; }
+        let end = items.last().is_some_and(|x| is_anchor(x, false));
+        if end { items = &items[..items.len() - 1]; }
+
+        let unanchored = self.mode == MatchMode::Substring;
+        let any = || Ast::Repetition(Box::new(Ast::Range(0, 255)));
+        let mut parts = vec![];
+        if unanchored && !start { parts.push(any()); }
+        parts.extend(items.iter().filter_map(|x| self.item(x)));
+        if unanchored && !end { parts.push(any()); }
+        parts.into_iter()
+            .reduce(|a, b| Ast::Concatenation(Box::new(a), Box::new(b)))
+            .unwrap_or(Ast::Epsilon)
+    }
+
+    // Only the case insensitivity is supported.
+    fn set_flags(&mut self, flags: &ast::Flags) {
+        let mut enable = true;
+        for item in flags.items.iter() {
+            match item.kind {
+                ast::FlagsItemKind::Negation => enable = false,
+                ast::FlagsItemKind::Flag(ast::Flag::CaseInsensitive) => self.ignore_case = enable,
+                ast::FlagsItemKind::Flag(_) => { self.unsupported(&item.span, "flag"); }
             }
-            result
-        },
-        _ => {
-            panic!("invalid regex command");
         }
     }
+
+    // An item of a concatenation, None for the flags, which apply to the rest of the group.
+    fn item(&mut self, ext: &ast::Ast) -> Option<Ast> {
+        match ext {
+            ast::Ast::Flags(x) => { self.set_flags(&x.flags); None }
+            _ => Some(self.ast(ext)),
+        }
+    }
+
+    fn range(&self, a: u8, b: u8) -> Ast {
+        if self.ignore_case { alternation(case_folded(&[(a, b)])) } else { Ast::Range(a, b) }
+    }
+
+    fn ast(&mut self, ext: &ast::Ast) -> Ast {
+        match ext {
+            ast::Ast::Literal(lit) => { let c = lit.c as u8; self.range(c, c) },
+            ast::Ast::Dot(_) => { Ast::Range(0, 255) },
+            ast::Ast::Concat(x) => {
+                x.asts.iter().filter_map(|child| self.item(child))
+                    .reduce(|a, b| Ast::Concatenation(Box::new(a), Box::new(b)))
+                    .unwrap_or(Ast::Epsilon)
+            },
+            ast::Ast::Alternation(x) => {
+                let mut result = self.ast(&x.asts[0]);
+                for child in x.asts[1..].iter() {
+                    result = Ast::Alternation(Box::new(result), Box::new(self.ast(child)));
+                }
+                result
+            },
+            ast::Ast::Repetition(a) => {
+                use ast::{RepetitionKind, RepetitionRange};
+                let body = Box::new(self.ast(&a.ast));
+                let (min, max) = match &a.op.kind {
+                    RepetitionKind::ZeroOrMore => return Ast::Repetition(body),
+                    RepetitionKind::ZeroOrOne => (0, Some(1)),
+                    RepetitionKind::OneOrMore => (1, None),
+                    RepetitionKind::Range(RepetitionRange::Exactly(n)) => (*n, Some(*n)),
+                    RepetitionKind::Range(RepetitionRange::AtLeast(n)) => (*n, None),
+                    RepetitionKind::Range(RepetitionRange::Bounded(m, n)) => (*m, Some(*n)),
+                };
+                let span = a.span.start.offset..a.span.end.offset;
+                if max.unwrap_or(min) > MAX_REPETITION_COUNT {
+                    self.error(span, format!("repetition count over {}", MAX_REPETITION_COUNT));
+                    return Ast::Epsilon;
+                }
+                let counted = Ast::Counted(body, min, max);
+                if counted.node_count() > MAX_REPETITION_NODES {
+                    self.error(span,
+                        format!("repetition expanded to over {} nodes", MAX_REPETITION_NODES));
+                    return Ast::Epsilon;
+                }
+                counted
+            },
+            ast::Ast::Group(a) => {
+                let ignore_case = self.ignore_case;
+                if let ast::GroupKind::NonCapturing(flags) = &a.kind { self.set_flags(flags); }
+                let result = self.ast(&a.ast);
+                self.ignore_case = ignore_case;
+                result
+            },
+            ast::Ast::ClassBracketed(x) => {
+                if x.negated {
+                    return self.unsupported(&x.span, "negated class");
+                }
+                match &x.kind {
+                    ast::ClassSet::Item(item) => {
+                        self.class_set_item(item)
+                    },
+                    ast::ClassSet::BinaryOp(op) => self.unsupported(&op.span, "class operation"),
+                }
+            }
+            ast::Ast::Empty(_) => Ast::Epsilon,
+            ast::Ast::Flags(x) => { self.set_flags(&x.flags); Ast::Epsilon },
+            ast::Ast::Assertion(x) if is_anchor(ext, true) || is_anchor(ext, false) => {
+                self.error(x.span.start.offset..x.span.end.offset,
+                    "anchor not at an end of the pattern".to_owned());
+                Ast::Epsilon
+            }
+            ast::Ast::Assertion(x) => self.unsupported(&x.span, "assertion"),
+            ast::Ast::ClassUnicode(x) => self.unsupported(&x.span, "unicode class"),
+            ast::Ast::ClassPerl(x) => self.unsupported(&x.span, "perl class"),
+        }
+    }
+
+    fn class_set_item(&mut self, item: &ast::ClassSetItem) -> Ast {
+        match item {
+            ast::ClassSetItem::Range(range) => {
+                self.range(range.start.c as u8, range.end.c as u8)
+            },
+            ast::ClassSetItem::Literal(c) => {
+                let c = c.c as u8;
+                self.range(c, c)
+            },
+            ast::ClassSetItem::Union(union) => {
+                let mut result = self.class_set_item(&union.items[0]);
+                for child in union.items[1..].iter() {
+                    result = Ast::Alternation(
+                        Box::new(result),
+                        Box::new(self.class_set_item(child))
+                    );
+                }
+                result
+            },
+            ast::ClassSetItem::Bracketed(x) => self.unsupported(&x.span, "nested class"),
+            ast::ClassSetItem::Empty(span) => self.unsupported(span, "empty class item"),
+            ast::ClassSetItem::Ascii(x) => {
+                let mut ranges = ascii_class(&x.kind).to_vec();
+                // Like in the regex crate, e.g. `(?i)[[:^lower:]]` matches no letters.
+                if self.ignore_case {
+                    ranges = case_folded(&ranges);
+                }
+                if x.negated {
+                    ranges = complement(&ranges);
+                }
+                alternation(ranges)
+            },
+            ast::ClassSetItem::Unicode(x) => self.unsupported(&x.span, "unicode class"),
+            ast::ClassSetItem::Perl(x) => self.unsupported(&x.span, "perl class"),
+        }
+    }
+}
+
+fn is_anchor(ext: &ast::Ast, start: bool) -> bool {
+    use ast::AssertionKind::*;
+    let ast::Ast::Assertion(x) = ext else { return false };
+    match x.kind {
+        StartLine | StartText => start,
+        EndLine | EndText => !start,
+        _ => false,
+    }
+}
+
+// The POSIX classes, like in grep.
+fn ascii_class(kind: &ast::ClassAsciiKind) -> &'static [(u8, u8)] {
+    use ast::ClassAsciiKind::*;
+    match kind {
+        Alnum => &[(b'0', b'9'), (b'A', b'Z'), (b'a', b'z')],
+        Alpha => &[(b'A', b'Z'), (b'a', b'z')],
+        Ascii => &[(0, 0x7F)],
+        Blank => &[(b'\t', b'\t'), (b' ', b' ')],
+        Cntrl => &[(0, 0x1F), (0x7F, 0x7F)],
+        Digit => &[(b'0', b'9')],
+        Graph => &[(b'!', b'~')],
+        Lower => &[(b'a', b'z')],
+        Print => &[(b' ', b'~')],
+        Punct => &[(b'!', b'/'), (b':', b'@'), (b'[', b'`'), (b'{', b'~')],
+        Space => &[(b'\t', b'\r'), (b' ', b' ')],
+        Upper => &[(b'A', b'Z')],
+        Word => &[(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')],
+        Xdigit => &[(b'0', b'9'), (b'A', b'F'), (b'a', b'f')],
+    }
+}
+
+fn alternation(ranges: Vec<(u8, u8)>) -> Ast {
+    ranges.into_iter().map(|(a, b)| Ast::Range(a, b))
+        .reduce(|a, b| Ast::Alternation(Box::new(a), Box::new(b)))
+        .unwrap_or(Ast::Epsilon)
+}
+
+// The ranges with their ASCII letters in both cases, sorted and merged.
+fn case_folded(ranges: &[(u8, u8)]) -> Vec<(u8, u8)> {
+    let mut all = ranges.to_vec();
+    for &(a, b) in ranges {
+        for (from, to) in [(b'A', b'Z'), (b'a', b'z')] {
+            let (lo, hi) = (a.max(from), b.min(to));
+            if lo <= hi { all.push((lo ^ 0x20, hi ^ 0x20)); }
+        }
+    }
+    all.sort_unstable();
+    let mut result: Vec<(u8, u8)> = vec![];
+    for (a, b) in all {
+        match result.last_mut() {
+            Some((_, last)) if a as u16 <= *last as u16 + 1 => *last = (*last).max(b),
+            _ => result.push((a, b)),
+        }
+    }
+    result
+}
+
+// The complement of sorted disjoint ranges, over all bytes.
+fn complement(ranges: &[(u8, u8)]) -> Vec<(u8, u8)> {
+    let mut result = vec![];
+    let mut next = 0u16;
+    for &(a, b) in ranges {
+        if next < a as u16 { result.push((next as u8, a - 1)); }
+        next = b as u16 + 1;
+    }
+    if next <= 255 { result.push((next as u8, 255)); }
+    result
 }
 
 #[cfg(test)]
@@ -130,4 +525,118 @@ mod tests {
         let ast = parse_regex("");
         assert_eq!(ast, Ast::Epsilon);
     }
+
+    #[test]
+    fn test_print_regex() {
+        let cases = [
+            ("", ""),
+            ("a", "a"),
+            ("(ab)c", "abc"),
+            ("a(bc)", "a(bc)"),
+            ("a|(b|c)", "a|(b|c)"),
+            ("(a|b)c*", "(a|b)c*"),
+            ("(ab)*", "(ab)*"),
+            ("[a-dA-D]", "[a-d]|[A-D]"),
+            ("a||b", "a|()|b"),
+            ("a()", "a()"),
+            ("()*", "()*"),
+            ("(a*)*", "(a*)*"),
+            (".[.][-]x\\x00\\xff", ".\\.\\-x\\x00\\xFF"),
+            ("[ -\\[]", "[ -\\[]"),
+            ("a?b+", "a{0,1}b{1,}"),
+            ("(ab){2}c{2,}d{0,3}", "(ab){2}c{2,}d{0,3}"),
+            ("a{2}{3}", "(a{2}){3}"),
+        ];
+        for (regex, expected) in cases {
+            let ast = parse_regex(regex);
+            let printed = ast.to_string();
+            assert_eq!(printed, expected, "{}", regex);
+            assert_eq!(parse_regex(&printed), ast, "{}", regex);
+            assert_eq!(parse_regex(&printed).to_string(), printed, "{}", regex);
+        }
+    }
+
+    #[test]
+    fn test_parse_regex_recovering() {
+        let (ast, errors) = parse_regex_recovering("a(b|c");
+        assert_eq!(ast, parse_regex("ab|c"));
+        assert_eq!(errors.iter().map(|e| e.span.clone()).collect::<Vec<_>>(), vec![1..2]);
+
+        let regex = "x\\d[^a]y[z-a](z|\\b)*)";
+        let (ast, errors) = parse_regex_recovering(regex);
+        let spans: Vec<_> = errors.iter().map(|e| &regex[e.span.clone()]).collect();
+        assert_eq!(spans, vec!["\\d", "[^a]", "z-a", "\\b", ")"]);
+        assert_eq!(ast.to_string(), "x()()y(z|())*");
+
+        let (ast, errors) = parse_regex_recovering("ab");
+        assert_eq!((ast, errors), (parse_regex("ab"), vec![]));
+    }
+
+    #[test]
+    fn test_counted_repetition() {
+        assert_eq!(parse_regex("a{2,3}"),
+            Ast::Counted(Box::new(Ast::Range(b'a', b'a')), 2, Some(3)));
+        assert_eq!(parse_regex("(ab){2,}").node_count(), 1 + 3 * 3);
+        assert_eq!(parse_regex("(a{10}){20}").node_count(), 1 + 20 * 11);
+
+        let (ast, errors) = parse_regex_recovering("xa{1001}");
+        assert_eq!(ast.to_string(), "x()");
+        assert_eq!(errors, vec![RegexError {
+            span: 1..8, message: "repetition count over 1000".to_owned() }]);
+        let regex = "((a{100}){100}){100}";
+        let (_, errors) = parse_regex_recovering(regex);
+        let spans: Vec<_> = errors.iter().map(|e| &regex[e.span.clone()]).collect();
+        assert_eq!(spans, vec![regex]);
+        assert!(parse_regex_recovering("a{3,2}").1[0].message.contains("invalid"));
+    }
+
+    #[test]
+    fn test_anchors() {
+        assert_eq!(parse_regex("^a|b$|\\Ac\\z"), parse_regex("a|b|c"));
+        let options = RegexOptions { mode: MatchMode::Substring, ..RegexOptions::default() };
+        let substring = |regex| parse_regex_in(regex, options).to_string();
+        assert_eq!(substring("ab"), ".*ab.*");
+        assert_eq!(substring("^a|b$|^(c|d)$"), "a.*|.*b|(c|d)");
+        assert_eq!(substring("^$"), "");
+        assert_eq!(substring(""), ".*.*");
+
+        let regex = "a^b|c\\b";
+        let (_, errors) = parse_regex_recovering_in(regex, options);
+        let errors: Vec<_> = errors.iter().map(|e| (&regex[e.span.clone()], &e.message[..]))
+            .collect();
+        assert_eq!(errors, vec![
+            ("^", "anchor not at an end of the pattern"), ("\\b", "assertion not supported")]);
+        assert_eq!(MatchMode::from_name("substring"), Some(MatchMode::Substring));
+    }
+
+    #[test]
+    fn test_ignore_case() {
+        assert_eq!(parse_regex("(?i)wa"), parse_regex("[Ww][Aa]"));
+        assert_eq!(parse_regex("a(?i)b|c"), parse_regex("a[Bb]|[Cc]"));
+        assert_eq!(parse_regex("(?i:a)b(?i)c(?-i)d"), parse_regex("[Aa]b[Cc]d"));
+        assert_eq!(parse_regex("(?i)[a-cX]").to_string(), "[A-C]|[a-c]|(X|x)");
+        assert_eq!(parse_regex("(?i)[[:^lower:]]"), parse_regex("[[:^alpha:]]"));
+        assert_eq!(parse_regex("(?i).[0-9]"), parse_regex(".[0-9]"));
+        let options = RegexOptions { ignore_case: true, ..RegexOptions::default() };
+        assert_eq!(parse_regex_in("warn", options), parse_regex("(?i)warn"));
+        assert_eq!(parse_regex_in("(?-i)x", options), parse_regex("x"));
+
+        let (ast, errors) = parse_regex_recovering("(?is)a");
+        assert_eq!(ast, parse_regex("[Aa]"));
+        assert_eq!(errors, vec![
+            RegexError { span: 3..4, message: "flag not supported".to_owned() }]);
+    }
+
+    #[test]
+    fn test_posix_classes() {
+        assert_eq!(parse_regex("[[:digit:]x]"), Ast::Alternation(
+            Box::new(Ast::Range(b'0', b'9')),
+            Box::new(Ast::Range(b'x', b'x')),
+        ));
+        assert_eq!(parse_regex("[[:^space:]]").to_string(),
+            "[\\x00-\\x08]|[\\x0E-\\x1F]|[!-\\xFF]");
+        assert_eq!(parse_regex("[[:alpha:]_]").to_string(), "[A-Z]|[a-z]|_");
+        assert_eq!(complement(&[(0, 255)]), vec![]);
+        assert_eq!(complement(&[]), vec![(0, 255)]);
+    }
 }
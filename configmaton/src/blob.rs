@@ -1,11 +1,22 @@
-// WARNING: No endianness handling is implemented yet, as we have no use case for BigEndian.
+// WARNING: The blobs are in the native byte order of the target which builds them. The blobs of
+// the other byte order get swapped when they are read (see `Swap`), which costs a walk through
+// the whole blob, see `root::TargetLayout`. The words (lengths, indices, pointers) take 8 bytes
+// on every target, so that the blobs built on 64-bit targets are usable on 32-bit ones (e.g. wasm32) and
+// vice versa: they are stored as u64 and `BlobPtr`.
 
+// The blob layer is built on unsafe, caller-checked pointer juggling; a `# Safety` section on every
+// accessor would only repeat "the blob must be valid and deserialized".
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::{Cell, RefCell};
 use std::mem::{align_of, size_of};
+use std::ops::RangeBounds;
 use std::marker::PhantomData;
 
 use twox_hash::XxHash64;
 
 use crate::guards::Guard;
+use root::BlobError;
 use vec::BlobVec;
 
 pub mod hashmap;
@@ -15,27 +26,40 @@ pub mod flagellum;
 pub mod tupellum;
 pub mod vec;
 pub mod sediment;
+pub mod vec_of_vecs;
 pub mod vecmap;
 pub mod listmap;
-pub mod arrmap;
+pub mod rangemap;
+pub mod memmap;
+pub mod cheader;
+pub mod context;
 pub mod state;
 pub mod bdd;
 pub mod keyval_state;
 pub mod automaton;
+pub mod root;
+pub mod reloc;
+pub mod snapshot;
 
-pub trait MyHash {
-    fn my_hash(&self) -> usize;
+// How keys of a BlobHashMap are hashed. The seed is stored in the map, so that each blob can use
+// its own (e.g. random, for hash-flood resistance). The hashes are 64-bit on every target, as they
+// select the buckets of the blobs.
+pub trait HashStrategy<K> {
+    fn hash(seed: u64, key: &K) -> u64;
 }
 
-impl MyHash for u8 {
-    fn my_hash(&self) -> usize {
-        *self as usize
+// XxHash64, of the byte for bytes.
+pub struct XxHashStrategy;
+
+impl HashStrategy<u8> for XxHashStrategy {
+    fn hash(seed: u64, key: &u8) -> u64 {
+        XxHash64::oneshot(seed, &[*key])
     }
 }
 
-impl MyHash for &[u8] {
-    fn my_hash(&self) -> usize {
-        XxHash64::oneshot(1234, self) as usize
+impl HashStrategy<&[u8]> for XxHashStrategy {
+    fn hash(seed: u64, key: &&[u8]) -> u64 {
+        XxHash64::oneshot(seed, key)
     }
 }
 
@@ -57,7 +81,7 @@ impl Matches<Guard> for u8 {
     }
 }
 
-impl<'a, 'b> Matches<BlobVec<'a, u8>> for &'b [u8] {
+impl<'a> Matches<BlobVec<'a, u8>> for &[u8] {
     unsafe fn matches(&self, other: &BlobVec<'a, u8>) -> bool {
         *self == other.as_ref()
     }
@@ -69,6 +93,61 @@ impl<T> Matches<T> for AnyMatch {
     unsafe fn matches(&self, _: &T) -> bool { true }
 }
 
+// Matches keys within a range, e.g. `RangeMatch(2..5)` or `RangeMatch(b"a".as_ref()..b"b")`.
+pub struct RangeMatch<R>(pub R);
+
+impl<K: Ord, R: RangeBounds<K>> Matches<K> for RangeMatch<R> {
+    unsafe fn matches(&self, other: &K) -> bool {
+        self.0.contains(other)
+    }
+}
+
+impl<'a, 'r, R: RangeBounds<&'r [u8]>> Matches<BlobVec<'a, u8>> for RangeMatch<R> {
+    unsafe fn matches(&self, other: &BlobVec<'a, u8>) -> bool {
+        self.0.contains(&other.as_ref())
+    }
+}
+
+pub struct AndMatch<A, B>(pub A, pub B);
+
+impl<T, A: Matches<T>, B: Matches<T>> Matches<T> for AndMatch<A, B> {
+    unsafe fn matches(&self, other: &T) -> bool {
+        self.0.matches(other) && self.1.matches(other)
+    }
+}
+
+pub struct OrMatch<A, B>(pub A, pub B);
+
+impl<T, A: Matches<T>, B: Matches<T>> Matches<T> for OrMatch<A, B> {
+    unsafe fn matches(&self, other: &T) -> bool {
+        self.0.matches(other) || self.1.matches(other)
+    }
+}
+
+pub struct NotMatch<A>(pub A);
+
+impl<T, A: Matches<T>> Matches<T> for NotMatch<A> {
+    unsafe fn matches(&self, other: &T) -> bool {
+        !self.0.matches(other)
+    }
+}
+
+// Matches by a closure. The closure may keep state (e.g. count the visited keys), hence the
+// RefCell, as `matches` takes `&self`.
+pub struct PredMatch<F>(pub RefCell<F>);
+
+impl<F> PredMatch<F> {
+    pub fn new(f: F) -> Self {
+        PredMatch(RefCell::new(f))
+    }
+}
+
+impl<T, F: FnMut(&T) -> bool> Matches<T> for PredMatch<F> {
+    unsafe fn matches(&self, other: &T) -> bool {
+        (self.0.borrow_mut())(other)
+    }
+}
+
 pub trait UnsafeIterator {
     type Item;
     unsafe fn next(&mut self) -> Option<Self::Item>;
@@ -76,6 +155,12 @@ pub trait UnsafeIterator {
 
 pub struct FakeSafeIterator<T: UnsafeIterator>(pub T);
 
+// Structures of variable size, which know where their data ends, so that they can be walked over
+// in sequences (see `Sediment::iter`).
+pub trait Stride {
+    unsafe fn end(&self) -> *const u8;
+}
+
 impl<T: UnsafeIterator> Iterator for FakeSafeIterator<T> {
     type Item = T::Item;
     fn next(&mut self) -> Option<Self::Item> {
@@ -83,8 +168,10 @@ impl<T: UnsafeIterator> Iterator for FakeSafeIterator<T> {
     }
 }
 
+// Saturating, so that the offsets read from a corrupted blob end up out of bounds instead of
+// overflowing.
 fn align_up(offset: usize, align: usize) -> usize {
-    (offset + align - 1) & !(align - 1)
+    offset.saturating_add(align - 1) & !(align - 1)
 }
 
 pub fn align_up_mut_ptr<A, B>(a: *mut A) -> *mut B {
@@ -111,65 +198,324 @@ impl Reserve {
 pub struct BuildCursor<A>{
     pub cur: usize,
     pub buf: *mut u8,
+    // The length of the buffer, see `try_get_mut`.
+    pub end: usize,
+    // How the deserialization treats the scalars.
+    pub swap: Swap,
+    // Where the deserialization records the pointers between the structures, null for unchecked.
+    pub targets: *const Targets,
     _phantom: PhantomData<A>,
 }
 
+// A field of a blob whose bytes depend on the byte order (of which a blob holds the one of its
+// builder, see `root::TargetLayout`).
+pub trait Scalar: Copy {
+    fn swap_bytes(self) -> Self;
+}
+
+macro_rules! scalar {
+    ($($t:ty),+) => {$(
+        impl Scalar for $t {
+            fn swap_bytes(self) -> Self { <$t>::swap_bytes(self) }
+        }
+    )+};
+}
+
+scalar!(u8, u16, u32, u64, u128, usize);
+
+impl Scalar for f64 {
+    fn swap_bytes(self) -> Self { f64::from_bits(self.to_bits().swap_bytes()) }
+}
+
+impl Scalar for Guard {
+    fn swap_bytes(self) -> Self { Guard(self.0.swap_bytes(), self.1.swap_bytes()) }
+}
+
+// Whether the deserialization swaps the byte order of the scalars: `Load` for a blob of the other
+// byte order, `Store` to turn a blob (not yet deserialized) into the other byte order. Either way,
+// every scalar of the blob is passed to `fix` exactly once (or to `peek` before that), which
+// returns it in the native byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Swap {
+    None,
+    Load,
+    Store,
+}
+
+impl Swap {
+    // Swap the scalar in place (unless None), returning its native value.
+    pub fn fix<T: Scalar>(self, x: &mut T) -> T {
+        match self {
+            Swap::None => *x,
+            Swap::Load => { *x = x.swap_bytes(); *x }
+            Swap::Store => { let native = *x; *x = native.swap_bytes(); native }
+        }
+    }
+
+    // The native value of a scalar which has not been fixed yet.
+    pub fn peek<T: Scalar>(self, x: &T) -> T {
+        if self == Swap::Load { x.swap_bytes() } else { *x }
+    }
+}
+
+// The result of deserialization: the cursor behind the deserialized structure, or the place where
+// the blob turned out to be corrupted.
+pub type CursorResult<A> = Result<BuildCursor<A>, BlobError>;
+
 impl<A> BuildCursor<A> {
     pub fn new(buf: *mut u8) -> Self {
-        Self { cur: 0, buf, _phantom: PhantomData }
+        Self::bounded(buf, usize::MAX)
     }
 
-    pub fn goto<B>(&self, at: *mut B) -> BuildCursor<B> {
-        BuildCursor {
-            cur: at as usize - self.buf as usize,
-            buf: self.buf,
-            _phantom: PhantomData
+    // A cursor of a buffer of `len` bytes.
+    pub fn bounded(buf: *mut u8, len: usize) -> Self {
+        Self {
+            cur: 0, buf, end: len, swap: Swap::None, targets: std::ptr::null(),
+            _phantom: PhantomData,
         }
     }
 
+    pub fn goto<B>(&self, at: *mut B) -> BuildCursor<B> {
+        BuildCursor { cur: at as usize - self.buf as usize, ..self.transmute() }
+    }
+
     pub fn inc(&mut self) {
-        self.cur += size_of::<A>();
+        self.cur = self.cur.saturating_add(size_of::<A>());
     }
 
     pub fn behind<B>(&self, n: usize) -> BuildCursor<B> {
         BuildCursor {
-            cur: align_up(self.cur + size_of::<A>() * n, align_of::<B>()),
-            buf: self.buf,
-            _phantom: PhantomData
+            cur: align_up(
+                self.cur.saturating_add(size_of::<A>().saturating_mul(n)), align_of::<B>()),
+            ..self.transmute()
         }
     }
 
     pub fn align<B>(&self) -> BuildCursor<B> {
-        BuildCursor {
-            cur: align_up(self.cur, align_of::<B>()),
-            buf: self.buf,
-            _phantom: PhantomData
-        }
+        BuildCursor { cur: align_up(self.cur, align_of::<B>()), ..self.transmute() }
     }
 
     pub fn transmute<B>(&self) -> BuildCursor<B> {
         BuildCursor {
             cur: self.cur,
             buf: self.buf,
+            end: self.end,
+            swap: self.swap,
+            targets: self.targets,
             _phantom: PhantomData
         }
     }
 
+    // Record the start of a `target` structure at the cursor, see `Targets`.
+    pub unsafe fn mark(&self, target: Target) {
+        if let Some(targets) = self.targets.as_ref() {
+            targets.starts[target as usize].borrow_mut().push(self.cur);
+        }
+    }
+
     pub unsafe fn get_mut(&self) -> *mut A {
         self.buf.add(self.cur) as *mut A
     }
+
+    // Like `get_mut`, but the whole item must lie within the buffer.
+    pub unsafe fn try_get_mut(&self) -> Result<*mut A, BlobError> {
+        match self.cur.checked_add(size_of::<A>()) {
+            Some(end) if end <= self.end => Ok(self.get_mut()),
+            _ => Err(BlobError::Corrupt { offset: self.cur }),
+        }
+    }
 }
 
 impl<A> Clone for BuildCursor<A> {
     fn clone(&self) -> Self {
-        Self { cur: self.cur, buf: self.buf, _phantom: PhantomData }
+        self.transmute()
     }
 }
 
-pub struct Shifter(pub *const u8);
+// A pointer within the blob, taking 8 bytes on every target. Until the blob is deserialized, it
+// holds the offset of the target from the start of the blob, as a u64.
+#[repr(C, align(8))]
+pub struct BlobPtr<T> {
+    // The upper half of the offset on 32-bit targets, zero. It is placed like in a u64.
+    #[cfg(target_endian = "big")]
+    high: [u8; 8 - size_of::<usize>()],
+    ptr: *const T,
+    #[cfg(target_endian = "little")]
+    high: [u8; 8 - size_of::<usize>()],
+}
+
+impl<T> BlobPtr<T> {
+    pub const NULL: Self = Self::new(std::ptr::null());
+
+    pub const fn new(ptr: *const T) -> Self {
+        BlobPtr { ptr, high: [0; 8 - size_of::<usize>()] }
+    }
+
+    // The offset of the target, for the serialized blob.
+    pub fn offset(offset: usize) -> Self {
+        Self::new(offset as *const T)
+    }
+
+    pub fn get(&self) -> *const T {
+        self.ptr
+    }
+
+    // Also for the offsets not fixed yet: in the other byte order, a 32-bit target keeps the
+    // significant bytes in `high`.
+    pub fn is_null(&self) -> bool {
+        self.ptr.is_null() && self.high == [0; 8 - size_of::<usize>()]
+    }
+}
+
+impl<T> Clone for BlobPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for BlobPtr<T> {}
+
+impl<T> PartialEq for BlobPtr<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for BlobPtr<T> {}
+
+impl<T> std::fmt::Debug for BlobPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.ptr.fmt(f)
+    }
+}
+
+// The structures referred to by the pointers of other structures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    U8State,
+    KeyValState,
+    Bdd,
+    U8Tags,
+}
+
+// The starts of the structures walked by a deserialization and the pointers to them. A pointer
+// into the middle of a structure (or to a structure of another type) would be followed to bytes
+// which have never been deserialized, so it is rejected by `check` once the walk is over.
+#[derive(Default)]
+pub struct Targets {
+    starts: [RefCell<Vec<usize>>; 4],
+    pointers: RefCell<Vec<(Target, usize)>>,
+    // The offsets of all the pointer fields shifted, if recorded, see `recording_fields`.
+    fields: Option<RefCell<Vec<usize>>>,
+}
+
+impl Targets {
+    // Also record the offsets of the pointer fields, e.g. for a relocation table.
+    pub fn recording_fields() -> Self {
+        Targets { fields: Some(RefCell::default()), ..Targets::default() }
+    }
+
+    // The offsets of the pointer fields recorded, ascending.
+    pub fn into_fields(self) -> Vec<usize> {
+        let mut fields = self.fields.map(RefCell::into_inner).unwrap_or_default();
+        fields.sort_unstable();
+        fields
+    }
+
+    pub fn check(&self) -> Result<(), BlobError> {
+        for starts in self.starts.iter() { starts.borrow_mut().sort_unstable(); }
+        for &(target, offset) in self.pointers.borrow().iter() {
+            check_starts(&[offset], &self.starts[target as usize].borrow())?;
+        }
+        Ok(())
+    }
+}
+
+// Check that the offsets point to the starts (sorted) of the parts of a structure, e.g. of the
+// values of a map, which are shared by the pointers of the map.
+pub fn check_starts(offsets: &[usize], starts: &[usize]) -> Result<(), BlobError> {
+    match offsets.iter().find(|offset| starts.binary_search(offset).is_err()) {
+        Some(&offset) => Err(BlobError::Corrupt { offset }),
+        None => Ok(()),
+    }
+}
+
+// Turns the offsets of a serialized blob into pointers. With `Swap::Store`, it only swaps them.
+pub struct Shifter {
+    buf: *const u8,
+    end: usize,
+    swap: Swap,
+    targets: *const Targets,
+}
+
 impl Shifter {
-    pub unsafe fn shift<T>(&self, x: &mut *const T) {
-        *x = self.0.add(*x as *const u8 as usize) as *const T
+    // Shifts into the buffer of the cursor, within its bounds.
+    pub fn of<A>(cur: &BuildCursor<A>) -> Self {
+        Shifter { buf: cur.buf, end: cur.end, swap: cur.swap, targets: cur.targets }
+    }
+
+    // See `Swap::fix`.
+    pub fn fix<T: Scalar>(&self, x: &mut T) -> T {
+        self.swap.fix(x)
+    }
+
+    // `fix` as a callback of a deserialization.
+    pub fn scalar<T: Scalar>(&self, x: &mut T) -> Result<(), BlobError> {
+        self.fix(x);
+        Ok(())
+    }
+
+    // The offset must point to an aligned T within the buffer.
+    pub unsafe fn shift<T>(&self, x: &mut BlobPtr<T>) -> Result<(), BlobError> {
+        self.shift_sized(x, size_of::<T>())
+    }
+
+    // Like `shift`, for unions of differently sized variants, of which only the first `size` bytes
+    // (e.g. of the kind) must be within the buffer.
+    pub unsafe fn shift_sized<T>(&self, x: &mut BlobPtr<T>, size: usize)
+        -> Result<(), BlobError>
+    {
+        self.shift_offset(x, size).map(|_| ())
+    }
+
+    // Like `shift_sized`, for a pointer to the start of a `target` structure, which is checked by
+    // `Targets::check` after the walk.
+    pub unsafe fn shift_to<T>(&self, x: &mut BlobPtr<T>, size: usize, target: Target)
+        -> Result<(), BlobError>
+    {
+        let offset = self.shift_offset(x, size)?;
+        if let Some(targets) = self.targets.as_ref() {
+            targets.pointers.borrow_mut().push((target, offset));
+        }
+        Ok(())
+    }
+
+    // Like `shift`, for a pointer to the structure deserialized at the cursor (e.g. to the next
+    // node of a list).
+    pub unsafe fn shift_at<T>(&self, x: &mut BlobPtr<T>, cur: &BuildCursor<T>)
+        -> Result<(), BlobError>
+    {
+        let offset = self.shift_offset(x, size_of::<T>())?;
+        if offset != cur.cur { return Err(BlobError::Corrupt { offset }); }
+        Ok(())
+    }
+
+    // Like `shift_sized`, returning the offset of the target.
+    pub unsafe fn shift_offset<T>(&self, x: &mut BlobPtr<T>, size: usize)
+        -> Result<usize, BlobError>
+    {
+        let word = self.fix(&mut *(x as *mut BlobPtr<T> as *mut u64));
+        let offset = usize::try_from(word).unwrap_or(usize::MAX);
+        if !offset.is_multiple_of(align_of::<T>())
+            || offset.checked_add(size).is_none_or(|end| end > self.end)
+        {
+            return Err(BlobError::Corrupt { offset });
+        }
+        if let Some(fields) = self.targets.as_ref().and_then(|targets| targets.fields.as_ref()) {
+            fields.borrow_mut().push(x as *const BlobPtr<T> as usize - self.buf as usize);
+        }
+        if self.swap != Swap::Store { x.ptr = self.buf.add(offset) as *const T; }
+        Ok(offset)
     }
 }
 
@@ -207,39 +553,127 @@ pub trait Build {
     type Origin;
 }
 
+// Why an origin cannot be serialized, reported by the `try_*` variants of reserve and serialize.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    // A reference to an item (e.g. a state) which does not exist.
+    IndexOutOfRange { what: &'static str, index: usize, len: usize },
+    // The origin violates a structural requirement of its container.
+    Malformed(&'static str),
+    // A length which does not fit into the length type of its container.
+    TooLong { len: usize, max: usize },
+}
+
+impl std::fmt::Display for BuildError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::IndexOutOfRange { what, index, len } =>
+                write!(f, "{} index {} out of range (there are {})", what, index, len),
+            BuildError::Malformed(msg) => write!(f, "malformed origin: {}", msg),
+            BuildError::TooLong { len, max } =>
+                write!(f, "length {} exceeds the maximum of {}", len, max),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// The first error of fallible callbacks, which are passed to a `reserve` as infallible ones. That
+// is how the `try_reserve`s of the containers share the layout with their `reserve`s.
+#[derive(Default)]
+pub struct FirstError(Cell<Option<BuildError>>);
+
+impl FirstError {
+    // The callback, skipped after the first error.
+    pub fn wrap<'e, X: ?Sized + 'e, F: FnMut(&X, &mut Reserve) -> Result<(), BuildError> + 'e>
+        (&'e self, mut f: F) -> impl FnMut(&X, &mut Reserve) + 'e
+    {
+        move |x, sz| {
+            let error = match self.0.take() { None => f(x, sz).err(), error => error };
+            self.0.set(error);
+        }
+    }
+
+    pub fn into_result<T>(self, value: T) -> Result<T, BuildError> {
+        match self.0.into_inner() { None => Ok(value), Some(error) => Err(error) }
+    }
+}
+
+pub fn check_indices<'i, I: IntoIterator<Item = &'i usize>>
+    (what: &'static str, indices: I, len: usize) -> Result<(), BuildError>
+{
+    for index in indices {
+        if *index >= len { return Err(BuildError::IndexOutOfRange { what, index: *index, len }); }
+    }
+    Ok(())
+}
+
+// The type of a stored length, see `BlobVec`.
+pub trait BlobLen: Scalar + 'static {
+    const MAX: usize;
+    fn from_usize(n: usize) -> Option<Self>;
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! blob_len {
+    ($($t:ty),+) => {$(
+        impl BlobLen for $t {
+            const MAX: usize = if size_of::<$t>() < size_of::<usize>()
+                { <$t>::MAX as usize } else { usize::MAX };
+            fn from_usize(n: usize) -> Option<Self> { n.try_into().ok() }
+            fn to_usize(self) -> usize { self as usize }
+        }
+    )+};
+}
+
+blob_len!(u8, u16, u32, u64, usize);
+
 impl Build for u8 { type Origin = u8; }
 impl Build for Guard { type Origin = Guard; }
 impl Build for usize { type Origin = usize; }
+// The words of the blobs, e.g. indices, see `BlobPtr`.
+impl Build for u64 { type Origin = usize; }
 impl Build for () { type Origin = (); }
 
 #[cfg(test)]
 pub mod tests {
-    use tupellum::Tupellum;
+    use tupellum::{Tupellum, Tupellum3};
 
     use crate::char_enfa::OrderedIxs;
 
+    use std::ops::Bound;
+
     use super::*;
     use super::{
         hashmap::*, assoc_list::*, state::{*, build::*}, vecmap::*, listmap::*, flagellum::*,
-        sediment::*,
+        sediment::*, list::SizedList, rangemap::RangeMap,
+        vec_of_vecs::VecOfVecs,
     };
     use crate::char_nfa;
 
+    #[test]
+    fn blob_ptr() {
+        let ptr = BlobPtr::<u32>::offset(0x1234);
+        assert_eq!(unsafe { std::mem::transmute::<BlobPtr<u32>, u64>(ptr) }, 0x1234);
+        assert_eq!(size_of::<BlobPtr<u32>>(), size_of::<u64>());
+        assert!(BlobPtr::<u32>::NULL.is_null());
+    }
+
     #[test]
     pub fn test_blobvec() {
         let origin = vec![1usize, 3, 5];
         let mut sz = Reserve(0);
-        let my_addr = BlobVec::<usize>::reserve(&origin, &mut sz);
+        let my_addr = BlobVec::<u64>::reserve(&origin, &mut sz);
         assert_eq!(my_addr, 0);
-        assert_eq!(sz.0, 4 * size_of::<usize>());
+        assert_eq!(sz.0, 4 * size_of::<u64>());
         let mut buf = vec![0u8; sz.0];
         let mut cur = BuildCursor::new(buf.as_mut_ptr());
-        cur = unsafe { BlobVec::<usize>::serialize(&origin, cur, |x, xcur| { *xcur = *x; }) };
+        cur = unsafe { BlobVec::<u64>::serialize(&origin, cur, |x, xcur| { *xcur = *x as u64; }) };
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let mut cur = BuildCursor::new(buf.as_mut_ptr());
-        cur = unsafe { BlobVec::<usize>::deserialize(cur, |_| ()) };
+        cur = unsafe { BlobVec::<u64>::deserialize(cur, |_| Ok(())) }.unwrap();
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
-        let blobvec = unsafe { &*(buf.as_ptr() as *const BlobVec<usize>) };
+        let blobvec = unsafe { &*(buf.as_ptr() as *const BlobVec<u64>) };
         assert_eq!(blobvec.len, 3);
         assert_eq!(unsafe { blobvec.get(0) }, &1);
         assert_eq!(unsafe { blobvec.get(1) }, &3);
@@ -252,6 +686,61 @@ pub mod tests {
         assert_eq!(unsafe{ blobvec.as_ref() }, &[1, 3, 5]);
     }
 
+    #[test]
+    pub fn test_blobvec_len() {
+        let origin = b"abc".to_vec();
+        let mut sz = Reserve(0);
+        BlobVec::<u8, u16>::try_reserve(&origin, &mut sz).unwrap();
+        assert_eq!(sz.0, size_of::<u16>() + 3);
+        let mut buf = vec![0u16; sz.0];
+        let buf = buf.as_mut_ptr() as *mut u8;
+        let _: BuildCursor<()> = unsafe {
+            BlobVec::<u8, u16>::serialize(&origin, BuildCursor::new(buf), |x, y| { *y = *x; }) };
+        let blobvec = unsafe { &*(buf as *const BlobVec<u8, u16>) };
+        assert_eq!(blobvec.len(), 3);
+        assert_eq!(unsafe { blobvec.as_ref() }, b"abc");
+        assert_eq!(unsafe { blobvec.behind::<u8>() as *const u8 }, unsafe { buf.add(5) });
+
+        // The offsets of a vector of vectors are bounded by the total of the items.
+        let origins = vec![vec![0u8; 200], vec![0u8; 100]];
+        assert_eq!(VecOfVecs::<u8, u8>::try_reserve(&origins, &mut Reserve(0)),
+            Err(BuildError::TooLong { len: 300, max: 255 }));
+        let mut sz = Reserve(0);
+        VecOfVecs::<u8, u16>::try_reserve(&origins, &mut sz).unwrap();
+        assert_eq!(sz.0, 4 * size_of::<u16>() + 300);
+        let mut buf = vec![0u16; sz.0];
+        let buf = buf.as_mut_ptr() as *mut u8;
+        let vecs = unsafe {
+            let _: BuildCursor<()> = VecOfVecs::<u8, u16>::serialize(
+                &origins, BuildCursor::new(buf), |x, y| { *y = *x; });
+            let _: BuildCursor<()> =
+                VecOfVecs::<u8, u16>::deserialize(BuildCursor::new(buf), |_| Ok(())).unwrap();
+            &*(buf as *const VecOfVecs<u8, u16>)
+        };
+        assert_eq!(unsafe { vecs.iter().map(<[u8]>::len).collect::<Vec<_>>() }, vec![200, 100]);
+
+        let origin = vec![0u8; 256];
+        assert_eq!(BlobVec::<u8, u8>::try_reserve(&origin, &mut Reserve(0)),
+            Err(BuildError::TooLong { len: 256, max: 255 }));
+        assert!(BlobVec::<u8, u16>::try_reserve(&origin, &mut Reserve(0)).is_ok());
+
+        // The error of an element fails the container, which reserves the same as `reserve`.
+        let origins = vec![vec![1u8], origin, vec![2]];
+        let mut calls = 0;
+        let err = Sediment::<BlobVec<u8, u8>>::try_reserve(&origins, &mut Reserve(0), |xs, sz| {
+            calls += 1;
+            BlobVec::<u8, u8>::try_reserve(xs, sz).map(|_| ())
+        });
+        assert_eq!(err, Err(BuildError::TooLong { len: 256, max: 255 }));
+        assert_eq!(calls, 2);
+        let (mut sz0, mut sz1) = (Reserve(1), Reserve(1));
+        let addr0 = Sediment::<BlobVec<u8, u16>>::reserve(&origins, &mut sz0,
+            |xs, sz| { BlobVec::<u8, u16>::reserve(xs, sz); });
+        let addr1 = Sediment::<BlobVec<u8, u16>>::try_reserve(&origins, &mut sz1,
+            |xs, sz| BlobVec::<u8, u16>::try_reserve(xs, sz).map(|_| ()));
+        assert_eq!((Ok(addr0), sz0.0), (addr1, sz1.0));
+    }
+
     #[test]
     pub fn test_vecmap() {
         let origin = vec![(1, b"foo".to_vec()), (3, b"hello".to_vec()), (5, b"".to_vec())];
@@ -259,7 +748,7 @@ pub mod tests {
         let addr = VecMap::<usize, BlobVec<u8>>::reserve(&origin, &mut sz, |x, sz| {
             BlobVec::<u8>::reserve(x, sz);
         });
-        assert_eq!(addr, if align_of::<usize>() == 1 { 0 } else { align_of::<usize>() });
+        assert_eq!(addr, align_of::<u64>());
         let mut buf = vec![0u8; sz.0];
         let mut cur = BuildCursor::new(unsafe { buf.as_mut_ptr().add(addr) });
         cur = unsafe { VecMap::<usize, BlobVec<u8>>::serialize(&origin, cur,
@@ -269,9 +758,9 @@ pub mod tests {
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let mut cur = BuildCursor::new(unsafe { buf.as_mut_ptr().add(addr) });
         cur = unsafe { VecMap::<usize, BlobVec<u8>>::deserialize(cur,
-            |_| (),
-            |xcur| BlobVec::<u8>::deserialize(xcur, |_| ())
-        )};
+            |_| Ok(()),
+            |xcur| BlobVec::<u8>::deserialize(xcur, |_| Ok(()))
+        )}.unwrap();
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let vecmap = unsafe {
             &*(buf.as_ptr().add(addr) as *const VecMap::<usize, BlobVec<u8>>) };
@@ -279,7 +768,7 @@ pub mod tests {
         let mut iter = unsafe { vecmap.iter_matches(&EqMatch(&3)) };
         let (k, v) = unsafe { iter.next().unwrap() };
         assert_eq!((k, unsafe { v.as_ref() }), (&3, b"hello".as_ref()));
-        assert_eq!(unsafe { iter.next() }.is_none(), true);
+        assert!(unsafe { iter.next() }.is_none());
 
         let mut iter = unsafe { vecmap.iter_matches(&AnyMatch) };
         let (k, v) = unsafe { iter.next().unwrap() };
@@ -288,6 +777,34 @@ pub mod tests {
         assert_eq!((k, unsafe { v.as_ref() }), (&3, b"hello".as_ref()));
         let (k, v) = unsafe { iter.next().unwrap() };
         assert_eq!((k, unsafe { v.as_ref() }), (&5, b"".as_ref()));
+
+        let range_keys = |iter: &mut vecmap::VecMapRangeIter<usize, BlobVec<u8>>| {
+            let mut keys = vec![];
+            while let Some((k, _)) = unsafe { iter.next() } { keys.push(*k); }
+            keys
+        };
+        assert_eq!(range_keys(&mut unsafe { vecmap.iter_range(2..=5) }), vec![3, 5]);
+        assert_eq!(range_keys(&mut unsafe { vecmap.iter_range(1..5) }), vec![1, 3]);
+        assert_eq!(range_keys(&mut unsafe { vecmap.iter_range(..) }), vec![1, 3, 5]);
+        assert_eq!(range_keys(&mut unsafe { vecmap.iter_range(6..) }), Vec::<usize>::new());
+        let exclusive = (Bound::Excluded(3), Bound::Excluded(5));
+        assert_eq!(range_keys(&mut unsafe { vecmap.iter_range(exclusive) }), Vec::<usize>::new());
+
+        fn keys<M: Matches<usize>>(vecmap: &VecMap<usize, BlobVec<u8>>, m: &M) -> Vec<usize> {
+            let mut iter = unsafe { vecmap.iter_matches(m) };
+            let mut keys = vec![];
+            while let Some((k, _)) = unsafe { iter.next() } { keys.push(*k); }
+            keys
+        }
+        assert_eq!(keys(vecmap, &NotMatch(EqMatch(&3))), vec![1, 5]);
+        assert_eq!(keys(vecmap, &OrMatch(EqMatch(&1), EqMatch(&5))), vec![1, 5]);
+        assert_eq!(keys(vecmap, &AndMatch(NotMatch(EqMatch(&1)), NotMatch(EqMatch(&5)))), vec![3]);
+        let mut visited = 0;
+        assert_eq!(keys(vecmap, &PredMatch::new(|k: &usize| { visited += 1; *k > 2 })), vec![3, 5]);
+        assert_eq!(visited, 3);
+        assert_eq!(keys(vecmap, &RangeMatch(2..=5)), vec![3, 5]);
+        assert_eq!(keys(vecmap, &RangeMatch(..3)), vec![1]);
+        assert_eq!(keys(vecmap, &NotMatch(RangeMatch(2..4))), vec![1, 5]);
     }
 
     #[test]
@@ -302,7 +819,7 @@ pub mod tests {
             |x, sz| { BlobVec::<u8>::reserve(x, sz); },
             |x, sz| { BlobVec::<u8>::reserve(x, sz); },
         );
-        assert_eq!(addr, if align_of::<usize>() == 1 { 0 } else { align_of::<usize>() });
+        assert_eq!(addr, align_of::<u64>());
         let mut buf = vec![0u8; sz.0];
         let mut cur = BuildCursor::new(unsafe { buf.as_mut_ptr().add(addr) });
         cur = unsafe { ListMap::<BlobVec<u8>, BlobVec<u8>>::serialize(&origin, cur,
@@ -312,9 +829,9 @@ pub mod tests {
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let mut cur = BuildCursor::new(unsafe { buf.as_mut_ptr().add(addr) });
         cur = unsafe { ListMap::<BlobVec<u8>, BlobVec<u8>>::deserialize(cur,
-            |xcur| BlobVec::<u8>::deserialize(xcur, |_| ()),
-            |xcur| BlobVec::<u8>::deserialize(xcur, |_| ()),
-        )};
+            |xcur| BlobVec::<u8>::deserialize(xcur, |_| Ok(())),
+            |xcur| BlobVec::<u8>::deserialize(xcur, |_| Ok(())),
+        )}.unwrap();
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let vecmap = unsafe {
             &*(buf.as_ptr().add(addr) as *const ListMap::<BlobVec<u8>, BlobVec<u8>>) };
@@ -325,16 +842,19 @@ pub mod tests {
         assert_eq!(unsafe { (k.as_ref(), v.as_ref()) }, (b"aa".as_ref(), b"foo".as_ref()));
         let (k, v) = unsafe { iter.next().unwrap() };
         assert_eq!(unsafe { (k.as_ref(), v.as_ref()) }, (b"aa".as_ref(), b"".as_ref()));
-        assert_eq!(unsafe { iter.next() }.is_none(), true);
+        assert!(unsafe { iter.next() }.is_none());
+
+        let range = RangeMatch(b"ab".as_ref()..);
+        let mut iter = unsafe { vecmap.iter_matches(&range) };
+        let (k, v) = unsafe { iter.next().unwrap() };
+        assert_eq!(unsafe { (k.as_ref(), v.as_ref()) }, (b"bb".as_ref(), b"hello".as_ref()));
+        assert!(unsafe { iter.next() }.is_none());
     }
 
     #[test]
     pub fn test_blobhashmap() {
         let origin0 = vec![(1, b"foo".to_vec()), (3, b"hello".to_vec()), (5, b"".to_vec())];
-        let mut origin = vec![vec![], vec![], vec![], vec![]];
-        for (k, v) in origin0 {
-            origin[k as usize & 3].push((k, v));
-        }
+        let origin = (7, bucketize::<XxHashStrategy, _, _>(7, 2, origin0, |(k, _)| k));
         let mut sz = Reserve(0);
         let my_addr = BlobHashMap::<AssocList<Flagellum<u8, BlobVec<u8>>>>::reserve(
             &origin, &mut sz,
@@ -368,15 +888,41 @@ pub mod tests {
         let mut cur = BuildCursor::new(buf.as_mut_ptr());
         cur = unsafe { BlobHashMap::<AssocList<Flagellum<u8, BlobVec<u8>>>>::deserialize(cur,
             |alist_cur| AssocList::<Flagellum<u8, BlobVec<u8>>>::deserialize(alist_cur,
-                |kv_cur| Flagellum::<u8, BlobVec<u8>>::deserialize(kv_cur, |_| (), |v_cur|
-                    BlobVec::<u8>::deserialize(v_cur, |_| ())
+                |kv_cur| Flagellum::<u8, BlobVec<u8>>::deserialize(kv_cur, |_| Ok(()), |v_cur|
+                    BlobVec::<u8>::deserialize(v_cur, |_| Ok(()))
                 )
             )
-        )};
+        )}.unwrap();
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let hash = unsafe { &*(buf.as_ptr() as
             *const BlobHashMap::<AssocList<Flagellum<u8, BlobVec<u8>>>>) };
         assert_eq!(unsafe { hash.get(&3).unwrap().as_ref() }, b"hello".as_ref());
+
+        let mut all = unsafe { hash.all() }
+            .map(|(k, v)| (*k, unsafe { v.as_ref() }))
+            .collect::<Vec<_>>();
+        all.sort();
+        assert_eq!(all, vec![(1, b"foo".as_ref()), (3, b"hello".as_ref()), (5, b"".as_ref())]);
+
+        type Map<'a> = BlobHashMap<'a, AssocList<'a, Flagellum<'a, u8, BlobVec<'a, u8>>>>;
+        assert!(Map::check(&origin).is_ok());
+        assert!(Map::check(&(0, vec![vec![], vec![], vec![]])).is_err());
+    }
+
+    #[test]
+    fn test_hash_strategies() {
+        assert_ne!(XxHashStrategy::hash(0, &b"ab".as_ref()), XxHashStrategy::hash(1, &b"ab".as_ref()));
+        assert_eq!(XxHashStrategy::hash(7, &b'a'), XxHashStrategy::hash(7, &b"a".as_ref()));
+
+        // Unlike `c ^ seed`, the seed does not just permute the buckets of the bytes: the bytes
+        // colliding under one seed mostly do not collide under another.
+        let buckets = |seed| (0..=255u8).map(|c| XxHashStrategy::hash(seed, &c) & 7)
+            .collect::<Vec<_>>();
+        let (b0, b1) = (buckets(0), buckets(1));
+        let together = (0..256).flat_map(|c| (0..c).map(move |d| (c, d)))
+            .filter(|&(c, d)| b0[c] == b0[d] && b1[c] == b1[d])
+            .count();
+        assert!(together < 32 * 31 * 8 / 4, "{}", together);
     }
 
     #[test]
@@ -399,20 +945,123 @@ pub mod tests {
         let cur = BuildCursor::new(unsafe { buf.as_mut_ptr().add(0) });
         unsafe { Tupellum::<Sediment<BlobVec<u8>>, BlobVec<u8>>::deserialize::<(), _, _>(cur,
             |xcur| Sediment::<BlobVec<u8>>::deserialize(xcur,
-                |xcur| BlobVec::<u8>::deserialize(xcur, |_| ())),
-            |xcur| BlobVec::<u8>::deserialize(xcur, |_| ()),
-        )};
+                |xcur| BlobVec::<u8>::deserialize(xcur, |_| Ok(()))),
+            |xcur| BlobVec::<u8>::deserialize(xcur, |_| Ok(())),
+        )}.unwrap();
         let tupellum = unsafe {
             &*(buf.as_ptr() as *const Tupellum::<Sediment<BlobVec<u8>>, BlobVec<u8>>) };
-        let mut behind: *const BlobVec<u8> = std::ptr::null();
-        let mut contents = vec![];
-        unsafe { tupellum.a.each( |x| {
-            contents.push(x.as_ref());
-            behind = x.behind();
-            behind
-        })};
+        let contents = unsafe { tupellum.a.iter() }.map(|x| unsafe { x.as_ref() }).collect::<Vec<_>>();
         assert_eq!(contents, vec![b"".as_slice(), b"foo".as_slice(), b"hello".as_slice()]);
-        assert_eq!(unsafe { (*behind).as_ref() }, b"barr".as_slice());
+        let behind: &BlobVec<u8> = unsafe { tupellum.a.behind() };
+        assert_eq!(unsafe { behind.as_ref() }, b"barr".as_slice());
+    }
+
+    #[test]
+    fn test_sediment_iter() {
+        type S<'a> = Sediment<'a, Sediment<'a, BlobVec<'a, usize>>>;
+        let origin = vec![vec![vec![1, 2], vec![]], vec![], vec![vec![3]]];
+        let mut sz = Reserve(0);
+        S::reserve(&origin, &mut sz, |xs, sz| { Sediment::<BlobVec<usize>>::reserve(xs, sz,
+            |xs, sz| { BlobVec::<usize>::reserve(xs, sz); }); });
+        BlobVec::<u8>::reserve(&b"end".to_vec(), &mut sz);
+        let mut buf = vec![0u8; sz.0 + size_of::<u64>()];
+        let buf = align_up_mut_ptr::<u8, u64>(buf.as_mut_ptr()) as *mut u8;
+        let cur = BuildCursor::new(buf);
+        let cur = unsafe { S::serialize(&origin, cur,
+            |x, xcur| Sediment::<BlobVec<usize>>::serialize(x, xcur,
+                |x, bcur| BlobVec::<usize>::serialize(x, bcur, |y, ycur| { *ycur = *y; }))) };
+        let _: BuildCursor<()> = unsafe {
+            BlobVec::<u8>::serialize(&b"end".to_vec(), cur, |y, ycur| { *ycur = *y; }) };
+        let sediment = unsafe { &*(buf as *const S) };
+
+        let contents = unsafe { sediment.iter() }
+            .map(|x| unsafe { x.iter() }.map(|y| unsafe { y.as_ref() }).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, vec![vec![[1, 2].as_slice(), &[]], vec![], vec![&[3]]]);
+        let behind: &BlobVec<u8> = unsafe { sediment.behind() };
+        assert_eq!(unsafe { behind.as_ref() }, b"end");
+    }
+
+    #[test]
+    fn test_tupellum3() {
+        type T<'a> = Tupellum3<'a, BlobVec<'a, u8>, BlobVec<'a, usize>, BlobVec<'a, u8>>;
+        let origin = (b"ab".to_vec(), vec![1, 2, 3], b"xyz".to_vec());
+        let mut sz = Reserve(0);
+        T::reserve(&origin, &mut sz,
+            |xs, sz| { BlobVec::<u8>::reserve(xs, sz); },
+            |xs, sz| { BlobVec::<usize>::reserve(xs, sz); },
+            |xs, sz| { BlobVec::<u8>::reserve(xs, sz); },
+        );
+        let mut buf = vec![0u8; sz.0 + size_of::<u64>()];
+        let buf = align_up_mut_ptr::<u8, u64>(buf.as_mut_ptr()) as *mut u8;
+        let _: BuildCursor<()> = unsafe { T::serialize(&origin, BuildCursor::new(buf),
+            |xs, cur| BlobVec::<u8>::serialize(xs, cur, |y, ycur| { *ycur = *y; }),
+            |xs, cur| BlobVec::<usize>::serialize(xs, cur, |y, ycur| { *ycur = *y; }),
+            |xs, cur| BlobVec::<u8>::serialize(xs, cur, |y, ycur| { *ycur = *y; }),
+        )};
+        let _: BuildCursor<()> = unsafe { T::deserialize(BuildCursor::new(buf),
+            |cur| BlobVec::<u8>::deserialize(cur, |_| Ok(())),
+            |cur| BlobVec::<usize>::deserialize(cur, |_| Ok(())),
+            |cur| BlobVec::<u8>::deserialize(cur, |_| Ok(())),
+        )}.unwrap();
+        let t = unsafe { &*(buf as *const T) };
+        let b: &BlobVec<usize> = unsafe { t.a.behind() };
+        let c: &BlobVec<u8> = unsafe { b.behind() };
+        assert_eq!(unsafe { t.a.as_ref() }, b"ab");
+        assert_eq!(unsafe { b.as_ref() }, &[1, 2, 3]);
+        assert_eq!(unsafe { c.as_ref() }, b"xyz");
+    }
+
+    #[test]
+    fn test_sized_list() {
+        let origins = vec![vec![], vec![b"a".to_vec(), b"bc".to_vec(), b"".to_vec()]];
+        for origin in origins {
+            let mut sz = Reserve(0);
+            SizedList::<BlobVec<u8>>::reserve(&origin, &mut sz,
+                |xs, sz| { BlobVec::<u8>::reserve(xs, sz); });
+            let mut buf = vec![0u8; sz.0 + size_of::<u64>()];
+            let buf = align_up_mut_ptr::<u8, u64>(buf.as_mut_ptr()) as *mut u8;
+            let end: BuildCursor<()> = unsafe { SizedList::<BlobVec<u8>>::serialize(&origin,
+                BuildCursor::new(buf),
+                |x, xcur| BlobVec::<u8>::serialize(x, xcur, |y, ycur| { *ycur = *y; })) };
+            assert!(end.cur <= sz.0);
+            let _: BuildCursor<()> = unsafe { SizedList::<BlobVec<u8>>::deserialize(
+                BuildCursor::new(buf), |xcur| BlobVec::<u8>::deserialize(xcur, |_| Ok(()))) }
+                .unwrap();
+            let list = unsafe { &*(buf as *const SizedList<BlobVec<u8>>) };
+
+            assert_eq!(list.len, origin.len() as u64);
+            let contents = unsafe { list.iter() }.map(|x| unsafe { x.as_ref() }.to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(contents, origin);
+            for (i, x) in origin.iter().enumerate() {
+                assert_eq!(unsafe { list.nth(i).unwrap().as_ref() }, x.as_slice());
+            }
+            assert!(unsafe { list.nth(origin.len()) }.is_none());
+        }
+    }
+
+    #[test]
+    fn test_vec_of_vecs() {
+        let origin = vec![b"foo".to_vec(), b"".to_vec(), b"hello".to_vec()];
+        let mut sz = Reserve(1);
+        let addr = VecOfVecs::<u8>::reserve(&origin, &mut sz);
+        BlobVec::<usize>::reserve(&vec![42], &mut sz);
+        let mut buf = vec![0u8; sz.0 + size_of::<u64>()];
+        let buf = align_up_mut_ptr::<u8, u64>(buf.as_mut_ptr()) as *mut u8;
+        let cur = BuildCursor::new(unsafe { buf.add(addr) });
+        let cur = unsafe { VecOfVecs::<u8>::serialize(&origin, cur, |x, y| { *y = *x; }) };
+        let _: BuildCursor<()> = unsafe { BlobVec::<usize>::serialize(&vec![42], cur,
+            |x, y| { *y = *x; }) };
+        let vv = unsafe { &*(buf.add(addr) as *const VecOfVecs<u8>) };
+
+        assert_eq!(vv.len(), 3);
+        assert_eq!(unsafe { vv.get(2) }, b"hello");
+        assert_eq!(unsafe { vv.get(1) }, b"");
+        assert_eq!(unsafe { vv.get(0) }, b"foo");
+        assert_eq!(unsafe { vv.iter() }.collect::<Vec<_>>(), vec![b"foo".as_slice(), b"", b"hello"]);
+        let behind: &BlobVec<usize> = unsafe { vv.behind() };
+        assert_eq!(unsafe { behind.as_ref() }, &[42]);
     }
 
     pub struct TestU8BuildConfig;
@@ -420,29 +1069,49 @@ pub mod tests {
         fn guard_size_keep(&self) -> u32 { 2 }
         fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
         fn dense_guard_count(&self) -> usize { 3 }
+        // Fixed, so that the tests can compare the blobs.
+        fn hash_seed(&self) -> u64 { 0 }
+    }
+
+    // The blob of the JSON config, read back as if received from elsewhere.
+    pub fn compile(config: &str) -> crate::keyval_nfa::Msg {
+        use crate::keyval_nfa::{Msg, Parser};
+        let (parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        unsafe {
+            Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len())
+        }
     }
 
     pub unsafe fn create_states<'a>(buf: &'a mut Vec<u8>, qs: Vec<char_nfa::State>)
         -> Vec<&'a U8State<'a>>
     {
-        let states = qs.iter().map(|q|
-            U8StatePrepared::prepare(&q, &TestU8BuildConfig)).collect();
+        create_states_with(buf, qs, &TestU8BuildConfig)
+    }
+
+    pub unsafe fn create_states_with<'a, Cfg: U8BuildConfig>
+        (buf: &'a mut Vec<u8>, qs: Vec<char_nfa::State>, cfg: &Cfg) -> Vec<&'a U8State<'a>>
+    {
+        let states = qs.iter().map(|q| U8StatePrepared::prepare(q, cfg)).collect();
         let mut sz = Reserve(0);
         let mut addrs = Vec::<usize>::new();
         let list_addr = Sediment::<U8State>::reserve(&states, &mut sz, |state, sz| {
             addrs.push(U8State::reserve(state, sz));
         });
         assert_eq!(list_addr, 0);
-        buf.resize(sz.0 + size_of::<usize>(), 0);
+        let sets = tag_sets(&states);
+        let mut tagptrs = hashbrown::HashMap::new();
+        reserve_tag_pool(&sets, &mut sz, &mut tagptrs);
+        buf.resize(sz.0 + align_of::<u128>(), 0);
         let buf = align_up_mut_ptr::<u8, u128>(buf.as_mut_ptr()) as *mut u8;
-        let mut cur = BuildCursor::new(buf);
-        cur = unsafe { Sediment::<U8State>::serialize(&states, cur,
-            |state, state_cur| { U8State::serialize(state, state_cur, &addrs) })};
-        assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
-        let mut cur = BuildCursor::new(buf);
-        cur = unsafe { Sediment::<U8State>::deserialize(cur,
-            |state_cur| U8State::deserialize(state_cur)) };
-        assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
+        unsafe {
+            let cur = Sediment::<U8State>::serialize(&states, BuildCursor::new(buf),
+                |state, state_cur| { U8State::serialize(state, state_cur, &addrs, &tagptrs) });
+            let _: BuildCursor<()> = serialize_tag_pool(&sets, cur);
+            let cur = Sediment::<U8State>::deserialize(BuildCursor::new(buf),
+                |state_cur| U8State::deserialize(state_cur)).unwrap();
+            let _: BuildCursor<()> = deserialize_tag_pool(cur).unwrap();
+        }
         (0..qs.len()).map(|i| &*(buf.add(addrs[i]) as *const U8State)).collect()
     }
 
@@ -460,6 +1129,37 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn dense_state_trans_len() {
+        let state = char_nfa::State {
+            tags: OrderedIxs(vec![]),
+            transitions: (0..4).map(|c| (Guard::from_range((c, c)), 0)).collect(),
+            end_transitions: vec![0],
+            is_deterministic: false,
+        };
+        let states = vec![U8StatePrepared::prepare(&state, &TestU8BuildConfig)];
+        assert_eq!(states[0].kind(), U8StateKind::Dense);
+        let mut sz = Reserve(0);
+        let mut addrs = Vec::<usize>::new();
+        Sediment::<U8State>::reserve(&states, &mut sz, |state, sz| {
+            addrs.push(U8State::reserve(state, sz));
+        });
+        let mut buf = vec![0u64; sz.0.div_ceil(size_of::<u64>())];
+        let buf = buf.as_mut_ptr() as *mut u8;
+        let tagptrs = hashbrown::HashMap::new();
+        unsafe {
+            let _: BuildCursor<()> = Sediment::<U8State>::serialize(&states, BuildCursor::new(buf),
+                |state, state_cur| U8State::serialize(state, state_cur, &addrs, &tagptrs));
+            // The length of the transitions, behind the kind and the tags.
+            let len = buf.add(addrs[0] + 2 * size_of::<u64>()) as *mut u64;
+            assert_eq!(*len, 257);
+            *len = 256;
+            let result: CursorResult<()> = Sediment::<U8State>::deserialize(
+                BuildCursor::new(buf), |state_cur| U8State::deserialize(state_cur));
+            assert!(matches!(result, Err(BlobError::Corrupt { .. })));
+        }
+    }
+
     #[test]
     fn test_states() {
         let states = vec![
@@ -470,11 +1170,13 @@ pub mod tests {
                     (Guard::from_range((b'a', b'a')), 1),
                     (Guard::from_range((b'd', b'z')), 1),
                 ],
+                end_transitions: vec![],
                 is_deterministic: false,
             },
             char_nfa::State {
                 tags: OrderedIxs(vec![1, 2]),
                 transitions: vec![(Guard::from_range((b'b', b'm')), 0)],
+                end_transitions: vec![],
                 is_deterministic: false,
             },
         ];
@@ -488,15 +1190,15 @@ pub mod tests {
 
         let mut iter = expect_dense(unsafe { state0.iter_matches(&b'a') });
         let mut succs = vec![
-            *unsafe { iter.next() }.unwrap(),
-            *unsafe { iter.next() }.unwrap(),
+            unsafe { iter.next() }.unwrap().get(),
+            unsafe { iter.next() }.unwrap().get(),
         ];
         assert!(unsafe { iter.next() }.is_none());
         succs.sort();
         assert_eq!(succs, [state0 as *const U8State, state1]);
 
         let mut iter = expect_dense(unsafe { state0.iter_matches(&b'p') });
-        let succs = vec![*unsafe { iter.next() }.unwrap()];
+        let succs = vec![unsafe { iter.next() }.unwrap().get()];
         assert!(unsafe { iter.next() }.is_none());
         assert_eq!(succs, vec![state1 as *const U8State]);
 
@@ -508,8 +1210,196 @@ pub mod tests {
         assert!(unsafe { iter.next() }.is_none());
         assert_eq!(succs, vec![state0 as *const U8State]);
 
-        let no_tags: &[usize] = &[];
+        let no_tags: &[u64] = &[];
         assert_eq!(unsafe { state0.get_tags() }, no_tags);
-        assert_eq!(unsafe { state1.get_tags() }, &[1usize, 2]);
+        assert_eq!(unsafe { state1.get_tags() }, &[1u64, 2]);
+
+        let mut succs = state0.successors(&b'a').map(|q| q as *const U8State).collect::<Vec<_>>();
+        succs.sort();
+        assert_eq!(succs, [state0 as *const U8State, state1]);
+        let mut succs = state1.successors(&b'c');
+        assert!(std::ptr::eq(succs.next().unwrap(), state0));
+        assert!(matches!(succs.guard(), TransitionGuard::Pattern(_)));
+        assert!(succs.next().is_none());
+        assert!(state1.successors(&b'a').next().is_none());
+    }
+    #[test]
+    fn test_ranged_states() {
+        struct RangedConfig;
+        impl U8BuildConfig for RangedConfig {
+            fn guard_size_keep(&self) -> u32 { 2 }
+            fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+            fn dense_guard_count(&self) -> usize { 3 }
+            fn max_ranged_ranges(&self) -> usize { 6 }
+        }
+
+        let states = vec![
+            char_nfa::State {
+                tags: OrderedIxs(vec![3]),
+                transitions: vec![
+                    (Guard::from_range((b'a', b'b')), 0),
+                    (Guard::from_range((b'a', b'a')), 1),
+                    (Guard::from_range((b'd', b'z')), 1),
+                ],
+                end_transitions: vec![],
+                is_deterministic: false,
+            },
+            char_nfa::State {
+                tags: OrderedIxs(vec![]),
+                transitions: vec![
+                    (Guard::from_range((b'a', b'a')), 0),
+                    (Guard::from_range((b'c', b'c')), 0),
+                    (Guard::from_range((b'e', b'e')), 0),
+                    (Guard::from_range((b'g', b'g')), 0),
+                ],
+                end_transitions: vec![],
+                is_deterministic: false,
+            },
+        ];
+        let mut buf = vec![];
+        let states = unsafe { create_states_with(&mut buf, states, &RangedConfig) };
+        let state0 = states[0];
+        let state1 = states[1];
+        assert_eq!(unsafe { state0.get_tags() }, &[3]);
+
+        fn succs<'a>(state: &'a U8State<'a>, c: u8) -> Vec<*const U8State<'a>> {
+            match unsafe { state.iter_matches(&c) } {
+                U8StateIterator::Ranged(iter) => {
+                    let mut succs = FakeSafeIterator(iter).map(BlobPtr::get).collect::<Vec<_>>();
+                    succs.sort();
+                    succs
+                },
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(succs(state0, b'a'), {
+            let mut expected = vec![state0 as *const U8State, state1];
+            expected.sort();
+            expected
+        });
+        assert_eq!(succs(state0, b'b'), vec![state0 as *const U8State]);
+        assert_eq!(succs(state0, b'c'), vec![]);
+        assert_eq!(succs(state0, b'z'), vec![state1 as *const U8State]);
+        assert_eq!(succs(state0, 255), vec![]);
+        assert_eq!(succs(state0, 0), vec![]);
+
+        assert_eq!(RangeMap::<BlobVec<u8>>::check(&(vec![(0, 0), (5, 1)], vec![vec![], vec![]])),
+            Ok(()));
+        assert!(RangeMap::<BlobVec<u8>>::check(&(vec![(1, 0)], vec![vec![]])).is_err());
+        assert!(RangeMap::<BlobVec<u8>>::check(&(vec![(0, 0), (0, 0)], vec![vec![]])).is_err());
+        assert_eq!(RangeMap::<BlobVec<u8>>::check(&(vec![(0, 1)], vec![vec![]])),
+            Err(BuildError::IndexOutOfRange { what: "RangeMap value", index: 1, len: 1 }));
+
+        // Nine ranges exceed the limit, so the second state stays dense.
+        expect_dense(unsafe { state1.iter_matches(&b'a') });
+    }
+
+    #[test]
+    fn test_end_transitions() {
+        struct RangedConfig;
+        impl U8BuildConfig for RangedConfig {
+            fn guard_size_keep(&self) -> u32 { 2 }
+            fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+            fn dense_guard_count(&self) -> usize { 2 }
+            fn max_ranged_ranges(&self) -> usize { 5 }
+        }
+
+        let state = |transitions: Vec<(u8, u8, usize)>, end_transitions| char_nfa::State {
+            tags: OrderedIxs(vec![]),
+            transitions: transitions.into_iter()
+                .map(|(a, b, q)| (Guard::from_range((a, b)), q)).collect(),
+            end_transitions,
+            is_deterministic: false,
+        };
+        let qs = || vec![
+            state(vec![(b'a', b'a', 1)], vec![1, 2]),
+            state(vec![(b'a', b'b', 0), (b'c', b'c', 1)], vec![]),
+            state(vec![(b'a', b'a', 1), (b'b', b'b', 2), (b'c', b'z', 0)], vec![0]),
+        ];
+
+        for ranged in [false, true] {
+            let mut buf = vec![];
+            let states = unsafe {
+                if ranged { create_states_with(&mut buf, qs(), &RangedConfig) }
+                else { create_states(&mut buf, qs()) }
+            };
+            let ptrs = states.iter().map(|q| BlobPtr::new(*q as *const U8State))
+                .collect::<Vec<_>>();
+            assert!(matches!(unsafe { states[0].iter_matches(&b'a') }, U8StateIterator::Sparse(_)));
+            assert_eq!(unsafe { states[0].end_successors() }, &[ptrs[1], ptrs[2]]);
+            assert_eq!(unsafe { states[1].end_successors() }, &[]);
+            assert_eq!(unsafe { states[2].end_successors() }, &[ptrs[0]]);
+            match unsafe { states[2].iter_matches(&b'a') } {
+                U8StateIterator::Ranged(_) => assert!(ranged),
+                U8StateIterator::Dense(_) => assert!(!ranged),
+                U8StateIterator::Sparse(_) => unreachable!(),
+            }
+
+            let mut runner = crate::char_runner::Runner::new([ptrs[0].get()]);
+            unsafe { runner.finish() };
+            assert_eq!(runner.states.into_iter().map(BlobPtr::new).collect::<Vec<_>>(), ptrs);
+        }
+    }
+
+    #[test]
+    fn narrow_dense_states() {
+        struct NarrowConfig;
+        impl U8BuildConfig for NarrowConfig {
+            fn guard_size_keep(&self) -> u32 { 2 }
+            fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+            fn dense_guard_count(&self) -> usize { 0 }
+            fn narrow_dense_offsets(&self) -> bool { true }
+        }
+        struct WideConfig;
+        impl U8BuildConfig for WideConfig {
+            fn guard_size_keep(&self) -> u32 { 2 }
+            fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+            fn dense_guard_count(&self) -> usize { 0 }
+        }
+
+        let qs = || vec![
+            char_nfa::State {
+                tags: OrderedIxs(vec![1]),
+                transitions: vec![
+                    (Guard::from_range((b'a', b'z')), 0), (Guard::from_range((b'x', b'x')), 1)],
+                end_transitions: vec![1],
+                is_deterministic: false,
+            },
+            char_nfa::State {
+                tags: OrderedIxs(vec![]),
+                transitions: vec![(Guard::from_range((0, 255)), 1)],
+                end_transitions: vec![],
+                is_deterministic: false,
+            },
+        ];
+        let narrow = U8StatePrepared::prepare(&qs()[0], &NarrowConfig);
+        let wide = U8StatePrepared::prepare(&qs()[0], &WideConfig);
+        assert_eq!((narrow.kind(), wide.kind()), (U8StateKind::NarrowDense, U8StateKind::Dense));
+        let (mut narrow_sz, mut wide_sz) = (Reserve(0), Reserve(0));
+        U8State::reserve(&narrow, &mut narrow_sz);
+        U8State::reserve(&wide, &mut wide_sz);
+        assert!(narrow_sz.0 + 258 * 6 <= wide_sz.0);
+
+        // The successors, as the indices of the states.
+        fn succs<'a>(states: &[&'a U8State<'a>], q: &'a U8State<'a>, c: Option<u8>)
+            -> Vec<usize>
+        {
+            let qs = match c {
+                Some(c) => FakeSafeIterator(expect_dense(unsafe { q.iter_matches(&c) }))
+                    .map(BlobPtr::get).collect::<Vec<_>>(),
+                None => unsafe { q.end_successors() }.iter().map(|q| q.get()).collect(),
+            };
+            qs.into_iter().map(|q| states.iter().position(|s| std::ptr::eq(*s, q)).unwrap())
+                .collect()
+        }
+        let (mut narrow_buf, mut wide_buf) = (vec![], vec![]);
+        let narrow = unsafe { create_states_with(&mut narrow_buf, qs(), &NarrowConfig) };
+        let wide = unsafe { create_states_with(&mut wide_buf, qs(), &WideConfig) };
+        for (n, w) in narrow.iter().zip(wide.iter()) {
+            assert_eq!(unsafe { n.get_tags() }, unsafe { w.get_tags() });
+            for c in (0..=255u8).map(Some).chain([None]) {
+                assert_eq!(succs(&narrow, n, c), succs(&wide, w, c));
+            }
+        }
     }
 }
@@ -1,5 +1,6 @@
 use super::{
-    Build, BuildCursor, Reserve, list::List, Assoc, Assocs, AssocsSuper, Matches, UnsafeIterator,
+    Build, BuildCursor, BuildError, CursorResult, FirstError, Reserve, list::List, Assoc, Assocs,
+    AssocsSuper, Matches, UnsafeIterator,
 };
 
 #[repr(C)]
@@ -8,10 +9,10 @@ pub struct AssocList<'a, KV>(List<'a, KV>);
 impl<'a, KV> AssocList<'a, KV> {
     pub unsafe fn deserialize
     <
-        F: FnMut(BuildCursor<KV>) -> BuildCursor<List<'a, KV>>,
+        F: FnMut(BuildCursor<KV>) -> CursorResult<List<'a, KV>>,
         After,
     >
-    (cur: BuildCursor<Self>, f: F) -> BuildCursor<After> {
+    (cur: BuildCursor<Self>, f: F) -> CursorResult<After> {
         <List<'a, KV>>::deserialize(cur.transmute(), f)
     }
 }
@@ -21,6 +22,14 @@ impl<'a, KV: Build> Build for AssocList<'a, KV> {
 }
 
 impl<'a, KV: Build> AssocList<'a, KV> {
+    pub fn try_reserve<F: FnMut(&KV::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> Result<usize, BuildError>
+    {
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(f));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<F: FnMut(&KV::Origin, &mut Reserve)>
     (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> usize
     { <List<'a, KV>>::reserve(origin, sz, f) }
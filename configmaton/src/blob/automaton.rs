@@ -1,13 +1,227 @@
-use super::{keyval_state::{Bytes, KeyValState}, sediment::Sediment, state::U8State, tupellum::Tupellum, vec::BlobVec};
-
-pub type States<'a> = Tupellum<'a, Sediment<'a, KeyValState<'a>>, Sediment<'a, U8State<'a>>>;
-pub type InitsAndStates<'a> = Tupellum<'a, BlobVec<'a, *const KeyValState<'a>>, States<'a>>;
-pub type ExtsAndAut<'a> = 
-    Tupellum<'a,
-        Sediment<'a, Bytes<'a>>,  // Exts
-        InitsAndStates<'a>
-    >;
-pub type Automaton<'a> = Tupellum<'a,
-    Sediment<'a, Bytes<'a>>,  // GetOlds
-    ExtsAndAut<'a>
+use hashbrown::HashSet;
+
+use super::{
+    keyval_state::{KeyValState, Leaf}, root::BlobRoot, sediment::Sediment,
+    state::{U8State, U8TagPool}, tupellum::Tupellum15, vec::BlobVec, vec_of_vecs::VecOfVecs, Build,
+    Scalar, UnsafeIterator, BlobPtr,
+};
+use crate::normalize::Normalizer;
+
+pub type Automaton<'a> = Tupellum15<'a,
+    VecOfVecs<'a, u8>,  // GetOlds
+    VecOfVecs<'a, u8>,  // Exts
+    BlobVec<'a, u64>,  // The rule of each of the Exts
+    BlobVec<'a, BlobPtr<KeyValState<'a>>>,  // Inits
+    VecOfVecs<'a, u8>,  // Keys of the defaults
+    VecOfVecs<'a, u8>,  // Values of the defaults, in the same order
+    VecOfVecs<'a, u8>,  // Keys with normalizers
+    BlobVec<'a, Normalizer>,  // Their normalizers, in the same order
+    VecOfVecs<'a, u8>,  // Keys of the patterns
+    BlobVec<'a, u64>,  // IDs of the patterns, in the same order
+    VecOfVecs<'a, u8>,  // Regexes of the patterns in the same order, empty if not embedded
+    BlobVec<'a, TagRule>,  // Sorted
+    Sediment<'a, KeyValState<'a>>,
+    Sediment<'a, U8State<'a>>,
+    U8TagPool<'a>,
 >;
+
+impl BlobRoot for Automaton<'_> {
+    const NAME: &'static str = "Automaton";
+    const SCHEMA: &'static str = "Automaton(getolds: VecOfVecs<u8>, exts: VecOfVecs<u8>, \
+        rules: BlobVec<u64>, inits: BlobVec<*KeyValState>, default_keys: VecOfVecs<u8>, \
+        default_values: VecOfVecs<u8>, normalized_keys: VecOfVecs<u8>, \
+        normalizers: BlobVec<Normalizer>, pattern_keys: VecOfVecs<u8>, \
+        pattern_ids: BlobVec<u64>, pattern_sources: VecOfVecs<u8>, \
+        tag_rules: BlobVec<TagRule(tag, rule, pattern, negated)>, \
+        keyval_states: Sediment<KeyValState(key, inits, num_guards, finals)>, \
+        u8_states: Sediment<U8State>, tag_pool: U8TagPool)";
+}
+
+// The sections of `Automaton`, in their order in the blob. Their offsets are listed by the section
+// directory of the blob (see `BlobHeader::sections`), so that they can be loaded separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Section {
+    Getolds,
+    Exts,
+    Rules,
+    Inits,
+    DefaultKeys,
+    DefaultValues,
+    NormalizedKeys,
+    Normalizers,
+    PatternKeys,
+    PatternIds,
+    PatternSources,
+    TagRules,
+    KeyValStates,
+    U8States,
+    TagPool,
+}
+
+impl Section {
+    pub const ALL: [Section; 15] = [
+        Section::Getolds, Section::Exts, Section::Rules, Section::Inits, Section::DefaultKeys,
+        Section::DefaultValues, Section::NormalizedKeys, Section::Normalizers,
+        Section::PatternKeys, Section::PatternIds, Section::PatternSources, Section::TagRules,
+        Section::KeyValStates, Section::U8States, Section::TagPool,
+    ];
+
+    // As in the schema and the memory map.
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Getolds => "getolds",
+            Section::Exts => "exts",
+            Section::Rules => "rules",
+            Section::Inits => "inits",
+            Section::DefaultKeys => "default_keys",
+            Section::DefaultValues => "default_values",
+            Section::NormalizedKeys => "normalized_keys",
+            Section::Normalizers => "normalizers",
+            Section::PatternKeys => "pattern_keys",
+            Section::PatternIds => "pattern_ids",
+            Section::PatternSources => "pattern_sources",
+            Section::TagRules => "tag_rules",
+            Section::KeyValStates => "keyval_states",
+            Section::U8States => "u8_states",
+            Section::TagPool => "tag_pool",
+        }
+    }
+}
+
+// A condition of the config: the key, the ID of the pattern (the tag of its DFA or numeric guard),
+// and its regex (or range) if it has been embedded (see `Parser::embed_patterns`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternInfo<'a> {
+    pub key: &'a [u8],
+    pub id: usize,
+    pub regex: Option<&'a [u8]>,
+}
+
+// A use of a tag (a pattern ID) by a condition: the rule and the index of the condition in
+// `patterns`. A regex shared by several conditions has one tag, so that a tag may have several
+// uses.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TagRule {
+    pub tag: u64,
+    pub rule: u64,
+    pub pattern: u64,
+    // 1 for a condition of `when_not`, satisfied by the values not matching the pattern.
+    pub negated: u64,
+}
+
+impl Build for TagRule { type Origin = TagRule; }
+
+impl Scalar for TagRule {
+    fn swap_bytes(self) -> Self {
+        TagRule {
+            tag: self.tag.swap_bytes(),
+            rule: self.rule.swap_bytes(),
+            pattern: self.pattern.swap_bytes(),
+            negated: self.negated.swap_bytes(),
+        }
+    }
+}
+
+impl Build for Normalizer { type Origin = Normalizer; }
+
+// The rules of the exts, i.e. of the commands emitted whenever the automaton starts.
+pub unsafe fn initial_rules<'a>(automaton: &Automaton<'a>) -> &'a [u64] {
+    let exts: &VecOfVecs<'a, u8> = automaton.a.behind();
+    exts.behind::<BlobVec<'a, u64>>().as_ref()
+}
+
+pub unsafe fn inits<'a>(automaton: &Automaton<'a>) -> &'a [BlobPtr<KeyValState<'a>>] {
+    let exts: &VecOfVecs<'a, u8> = automaton.a.behind();
+    let rules: &BlobVec<u64> = exts.behind();
+    rules.behind::<BlobVec<'a, BlobPtr<KeyValState<'a>>>>().as_ref()
+}
+
+unsafe fn behind_inits<'a, After>(automaton: &Automaton<'a>) -> &'a After {
+    let exts: &VecOfVecs<'a, u8> = automaton.a.behind();
+    let rules: &BlobVec<u64> = exts.behind();
+    let inits: &BlobVec<BlobPtr<KeyValState<'a>>> = rules.behind();
+    inits.behind()
+}
+
+// The key-values the config sets initially, in the order of the config.
+pub unsafe fn defaults<'a>(automaton: &Automaton<'a>)
+    -> impl Iterator<Item = (&'a [u8], &'a [u8])> + 'a
+{
+    let keys: &'a VecOfVecs<'a, u8> = behind_inits(automaton);
+    let values: &'a VecOfVecs<'a, u8> = keys.behind();
+    keys.iter().zip(values.iter())
+}
+
+// The keys whose values are normalized before matching, with their normalizers.
+pub unsafe fn normalizers<'a>(automaton: &Automaton<'a>)
+    -> impl Iterator<Item = (&'a [u8], Normalizer)> + 'a
+{
+    let default_keys: &'a VecOfVecs<'a, u8> = behind_inits(automaton);
+    let default_values: &'a VecOfVecs<'a, u8> = default_keys.behind();
+    let keys: &'a VecOfVecs<'a, u8> = default_values.behind();
+    let normalizers: &'a BlobVec<'a, Normalizer> = keys.behind();
+    keys.iter().zip(normalizers.as_ref().iter().copied())
+}
+
+unsafe fn behind_normalizers<'a, After>(automaton: &Automaton<'a>) -> &'a After {
+    let default_keys: &'a VecOfVecs<'a, u8> = behind_inits(automaton);
+    let default_values: &'a VecOfVecs<'a, u8> = default_keys.behind();
+    let keys: &'a VecOfVecs<'a, u8> = default_values.behind();
+    let normalizers: &'a BlobVec<'a, Normalizer> = keys.behind();
+    normalizers.behind()
+}
+
+// The conditions in the order of the config, each (key, pattern) pair once.
+pub unsafe fn patterns<'a>(automaton: &Automaton<'a>) -> Vec<PatternInfo<'a>> {
+    let keys: &'a VecOfVecs<'a, u8> = behind_normalizers(automaton);
+    let ids: &'a BlobVec<'a, u64> = keys.behind();
+    let regexes: &'a VecOfVecs<'a, u8> = ids.behind();
+    let embedded = !regexes.is_empty();
+    keys.iter().zip(ids.as_ref()).enumerate().map(|(ix, (key, id))| PatternInfo {
+        key,
+        id: *id as usize,
+        regex: if embedded { Some(regexes.get(ix)) } else { None },
+    }).collect()
+}
+
+// The uses of all the tags, sorted.
+pub unsafe fn tag_rules<'a>(automaton: &Automaton<'a>) -> &'a [TagRule] {
+    let keys: &'a VecOfVecs<'a, u8> = behind_normalizers(automaton);
+    let ids: &'a BlobVec<'a, u64> = keys.behind();
+    let regexes: &'a VecOfVecs<'a, u8> = ids.behind();
+    let tag_rules: &'a BlobVec<'a, TagRule> = regexes.behind();
+    tag_rules.as_ref()
+}
+
+// The uses of the tag, e.g. to translate the tags of the u8 states into the rules and conditions
+// of the config.
+pub unsafe fn uses_of_tag<'a>(automaton: &Automaton<'a>, tag: usize) -> &'a [TagRule] {
+    let tag_rules = tag_rules(automaton);
+    let tag = tag as u64;
+    let start = tag_rules.partition_point(|x| x.tag < tag);
+    let end = start + tag_rules[start..].partition_point(|x| x.tag == tag);
+    &tag_rules[start..end]
+}
+
+// The keyval states reachable from the inits (e.g. of `inits`), and the leaves of their
+// transitions.
+pub unsafe fn reachable_keyval_states<'a>(inits: &[BlobPtr<KeyValState<'a>>])
+    -> (HashSet<*const KeyValState<'a>>, HashSet<*const Leaf<'a>>)
+{
+    let mut frontier = inits.iter().map(BlobPtr::get).collect::<Vec<_>>();
+    let mut states = frontier.iter().copied().collect::<HashSet<_>>();
+    let mut leaves = HashSet::new();
+    while let Some(state) = frontier.pop() {
+        let mut keyvals = (*state).keyvals();
+        while let Some((_, tran)) = keyvals.next() {
+            tran.finals().for_each_leaf(&mut |leaf| {
+                leaves.insert(leaf as *const Leaf);
+                for right in leaf.states() {
+                    if states.insert(right.get()) { frontier.push(right.get()); }
+                }
+            });
+        }
+    }
+    (states, leaves)
+}
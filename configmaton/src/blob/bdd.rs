@@ -2,7 +2,23 @@ use std::marker::PhantomData;
 
 use hashbrown::HashMap;
 
-use super::{get_behind_struct, Build, BuildCursor, Reserve, Shifter};
+use super::{
+    get_behind_struct, root::BlobError, Build, BuildCursor, BuildError, CursorResult, FirstError,
+    Reserve, Shifter, Target, BlobPtr,
+};
+
+// The values of the variables of a BDD, e.g. the tags matched by the DFAs (see
+// `keyval_runner::MatchedTags`). Along a path of a BDD, the variables are queried in ascending
+// order, each one at most once.
+pub trait VarProvider<Var> {
+    fn holds(&self, var: &Var) -> bool;
+}
+
+impl<Var, F: Fn(&Var) -> bool> VarProvider<Var> for F {
+    fn holds(&self, var: &Var) -> bool {
+        self(var)
+    }
+}
 
 pub enum BddOrigin<Var, Leaf> {
     Leaf(Leaf),
@@ -59,6 +75,52 @@ impl<Var, Leaf> BddOrigin<Var, Leaf> {
         }
     }
 
+    // The origin counterpart of `Bdd::evaluate`, so that the compiler and the runtime share the
+    // semantics.
+    pub unsafe fn evaluate<F: FnMut(&Var) -> bool>(&self, mut f: F) -> &Leaf {
+        let mut cur = self;
+        loop {
+            match cur {
+                BddOrigin::Leaf(leaf) => return leaf,
+                _ => { cur = if f(cur.get_var()) { cur.get_pos() } else { cur.get_neg() }; }
+            }
+        }
+    }
+
+    pub unsafe fn evaluate_with<P: VarProvider<Var> + ?Sized>(&self, provider: &P) -> &Leaf {
+        self.evaluate(|var| provider.holds(var))
+    }
+
+    // Visit all leaves reachable from the node. Leaves shared by several paths are visited once
+    // per path.
+    pub unsafe fn for_each_leaf<E, F: FnMut(&Leaf) -> Result<(), E>>(&self, f: &mut F)
+        -> Result<(), E>
+    {
+        match self {
+            BddOrigin::Leaf(leaf) => f(leaf),
+            _ => {
+                self.get_pos().for_each_leaf(f)?;
+                self.get_neg().for_each_leaf(f)
+            },
+        }
+    }
+
+    // Update the variables and leaves of the owned nodes (the unowned ones are owned elsewhere in
+    // the same diagram, so everything is visited exactly once).
+    pub fn update<FV: FnMut(&mut Var), FL: FnMut(&mut Leaf)>(&mut self, fv: &mut FV, fl: &mut FL) {
+        match self {
+            BddOrigin::Leaf(leaf) => fl(leaf),
+            BddOrigin::NodeNoOwned { var, .. } => fv(var),
+            BddOrigin::NodePosOwned { var, pos, .. } => { fv(var); pos.update(fv, fl); },
+            BddOrigin::NodeNegOwned { var, neg, .. } => { fv(var); neg.update(fv, fl); },
+            BddOrigin::NodeBothOwned { var, pos, neg } => {
+                fv(var);
+                pos.update(fv, fl);
+                neg.update(fv, fl);
+            },
+        }
+    }
+
     pub fn owns_pos(&self) -> bool {
         match self {
             BddOrigin::Leaf(_) => false,
@@ -90,6 +152,9 @@ pub enum BddType {
     NodeBothOwned,
 }
 
+// Checked as an u32 when deserializing.
+const _: () = assert!(size_of::<BddType>() == size_of::<u32>());
+
 #[repr(C)]
 pub struct Bdd<'a, Var, Leaf> {
     type_: BddType,
@@ -99,14 +164,14 @@ pub struct Bdd<'a, Var, Leaf> {
 #[repr(C)]
 pub struct NodeNoOwned<'a, Var, Leaf> {
     var: Var,
-    pos: *const Bdd<'a, Var, Leaf>,
-    neg: *const Bdd<'a, Var, Leaf>,
+    pos: BlobPtr<Bdd<'a, Var, Leaf>>,
+    neg: BlobPtr<Bdd<'a, Var, Leaf>>,
 }
 
 #[repr(C)]
 pub struct NodeOwned<'a, Var, Leaf> {
     var: Var,
-    unowned: *const Bdd<'a, Var, Leaf>,
+    unowned: BlobPtr<Bdd<'a, Var, Leaf>>,
     owned: Bdd<'a, Var, Leaf>,
 }
 
@@ -118,69 +183,99 @@ impl<'a, Var, Leaf> Bdd<'a, Var, Leaf> {
                 BddType::Leaf => { return &*get_behind_struct(cur); }
                 BddType::NodeNoOwned => {
                     let node: &NodeNoOwned<Var, Leaf> = &*get_behind_struct(cur);
-                    cur = if f(&node.var) { &*node.pos } else { &*node.neg };
+                    cur = if f(&node.var) { &*node.pos.get() } else { &*node.neg.get() };
                 }
                 BddType::NodePosOwned => {
                     let node: &NodeOwned<Var, Leaf> = &*get_behind_struct(cur);
-                    cur = if f(&node.var) { &node.owned } else { &*node.unowned };
+                    cur = if f(&node.var) { &node.owned } else { &*node.unowned.get() };
                 }
                 BddType::NodeNegOwned => {
                     let node: &NodeOwned<Var, Leaf> = &*get_behind_struct(cur);
-                    cur = if f(&node.var) { &*node.unowned } else { &node.owned };
+                    cur = if f(&node.var) { &*node.unowned.get() } else { &node.owned };
                 }
                 BddType::NodeBothOwned => {
                     let node: &NodeOwned<Var, Leaf> = &*get_behind_struct(cur);
-                    cur = if f(&node.var) { &node.owned } else { &*node.unowned };
+                    cur = if f(&node.var) { &node.owned } else { &*node.unowned.get() };
                 }
             }
         }
     }
 
+    pub unsafe fn evaluate_with<P: VarProvider<Var> + ?Sized>(&self, provider: &P) -> &'a Leaf {
+        self.evaluate(|var| provider.holds(var))
+    }
+
+    // Visit the reachable leaves, the shared ones once per path.
+    pub unsafe fn for_each_leaf<F: FnMut(&'a Leaf)>(&self, f: &mut F) {
+        match self.type_ {
+            BddType::Leaf => f(&*get_behind_struct(self)),
+            BddType::NodeNoOwned => {
+                let node: &NodeNoOwned<Var, Leaf> = &*get_behind_struct(self);
+                (*node.pos.get()).for_each_leaf(f);
+                (*node.neg.get()).for_each_leaf(f);
+            }
+            _ => {
+                let node: &NodeOwned<Var, Leaf> = &*get_behind_struct(self);
+                node.owned.for_each_leaf(f);
+                (*node.unowned.get()).for_each_leaf(f);
+            }
+        }
+    }
+
     pub unsafe fn deserialize
     <
         After,
-        FLeaf: FnMut(BuildCursor<Leaf>) -> BuildCursor<Self>,
-        FVar: FnMut(&mut Var),
+        FLeaf: FnMut(BuildCursor<Leaf>) -> CursorResult<Self>,
+        FVar: FnMut(&mut Var) -> Result<(), BlobError>,
     >
     (
         mut cur: BuildCursor<Self>,
         mut f_leaf: FLeaf,
         mut f_var: FVar,
     )
-    -> BuildCursor<After>
+    -> CursorResult<After>
     {
-        let shifter = Shifter(cur.buf);
+        let shifter = Shifter::of(&cur);
         let mut todo_count: usize = 1;
         while todo_count > 0 {
-            let bdd = &mut *cur.get_mut();
-            match bdd.type_ {
-                BddType::Leaf => { cur = f_leaf(cur.behind(1)); }
-                BddType::NodeNoOwned => {
+            cur.mark(Target::Bdd);
+            // Matched as read, the field itself is swapped to the other byte order by
+            // `Swap::Store`.
+            let type_ = cur.swap.fix(&mut *cur.transmute::<u32>().try_get_mut()?);
+            if type_ > BddType::NodeBothOwned as u32 {
+                return Err(BlobError::Corrupt { offset: cur.cur });
+            }
+            const LEAF: u32 = BddType::Leaf as u32;
+            const NODE_NO_OWNED: u32 = BddType::NodeNoOwned as u32;
+            const NODE_BOTH_OWNED: u32 = BddType::NodeBothOwned as u32;
+            match type_ {
+                LEAF => { cur = f_leaf(cur.behind(1))?; }
+                NODE_NO_OWNED => {
                     let node_cur = cur.behind(1);
-                    let node: &mut NodeNoOwned<Var, Leaf> = &mut *node_cur.get_mut();
-                    f_var(&mut node.var);
-                    shifter.shift(&mut node.pos);
-                    shifter.shift(&mut node.neg);
+                    let node: &mut NodeNoOwned<Var, Leaf> = &mut *node_cur.try_get_mut()?;
+                    f_var(&mut node.var)?;
+                    shifter.shift_to(&mut node.pos, size_of::<Self>(), Target::Bdd)?;
+                    shifter.shift_to(&mut node.neg, size_of::<Self>(), Target::Bdd)?;
                     cur = node_cur.behind(1);
                 }
-                BddType::NodeBothOwned => {
-                    let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).get_mut();
-                    f_var(&mut node.var);
-                    shifter.shift(&mut node.unowned);
+                NODE_BOTH_OWNED => {
+                    let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).try_get_mut()?;
+                    f_var(&mut node.var)?;
+                    shifter.shift_to(&mut node.unowned, size_of::<Self>(), Target::Bdd)?;
                     todo_count += 2;
                     cur = cur.goto(&mut node.owned);
                 }
                 _ => {
-                    let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).get_mut();
-                    f_var(&mut node.var);
-                    shifter.shift(&mut node.unowned);
+                    let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).try_get_mut()?;
+                    f_var(&mut node.var)?;
+                    shifter.shift_to(&mut node.unowned, size_of::<Self>(), Target::Bdd)?;
                     todo_count += 1;
                     cur = cur.goto(&mut node.owned);
                 }
             }
             todo_count -= 1;
         }
-        cur.align()
+        Ok(cur.align())
     }
 }
 
@@ -189,6 +284,14 @@ impl<'a, Var: Build, Leaf: Build> Build for Bdd<'a, Var, Leaf> {
 }
 
 impl<'a, Var: Build, Leaf: Build> Bdd<'a, Var, Leaf> {
+    pub fn try_reserve<FLeaf: FnMut(&Leaf::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, fleaf: FLeaf) -> Result<usize, BuildError>
+    {
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(fleaf));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<FLeaf: FnMut(&Leaf::Origin, &mut Reserve)>
     (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut fleaf: FLeaf) -> usize
     {
@@ -200,26 +303,26 @@ impl<'a, Var: Build, Leaf: Build> Bdd<'a, Var, Leaf> {
             match origin {
                 BddOrigin::Leaf(leaf) => { fleaf(leaf, sz); }
                 BddOrigin::NodeNoOwned { .. } => {
-                    sz.add::<NodeNoOwned<Var::Origin, Leaf::Origin>>(1);
+                    sz.add::<NodeNoOwned<Var, Leaf>>(1);
                 }
                 BddOrigin::NodePosOwned { pos, .. } => {
-                    sz.add::<NodeOwned<Var::Origin, Leaf::Origin>>(0);
+                    sz.add::<NodeOwned<Var, Leaf>>(0);
                     sz.add::<Var>(1);
-                    sz.add::<*const Self>(1);
-                    todo.push(&pos);
+                    sz.add::<BlobPtr<Self>>(1);
+                    todo.push(pos);
                 }
                 BddOrigin::NodeNegOwned { neg, .. } => {
-                    sz.add::<NodeOwned<Var::Origin, Leaf::Origin>>(0);
+                    sz.add::<NodeOwned<Var, Leaf>>(0);
                     sz.add::<Var>(1);
-                    sz.add::<*const Self>(1);
-                    todo.push(&neg);
+                    sz.add::<BlobPtr<Self>>(1);
+                    todo.push(neg);
                 }
                 BddOrigin::NodeBothOwned { pos, neg, .. } => {
-                    sz.add::<NodeOwned<Var::Origin, Leaf::Origin>>(0);
+                    sz.add::<NodeOwned<Var, Leaf>>(0);
                     sz.add::<Var>(1);
-                    sz.add::<*const Self>(1);
-                    todo.push(&neg);
-                    todo.push(&pos);
+                    sz.add::<BlobPtr<Self>>(1);
+                    todo.push(neg);
+                    todo.push(pos);
                 }
             }
             sz.add::<Self>(0);
@@ -268,22 +371,22 @@ impl<'a, Var: Build, Leaf: Build> Bdd<'a, Var, Leaf> {
                     let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).get_mut();
                     fvar(var, &mut node.var);
                     cur = cur.goto(&mut node.owned);
-                    todo.push(&pos);
+                    todo.push(pos);
                 }
                 BddOrigin::NodeNegOwned { var, neg, .. } => {
                     bdd.type_ = BddType::NodeNegOwned;
                     let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).get_mut();
                     fvar(var, &mut node.var);
                     cur = cur.goto(&mut node.owned);
-                    todo.push(&neg);
+                    todo.push(neg);
                 }
                 BddOrigin::NodeBothOwned { var, pos, neg, .. } => {
                     bdd.type_ = BddType::NodeBothOwned;
                     let node: &mut NodeOwned<Var, Leaf> = &mut *cur.behind(1).get_mut();
                     fvar(var, &mut node.var);
                     cur = cur.goto(&mut node.owned);
-                    todo.push(&neg);
-                    todo.push(&pos);
+                    todo.push(neg);
+                    todo.push(pos);
                 }
             }
         }
@@ -295,25 +398,24 @@ impl<'a, Var: Build, Leaf: Build> Bdd<'a, Var, Leaf> {
                 BddOrigin::Leaf(_) => {}
                 BddOrigin::NodeNoOwned { pos, neg, .. } => {
                     let node: &mut NodeNoOwned<Var, Leaf> = &mut *curs[i].behind(1).get_mut();
-                    node.pos = *ptrmap.get(pos).unwrap() as *const Bdd<'a, Var, Leaf>;
-                    node.neg = *ptrmap.get(neg).unwrap() as *const Bdd<'a, Var, Leaf>;
+                    node.pos = BlobPtr::offset(*ptrmap.get(pos).unwrap());
+                    node.neg = BlobPtr::offset(*ptrmap.get(neg).unwrap());
                 }
                 BddOrigin::NodePosOwned { pos, neg, .. } => {
                     let node: &mut NodeOwned<Var, Leaf> = &mut *curs[i].behind(1).get_mut();
-                    node.unowned = *ptrmap.get(neg).unwrap() as *const Bdd<'a, Var, Leaf>;
-                    todo.push(&pos);
+                    node.unowned = BlobPtr::offset(*ptrmap.get(neg).unwrap());
+                    todo.push(pos);
                 }
                 BddOrigin::NodeNegOwned { neg, pos, .. } => {
                     let node: &mut NodeOwned<Var, Leaf> = &mut *curs[i].behind(1).get_mut();
-                    node.unowned = *ptrmap.get(pos).unwrap() as *const Bdd<'a, Var, Leaf>;
-                    todo.push(&neg);
+                    node.unowned = BlobPtr::offset(*ptrmap.get(pos).unwrap());
+                    todo.push(neg);
                 }
                 BddOrigin::NodeBothOwned { pos, neg, .. } => {
                     let node: &mut NodeOwned<Var, Leaf> = &mut *curs[i].behind(1).get_mut();
-                    node.unowned =
-                        *ptrmap.get(&(&**neg as *const _)).unwrap() as *const Bdd<'a, Var, Leaf>;
-                    todo.push(&neg);
-                    todo.push(&pos);
+                    node.unowned = BlobPtr::offset(*ptrmap.get(&(&**neg as *const _)).unwrap());
+                    todo.push(neg);
+                    todo.push(pos);
                 }
             }
             i += 1;
@@ -371,9 +473,9 @@ mod test {
             );
             Bdd::<u8, BlobVec<u8>>::deserialize::<(), _, _>(
                 cur,
-                |xcur| { BlobVec::<u8>::deserialize(xcur, |_| ()) },
-                |_| (),
-            );
+                |xcur| { BlobVec::<u8>::deserialize(xcur, |_| Ok(())) },
+                |_| Ok(()),
+            ).unwrap();
         }
         let bdd = unsafe { &*(buf.as_ptr() as *const Bdd<u8, BlobVec<u8>>) };
 
@@ -0,0 +1,231 @@
+use std::io::Write;
+use std::mem::{align_of, size_of};
+
+use super::{
+    automaton::{Automaton, TagRule}, bdd::{Bdd, NodeNoOwned, NodeOwned}, hashmap::BlobHashMap,
+    keyval_state::{KeyValState, Leaf, NumGuard}, list::{List, SizedList}, rangemap::RangeMap,
+    root::{BlobHeader, BlobRoot, BLOB_MAGIC, FORMAT_VERSION}, sediment::Sediment,
+    state::{U8DenseState, U8NarrowDenseState, U8RangedState, U8SparseState, U8State},
+    vec::BlobVec, vec_of_vecs::VecOfVecs,
+};
+use crate::guards::Guard;
+
+// Emit C definitions of the blob layout, so that native consumers can read blobs directly. The
+// sizes come from this build, and are checked by static assertions on the C side.
+pub fn write_c_header<W: Write>(mut writer: W) {
+    let mut write = |x: String| writer.write_all(x.as_bytes()).unwrap();
+
+    write(r#"#ifndef CONFIGMATON_BLOB_H
+#define CONFIGMATON_BLOB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+// The words of the blob take 8 bytes on every target, including the pointers, so that the blobs
+// are portable between 32-bit and 64-bit targets.
+#define CFGM_PTR(T) union { const T *ptr; uint64_t word; }
+
+// Data behind a structure starts at the first address aligned for its type.
+#define CFGM_ALIGN_UP(p, a) \
+    ((const void *)(((uintptr_t)(p) + (a) - 1) & ~(uintptr_t)((a) - 1)))
+#define CFGM_BEHIND(p, T) \
+    ((const T *)CFGM_ALIGN_UP((const char *)(p) + sizeof(*(p)), _Alignof(T)))
+
+// Followed by `len` items of type T, see CFGM_BLOB_VEC_ITEMS.
+typedef struct { uint64_t len; } cfgm_blob_vec;
+#define CFGM_BLOB_VEC_ITEMS(v, T) CFGM_BEHIND(v, T)
+// The data behind a vector, e.g. the next section.
+#define CFGM_BLOB_VEC_BEHIND(v, T, After) \
+    ((const After *)CFGM_ALIGN_UP(CFGM_BLOB_VEC_ITEMS(v, T) + (v)->len, _Alignof(After)))
+
+// Followed by `len` items of variable size, each behind the previous one.
+typedef struct { uint64_t len; } cfgm_sediment;
+
+// Followed by `len + 1` offsets (uint64_t), and the items. Vector `i` spans the items from
+// offset `i` to offset `i + 1`.
+typedef struct { uint64_t len; } cfgm_vec_of_vecs;
+#define CFGM_VEC_OF_VECS_OFFSETS(v) CFGM_BEHIND(v, uint64_t)
+#define CFGM_VEC_OF_VECS_ITEMS(v, T) \
+    ((const T *)CFGM_ALIGN_UP(CFGM_VEC_OF_VECS_OFFSETS(v) + (v)->len + 1, _Alignof(T)))
+// The same with uint16_t offsets.
+typedef struct { uint16_t len; } cfgm_vec_of_vecs16;
+#define CFGM_VEC_OF_VECS16_OFFSETS(v) CFGM_BEHIND(v, uint16_t)
+#define CFGM_VEC_OF_VECS16_ITEMS(v, T) \
+    ((const T *)CFGM_ALIGN_UP(CFGM_VEC_OF_VECS16_OFFSETS(v) + (v)->len + 1, _Alignof(T)))
+
+typedef struct cfgm_list { CFGM_PTR(struct cfgm_list) next; } cfgm_list;
+#define CFGM_LIST_VALUE(l, T) CFGM_BEHIND(l, T)
+
+// Followed by a cfgm_list, unless `len` is zero.
+typedef struct { uint64_t len; } cfgm_sized_list;
+
+"#.to_owned());
+
+    write(format!(r#"typedef struct {{ _Alignas({}) uint8_t bits[{}]; }} cfgm_guard;
+
+// Followed by `len` range starts (uint8_t), `len` pointers to the values, and the values.
+typedef struct {{ uint64_t len; uint64_t value_count; }} cfgm_range_map;
+
+// Followed by further `mask` bucket pointers, null for empty buckets. In the explicit transitions
+// of the sparse states, byte `c` is in bucket `XXH64(&c, 1, seed) & mask` and the keys of each
+// bucket are ascending.
+typedef struct {{ uint64_t mask; uint64_t seed; CFGM_PTR(void) buckets[1]; }} cfgm_hashmap;
+
+enum {{ CFGM_U8_SPARSE = 0, CFGM_U8_DENSE = 1, CFGM_U8_RANGED = 2, CFGM_U8_NARROW_DENSE = 3 }};
+
+// The end_trans pointers (successors after the end of the value) are null if there are none. The
+// tags point into the tag pool of the automaton (null if there are none), so states with equal tags
+// have equal tag pointers.
+typedef struct {{
+    uint8_t kind;
+    CFGM_PTR(cfgm_blob_vec) tags;
+    CFGM_PTR(cfgm_blob_vec) end_trans;
+    CFGM_PTR(cfgm_hashmap) explicit_trans;
+    cfgm_blob_vec pattern_trans;
+}} cfgm_u8_sparse_state;
+
+typedef struct {{
+    uint8_t kind;
+    CFGM_PTR(cfgm_blob_vec) tags;
+    cfgm_vec_of_vecs trans;  // 257 vectors of state pointers, the last one after the value end
+}} cfgm_u8_dense_state;
+
+typedef struct {{
+    uint8_t kind;
+    CFGM_PTR(cfgm_blob_vec) tags;
+    cfgm_vec_of_vecs16 trans;  // like in cfgm_u8_dense_state
+}} cfgm_u8_narrow_dense_state;
+
+typedef struct {{
+    uint8_t kind;
+    CFGM_PTR(cfgm_blob_vec) tags;
+    CFGM_PTR(cfgm_blob_vec) end_trans;
+    cfgm_range_map trans;
+}} cfgm_u8_ranged_state;
+
+typedef union {{
+    cfgm_u8_sparse_state sparse;
+    cfgm_u8_dense_state dense;
+    cfgm_u8_ranged_state ranged;
+    cfgm_u8_narrow_dense_state narrow_dense;
+}} cfgm_u8_state;
+
+enum {{
+    CFGM_BDD_LEAF = 0,
+    CFGM_BDD_NODE_NO_OWNED = 1,
+    CFGM_BDD_NODE_POS_OWNED = 2,
+    CFGM_BDD_NODE_NEG_OWNED = 3,
+    CFGM_BDD_NODE_BOTH_OWNED = 4,
+}};
+
+// Followed by the leaf or the node.
+typedef struct {{ int{}_t type; }} cfgm_bdd;
+typedef struct {{
+    uint64_t var; CFGM_PTR(cfgm_bdd) pos; CFGM_PTR(cfgm_bdd) neg;
+}} cfgm_bdd_node_no_owned;
+typedef struct {{ uint64_t var; CFGM_PTR(cfgm_bdd) unowned; cfgm_bdd owned; }} cfgm_bdd_node_owned;
+
+// A leaf is a cfgm_blob_vec of keyval state pointers, followed by the getolds and the exts (two
+// cfgm_sediments of byte vectors), the rule group (a byte vector, empty for none) and the rule IDs
+// of the exts (a cfgm_blob_vec of uint64_t).
+//
+// A keyval state is a cfgm_sized_list of transitions. A transition is a byte vector (the key),
+// followed by a vector of u8 state pointers (the initial states of the value DFA), a vector of
+// cfgm_num_guards and a cfgm_bdd.
+typedef cfgm_sized_list cfgm_keyval_state;
+
+// Sets the tag if the value is a decimal number within [min, max], the bounds being infinite if
+// the range is open.
+typedef struct {{ uint64_t tag; double min; double max; }} cfgm_num_guard;
+
+// A blob starts with a header, followed by the automaton. The header starts with CFGM_BLOB_MAGIC,
+// the blob must be built on a target of the same alignment of the words and of the buffer (in
+// bytes) and byte order (zero for little-endian), the length covers the whole blob and the root
+// tag is CFGM_AUTOMATON_ROOT_TAG for the blobs of this layout. Unless zero, relocations is the
+// offset of the relocation table behind the automaton: a cfgm_blob_vec of the ascending offsets
+// of all the pointer fields of the blob, which are to be shifted by the address of the blob.
+// Sections is the offset of the section directory behind the automaton: a cfgm_blob_vec of the
+// offsets of the sections of the automaton, in their order. Content_hash is the XXH64 (seed zero)
+// of the bytes behind the header up to the relocation table, as built, or zero in older blobs.
+typedef struct {{
+    _Alignas({}) char magic[4];
+    uint8_t word_alignment;
+    uint8_t big_endian;
+    uint8_t alignment;
+    uint8_t version;
+    uint64_t length;
+    uint64_t root_tag;
+    uint64_t relocations;
+    uint64_t sections;
+    uint64_t content_hash;
+}} cfgm_blob_header;
+#define CFGM_BLOB_MAGIC "{}"
+#define CFGM_FORMAT_VERSION {}
+#define CFGM_AUTOMATON_ROOT_TAG {:#x}ULL
+
+// The automaton consists of the following sections, each behind the previous one:
+// getolds (cfgm_vec_of_vecs of bytes), exts (ditto), rule IDs of the exts (cfgm_blob_vec of
+// uint64_t), inits (cfgm_blob_vec of keyval state pointers), keys and values of the defaults (two
+// cfgm_vec_of_vecs of bytes, in the same order), normalized keys (cfgm_vec_of_vecs of bytes) and
+// their normalizers (cfgm_blob_vec of uint8_t flags: 1 trim, 2 strip quotes, 4 lowercase, applied
+// in this order), keys of the patterns (cfgm_vec_of_vecs of bytes),
+// IDs of the patterns (cfgm_blob_vec of uint64_t), regexes of the patterns (cfgm_vec_of_vecs of
+// bytes, empty if not embedded), uses of the tags (cfgm_blob_vec of cfgm_tag_rule, sorted),
+// keyval states (cfgm_sediment of cfgm_keyval_state), u8 states (cfgm_sediment of cfgm_u8_state),
+// tag pool (cfgm_sediment of cfgm_blob_vecs of uint64_t).
+typedef cfgm_sediment cfgm_automaton;
+
+// The rule and the index of the pattern (among the keys of the patterns) using a tag, negated is
+// 1 for a condition of when_not.
+typedef struct {{ uint64_t tag; uint64_t rule; uint64_t pattern; uint64_t negated; }} cfgm_tag_rule;
+
+"#,
+        align_of::<Guard>(), size_of::<Guard>(), size_of::<Bdd<u64, Leaf>>() * 8,
+        align_of::<BlobHeader>(), std::str::from_utf8(&BLOB_MAGIC).unwrap(), FORMAT_VERSION,
+        Automaton::tag(),
+    ));
+
+    let checks: [(&str, usize); 20] = [
+        ("cfgm_blob_header", size_of::<BlobHeader>()),
+        ("cfgm_blob_vec", size_of::<BlobVec<u8>>()),
+        ("cfgm_sediment", size_of::<Sediment<u8>>()),
+        ("cfgm_vec_of_vecs", size_of::<VecOfVecs<u8>>()),
+        ("cfgm_vec_of_vecs16", size_of::<VecOfVecs<u8, u16>>()),
+        ("cfgm_list", size_of::<List<()>>()),
+        ("cfgm_sized_list", size_of::<SizedList<u8>>()),
+        ("cfgm_guard", size_of::<Guard>()),
+        ("cfgm_range_map", size_of::<RangeMap<u8>>()),
+        ("cfgm_hashmap", size_of::<BlobHashMap<u8>>()),
+        ("cfgm_u8_sparse_state", size_of::<U8SparseState>()),
+        ("cfgm_u8_dense_state", size_of::<U8DenseState>()),
+        ("cfgm_u8_narrow_dense_state", size_of::<U8NarrowDenseState>()),
+        ("cfgm_u8_ranged_state", size_of::<U8RangedState>()),
+        ("cfgm_u8_state", size_of::<U8State>()),
+        ("cfgm_bdd_node_no_owned", size_of::<NodeNoOwned<u64, Leaf>>()),
+        ("cfgm_bdd_node_owned", size_of::<NodeOwned<u64, Leaf>>()),
+        ("cfgm_keyval_state", size_of::<KeyValState>()),
+        ("cfgm_num_guard", size_of::<NumGuard>()),
+        ("cfgm_tag_rule", size_of::<TagRule>()),
+    ];
+    for (name, size) in checks {
+        write(format!("_Static_assert(sizeof({}) == {}, \"{} layout\");\n", name, size, name));
+    }
+
+    write("\n#endif\n".to_owned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_header() {
+        let mut header = vec![];
+        write_c_header(&mut header);
+        let header = String::from_utf8(header).unwrap();
+        assert!(header.contains("typedef union {\n    cfgm_u8_sparse_state sparse;"));
+        assert!(header.contains(
+            &format!("_Static_assert(sizeof(cfgm_u8_state) == {}", size_of::<U8State>())));
+        assert!(header.trim_end().ends_with("#endif"));
+    }
+}
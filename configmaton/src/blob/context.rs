@@ -0,0 +1,63 @@
+use std::marker::PhantomData;
+
+use hashbrown::HashMap;
+
+// Composable serialization contexts. A serializer that needs several things (e.g. the addresses
+// of both kinds of states) asks for `C: Has<A, IA> + Has<B, IB>`, and the caller passes any
+// combination of them, e.g. `CtxPair(a, CtxPair(b, stats))`. The index parameters are inferred,
+// they only tell where in the pair tree the item is.
+pub trait Has<T, Ix> {
+    fn get(&self) -> &T;
+}
+
+pub struct CtxPair<A, B>(pub A, pub B);
+
+pub struct Itself;
+pub struct InLeft<Ix>(PhantomData<Ix>);
+pub struct InRight<Ix>(PhantomData<Ix>);
+
+impl<T> Has<T, Itself> for T {
+    fn get(&self) -> &T { self }
+}
+
+impl<T, Ix, A: Has<T, Ix>, B> Has<T, InLeft<Ix>> for CtxPair<A, B> {
+    fn get(&self) -> &T { self.0.get() }
+}
+
+impl<T, Ix, A, B: Has<T, Ix>> Has<T, InRight<Ix>> for CtxPair<A, B> {
+    fn get(&self) -> &T { self.1.get() }
+}
+
+// Addresses of the U8States within the blob, indexed by the state index of the origin.
+pub struct U8StatePtrs<'a>(pub &'a [usize]);
+
+// Addresses of the KeyValStates within the blob, indexed by the state index of the origin.
+pub struct KeyValStatePtrs<'a>(pub &'a [usize]);
+
+// Addresses of the tag sets within the tag pool of the blob, see `state::U8TagPool`.
+pub struct TagSetPtrs<'a>(pub &'a HashMap<Vec<usize>, usize>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stats(usize);
+
+    fn lookup<'a, C, I1, I2, I3>(ctx: &C) -> (usize, usize, usize)
+        where C: Has<U8StatePtrs<'a>, I1> + Has<KeyValStatePtrs<'a>, I2> + Has<Stats, I3>
+    {
+        let u8qs: &U8StatePtrs = ctx.get();
+        let kvqs: &KeyValStatePtrs = ctx.get();
+        let stats: &Stats = ctx.get();
+        (u8qs.0[1], kvqs.0[0], stats.0)
+    }
+
+    #[test]
+    fn compose() {
+        let (u8qs, kvqs) = (vec![10, 20], vec![30]);
+        let ctx = CtxPair(U8StatePtrs(&u8qs), CtxPair(Stats(7), KeyValStatePtrs(&kvqs)));
+        assert_eq!(lookup(&ctx), (20, 30, 7));
+        let ctx = CtxPair(CtxPair(KeyValStatePtrs(&kvqs), Stats(8)), U8StatePtrs(&u8qs));
+        assert_eq!(lookup(&ctx), (20, 30, 8));
+    }
+}
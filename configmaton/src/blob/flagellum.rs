@@ -1,6 +1,8 @@
 use std::marker::PhantomData;
 
-use super::{Assoc, Build, BuildCursor, Reserve};
+use super::{
+    root::BlobError, Assoc, Build, BuildCursor, BuildError, CursorResult, FirstError, Reserve,
+};
 
 #[repr(C)]
 pub struct Flagellum<'a, K, V> {
@@ -13,12 +15,12 @@ impl<'a, K, V> Flagellum<'a, K, V> {
     pub unsafe fn deserialize
     <
         After,
-        FK: FnMut(&mut K),
-        FV: FnMut(BuildCursor<V>) -> BuildCursor<After>,
+        FK: FnMut(&mut K) -> Result<(), BlobError>,
+        FV: FnMut(BuildCursor<V>) -> CursorResult<After>,
     >
-    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> BuildCursor<After>
+    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> CursorResult<After>
     {
-        fk(&mut (*cur.get_mut()).key);
+        fk(&mut *cur.transmute::<K>().try_get_mut()?)?;
         fv(cur.transmute::<K>().behind(1))
     }
 }
@@ -28,8 +30,16 @@ impl<'a, K: Build, V: Build> Build for Flagellum<'a, K, V> {
 }
 
 impl<'a, K: Build, V: Build> Flagellum<'a, K, V> {
-    pub fn reserve<FV: Fn(&V::Origin, &mut Reserve)>
-    (origin: &<Self as Build>::Origin, sz: &mut Reserve, fv: FV) -> usize
+    pub fn try_reserve<FV: FnMut(&V::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, fv: FV) -> Result<usize, BuildError>
+    {
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(fv));
+        errors.into_result(my_addr)
+    }
+
+    pub fn reserve<FV: FnMut(&V::Origin, &mut Reserve)>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut fv: FV) -> usize
     {
         sz.add::<Self>(0);
         let my_addr = sz.0;
@@ -1,64 +1,146 @@
 use std::marker::PhantomData;
 
 use super::{
-    Assocs, UnsafeIterator, Build, BuildCursor, IsEmpty, Reserve, Shifter, MyHash, EqMatch
+    Assocs, UnsafeIterator, Build, BuildCursor, IsEmpty, Reserve, Shifter, HashStrategy, EqMatch,
+    AnyMatch, FakeSafeIterator, XxHashStrategy, BuildError, CursorResult, BlobPtr, FirstError,
 };
 
 #[repr(C)]
-pub struct BlobHashMap<'a, AList> {
-    mask: usize,
-    arr: *const AList,
-    _phantom: PhantomData<&'a AList>,
+pub struct BlobHashMap<'a, AList, H = XxHashStrategy> {
+    mask: u64,
+    seed: u64,
+    arr: BlobPtr<AList>,
+    _phantom: PhantomData<(&'a AList, H)>,
 }
 
-impl<'a, AList: Assocs<'a>> BlobHashMap<'a, AList> {
+// Distribute items into 2**cap_power buckets, forming the origin of a BlobHashMap (together with
+// the seed). The item goes to the bucket `H::hash(seed, key) & mask` and the items of each bucket
+// keep their order, so sorted items give sorted buckets. This is a part of the blob layout, the
+// hashes must not change with the versions of the hash functions or with the platform.
+pub fn bucketize<H: HashStrategy<K>, K, X>
+    (seed: u64, cap_power: usize, items: impl IntoIterator<Item = X>, key: impl Fn(&X) -> &K)
+    -> Vec<Vec<X>>
+{
+    let mask = (1 << cap_power) - 1;
+    let mut buckets = (0..=mask).map(|_| Vec::new()).collect::<Vec<_>>();
+    for item in items {
+        buckets[(H::hash(seed, key(&item)) & mask) as usize].push(item);
+    }
+    buckets
+}
+
+impl<'a, AList: Assocs<'a>, H> BlobHashMap<'a, AList, H> {
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
     pub unsafe fn get(&self, key: &AList::Key) -> Option<&AList::Val>
-        where AList::Key: Eq + MyHash
+        where AList::Key: Eq, H: HashStrategy<AList::Key>
     {
-        let ix = key.my_hash() & self.mask;
-        let alist_ptr = *(&self.arr as *const *const AList).add(ix);
+        let ix = (H::hash(self.seed, key) & self.mask) as usize;
+        let alist_ptr = (*(&self.arr as *const BlobPtr<AList>).add(ix)).get();
         if alist_ptr.is_null() {
             return None;
         }
         let alist = &*alist_ptr;
         alist.iter_matches(&EqMatch(key)).next().map(|(_, val)| val)
     }
+
+    // Iterate over all key-value pairs, bucket by bucket.
+    pub unsafe fn iter_all(&self) -> BlobHashMapIter<'a, AList> {
+        BlobHashMapIter {
+            buckets: &self.arr,
+            ix: 0,
+            cap: self.mask as usize + 1,
+            alist_iter: None,
+        }
+    }
+
+    pub unsafe fn all(&self) -> FakeSafeIterator<BlobHashMapIter<'a, AList>> {
+        FakeSafeIterator(self.iter_all())
+    }
 }
 
-impl<'a, AList> BlobHashMap<'a, AList> {
+pub struct BlobHashMapIter<'a, AList: Assocs<'a>> {
+    buckets: *const BlobPtr<AList>,
+    ix: usize,
+    cap: usize,
+    alist_iter: Option<AList::I<'a, AnyMatch>>,
+}
+
+impl<'a, AList: Assocs<'a> + 'a> UnsafeIterator for BlobHashMapIter<'a, AList> {
+    type Item = (&'a AList::Key, &'a AList::Val);
+
+    unsafe fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(alist_iter) = self.alist_iter.as_mut() {
+                if let Some(item) = alist_iter.next() { return Some(item); }
+                self.alist_iter = None;
+            }
+            if self.ix == self.cap { return None; }
+            let alist_ptr = (*self.buckets.add(self.ix)).get();
+            self.ix += 1;
+            if !alist_ptr.is_null() {
+                let alist: &'a AList = &*alist_ptr;
+                self.alist_iter = Some(alist.iter_matches(&AnyMatch));
+            }
+        }
+    }
+}
+
+impl<'a, AList, H> BlobHashMap<'a, AList, H> {
     pub unsafe fn deserialize
     <
-        F: FnMut(BuildCursor<AList>) -> BuildCursor<AList>,
+        F: FnMut(BuildCursor<AList>) -> CursorResult<AList>,
         After,
     >
-    (cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After> {
-        let mut arr_cur = cur.transmute::<usize>().behind::<*const AList>(1);
-        let hashmap_cap = (*cur.get_mut()).mask + 1;
+    (cur: BuildCursor<Self>, mut f: F) -> CursorResult<After> {
+        let mut arr_cur = cur.transmute::<u64>().behind::<BlobPtr<AList>>(2);
+        let shifter = Shifter::of(&cur);
+        let slf = &mut *cur.try_get_mut()?;
+        shifter.fix(&mut slf.seed);
+        let hashmap_cap = (shifter.fix(&mut slf.mask) as usize).saturating_add(1);
         let mut alist_cur = arr_cur.behind::<AList>(hashmap_cap);
-        for _ in 0..(*cur.get_mut()).mask + 1 {
-            let arr_ptr = arr_cur.get_mut();
+        for _ in 0..hashmap_cap {
+            let arr_ptr = arr_cur.try_get_mut()?;
             if !(*arr_ptr).is_null() {
-                Shifter(cur.buf).shift(&mut *arr_ptr);
-                alist_cur = f(alist_cur);
+                alist_cur = alist_cur.align();
+                shifter.shift_at(&mut *arr_ptr, &alist_cur)?;
+                alist_cur = f(alist_cur)?;
             }
             arr_cur.inc();
         }
-        alist_cur.align()
+        Ok(alist_cur.align())
     }
 }
 
-impl<'a, AList: Build> Build for BlobHashMap<'a, AList> {
-    type Origin = Vec<AList::Origin>;
+impl<'a, AList: Build, H> Build for BlobHashMap<'a, AList, H> {
+    // The seed and the buckets, see `bucketize`.
+    type Origin = (u64, Vec<AList::Origin>);
 }
 
-impl<'a, AList: Build> BlobHashMap<'a, AList> where AList::Origin: IsEmpty {
+impl<'a, AList: Build, H> BlobHashMap<'a, AList, H> where AList::Origin: IsEmpty {
+    pub fn check(origin: &<Self as Build>::Origin) -> Result<(), BuildError> {
+        if origin.1.len().is_power_of_two() { Ok(()) }
+        else { Err(BuildError::Malformed("BlobHashMap bucket count must be a power of two")) }
+    }
+
+    pub fn try_reserve<F: FnMut(&AList::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> Result<usize, BuildError>
+    {
+        Self::check(origin)?;
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(f));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<F: FnMut(&AList::Origin, &mut Reserve)>
     (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut f: F) -> usize {
         sz.add::<Self>(0);
         let my_addr = sz.0;
-        sz.add::<usize>(1);
-        sz.add::<*const AList>(origin.len());
-        for alist in origin.iter() {
+        sz.add::<u64>(2);
+        sz.add::<BlobPtr<AList>>(origin.1.len());
+        for alist in origin.1.iter() {
             if !alist.is_empty() {
                 f(alist, sz);
             }
@@ -73,14 +155,16 @@ impl<'a, AList: Build> BlobHashMap<'a, AList> where AList::Origin: IsEmpty {
     >
     (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
     {
-        (*cur.get_mut()).mask = origin.len() - 1;
-        let mut arr_cur = cur.transmute::<usize>().behind::<*const AList>(1);
-        let mut alist_cur = arr_cur.behind::<AList>(origin.len());
-        for alist_origin in origin.iter() {
+        let (seed, buckets) = origin;
+        (*cur.get_mut()).mask = buckets.len() as u64 - 1;
+        (*cur.get_mut()).seed = *seed;
+        let mut arr_cur = cur.transmute::<u64>().behind::<BlobPtr<AList>>(2);
+        let mut alist_cur = arr_cur.behind::<AList>(buckets.len());
+        for alist_origin in buckets.iter() {
             if alist_origin.is_empty() {
-                *arr_cur.get_mut() = std::ptr::null();
+                *arr_cur.get_mut() = BlobPtr::NULL;
             } else {
-                *arr_cur.get_mut() = alist_cur.cur as *const AList;
+                *arr_cur.get_mut() = BlobPtr::offset(alist_cur.cur);
                 alist_cur = f(alist_origin, alist_cur);
             }
             arr_cur.inc()
@@ -1,37 +1,66 @@
-use super::{bdd::{Bdd, BddOrigin}, list::List, sediment::Sediment, state::U8State, tupellum::Tupellum, vec::BlobVec, Build, BuildCursor, Reserve, Shifter, UnsafeIterator};
+use super::context::{CtxPair, Has, KeyValStatePtrs, U8StatePtrs};
+use crate::numeric::NumRange;
+use super::{
+    bdd::{Bdd, BddOrigin}, list::{List, SizedList}, sediment::Sediment, state::U8State,
+    tupellum::{Tupellum, Tupellum4}, vec::BlobVec, Build, BuildCursor, BuildError, CursorResult,
+    Reserve, Scalar, Shifter, Target, UnsafeIterator, BlobPtr, check_indices,
+};
 
+#[derive(Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct LeafOrigin {
     pub states: Vec<usize>,
     pub get_olds: Vec<Vec<u8>>,
     pub exts: Vec<Vec<u8>>,
+    // The rule which emits each of the exts, see `Parser` for the numbering.
+    pub rules: Vec<usize>,
+    // The rule group of the leaf (empty for none), see `Runner::set_group_enabled`.
+    pub group: Vec<u8>,
 }
 
 pub struct TranOrigin {
     pub key: Vec<u8>,
     pub dfa_inits: Vec<usize>,
+    pub num_guards: Vec<NumGuard>,
     pub bdd: BddOrigin<usize, LeafOrigin>,
 }
 
+// A numeric guard of a transition, setting the tag (a BDD variable, like the tags of the DFAs)
+// if the value is a number in the range.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NumGuard {
+    pub tag: u64,
+    pub range: NumRange,
+}
+
 pub struct StateOrigin {
     pub transitions: Vec<TranOrigin>,
 }
 
 pub type Bytes<'a> = BlobVec<'a, u8>;
-pub type LeafMeta<'a> = Tupellum<'a, Sediment<'a, Bytes<'a>>, Sediment<'a, Bytes<'a>>>;
-pub type Leaf0<'a> = Tupellum<'a, BlobVec<'a, *const KeyValState<'a>>, LeafMeta<'a>>;
+pub type LeafMeta<'a> = Tupellum4<'a,
+    Sediment<'a, Bytes<'a>>,  // GetOlds
+    Sediment<'a, Bytes<'a>>,  // Exts
+    Bytes<'a>,  // Group
+    BlobVec<'a, u64>,  // Rules
+>;
+pub type Leaf0<'a> = Tupellum<'a, BlobVec<'a, BlobPtr<KeyValState<'a>>>, LeafMeta<'a>>;
 pub struct Leaf<'a>(pub Leaf0<'a>);
-pub type Finals<'a> = Bdd<'a, usize, Leaf<'a>>;
-pub type InitsAndFinals<'a> = Tupellum<'a, BlobVec<'a, *const U8State<'a>>, Finals<'a>>;
+pub type Finals<'a> = Bdd<'a, u64, Leaf<'a>>;
+pub type GuardsAndFinals<'a> = Tupellum<'a, BlobVec<'a, NumGuard>, Finals<'a>>;
+pub type InitsAndFinals<'a> =
+    Tupellum<'a, BlobVec<'a, BlobPtr<U8State<'a>>>, GuardsAndFinals<'a>>;
 pub type Tran0<'a> = Tupellum<'a, Bytes<'a>, InitsAndFinals<'a>>;
 pub struct Tran<'a>(Tran0<'a>);
-pub type KeyValStateSparse<'a> = List<'a, Tran<'a>>;
+pub type KeyValStateSparse<'a> = SizedList<'a, Tran<'a>>;
 
 #[repr(C)]
 pub struct KeyValState<'a> {
     pub sparse: KeyValStateSparse<'a>,
 }
 
-pub struct SparseIterator<'a>(*const KeyValStateSparse<'a>);
+pub struct SparseIterator<'a>(*const List<'a, Tran<'a>>);
 
 impl<'a> UnsafeIterator for SparseIterator<'a> {
     type Item = (&'a [u8], &'a InitsAndFinals<'a>);
@@ -43,85 +72,146 @@ impl<'a> UnsafeIterator for SparseIterator<'a> {
     }
 }
 
-impl Build for *const KeyValState<'_> { type Origin = usize; }
+impl Build for BlobPtr<KeyValState<'_>> { type Origin = usize; }
+impl Build for NumGuard { type Origin = NumGuard; }
+
+impl Scalar for NumGuard {
+    fn swap_bytes(self) -> Self {
+        let range = NumRange { min: self.range.min.swap_bytes(), max: self.range.max.swap_bytes() };
+        NumGuard { tag: self.tag.swap_bytes(), range }
+    }
+}
 impl<'a> Build for Leaf<'a> { type Origin = LeafOrigin; }
 impl<'a> Build for Tran<'a> { type Origin = TranOrigin; }
 impl<'a> Build for KeyValState<'a> { type Origin = StateOrigin; }
 
+impl<'a> Leaf<'a> {
+    pub unsafe fn states(&self) -> &'a [BlobPtr<KeyValState<'a>>] {
+        self.0.a.as_ref()
+    }
+
+    pub unsafe fn get_olds(&self) -> &'a Sediment<'a, Bytes<'a>> {
+        let meta: &'a LeafMeta<'a> = self.0.a.behind();
+        &meta.a
+    }
+
+    pub unsafe fn exts(&self) -> &'a Sediment<'a, Bytes<'a>> {
+        self.get_olds().behind()
+    }
+
+    pub unsafe fn group(&self) -> &'a [u8] {
+        self.exts().behind::<Bytes<'a>>().as_ref()
+    }
+
+    pub unsafe fn rules(&self) -> &'a [u64] {
+        let group: &'a Bytes<'a> = self.exts().behind();
+        group.behind::<BlobVec<'a, u64>>().as_ref()
+    }
+}
+
+// The parts of a transition behind its key.
+impl<'a> InitsAndFinals<'a> {
+    // The initial states of the DFAs of the value.
+    pub unsafe fn inits(&self) -> &'a [BlobPtr<U8State<'a>>] {
+        self.a.as_ref()
+    }
+
+    pub unsafe fn num_guards(&self) -> &'a [NumGuard] {
+        self.a.behind::<GuardsAndFinals<'a>>().a.as_ref()
+    }
+
+    pub unsafe fn finals(&self) -> &'a Finals<'a> {
+        self.a.behind::<GuardsAndFinals<'a>>().a.behind()
+    }
+}
+
 impl<'a> KeyValState<'a> {
     pub fn keyvals(&self) -> SparseIterator<'a> {
-        SparseIterator(&self.sparse)
+        SparseIterator(unsafe { self.sparse.list() })
     }
 
-    pub unsafe fn deserialize<B>(state_cur: BuildCursor<KeyValState>) -> BuildCursor<B> {
-        let shifter = Shifter(state_cur.buf);
-        let state = &mut *state_cur.get_mut();
+    pub fn transition_count(&self) -> usize {
+        self.sparse.len as usize
+    }
+
+    pub unsafe fn transition(&self, ix: usize) -> Option<(&'a [u8], &'a InitsAndFinals<'a>)> {
+        self.sparse.nth(ix).map(|tupellum| (tupellum.0.a.as_ref(), tupellum.0.a.behind()))
+    }
+
+    pub unsafe fn deserialize<B>(state_cur: BuildCursor<KeyValState>) -> CursorResult<B> {
+        state_cur.mark(Target::KeyValState);
+        let shifter = Shifter::of(&state_cur);
+        let state = &mut *state_cur.try_get_mut()?;
         let sparse_cur = state_cur.goto(&mut state.sparse);
         KeyValStateSparse::deserialize(sparse_cur,
             |keyval_cur| Tran0::deserialize(keyval_cur.transmute(),
                 |key_cur| {
-                    Bytes::deserialize(key_cur, |_| ())
+                    Bytes::deserialize(key_cur, |_| Ok(()))
                 },
                 |iaf_cur| InitsAndFinals::deserialize(iaf_cur,
-                    |inits_cur| BlobVec::<*const U8State>::deserialize(inits_cur,
-                        |initq| shifter.shift(initq),
+                    |inits_cur| BlobVec::<BlobPtr<U8State>>::deserialize(inits_cur,
+                        |initq| U8State::shift_ptr(initq, &shifter),
                     ),
-                    |finals_cur| Finals::deserialize(finals_cur,
-                        |leaf_cur| Leaf0::deserialize(leaf_cur.transmute(),
-                            |post_cur| BlobVec::<*const KeyValState>::deserialize(post_cur,
-                                |postq| shifter.shift(postq),
-                            ),
-                            |meta_cur| LeafMeta::deserialize(meta_cur,
-                                |getolds_cur| Sediment::<Bytes>::deserialize(getolds_cur,
-                                    |getold_cur| Bytes::deserialize(getold_cur, |_| ())
+                    |gaf_cur| GuardsAndFinals::deserialize(gaf_cur,
+                        |guards_cur| BlobVec::<NumGuard>::deserialize(guards_cur,
+                            |guard| shifter.scalar(guard)),
+                        |finals_cur| Finals::deserialize(finals_cur,
+                            |leaf_cur| Leaf0::deserialize(leaf_cur.transmute(),
+                                |post_cur| BlobVec::<BlobPtr<KeyValState>>::deserialize(post_cur,
+                                    |postq| shifter.shift_to(
+                                        postq, size_of::<KeyValState>(), Target::KeyValState),
                                 ),
-                                |exts_cur| Sediment::<Bytes>::deserialize(exts_cur,
-                                    |ext_cur| Bytes::deserialize(ext_cur, |_| ())
-                                ),
-                            )
-                        ),
-                        |_| (),
+                                |meta_cur| LeafMeta::deserialize(meta_cur,
+                                    |getolds_cur| Sediment::<Bytes>::deserialize(getolds_cur,
+                                        |getold_cur| Bytes::deserialize(getold_cur, |_| Ok(()))
+                                    ),
+                                    |exts_cur| Sediment::<Bytes>::deserialize(exts_cur,
+                                        |ext_cur| Bytes::deserialize(ext_cur, |_| Ok(()))
+                                    ),
+                                    |group_cur| Bytes::deserialize(group_cur, |_| Ok(())),
+                                    |rules_cur| BlobVec::<u64>::deserialize(
+                                        rules_cur, |rule| shifter.scalar(rule)),
+                                )
+                            ),
+                            |var| shifter.scalar(var),
+                        )
                     )
                 )
             )
         )
     }
 
+    // Like reserve, but check that the referenced states exist (below `kv_count` and `u8_count`),
+    // so that serialize does not fail.
+    pub fn try_reserve
+        (origin: &<Self as Build>::Origin, sz: &mut Reserve, kv_count: usize, u8_count: usize)
+        -> Result<usize, BuildError>
+    {
+        for tran in origin.transitions.iter() {
+            check_indices("U8State", &tran.dfa_inits, u8_count)?;
+            unsafe { tran.bdd.for_each_leaf(
+                &mut |leaf| check_indices("KeyValState", &leaf.states, kv_count)) }?;
+        }
+        Ok(Self::reserve(origin, sz))
+    }
+
     pub fn reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve) -> usize {
         sz.add::<KeyValState>(0);
         let result = sz.0;
         KeyValStateSparse::reserve(&origin.transitions, sz,
             |tran, sz| {
-                Tran0::reserve(&(&tran.key, &(&tran.dfa_inits, &tran.bdd)), sz,
+                let guards_and_finals = (&tran.num_guards, &tran.bdd);
+                Tran0::reserve(&(&tran.key, &(&tran.dfa_inits, &guards_and_finals)), sz,
                     |key, sz| { Bytes::reserve(key, sz); },
                     |iaf, sz| {
                         InitsAndFinals::reserve(iaf, sz,
-                            |inits, sz| { BlobVec::<*const U8State>::reserve(inits, sz); },
-                            |finals, sz| {
-                                Finals::reserve(finals, sz,
-                                    |leaf, sz| {
-                                        Leaf0::reserve(
-                                            &(&leaf.states, &(&leaf.get_olds, &leaf.exts)), sz,
-                                            |postq, sz| {
-                                                BlobVec::<*const KeyValState>::reserve(postq, sz);
-                                            },
-                                            |meta, sz| {
-                                                LeafMeta::reserve(meta, sz,
-                                                    |getolds, sz| {
-                                                        Sediment::<Bytes>::reserve(getolds, sz,
-                                                            |getold, sz|
-                                                                { Bytes::reserve(getold, sz); }
-                                                        );
-                                                    },
-                                                    |exts, sz| {
-                                                        Sediment::<Bytes>::reserve(exts, sz,
-                                                            |ext, sz| { Bytes::reserve(ext, sz); }
-                                                        );
-                                                    }
-                                                );
-                                            }
-                                        );
-                                    }
+                            |inits, sz| { BlobVec::<BlobPtr<U8State>>::reserve(inits, sz); },
+                            |gaf, sz| {
+                                GuardsAndFinals::reserve(gaf, sz,
+                                    |guards, sz| { BlobVec::<NumGuard>::reserve(guards, sz); },
+                                    |finals, sz| {
+                                        Finals::reserve(finals, sz, Self::reserve_leaf);
+                                    },
                                 );
                             }
                         );
@@ -132,42 +222,84 @@ impl<'a> KeyValState<'a> {
         result
     }
 
+    fn reserve_leaf(leaf: &LeafOrigin, sz: &mut Reserve) {
+        Leaf0::reserve(
+            &(&leaf.states, &(&leaf.get_olds, &leaf.exts, &leaf.group, &leaf.rules)),
+            sz,
+            |postq, sz| { BlobVec::<BlobPtr<KeyValState>>::reserve(postq, sz); },
+            |meta, sz| {
+                LeafMeta::reserve(meta, sz,
+                    |getolds, sz| {
+                        Sediment::<Bytes>::reserve(getolds, sz,
+                            |getold, sz| { Bytes::reserve(getold, sz); }
+                        );
+                    },
+                    |exts, sz| {
+                        Sediment::<Bytes>::reserve(exts, sz,
+                            |ext, sz| { Bytes::reserve(ext, sz); }
+                        );
+                    },
+                    |group, sz| { Bytes::reserve(group, sz); },
+                    |rules, sz| { BlobVec::<u64>::reserve(rules, sz); },
+                );
+            }
+        );
+    }
+
     pub unsafe fn serialize<After>(
         origin: &<Self as Build>::Origin,
         state_cur: BuildCursor<KeyValState>,
-        u8qptrs: &Vec<usize>,
-        kvqptrs: &Vec<usize>,
+        u8qptrs: &[usize],
+        kvqptrs: &[usize],
+    ) -> BuildCursor<After>
+    {
+        Self::serialize_in(
+            origin, state_cur, &CtxPair(U8StatePtrs(u8qptrs), KeyValStatePtrs(kvqptrs)))
+    }
+
+    pub unsafe fn serialize_in<'p, After, I1, I2, C>(
+        origin: &<Self as Build>::Origin,
+        state_cur: BuildCursor<KeyValState>,
+        ctx: &C,
     ) -> BuildCursor<After>
+        where C: Has<U8StatePtrs<'p>, I1> + Has<KeyValStatePtrs<'p>, I2>
     {
+        let u8qptrs = Has::<U8StatePtrs, I1>::get(ctx).0;
+        let kvqptrs = Has::<KeyValStatePtrs, I2>::get(ctx).0;
+        let serialize_leaf = |leaf: &LeafOrigin, leaf_cur: BuildCursor<Leaf>| Leaf0::serialize(
+            &(&leaf.states, &(&leaf.get_olds, &leaf.exts, &leaf.group, &leaf.rules)),
+            leaf_cur.transmute(),
+            |postq, post_cur| BlobVec::<BlobPtr<KeyValState>>::serialize(
+                postq, post_cur, |x, y| *y = BlobPtr::offset(kvqptrs[*x]),
+            ),
+            |meta, meta_cur| LeafMeta::serialize(meta, meta_cur,
+                |getolds, getolds_cur| Sediment::<Bytes>::serialize(getolds, getolds_cur,
+                    |getold, getold_cur| Bytes::serialize(getold, getold_cur, |x, y| *y = *x)
+                ),
+                |exts, exts_cur| Sediment::<Bytes>::serialize(exts, exts_cur,
+                    |ext, ext_cur| Bytes::serialize(ext, ext_cur, |x, y| *y = *x)
+                ),
+                |group, group_cur| Bytes::serialize(group, group_cur, |x, y| *y = *x),
+                |rules, rules_cur| BlobVec::<u64>::serialize(
+                    rules, rules_cur, |x, y| *y = *x as u64),
+            )
+        );
         let state = &mut *state_cur.get_mut();
         let sparse_cur = state_cur.goto(&mut state.sparse);
         KeyValStateSparse::serialize(&origin.transitions, sparse_cur,
             |tran, tran_cur| Tran0::serialize(
-                &(&tran.key, &(&tran.dfa_inits, &tran.bdd)),
+                &(&tran.key, &(&tran.dfa_inits, &(&tran.num_guards, &tran.bdd))),
                 tran_cur.transmute(),
                 |key, key_cur| Bytes::serialize(key, key_cur, |x, y| *y = *x),
                 |iaf, iaf_cur| InitsAndFinals::serialize(iaf, iaf_cur,
-                    |inits, inits_cur| BlobVec::<*const U8State>::serialize(
-                        inits, inits_cur, |x, y| *y = u8qptrs[*x] as *const U8State
+                    |inits, inits_cur| BlobVec::<BlobPtr<U8State>>::serialize(
+                        inits, inits_cur, |x, y| *y = BlobPtr::offset(u8qptrs[*x])
                     ),
-                    |finals, finals_cur| Finals::serialize(finals, finals_cur,
-                        |leaf, leaf_cur| Leaf0::serialize(
-                            &(&leaf.states, &(&leaf.get_olds, &leaf.exts)), leaf_cur.transmute(),
-                            |postq, post_cur| BlobVec::<*const KeyValState>::serialize(
-                                postq, post_cur, |x, y| *y = kvqptrs[*x] as *const KeyValState,
-                            ),
-                            |meta, meta_cur| LeafMeta::serialize(meta, meta_cur,
-                                |getolds, getolds_cur| Sediment::<Bytes>::serialize(
-                                    getolds, getolds_cur,
-                                    |getold, getold_cur| Bytes::serialize(
-                                        getold, getold_cur, |x, y| *y = *x)
-                                ),
-                                |exts, exts_cur| Sediment::<Bytes>::serialize(exts, exts_cur,
-                                    |ext, ext_cur| Bytes::serialize(ext, ext_cur, |x, y| *y = *x)
-                                ),
-                            )
-                        ),
-                        |x, y| *y = *x,
+                    |gaf, gaf_cur| GuardsAndFinals::serialize(gaf, gaf_cur,
+                        |guards, guards_cur| BlobVec::<NumGuard>::serialize(
+                            guards, guards_cur, |x, y| *y = *x),
+                        |finals, finals_cur| Finals::serialize(
+                            finals, finals_cur, serialize_leaf, |x, y| *y = *x as u64),
                     )
                 )
             )
@@ -178,7 +310,8 @@ impl<'a> KeyValState<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::blob::{align_up_ptr, get_behind_struct};
+
+    use crate::keyval_runner::MatchedTags;
 
     use super::*;
 
@@ -190,6 +323,7 @@ mod tests {
                     TranOrigin {
                         key: b"key1".to_vec(),
                         dfa_inits: vec![0, 2],
+                        num_guards: vec![NumGuard { tag: 5, range: NumRange::new(Some(1.), None) }],
                         bdd: BddOrigin::NodeBothOwned {
                             var: 3,
                             pos: Box::new(
@@ -198,6 +332,8 @@ mod tests {
                                         states: vec![0],
                                         get_olds: vec![b"get1a".to_vec(), b"get1b".to_vec()],
                                         exts: vec![],
+                                        rules: vec![],
+                                        group: b"beta".to_vec(),
                                     }
                                 )
                             ),
@@ -207,6 +343,8 @@ mod tests {
                                         states: vec![],
                                         get_olds: vec![],
                                         exts: vec![b"ext1a".to_vec()],
+                                        rules: vec![7],
+                                        group: vec![],
                                     }
                                 )
                             ),
@@ -227,13 +365,13 @@ mod tests {
         let mut cur = BuildCursor::new(buf);
         cur = unsafe { Sediment::<KeyValState>::serialize(&state_origins, cur,
             |state, state_cur| {
-                KeyValState::serialize(state, state_cur, &vec![256, 1024, 4096], &addrs)
+                KeyValState::serialize(state, state_cur, &[256, 1024, 4096], &addrs)
             }
         )};
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let mut cur = BuildCursor::new(buf);
         cur = unsafe { Sediment::<KeyValState>::deserialize(cur,
-            |state_cur| KeyValState::deserialize(state_cur)) };
+            |state_cur| KeyValState::deserialize(state_cur)) }.unwrap();
         assert_eq!(cur.cur, cur.cur);  // suppress unused_assign warning
         let q0 = unsafe { &*(buf.add(addrs[0]) as *const KeyValState) };
 
@@ -241,49 +379,46 @@ mod tests {
         let (key, tran) = unsafe { keyvals.next() }.unwrap();
         assert!(unsafe { keyvals.next() }.is_none());
         assert_eq!(key, b"key1");
+        assert_eq!(q0.transition_count(), 1);
+        assert_eq!(unsafe { q0.transition(0) }.unwrap().0, b"key1");
+        assert!(unsafe { q0.transition(1) }.is_none());
         assert_eq!(
             unsafe { tran.a.as_ref() }.iter().copied()
-                .map(|x| x as usize - buf as usize).collect::<Vec<_>>(),
+                .map(|x| x.get() as usize - buf as usize).collect::<Vec<_>>(),
             vec![256, 4096],
         );
-        let bdd: &Finals = unsafe { tran.a.behind() };
+        assert_eq!(unsafe { tran.inits() }, unsafe { tran.a.as_ref() });
+        assert_eq!(unsafe { tran.num_guards() }, state_origins[0].transitions[0].num_guards);
+        let bdd = unsafe { tran.finals() };
 
         let leaf = unsafe { bdd.evaluate(|var| match *var { 3 => true, _ => unreachable!() }) };
-        assert_eq!(unsafe { leaf.0.a.as_ref() }, [q0 as *const _]);
-        let meta: &LeafMeta = unsafe { leaf.0.a.behind() };
-        let mut getolds = vec![];
-        let mut behind = unsafe { get_behind_struct(meta) };
-        unsafe { meta.a.each(|x| {
-            getolds.push(x.as_ref());
-            behind = x.behind();
-            behind
-        })};
+        assert_eq!(unsafe { leaf.0.a.as_ref() }, [BlobPtr::new(q0 as *const _)]);
+        let bdd_origin = &state_origins[0].transitions[0].bdd;
+        let leaf_origin = unsafe { bdd_origin.evaluate(|var| *var == 3) };
+        assert_eq!(leaf_origin.get_olds, vec![b"get1a", b"get1b"]);
+        let mut leaf_count = 0;
+        let _ = unsafe { bdd_origin.for_each_leaf(&mut |_| { leaf_count += 1; Ok::<_, ()>(()) }) };
+        assert_eq!(leaf_count, 2);
+        let getolds = unsafe { leaf.get_olds().iter() }.map(|x| unsafe { x.as_ref() })
+            .collect::<Vec<_>>();
         assert_eq!(getolds, vec![b"get1a", b"get1b"]);
-        let mut exts_vec = vec![];
-        let exts: &Sediment<BlobVec<u8>> = unsafe { &*align_up_ptr(behind) };
-        unsafe { exts.each(|x| {
-            exts_vec.push(x.as_ref());
-            x.behind()
-        })};
-        assert!(exts_vec.is_empty());
+        assert_eq!(unsafe { leaf.exts() }.len, 0);
+        assert_eq!(unsafe { leaf.group() }, b"beta");
 
         let leaf = unsafe { bdd.evaluate(|var| match *var { 3 => false, _ => unreachable!() }) };
         assert!(unsafe { leaf.0.a.as_ref() }.is_empty());
-        let meta: &LeafMeta = unsafe { leaf.0.a.behind() };
-        let mut getolds = vec![];
-        let mut behind = unsafe { get_behind_struct(meta) };
-        unsafe { meta.a.each(|x| {
-            getolds.push(x.as_ref());
-            behind = x.behind();
-            behind
-        })};
-        assert!(getolds.is_empty());
-        let mut exts_vec = vec![];
-        let exts: &Sediment<BlobVec<u8>> = unsafe { &*align_up_ptr(behind) };
-        unsafe { exts.each(|x| {
-            exts_vec.push(x.as_ref());
-            x.behind()
-        })};
-        assert_eq!(exts_vec, vec![b"ext1a"]);
+        assert_eq!(unsafe { leaf.get_olds() }.len, 0);
+        let exts = unsafe { leaf.exts().iter() }.map(|x| unsafe { x.as_ref() })
+            .collect::<Vec<_>>();
+        assert_eq!(exts, vec![b"ext1a"]);
+        assert!(unsafe { leaf.group() }.is_empty());
+        assert_eq!(unsafe { leaf.rules() }, [7]);
+
+        let pos = unsafe { bdd.evaluate_with(&MatchedTags::new(&[1, 3, 5])) };
+        assert_eq!(unsafe { pos.0.a.as_ref() }, [BlobPtr::new(q0 as *const _)]);
+        let neg = unsafe { bdd.evaluate_with(&MatchedTags::new(&[2, 4])) };
+        assert!(std::ptr::eq(neg, leaf));
+        let leaf_origin = unsafe { bdd_origin.evaluate_with(&|var: &usize| *var == 3) };
+        assert_eq!(leaf_origin.get_olds, vec![b"get1a", b"get1b"]);
     }
 }
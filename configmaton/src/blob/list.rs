@@ -1,10 +1,13 @@
 use std::marker::PhantomData;
 
-use super::{UnsafeIterator, Build, BuildCursor, Reserve, Shifter};
+use super::{
+    get_behind_struct, UnsafeIterator, Build, BuildCursor, BuildError, CursorResult,
+    FakeSafeIterator, FirstError, Reserve, Shifter, BlobPtr,
+};
 
 #[repr(C)]
 pub struct List<'a, X> {
-    pub next: *const Self,
+    pub next: BlobPtr<Self>,
     value: X,
     _phantom: PhantomData<&'a ()>,
 }
@@ -12,27 +15,42 @@ pub struct List<'a, X> {
 impl<'a, X: 'a> UnsafeIterator for *const List<'a, X> {
     type Item = &'a X;
     unsafe fn next(&mut self) -> Option<Self::Item> {
-        loop {
-            if self.is_null() {
-                return None;
-            }
-            let item = *self;
-            *self = (*item).next;
-            return Some(&(*item).value);
+        if self.is_null() {
+            return None;
+        }
+        let item = *self;
+        *self = (*item).next.get();
+        Some(&(*item).value)
+    }
+}
+
+impl<'a, X: 'a> List<'a, X> {
+    pub unsafe fn iter(&self) -> FakeSafeIterator<*const Self> {
+        FakeSafeIterator(self)
+    }
+
+    pub unsafe fn nth(&self, ix: usize) -> Option<&'a X> {
+        let mut cur: *const Self = self;
+        for _ in 0..ix {
+            if cur.is_null() { return None; }
+            cur = (*cur).next.get();
         }
+        cur.next()
     }
 }
 
 impl<'a, X> List<'a, X> {
     pub unsafe fn deserialize
-    <F: FnMut(BuildCursor<X>) -> BuildCursor<Self>, After>
-    (mut cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
+    <F: FnMut(BuildCursor<X>) -> CursorResult<Self>, After>
+    (mut cur: BuildCursor<Self>, mut f: F) -> CursorResult<After>
     {
+        let shifter = Shifter::of(&cur);
         loop {
-            let alist = &mut *cur.get_mut();
-            cur = f(cur.transmute::<*const Self>().behind(1));
-            if alist.next.is_null() { return cur.align(); }
-            Shifter(cur.buf).shift(&mut alist.next);
+            let alist = &mut *cur.try_get_mut()?;
+            cur = f(cur.transmute::<BlobPtr<Self>>().behind(1))?;
+            if alist.next.is_null() { return Ok(cur.align()); }
+            cur = cur.align();
+            shifter.shift_at(&mut alist.next, &cur)?;
         }
     }
 }
@@ -42,12 +60,26 @@ impl<'a, X: Build> Build for List<'a, X> {
 }
 
 impl<'a, X: Build> List<'a, X> {
+    // Lists cannot be empty, use SizedList for possibly empty ones.
+    pub fn check(origin: &<Self as Build>::Origin) -> Result<(), BuildError> {
+        if origin.is_empty() { Err(BuildError::Malformed("empty List")) } else { Ok(()) }
+    }
+
+    pub fn try_reserve<F: FnMut(&X::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> Result<usize, BuildError>
+    {
+        Self::check(origin)?;
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(f));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<F: FnMut(&X::Origin, &mut Reserve)>
     (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut f: F) -> usize
     {
         sz.add::<Self>(0);
         let my_addr = sz.0;
-        for x in origin.iter() { sz.add::<*const Self>(1); f(x, sz); }
+        for x in origin.iter() { sz.add::<BlobPtr<Self>>(1); f(x, sz); }
         sz.add::<Self>(0);
         my_addr
     }
@@ -61,14 +93,84 @@ impl<'a, X: Build> List<'a, X> {
     {
         for (i, x) in origin.iter().enumerate() {
             if i == origin.len() - 1 {
-                (*cur.get_mut()).next = std::ptr::null();
-                cur = f(x, cur.transmute::<*const Self>().behind(1));
+                (*cur.get_mut()).next = BlobPtr::NULL;
+                cur = f(x, cur.transmute::<BlobPtr<Self>>().behind(1));
             } else {
                 let next = &mut (*cur.get_mut()).next;
-                cur = f(x, cur.transmute::<*const Self>().behind(1));
-                *next = cur.cur as *const Self;
+                cur = f(x, cur.transmute::<BlobPtr<Self>>().behind(1));
+                *next = BlobPtr::offset(cur.cur);
             }
         }
         cur.align()
     }
 }
+
+// A List prefixed with its length, which also makes empty lists representable.
+#[repr(C)]
+pub struct SizedList<'a, X> {
+    pub len: u64,
+    _phantom: PhantomData<&'a X>,
+}
+
+impl<'a, X: 'a> SizedList<'a, X> {
+    pub unsafe fn list(&self) -> *const List<'a, X> {
+        if self.len == 0 { std::ptr::null() }
+        else { get_behind_struct(self) }
+    }
+
+    pub unsafe fn iter(&self) -> FakeSafeIterator<*const List<'a, X>> {
+        FakeSafeIterator(self.list())
+    }
+
+    pub unsafe fn nth(&self, ix: usize) -> Option<&'a X> {
+        if ix as u64 >= self.len { return None; }
+        (*self.list()).nth(ix)
+    }
+
+    pub unsafe fn deserialize
+    <F: FnMut(BuildCursor<X>) -> CursorResult<List<'a, X>>, After>
+    (cur: BuildCursor<Self>, f: F) -> CursorResult<After>
+    {
+        let list_cur = cur.behind::<List<'a, X>>(1);
+        if cur.swap.fix(&mut (*cur.try_get_mut()?).len) == 0 { return Ok(list_cur.align()); }
+        List::deserialize(list_cur, f)
+    }
+}
+
+impl<'a, X: Build> Build for SizedList<'a, X> {
+    type Origin = Vec<X::Origin>;
+}
+
+impl<'a, X: Build> SizedList<'a, X> {
+    pub fn try_reserve<F: FnMut(&X::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> Result<usize, BuildError>
+    {
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(f));
+        errors.into_result(my_addr)
+    }
+
+    pub fn reserve<F: FnMut(&X::Origin, &mut Reserve)>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> usize
+    {
+        sz.add::<Self>(0);
+        let my_addr = sz.0;
+        sz.add::<Self>(1);
+        if !origin.is_empty() { List::<'a, X>::reserve(origin, sz, f); }
+        sz.add::<List<'a, X>>(0);
+        my_addr
+    }
+
+    pub unsafe fn serialize
+    <
+        After,
+        F: FnMut(&X::Origin, BuildCursor<X>) -> BuildCursor<List<'a, X>>,
+    >
+    (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, f: F) -> BuildCursor<After>
+    {
+        (*cur.get_mut()).len = origin.len() as u64;
+        let list_cur = cur.behind::<List<'a, X>>(1);
+        if origin.is_empty() { return list_cur.align(); }
+        List::serialize(origin, list_cur, f)
+    }
+}
@@ -1,13 +1,13 @@
 use std::marker::PhantomData;
 
 use super::{
-    list::List, AssocsSuper, Build, BuildCursor, Matches, Reserve, Shifter,
-    UnsafeIterator, Assocs,
+    list::List, AssocsSuper, Build, BuildCursor, BuildError, CursorResult, FirstError, Matches,
+    Reserve, Shifter, UnsafeIterator, Assocs, BlobPtr, check_starts,
 };
 
 #[repr(C)]
 pub struct ListMapItem<K, V> {
-    val: *const V,
+    val: BlobPtr<V>,
     key: K,
 }
 
@@ -27,6 +27,18 @@ impl<'a, K: Build, V: Build> Build for ListMap<'a, K, V> {
 }
 
 impl<'a, K: Build, V: Build> ListMap<'a, K, V> {
+    pub fn try_reserve<
+        FK: FnMut(&K::Origin, &mut Reserve) -> Result<(), BuildError>,
+        FV: FnMut(&V::Origin, &mut Reserve) -> Result<(), BuildError>,
+    >
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, fk: FK, fv: FV)
+        -> Result<usize, BuildError>
+    {
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(fk), errors.wrap(fv));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<
         FK: FnMut(&K::Origin, &mut Reserve),
         FV: FnMut(&V::Origin, &mut Reserve),
@@ -52,10 +64,10 @@ impl<'a, K: Build, V: Build> ListMap<'a, K, V> {
         let mut item_curs = Vec::with_capacity(origin.len());
         let mut vcur = <ListMapList<'a, K, V>>::serialize(origin, kcur, |kv, item_cur| {
             item_curs.push(item_cur.clone());
-            fk(&kv.0, item_cur.transmute::<*const V>().behind(1))
+            fk(&kv.0, item_cur.transmute::<BlobPtr<V>>().behind(1))
         });
         for (kv, item_cur) in origin.iter().zip(item_curs) {
-            (*item_cur.get_mut()).val = vcur.cur as *const V;
+            (*item_cur.get_mut()).val = BlobPtr::offset(vcur.cur);
             vcur = fv(&kv.1, vcur);
         }
         vcur.align()
@@ -65,21 +77,27 @@ impl<'a, K: Build, V: Build> ListMap<'a, K, V> {
 impl<'a, K, V> ListMap<'a, K, V> {
     pub unsafe fn deserialize<
         After,
-        FK: FnMut(BuildCursor<K>) -> BuildCursor<ListMapList<'a, K, V>>,
-        FV: FnMut(BuildCursor<V>) -> BuildCursor<V>,
+        FK: FnMut(BuildCursor<K>) -> CursorResult<ListMapList<'a, K, V>>,
+        FV: FnMut(BuildCursor<V>) -> CursorResult<V>,
     >
-    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> BuildCursor<After>
+    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> CursorResult<After>
     {
         let kcur = cur.behind::<ListMapList<'a, K, V>>(0);
-        let mut len = 0;
-        let shifter = Shifter(cur.buf);
+        let mut offsets = vec![];
+        let shifter = Shifter::of(&cur);
         let mut vcur = ListMapList::deserialize(kcur, |item_cur| {
-            len += 1;
-            shifter.shift(&mut (*item_cur.get_mut()).val);
-            fk(item_cur.transmute::<*const V>().behind(1))
-        });
-        for _ in 0..len { vcur = fv(vcur); }
-        vcur.align()
+            let val = &mut (*item_cur.try_get_mut()?).val;
+            offsets.push(shifter.shift_offset(val, size_of::<V>())?);
+            fk(item_cur.transmute::<BlobPtr<V>>().behind(1))
+        })?;
+        let mut starts = Vec::with_capacity(offsets.len());
+        for _ in 0..offsets.len() {
+            vcur = vcur.align();
+            starts.push(vcur.cur);
+            vcur = fv(vcur)?;
+        }
+        check_starts(&offsets, &starts)?;
+        Ok(vcur.align())
     }
 }
 
@@ -95,7 +113,7 @@ impl<'a, 'b, X: Matches<K>, K, V: 'a + 'b> UnsafeIterator for ListMapIter<'a, 'b
     unsafe fn next(&mut self) -> Option<Self::Item> {
         while let Some(item) = self.list_iter.next() {
             if self.x.matches(&item.key) {
-                return Some((&item.key, &*item.val));
+                return Some((&item.key, &*item.val.get()));
             }
         }
         None
@@ -0,0 +1,57 @@
+use std::io::Write;
+
+// A map of which byte ranges of a blob belong to which structures, recorded while reserving the
+// blob. Useful for debugging reserve/serialize mismatches and for explaining the blob size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    pub start: usize,
+    pub end: usize,
+    pub depth: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap { regions: Vec::new() }
+    }
+
+    pub fn add<S: Into<String>>(&mut self, name: S, start: usize, end: usize, depth: usize) {
+        self.regions.push(Region { name: name.into(), start, end, depth });
+    }
+
+    // Regions ordered by their start, enclosing regions before the enclosed ones.
+    pub fn sorted(&self) -> Vec<&Region> {
+        let mut regions = self.regions.iter().collect::<Vec<_>>();
+        regions.sort_by_key(|r| (r.start, r.depth));
+        regions
+    }
+
+    pub fn to_text<W: Write>(&self, mut writer: W) {
+        for r in self.sorted() {
+            writeln!(writer, "{:08x}..{:08x} {:>8}  {}{}",
+                r.start, r.end, r.end - r.start, "  ".repeat(r.depth), r.name).unwrap();
+        }
+    }
+
+    // Each region is a node, linked to the regions directly enclosed in it.
+    pub fn to_dot<W: Write>(&self, mut writer: W) {
+        writer.write_all(b"digraph G {\n  node [ shape=\"record\" ]\n").unwrap();
+        let regions = self.sorted();
+        let mut parents: Vec<usize> = Vec::new();
+        for (i, r) in regions.iter().enumerate() {
+            writeln!(writer, "  r{} [ label=\"{{{}|{:#x}..{:#x}|{} B}}\" ]",
+                i, r.name, r.start, r.end, r.end - r.start).unwrap();
+            parents.truncate(r.depth);
+            if let Some(parent) = parents.last() {
+                writeln!(writer, "  r{} -> r{}", parent, i).unwrap();
+            }
+            parents.push(i);
+        }
+        writer.write_all(b"}\n").unwrap();
+    }
+}
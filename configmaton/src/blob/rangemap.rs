@@ -0,0 +1,151 @@
+use std::marker::PhantomData;
+
+use super::{
+    check_indices, Build, BuildCursor, BuildError, CursorResult, FirstError, Reserve, Shifter,
+    BlobPtr, check_starts,
+};
+
+// A map from u8 to V, stored as sorted range starts, each pointing to one of the (deduplicated)
+// values. Layout: header, [u8; len] starts, [BlobPtr<V>; len] value pointers, values.
+#[repr(C)]
+pub struct RangeMap<'a, V> {
+    len: u64,
+    value_count: u64,
+    _phantom: PhantomData<&'a V>
+}
+
+impl<'a, V: Build> Build for RangeMap<'a, V> {
+    // Range starts (the first one must be 0) with indices to the values.
+    type Origin = (Vec<(u8, usize)>, Vec<V::Origin>);
+}
+
+impl<'a, V: Build> RangeMap<'a, V> {
+    pub fn check(origin: &<Self as Build>::Origin) -> Result<(), BuildError> {
+        let (ranges, values) = origin;
+        if ranges.first().map(|(start, _)| *start) != Some(0) {
+            return Err(BuildError::Malformed("RangeMap ranges must start at 0"));
+        }
+        if ranges.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(BuildError::Malformed("RangeMap ranges must be sorted"));
+        }
+        check_indices("RangeMap value", ranges.iter().map(|(_, vix)| vix), values.len())
+    }
+
+    pub fn try_reserve<FV: FnMut(&V::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, fv: FV) -> Result<usize, BuildError>
+    {
+        Self::check(origin)?;
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(fv));
+        errors.into_result(my_addr)
+    }
+
+    pub fn reserve<FV: FnMut(&V::Origin, &mut Reserve)>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut fv: FV) -> usize
+    {
+        sz.add::<Self>(0);
+        let my_addr = sz.0;
+        sz.add::<Self>(1);
+        sz.add::<u8>(origin.0.len());
+        sz.add::<BlobPtr<V>>(origin.0.len());
+        for v in origin.1.iter() {
+            fv(v, sz);
+        }
+        my_addr
+    }
+
+    pub unsafe fn serialize
+    <
+        After,
+        FV: FnMut(&V::Origin, BuildCursor<V>) -> BuildCursor<V>,
+    >
+    (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, mut fv: FV)
+    -> BuildCursor<After>
+    {
+        let (ranges, values) = origin;
+        let slf = &mut *cur.get_mut();
+        slf.len = ranges.len() as u64;
+        slf.value_count = values.len() as u64;
+
+        let starts_cur = cur.behind::<u8>(1);
+        let starts = starts_cur.get_mut();
+        let ptrs_cur = starts_cur.behind::<BlobPtr<V>>(ranges.len());
+        let ptrs = ptrs_cur.get_mut();
+
+        let mut vcur = ptrs_cur.behind::<V>(ranges.len());
+        let mut addrs = Vec::with_capacity(values.len());
+        for v in values.iter() {
+            addrs.push(vcur.cur);
+            vcur = fv(v, vcur.clone());
+        }
+
+        for (i, (start, vix)) in ranges.iter().enumerate() {
+            *starts.add(i) = *start;
+            *ptrs.add(i) = BlobPtr::offset(addrs[*vix]);
+        }
+
+        vcur.align()
+    }
+}
+
+impl<'a, V> RangeMap<'a, V> {
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    unsafe fn starts(&self) -> &'a [u8] {
+        let starts = (self as *const Self).add(1) as *const u8;
+        std::slice::from_raw_parts(starts, self.len())
+    }
+
+    unsafe fn ptrs(&self) -> &'a [BlobPtr<V>] {
+        let ptrs = super::align_up_ptr::<u8, BlobPtr<V>>(self.starts().as_ptr().add(self.len()));
+        std::slice::from_raw_parts(ptrs, self.len())
+    }
+
+    pub unsafe fn get(&self, key: u8) -> &'a V {
+        let ix = self.starts().partition_point(|start| *start <= key) - 1;
+        &*self.ptrs()[ix].get()
+    }
+
+    // The ranges as (first, last, value) triples.
+    pub unsafe fn ranges(&self) -> impl Iterator<Item = (u8, u8, &'a V)> + 'a {
+        let starts = self.starts();
+        let ptrs = self.ptrs();
+        (0..self.len()).map(move |i| {
+            let last = if i + 1 == starts.len() { 255 } else { starts[i + 1] - 1 };
+            (starts[i], last, &*ptrs[i].get())
+        })
+    }
+
+    pub unsafe fn deserialize<
+        After,
+        FV: FnMut(BuildCursor<V>) -> CursorResult<V>,
+    >
+    (cur: BuildCursor<Self>, mut fv: FV) -> CursorResult<After>
+    {
+        let shifter = Shifter::of(&cur);
+        let slf = &mut *cur.try_get_mut()?;
+        let len = shifter.fix(&mut slf.len) as usize;
+        let value_count = shifter.fix(&mut slf.value_count);
+        let mut ptrs_cur = cur.behind::<u8>(1).behind::<BlobPtr<V>>(len);
+        let mut offsets = Vec::with_capacity(len);
+        for _ in 0..len {
+            offsets.push(shifter.shift_offset(&mut *ptrs_cur.try_get_mut()?, size_of::<V>())?);
+            ptrs_cur.inc();
+        }
+        let mut vcur = ptrs_cur.align();
+        let mut starts = Vec::with_capacity(len);
+        for _ in 0..value_count {
+            vcur = vcur.align();
+            starts.push(vcur.cur);
+            vcur = fv(vcur)?;
+        }
+        check_starts(&offsets, &starts)?;
+        Ok(vcur.align())
+    }
+}
@@ -0,0 +1,85 @@
+// The relocation table of a blob lists the offsets of all its pointer fields, so that loading the
+// blob is a single loop adding the address of the buffer to each of them, instead of a walk
+// through all its structures. It is a BlobVec<u64> of ascending offsets behind the root, pointed
+// to by `BlobHeader::relocations`.
+
+use super::{
+    get_behind_struct, root::{BlobError, BlobHeader}, vec::BlobVec, BlobPtr, BuildCursor, Reserve,
+    Shifter,
+};
+
+// Tables of at least this many fields are shifted in parallel (with the `parallel` feature).
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN: usize = 1 << 16;
+#[cfg(feature = "parallel")]
+const CHUNK: usize = 1 << 14;
+
+pub fn reserve(table: &[usize], sz: &mut Reserve) -> usize {
+    sz.add::<BlobVec<u64>>(0);
+    let my_addr = sz.0;
+    sz.add::<BlobVec<u64>>(1);
+    sz.add::<u64>(table.len());
+    my_addr
+}
+
+// Write the table reserved at `addr` and point the header to it.
+pub unsafe fn serialize(table: &[usize], buf: *mut u8, addr: usize) {
+    let vec = buf.add(addr) as *mut BlobVec<u64>;
+    (*vec).len = table.len() as u64;
+    let items = get_behind_struct::<_, u64>(vec) as *mut u64;
+    for (ix, offset) in table.iter().enumerate() { *items.add(ix) = *offset as u64; }
+    (*(buf as *mut BlobHeader)).relocations = addr as u64;
+}
+
+// Shift the pointers of the blob (with a valid header) by its relocation table. Returns false,
+// leaving the blob intact, if it has none. Only the pointers and their targets are checked to lie
+// within the blob (in front of the table), not the structures around them, so use this only for
+// the blobs which are known to be intact.
+pub unsafe fn relocate(buf: *mut u8) -> Result<bool, BlobError> {
+    let header = &*(buf as *const BlobHeader);
+    if header.relocations == 0 { return Ok(false); }
+    let at = header.relocations as usize;
+    if at < size_of::<BlobHeader>() || !at.is_multiple_of(align_of::<BlobVec<u64>>()) {
+        return Err(BlobError::Corrupt { offset: at });
+    }
+    let mut cur = BuildCursor::<BlobVec<u64>>::bounded(buf, header.length as usize);
+    cur.cur = at;
+    let _: BuildCursor<()> = BlobVec::<u64>::deserialize(cur.clone(), |_| Ok(()))?;
+    let table = (*cur.get_mut()).as_ref();
+
+    #[cfg(feature = "parallel")]
+    if table.len() >= PARALLEL_MIN {
+        use rayon::prelude::*;
+        let base = buf as usize;
+        table.par_chunks(CHUNK).enumerate().try_for_each(|(ix, fields)| {
+            let prev = if ix == 0 { None } else { Some(table[ix * CHUNK - 1]) };
+            shift_fields(base as *mut u8, at, prev, fields)
+        })?;
+        return Ok(true);
+    }
+
+    shift_fields(buf, at, None, table)?;
+    Ok(true)
+}
+
+// The fields must ascend (after `prev`), so that none is shifted twice, and lie behind the header
+// and in front of `end`, as well as their targets.
+unsafe fn shift_fields(buf: *mut u8, end: usize, mut prev: Option<u64>, fields: &[u64])
+    -> Result<(), BlobError>
+{
+    let shifter = Shifter::of(&BuildCursor::<u8>::bounded(buf, end));
+    for &offset in fields {
+        if prev.is_some_and(|prev| offset <= prev)
+            || offset < size_of::<BlobHeader>() as u64
+            || !offset.is_multiple_of(align_of::<BlobPtr<u8>>() as u64)
+            || offset.checked_add(size_of::<BlobPtr<u8>>() as u64)
+                .is_none_or(|field_end| field_end > end as u64)
+        {
+            return Err(BlobError::Corrupt { offset: offset as usize });
+        }
+        // The target is a byte, in front of the end.
+        shifter.shift(&mut *(buf.add(offset as usize) as *mut BlobPtr<u8>))?;
+        prev = Some(offset);
+    }
+    Ok(())
+}
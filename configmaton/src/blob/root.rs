@@ -0,0 +1,206 @@
+use std::fmt;
+
+use twox_hash::XxHash64;
+
+use super::{automaton::Automaton, snapshot::Snapshot, Swap};
+
+pub const BLOB_MAGIC: [u8; 4] = *b"CFGB";
+// The version of the header. The layout of the root is versioned by its tag.
+pub const FORMAT_VERSION: u8 = 5;
+
+// The start of every blob, followed by its root structure (aligned like the whole buffer, so that
+// the root stays aligned as well).
+#[repr(C, align(16))]
+pub struct BlobHeader {
+    // The bytes up to the length read the same on every target.
+    pub magic: [u8; 4],
+    pub target: TargetLayout,
+    pub version: u8,
+    // Of the whole blob, including the header.
+    pub length: u64,
+    // See `BlobRoot::tag`.
+    pub root_tag: u64,
+    // The offset of the relocation table (see `reloc`), zero if the blob has none. It takes the
+    // former padding, so the older blobs have none.
+    pub relocations: u64,
+    // The offset of the section directory behind the root: a BlobVec<u64> of the offsets of the
+    // sections of the root (see `Section`).
+    pub sections: u64,
+    // A hash of the blob behind the header as built, before any relocation table, see
+    // `snapshot::fingerprint`. It takes the former padding, so the older blobs have zero.
+    pub content_hash: u64,
+}
+
+impl BlobHeader {
+    // Just the fields, the padding stays zeroed.
+    pub unsafe fn write<T: BlobRoot>(header: *mut BlobHeader, length: usize) {
+        (*header).magic = BLOB_MAGIC;
+        (*header).target = TargetLayout::HOST;
+        (*header).version = FORMAT_VERSION;
+        (*header).length = length as u64;
+        (*header).root_tag = T::tag();
+        (*header).relocations = 0;
+        (*header).sections = 0;
+        (*header).content_hash = 0;
+    }
+
+    // Of the blob whose root is given (the root lies right behind the header).
+    pub unsafe fn of_root<T: BlobRoot>(root: &T) -> &BlobHeader {
+        &*(root as *const T as *const BlobHeader).sub(1)
+    }
+
+    // Whether the buffer of `len` bytes holds a blob of the root T, usable on this target.
+    pub unsafe fn check<T: BlobRoot>(data: *const u8, len: usize) -> Result<(), BlobError> {
+        Self::check_in::<T>(data, len, false).map(|_| ())
+    }
+
+    // Like `check`, but a blob of the other byte order (see `TargetLayout::SWAPPED`) passes too,
+    // to be deserialized by `Swap::Load`. Returns how to deserialize the blob.
+    pub unsafe fn check_any_order<T: BlobRoot>(data: *const u8, len: usize)
+        -> Result<Swap, BlobError>
+    {
+        Self::check_in::<T>(data, len, true)
+    }
+
+    unsafe fn check_in<T: BlobRoot>(data: *const u8, len: usize, swappable: bool)
+        -> Result<Swap, BlobError>
+    {
+        if len < size_of::<BlobHeader>() {
+            return Err(BlobError::Truncated {
+                length: size_of::<BlobHeader>() as u64, available: len });
+        }
+        let header = &*(data as *const BlobHeader);
+        if header.magic != BLOB_MAGIC { return Err(BlobError::Magic); }
+        if header.version != FORMAT_VERSION {
+            return Err(BlobError::Version { found: header.version });
+        }
+        let swap = match header.target {
+            TargetLayout::HOST => Swap::None,
+            TargetLayout::SWAPPED if swappable => Swap::Load,
+            found => return Err(BlobError::Target { found }),
+        };
+        let length = swap.peek(&header.length);
+        if length > len as u64 {
+            return Err(BlobError::Truncated { length, available: len });
+        }
+        let root_tag = swap.peek(&header.root_tag);
+        if root_tag != T::tag() {
+            return Err(BlobError::Root { expected: T::NAME, found: root_tag });
+        }
+        Ok(swap)
+    }
+
+    // Swap the byte order of the header, by `Swap::Load` if it has passed `check_any_order`. The
+    // relocation table is dropped, as it is not swapped.
+    pub unsafe fn swap(header: *mut BlobHeader, swap: Swap) {
+        let header = &mut *header;
+        if swap == Swap::None { return; }
+        if header.relocations != 0 {
+            header.length = header.relocations;
+            header.relocations = 0;
+        }
+        let words = [
+            &mut header.length, &mut header.root_tag, &mut header.sections,
+            &mut header.content_hash,
+        ];
+        for word in words {
+            swap.fix(word);
+        }
+        header.target =
+            if swap == Swap::Load { TargetLayout::HOST } else { TargetLayout::SWAPPED };
+    }
+}
+
+// The blobs hold 8-byte words (offsets, lengths, hashes) in the byte order of the target which
+// builds them, so they can be shipped only to targets of the same alignment of the words,
+// regardless of the pointer width (e.g. from x86_64 to aarch64 or wasm32, but not to i686, which
+// aligns u64 to 4 bytes). The targets of the other byte order swap the blobs when reading them
+// (see `Swap`), which takes a walk through the whole blob, so ship them in the byte order of the
+// readers (see `Msg::swap_byte_order`) where it matters.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TargetLayout {
+    // Of u64 (and f64), in bytes.
+    pub word_alignment: u8,
+    // Zero for little-endian, one for big-endian.
+    pub big_endian: u8,
+    // Of the buffer (and of u128), in bytes.
+    pub alignment: u8,
+}
+
+impl TargetLayout {
+    pub const HOST: TargetLayout = TargetLayout {
+        word_alignment: align_of::<u64>() as u8,
+        big_endian: cfg!(target_endian = "big") as u8,
+        alignment: align_of::<u128>() as u8,
+    };
+
+    // Of the targets whose blobs get swapped by this one.
+    pub const SWAPPED: TargetLayout =
+        TargetLayout { big_endian: 1 - TargetLayout::HOST.big_endian, ..TargetLayout::HOST };
+}
+
+impl fmt::Display for TargetLayout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let endian = match self.big_endian { 0 => "little", 1 => "big", _ => "unknown" };
+        write!(f, "{}-endian (words aligned to {} bytes, buffers to {} bytes)", endian,
+            self.word_alignment, self.alignment)
+    }
+}
+
+// A structure which can be the root of a blob.
+pub trait BlobRoot {
+    const NAME: &'static str;
+    // A description of the layout. It must be changed whenever the layout changes, so that the
+    // blobs of the old layout get rejected.
+    const SCHEMA: &'static str;
+
+    fn tag() -> u64 {
+        XxHash64::oneshot(0, Self::SCHEMA.as_bytes())
+    }
+}
+
+// The known roots, by their names and tags.
+pub fn registry() -> Vec<(&'static str, u64)> {
+    vec![(Automaton::NAME, Automaton::tag()), (Snapshot::NAME, Snapshot::tag())]
+}
+
+// Why a blob cannot be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobError {
+    Magic,
+    // The blob has been produced by a version of configmaton with a different header.
+    Version { found: u8 },
+    // The blob has been built on a target of a different layout.
+    Target { found: TargetLayout },
+    // The blob has been produced for a different root structure (or a different layout of it).
+    Root { expected: &'static str, found: u64 },
+    // The blob is longer than its buffer.
+    Truncated { length: u64, available: usize },
+    // A structure of the blob at the offset (or pointed to by it) does not fit into the blob.
+    Corrupt { offset: usize },
+}
+
+impl fmt::Display for BlobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobError::Magic => write!(f, "not a configmaton blob"),
+            BlobError::Version { found } => write!(f, "the blob has the format version {}, \
+                this one reads {}", found, FORMAT_VERSION),
+            BlobError::Target { found } => write!(f, "the blob has been built for a {} target, \
+                this one is {}", found, TargetLayout::HOST),
+            BlobError::Root { expected, found } =>
+                match registry().into_iter().find(|(_, tag)| tag == found) {
+                    Some((name, _)) => write!(f, "the blob root is {}, not {}", name, expected),
+                    None => write!(f, "the blob root has an unknown tag {:#x}, not the one of {}",
+                        found, expected),
+                },
+            BlobError::Truncated { length, available } =>
+                write!(f, "the blob has {} bytes, only {} are available", length, available),
+            BlobError::Corrupt { offset } =>
+                write!(f, "the blob is corrupted at the offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for BlobError {}
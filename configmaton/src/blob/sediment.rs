@@ -1,35 +1,101 @@
 use std::marker::PhantomData;
 
-use super::{Build, BuildCursor, Reserve, get_behind_struct};
+use super::{
+    align_up_ptr, get_behind_struct, BlobLen, Build, BuildCursor, BuildError, CursorResult,
+    FakeSafeIterator, FirstError, Reserve, Stride, UnsafeIterator,
+};
 
+// The length is stored as `L`, see `BlobVec`.
 #[repr(C)]
-pub struct Sediment<'a, X> {
-    pub len: usize,
+pub struct Sediment<'a, X, L = u64> {
+    pub len: L,
     _phantom: PhantomData<&'a X>,
 }
 
-impl<'a, X: Build> Build for Sediment<'a, X> {
+impl<'a, X: Build, L> Build for Sediment<'a, X, L> {
     type Origin = Vec<X::Origin>;
 }
 
-impl<'a, X> Sediment<'a, X> {
+impl<'a, X, L: BlobLen> Sediment<'a, X, L> {
+    pub fn len(&self) -> usize {
+        self.len.to_usize()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub unsafe fn each<F: FnMut(&X) -> *const X>(&self, mut f: F) {
         let mut cur = get_behind_struct::<_, X>(self);
-        for _ in 0..self.len {
+        for _ in 0..self.len() {
             cur = f(&*cur);
         }
     }
 
-    pub unsafe fn deserialize<F: FnMut(BuildCursor<X>) -> BuildCursor<X>, After>
-    (cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
+    pub unsafe fn deserialize<F: FnMut(BuildCursor<X>) -> CursorResult<X>, After>
+    (cur: BuildCursor<Self>, mut f: F) -> CursorResult<After>
     {
         let mut xcur = cur.behind(1);
-        for _ in 0..(*cur.get_mut()).len { xcur = f(xcur); }
-        xcur.align()
+        let len = cur.swap.fix(&mut (*cur.try_get_mut()?).len).to_usize();
+        for _ in 0..len { xcur = f(xcur)?; }
+        Ok(xcur.align())
     }
 }
 
-impl<'a, X: Build> Sediment<'a, X> {
+pub struct SedimentIter<'a, X> {
+    cur: *const X,
+    left: usize,
+    _phantom: PhantomData<&'a X>,
+}
+
+impl<'a, X: Stride + 'a> UnsafeIterator for SedimentIter<'a, X> {
+    type Item = &'a X;
+
+    unsafe fn next(&mut self) -> Option<Self::Item> {
+        if self.left == 0 { return None; }
+        let x = &*self.cur;
+        self.left -= 1;
+        if self.left > 0 { self.cur = align_up_ptr(x.end()); }
+        Some(x)
+    }
+}
+
+impl<'a, X: Stride + 'a, L: BlobLen> Sediment<'a, X, L> {
+    pub unsafe fn iter(&self) -> FakeSafeIterator<SedimentIter<'a, X>> {
+        FakeSafeIterator(SedimentIter {
+            cur: get_behind_struct(self),
+            left: self.len(),
+            _phantom: PhantomData,
+        })
+    }
+
+    pub unsafe fn behind<After>(&self) -> &'a After {
+        &*align_up_ptr(self.end())
+    }
+}
+
+impl<'a, X: Stride + 'a, L: BlobLen> Stride for Sediment<'a, X, L> {
+    unsafe fn end(&self) -> *const u8 {
+        match self.iter().last() {
+            Some(x) => x.end(),
+            None => (self as *const Self).add(1) as *const u8,
+        }
+    }
+}
+
+impl<'a, X: Build, L: BlobLen> Sediment<'a, X, L> {
+    // Like reserve, but check that the length fits into `L` and propagate the errors of `f`.
+    pub fn try_reserve<F: FnMut(&X::Origin, &mut Reserve) -> Result<(), BuildError>>
+        (origin: &<Self as Build>::Origin, sz: &mut Reserve, f: F) -> Result<usize, BuildError>
+    {
+        if L::from_usize(origin.len()).is_none() {
+            return Err(BuildError::TooLong { len: origin.len(), max: L::MAX });
+        }
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(f));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<F: FnMut(&X::Origin, &mut Reserve)>
         (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut f: F) -> usize
     {
@@ -44,7 +110,7 @@ impl<'a, X: Build> Sediment<'a, X> {
     pub unsafe fn serialize<F: FnMut(&X::Origin, BuildCursor<X>) -> BuildCursor<X>, After>
     (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
     {
-        (*cur.get_mut()).len = origin.len();
+        (*cur.get_mut()).len = L::from_usize(origin.len()).expect("checked by try_reserve");
         let mut xcur = cur.behind(1);
         for x in origin.iter() { xcur = f(x, xcur); }
         xcur.align()
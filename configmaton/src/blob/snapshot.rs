@@ -0,0 +1,309 @@
+// The runtime state of a configmaton, see `Configmaton::snapshot`. Unlike the automaton, the
+// snapshot holds no pointers: the byte strings are referred to by their indices in the string pool
+// and the keyval states by their offsets in the automaton. So it needs no deserialization, and it
+// can be restored by another process loading the same automaton.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use hashbrown::HashMap;
+use twox_hash::XxHash64;
+
+use super::{
+    automaton::{self, Automaton}, get_behind_struct, keyval_state::KeyValState,
+    root::{BlobError, BlobHeader, BlobRoot}, tupellum::Tupellum8, vec::BlobVec,
+    vec_of_vecs::VecOfVecs, Build, BuildCursor, Reserve,
+};
+use crate::keyval_simulator::{Emitter, SimulationState};
+use crate::onion::Meta;
+
+pub type Snapshot<'a> = Tupellum8<'a,
+    SnapshotInfo,
+    VecOfVecs<'a, u8>,  // The strings
+    BlobVec<'a, SnapshotEntry>,  // The values of the onion, in the order of `Onion::entries`
+    BlobVec<'a, u64>,  // The offsets of the current states in the automaton
+    BlobVec<'a, SnapshotCommand>,  // The queued commands, from the first one
+    BlobVec<'a, u64>,  // The disabled groups
+    BlobVec<'a, SnapshotSet>,  // The suspended sets
+    BlobVec<'a, u64>,  // The keys whose old values are yet to be read
+>;
+
+impl BlobRoot for Snapshot<'_> {
+    const NAME: &'static str = "Snapshot";
+    const SCHEMA: &'static str = "Snapshot(info: SnapshotInfo(automaton, trigger), \
+        strings: VecOfVecs<u8>, entries: BlobVec<SnapshotEntry(key, value, source, set_at)>, \
+        states: BlobVec<u64>, commands: BlobVec<SnapshotCommand(command, rule, key)>, \
+        disabled_groups: BlobVec<u64>, pending: BlobVec<SnapshotSet(key, value)>, \
+        getolds: BlobVec<u64>)";
+}
+
+// The index of no string, or the rule of no emitter.
+const NONE: u64 = u64::MAX;
+const NO_TIME: u64 = u64::MAX;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    // See `fingerprint`.
+    pub automaton: u64,
+    pub trigger: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub key: u64,
+    pub value: u64,
+    pub source: u64,
+    // In nanoseconds since the Unix epoch.
+    pub set_at: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotCommand {
+    pub command: u64,
+    pub rule: u64,
+    pub key: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotSet {
+    pub key: u64,
+    pub value: u64,
+}
+
+impl Build for SnapshotInfo { type Origin = SnapshotInfo; }
+impl Build for SnapshotEntry { type Origin = SnapshotEntry; }
+impl Build for SnapshotCommand { type Origin = SnapshotCommand; }
+impl Build for SnapshotSet { type Origin = SnapshotSet; }
+
+// What a snapshot holds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotContents<'a> {
+    pub entries: Vec<(&'a [u8], &'a [u8], Meta<'a>)>,
+    pub simulation: SimulationState<'a>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreError {
+    Blob(BlobError),
+    // The snapshot has been taken with another automaton.
+    Automaton,
+}
+
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestoreError::Blob(error) => write!(f, "invalid snapshot: {}", error),
+            RestoreError::Automaton => write!(f, "the snapshot is of another automaton"),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+impl From<BlobError> for RestoreError {
+    fn from(error: BlobError) -> Self {
+        RestoreError::Blob(error)
+    }
+}
+
+// Tells the automata apart, so that a snapshot is not restored with another one: a hash of the
+// offsets of the initial states and of the patterns, seeded by the content hash of the blob (see
+// `BlobHeader::content_hash`), so that the automata of the same shape differ as well, e.g. by a
+// regex. The automaton must be the root of a blob.
+pub unsafe fn fingerprint(automaton: &Automaton) -> u64 {
+    let base = automaton as *const Automaton as usize;
+    let mut hash = BlobHeader::of_root(automaton).content_hash;
+    for init in automaton::inits(automaton) {
+        hash = XxHash64::oneshot(hash, &((init.get() as usize - base) as u64).to_le_bytes());
+    }
+    for pattern in automaton::patterns(automaton) {
+        hash = XxHash64::oneshot(hash, pattern.key);
+        hash = XxHash64::oneshot(hash, &(pattern.id as u64).to_le_bytes());
+    }
+    hash
+}
+
+#[derive(Default)]
+struct StringPool<'a> {
+    strings: Vec<Vec<u8>>,
+    indices: HashMap<&'a [u8], u64>,
+}
+
+impl<'a> StringPool<'a> {
+    fn add(&mut self, string: &'a [u8]) -> u64 {
+        *self.indices.entry(string).or_insert_with(|| {
+            self.strings.push(string.to_vec());
+            self.strings.len() as u64 - 1
+        })
+    }
+
+    fn add_opt(&mut self, string: Option<&'a [u8]>) -> u64 {
+        string.map_or(NONE, |string| self.add(string))
+    }
+}
+
+// The states must be of the automaton.
+pub unsafe fn serialize<'a>(automaton: &Automaton<'a>, contents: &SnapshotContents<'a>)
+    -> Vec<u8>
+{
+    let base = automaton as *const Automaton as usize;
+    let simulation = &contents.simulation;
+    let mut pool = StringPool::default();
+    let entries = contents.entries.iter().map(|(key, value, meta)| SnapshotEntry {
+        key: pool.add(key),
+        value: pool.add(value),
+        source: pool.add_opt(meta.source),
+        // The times before the epoch are not kept.
+        set_at: meta.set_at
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .and_then(|since| u64::try_from(since.as_nanos()).ok())
+            .unwrap_or(NO_TIME),
+    }).collect::<Vec<_>>();
+    let states = simulation.states.iter().map(|state| *state as usize - base).collect::<Vec<_>>();
+    let commands = simulation.commands.iter().map(|(command, emitter)| SnapshotCommand {
+        command: pool.add(command),
+        rule: emitter.map_or(NONE, |emitter| emitter.rule as u64),
+        key: pool.add_opt(emitter.and_then(|emitter| emitter.key)),
+    }).collect::<Vec<_>>();
+    // Not borrowed for `'a`, so not pooled.
+    let groups = simulation.disabled_groups.iter()
+        .map(|group| {
+            let index = pool.strings.len();
+            pool.strings.push(group.clone());
+            index
+        })
+        .collect::<Vec<_>>();
+    let pending = simulation.pending.iter()
+        .map(|(key, value)| SnapshotSet { key: pool.add(key), value: pool.add(value) })
+        .collect::<Vec<_>>();
+    let getolds = simulation.getolds.iter().map(|key| pool.add(key) as usize).collect::<Vec<_>>();
+    let info = SnapshotInfo {
+        automaton: fingerprint(automaton),
+        trigger: pool.add_opt(simulation.trigger),
+    };
+    let origin = (info, pool.strings, entries, states, commands, groups, pending, getolds);
+
+    let mut sz = Reserve(0);
+    sz.add::<BlobHeader>(1);
+    Snapshot::reserve(&origin, &mut sz,
+        |_, sz| sz.add::<SnapshotInfo>(1),
+        |strings, sz| { VecOfVecs::<u8>::reserve(strings, sz); },
+        |entries, sz| { BlobVec::<SnapshotEntry>::reserve(entries, sz); },
+        |states, sz| { BlobVec::<u64>::reserve(states, sz); },
+        |commands, sz| { BlobVec::<SnapshotCommand>::reserve(commands, sz); },
+        |groups, sz| { BlobVec::<u64>::reserve(groups, sz); },
+        |pending, sz| { BlobVec::<SnapshotSet>::reserve(pending, sz); },
+        |getolds, sz| { BlobVec::<u64>::reserve(getolds, sz); },
+    );
+
+    let len = sz.0;
+    let mut buff = vec![0u128; len.div_ceil(size_of::<u128>())];
+    let buf = buff.as_mut_ptr() as *mut u8;
+    let header = BuildCursor::<BlobHeader>::new(buf);
+    BlobHeader::write::<Snapshot>(header.get_mut(), len);
+    let _: BuildCursor<()> = Snapshot::serialize(&origin, header.behind(1),
+        |info, cur| { *cur.get_mut() = *info; cur.behind(1) },
+        |strings, cur| VecOfVecs::<u8>::serialize(strings, cur, |x, y| { *y = *x; }),
+        |entries, cur| BlobVec::<SnapshotEntry>::serialize(entries, cur, |x, y| { *y = *x; }),
+        |states, cur| BlobVec::<u64>::serialize(states, cur, |x, y| { *y = *x as u64; }),
+        |commands, cur| BlobVec::<SnapshotCommand>::serialize(commands, cur, |x, y| { *y = *x; }),
+        |groups, cur| BlobVec::<u64>::serialize(groups, cur, |x, y| { *y = *x as u64; }),
+        |pending, cur| BlobVec::<SnapshotSet>::serialize(pending, cur, |x, y| { *y = *x; }),
+        |getolds, cur| BlobVec::<u64>::serialize(getolds, cur, |x, y| { *y = *x as u64; }),
+    );
+    std::slice::from_raw_parts(buf, len).to_vec()
+}
+
+// Read the snapshot of a configmaton of the automaton. The strings borrow the snapshot.
+pub unsafe fn restore<'a>(automaton: &Automaton<'a>, snapshot: &'a [u8])
+    -> Result<SnapshotContents<'a>, RestoreError>
+{
+    // The snapshot need not be aligned, so it is read from an aligned copy. The strings are then
+    // taken from the snapshot itself, at the same offsets.
+    let mut buff = vec![0u128; snapshot.len().div_ceil(size_of::<u128>())];
+    let buf = buff.as_mut_ptr() as *mut u8;
+    std::ptr::copy_nonoverlapping(snapshot.as_ptr(), buf, snapshot.len());
+    BlobHeader::check::<Snapshot>(buf, snapshot.len())?;
+    let header = BuildCursor::<BlobHeader>::bounded(buf, (*(buf as *const BlobHeader)).length as _);
+    let cur = header.behind::<Snapshot>(1);
+    let _: BuildCursor<()> = Snapshot::deserialize(cur.clone(),
+        |cur| { cur.try_get_mut()?; Ok(cur.behind(1)) },
+        |cur| VecOfVecs::<u8>::deserialize(cur, |_| Ok(())),
+        |cur| BlobVec::<SnapshotEntry>::deserialize(cur, |_| Ok(())),
+        |cur| BlobVec::<u64>::deserialize(cur, |_| Ok(())),
+        |cur| BlobVec::<SnapshotCommand>::deserialize(cur, |_| Ok(())),
+        |cur| BlobVec::<u64>::deserialize(cur, |_| Ok(())),
+        |cur| BlobVec::<SnapshotSet>::deserialize(cur, |_| Ok(())),
+        |cur| BlobVec::<u64>::deserialize(cur, |_| Ok(())),
+    )?;
+
+    let info = &*(cur.get_mut() as *const SnapshotInfo);
+    if info.automaton != fingerprint(automaton) { return Err(RestoreError::Automaton); }
+    let strings: &VecOfVecs<u8> = &*get_behind_struct(info);
+    let entries: &BlobVec<SnapshotEntry> = strings.behind();
+    let states: &BlobVec<u64> = entries.behind();
+    let commands: &BlobVec<SnapshotCommand> = states.behind();
+    let groups: &BlobVec<u64> = commands.behind();
+    let pending: &BlobVec<SnapshotSet> = groups.behind();
+    let getolds: &BlobVec<u64> = pending.behind();
+
+    // The offset of the item in the blob, for the errors.
+    let corrupt = |item: *const u8| BlobError::Corrupt { offset: item as usize - buf as usize };
+    let string = |ix: u64, item: *const u8| -> Result<&'a [u8], BlobError> {
+        if ix >= strings.len() as u64 { return Err(corrupt(item)); }
+        let string = strings.get(ix as usize);
+        let start = string.as_ptr() as usize - buf as usize;
+        Ok(&snapshot[start..start + string.len()])
+    };
+    let string_opt = |ix: u64, item: *const u8| match ix {
+        NONE => Ok(None),
+        ix => string(ix, item).map(Some),
+    };
+
+    let entries = entries.as_ref().iter().map(|entry| {
+        let item = entry as *const SnapshotEntry as *const u8;
+        let set_at = match entry.set_at {
+            NO_TIME => None,
+            nanos => Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos)),
+        };
+        let meta = Meta { set_at, source: string_opt(entry.source, item)? };
+        Ok((string(entry.key, item)?, string(entry.value, item)?, meta))
+    }).collect::<Result<Vec<_>, BlobError>>()?;
+
+    let base = automaton as *const Automaton as usize;
+    let (reachable, _) = automaton::reachable_keyval_states(automaton::inits(automaton));
+    let states = states.as_ref().iter().map(|offset| {
+        let state = usize::try_from(*offset)
+            .map_or(std::ptr::null(), |offset| base.wrapping_add(offset) as *const KeyValState);
+        if reachable.contains(&state) { Ok(state) } else { Err(corrupt(offset as *const _ as _)) }
+    }).collect::<Result<Vec<_>, BlobError>>()?;
+
+    let commands = commands.as_ref().iter().map(|command| {
+        let item = command as *const SnapshotCommand as *const u8;
+        let emitter = match command.rule {
+            NONE => None,
+            rule => Some(Emitter { rule: rule as usize, key: string_opt(command.key, item)? }),
+        };
+        Ok((string(command.command, item)?, emitter))
+    }).collect::<Result<Vec<_>, BlobError>>()?;
+
+    let disabled_groups = groups.as_ref().iter()
+        .map(|group| string(*group, group as *const u64 as _).map(<[u8]>::to_vec))
+        .collect::<Result<Vec<_>, BlobError>>()?;
+    let pending = pending.as_ref().iter().map(|set| {
+        let item = set as *const SnapshotSet as *const u8;
+        Ok((string(set.key, item)?, string(set.value, item)?))
+    }).collect::<Result<Vec<_>, BlobError>>()?;
+    let getolds = getolds.as_ref().iter()
+        .map(|key| string(*key, key as *const u64 as _))
+        .collect::<Result<Vec<_>, BlobError>>()?;
+    let trigger = string_opt(info.trigger, info as *const SnapshotInfo as _)?;
+
+    let simulation =
+        SimulationState { states, commands, disabled_groups, pending, getolds, trigger };
+    Ok(SnapshotContents { entries, simulation })
+}
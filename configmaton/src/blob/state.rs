@@ -1,42 +1,84 @@
 use std::mem::ManuallyDrop;
 
+use hashbrown::HashMap;
+
 use super::{
-    Build, BuildCursor, Reserve, Shifter, UnsafeIterator,
-    vec::{BlobVec, BlobVecIter}, vecmap::{VecMap, VecMapIter}, hashmap::BlobHashMap,
-    arrmap::ArrMap, Assocs as _
+    Build, BuildCursor, BuildError, CursorResult, Reserve, Shifter, UnsafeIterator, XxHashStrategy,
+    BlobPtr, Target,
+    check_indices, root::BlobError, context::{CtxPair, Has, TagSetPtrs, U8StatePtrs},
+    sediment::Sediment,
+    vec::{BlobVec, BlobVecIter}, vecmap::{VecMap, VecMapIter}, hashmap::{BlobHashMap, bucketize},
+    vec_of_vecs::VecOfVecs, rangemap::RangeMap, Assocs as _
 };
 use crate::guards::Guard;
 
-type U8States<'a> = BlobVec<'a, *const U8State<'a>>;
+type U8States<'a> = BlobVec<'a, BlobPtr<U8State<'a>>>;
 type U8AList<'a> = VecMap<'a, u8, U8States<'a>>;
 type U8ExplicitTrans<'a> = BlobHashMap<'a, U8AList<'a>>;
-type U8Tags<'a> = BlobVec<'a, usize>;
+type U8Tags<'a> = BlobVec<'a, u64>;
 type U8PatternTrans<'a> = VecMap<'a, Guard, U8States<'a>>;
-type U8ArrMap<'a> = ArrMap<'a, 256, U8States<'a>>;
-
-impl Build for *const U8State<'_> {
+// The successors by the byte, the last vector holds those after the end of the value. The offsets
+// of the neighbouring bytes share a cache line and the successors follow them, so a step reads no
+// pointers to separate vectors.
+type U8DenseTrans<'a> = VecOfVecs<'a, BlobPtr<U8State<'a>>>;
+// The same with 2-byte offsets, chosen by `U8BuildConfig::narrow_dense_offsets`.
+type U8NarrowDenseTrans<'a> = VecOfVecs<'a, BlobPtr<U8State<'a>>, u16>;
+type U8RangeMap<'a> = RangeMap<'a, U8States<'a>>;
+// The distinct tag sets of the states, each stored once. The states point into the pool, so states
+// with equal tags have equal tag pointers.
+pub type U8TagPool<'a> = Sediment<'a, U8Tags<'a>>;
+
+impl Build for BlobPtr<U8State<'_>> {
     type Origin = usize;
 }
 
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum U8StateKind {
+    Sparse,
+    Dense,
+    Ranged,
+    NarrowDense,
+}
+
 #[repr(C)]
 pub struct U8SparseState<'a> {
-    is_dense: bool,
-    tags: *const U8Tags<'a>,
-    explicit_trans: *const U8ExplicitTrans<'a>,
+    kind: U8StateKind,
+    tags: BlobPtr<U8Tags<'a>>,
+    // The successors after the end of the value, null if there are none.
+    end_trans: BlobPtr<U8States<'a>>,
+    explicit_trans: BlobPtr<U8ExplicitTrans<'a>>,
     pattern_trans: U8PatternTrans<'a>,
 }
 
 #[repr(C)]
 pub struct U8DenseState<'a> {
-    is_dense: bool,
-    tags: *const U8Tags<'a>,
-    trans: U8ArrMap<'a>,
+    kind: U8StateKind,
+    tags: BlobPtr<U8Tags<'a>>,
+    trans: U8DenseTrans<'a>,
+}
+
+#[repr(C)]
+pub struct U8NarrowDenseState<'a> {
+    kind: U8StateKind,
+    tags: BlobPtr<U8Tags<'a>>,
+    trans: U8NarrowDenseTrans<'a>,
+}
+
+#[repr(C)]
+pub struct U8RangedState<'a> {
+    kind: U8StateKind,
+    tags: BlobPtr<U8Tags<'a>>,
+    end_trans: BlobPtr<U8States<'a>>,
+    trans: U8RangeMap<'a>,
 }
 
 #[repr(C)]
 pub union U8State<'a> {
     sparse: ManuallyDrop<U8SparseState<'a>>,
     dense: ManuallyDrop<U8DenseState<'a>>,
+    ranged: ManuallyDrop<U8RangedState<'a>>,
+    narrow_dense: ManuallyDrop<U8NarrowDenseState<'a>>,
 }
 
 impl<'a> Build for U8State<'a> {
@@ -47,111 +89,237 @@ impl<'a> U8State<'a> {
     pub unsafe fn iter_matches<'c, 'b>(&'c self, key: &'b u8) -> U8StateIterator<'a, 'b>
         where 'a: 'b + 'c
     {
-        if self.sparse.is_dense {
-            U8StateIterator::Dense(self.dense.trans.get(*key as usize).iter())
-        } else {
-            let sparse = &self.sparse;
-            U8StateIterator::Sparse(
-                U8SparseStateIterator {
-                    pattern_iter: sparse.pattern_trans.iter_matches(key),
-                    states_iter: None,
-                    explicit_trans: sparse.explicit_trans,
-                }
-            )
+        match self.sparse.kind {
+            U8StateKind::Dense =>
+                U8StateIterator::Dense(self.dense.trans.get(*key as usize).into()),
+            U8StateKind::NarrowDense =>
+                U8StateIterator::Dense(self.narrow_dense.trans.get(*key as usize).into()),
+            U8StateKind::Ranged =>
+                U8StateIterator::Ranged(self.ranged.trans.get(*key).iter()),
+            U8StateKind::Sparse => {
+                let sparse = &self.sparse;
+                U8StateIterator::Sparse(
+                    U8SparseStateIterator {
+                        pattern_iter: sparse.pattern_trans.iter_matches(key),
+                        states_iter: None,
+                        explicit_trans: sparse.explicit_trans.get(),
+                        guard: None,
+                    }
+                )
+            },
         }
     }
 
-    pub unsafe fn get_tags(&self) -> &[usize] {
+    // A safe variant of `iter_matches`. The blob states cannot be created in safe code, so a state
+    // borrowed for 'a belongs to a deserialized blob which lives at least as long, and so do its
+    // successors.
+    pub fn successors<'b>(&'a self, key: &'b u8) -> Successors<'a, 'b> where 'a: 'b {
+        let iter = unsafe { self.iter_matches(key) };
+        let guard = match iter {
+            U8StateIterator::Dense(_) => TransitionGuard::Dense,
+            U8StateIterator::Ranged(_) => TransitionGuard::Ranged,
+            U8StateIterator::Sparse(_) => TransitionGuard::Explicit,
+        };
+        Successors { iter, guard }
+    }
+
+    pub unsafe fn get_tags(&self) -> &[u64] {
         if self.sparse.tags.is_null() { &[] }
-        else { (*self.sparse.tags).as_ref() }
+        else { (*self.sparse.tags.get()).as_ref() }
+    }
+
+    // Identifies the tag set within the pool, states with equal tags have equal IDs (null for no
+    // tags).
+    pub fn tag_set_id(&self) -> *const () {
+        unsafe { self.sparse.tags.get() as *const () }
     }
 
-    pub unsafe fn deserialize<B>(state_cur: BuildCursor<U8State>) -> BuildCursor<B> {
-        let shifter = Shifter(state_cur.buf);
-        let state = &mut *state_cur.get_mut();
-        let f_is_dense_cur = state_cur.transmute::<bool>();
-        let f_tags_cur = f_is_dense_cur.behind::<*const U8Tags>(1);
-        let shiftq = |q: &mut *const U8State| shifter.shift(q);
-
-        if state.sparse.is_dense {
-            let dense = &mut state.dense;
-            let f_trans_cur = f_tags_cur.behind::<U8ArrMap>(1);
-            let tags_cur: BuildCursor<u8> = U8ArrMap::deserialize(f_trans_cur,
-                |qs_cur| U8States::deserialize(qs_cur, shiftq));
-
-            if dense.tags.is_null() { tags_cur.align() }
-            else {
-                shifter.shift(&mut dense.tags);
-                U8Tags::deserialize(tags_cur.align(), |_| ())
+    // The successors after the end of the value.
+    pub unsafe fn end_successors(&self) -> &[BlobPtr<U8State<'a>>] {
+        let end_trans = match self.sparse.kind {
+            U8StateKind::Dense => return self.dense.trans.get(256),
+            U8StateKind::NarrowDense => return self.narrow_dense.trans.get(256),
+            U8StateKind::Ranged => self.ranged.end_trans,
+            U8StateKind::Sparse => self.sparse.end_trans,
+        };
+        if end_trans.is_null() { &[] } else { (*end_trans.get()).as_ref() }
+    }
+
+    pub unsafe fn deserialize<B>(state_cur: BuildCursor<U8State>) -> CursorResult<B> {
+        state_cur.mark(Target::U8State);
+        let shifter = Shifter::of(&state_cur);
+        let shift_tags = |tags: &mut BlobPtr<U8Tags>| match tags.is_null() {
+            true => Ok(()),
+            false => shifter.shift_to(tags, size_of::<U8Tags>(), Target::U8Tags),
+        };
+        let f_kind_cur = state_cur.transmute::<U8StateKind>();
+        let f_tags_cur = f_kind_cur.behind::<BlobPtr<U8Tags>>(1);
+        let shiftq = |q: &mut BlobPtr<U8State>| Self::shift_ptr(q, &shifter);
+
+        // Read as a byte first, a corrupted kind may be out of the enum.
+        let kind = *state_cur.transmute::<u8>().try_get_mut()?;
+        if kind == U8StateKind::Ranged as u8 {
+            let ranged = &mut *state_cur.transmute::<U8RangedState>().try_get_mut()?;
+            let f_end_trans_cur = f_tags_cur.behind::<BlobPtr<U8States>>(1);
+            let f_trans_cur = f_end_trans_cur.behind::<U8RangeMap>(1);
+            let end_cur: BuildCursor<u8> = U8RangeMap::deserialize(f_trans_cur,
+                |qs_cur| U8States::deserialize(qs_cur, shiftq))?;
+            shift_tags(&mut ranged.tags)?;
+            Self::deserialize_end(&mut ranged.end_trans, end_cur, &shifter)
+        } else if kind == U8StateKind::Dense as u8 {
+            let dense = &mut *state_cur.transmute::<U8DenseState>().try_get_mut()?;
+            let f_trans_cur = f_tags_cur.behind::<U8DenseTrans>(1);
+            if f_trans_cur.swap.peek(&(*f_trans_cur.try_get_mut()?).len) != 257 {
+                return Err(BlobError::Corrupt { offset: f_trans_cur.cur });
             }
-        } else {
-            let sparse = &mut state.sparse;
-            shifter.shift(&mut sparse.explicit_trans);
+            shift_tags(&mut dense.tags)?;
+            U8DenseTrans::deserialize(f_trans_cur, shiftq)
+        } else if kind == U8StateKind::NarrowDense as u8 {
+            let dense = &mut *state_cur.transmute::<U8NarrowDenseState>().try_get_mut()?;
+            let f_trans_cur = f_tags_cur.behind::<U8NarrowDenseTrans>(1);
+            if f_trans_cur.swap.peek(&(*f_trans_cur.try_get_mut()?).len) != 257 {
+                return Err(BlobError::Corrupt { offset: f_trans_cur.cur });
+            }
+            shift_tags(&mut dense.tags)?;
+            U8NarrowDenseTrans::deserialize(f_trans_cur, shiftq)
+        } else if kind == U8StateKind::Sparse as u8 {
+            let sparse = &mut *state_cur.transmute::<U8SparseState>().try_get_mut()?;
 
-            let f_explicit_trans_cur = f_tags_cur.behind::<*const U8ExplicitTrans>(1);
+            let f_end_trans_cur = f_tags_cur.behind::<BlobPtr<U8States>>(1);
+            let f_explicit_trans_cur = f_end_trans_cur.behind::<BlobPtr<U8ExplicitTrans>>(1);
             let f_pattern_trans_cur = f_explicit_trans_cur.behind::<U8PatternTrans>(1);
-            let exp_cur = U8PatternTrans::deserialize(
-                f_pattern_trans_cur, |_| (), |qs_cur| U8States::deserialize(qs_cur, shiftq));
+            let exp_cur = U8PatternTrans::deserialize(f_pattern_trans_cur,
+                |guard| shifter.scalar(guard), |qs_cur| U8States::deserialize(qs_cur, shiftq))?;
+            shifter.shift_at(&mut sparse.explicit_trans, &exp_cur)?;
 
-            let tags_cur: BuildCursor<u8> = U8ExplicitTrans::deserialize(exp_cur, |alist_cur|
-                U8AList::deserialize(alist_cur, |_| (),
+            let end_cur: BuildCursor<u8> = U8ExplicitTrans::deserialize(exp_cur, |alist_cur|
+                U8AList::deserialize(alist_cur, |_| Ok(()),
                     |qs_cur| U8States::deserialize(qs_cur, shiftq))
-            );
+            )?;
+            shift_tags(&mut sparse.tags)?;
+            Self::deserialize_end(&mut sparse.end_trans, end_cur, &shifter)
+        } else {
+            Err(BlobError::Corrupt { offset: state_cur.cur })
+        }
+    }
 
-            if sparse.tags.is_null() { tags_cur.align() }
-            else {
-                shifter.shift(&mut sparse.tags);
-                U8Tags::deserialize(tags_cur.align(), |_| ())
-            }
+    // Shift a pointer to a state. Only the kind of the state is checked to be within the blob, the
+    // rest of it (whose size depends on the kind) is checked when the state is deserialized, and
+    // that it is a state at all after the walk, see `Targets`.
+    pub unsafe fn shift_ptr(q: &mut BlobPtr<U8State>, shifter: &Shifter)
+        -> Result<(), BlobError>
+    {
+        shifter.shift_to(q, size_of::<U8StateKind>(), Target::U8State)
+    }
+
+    unsafe fn deserialize_end<'b, B>
+    (end_trans: &mut BlobPtr<U8States<'b>>, cur: BuildCursor<u8>, shifter: &Shifter)
+        -> CursorResult<B>
+    {
+        if end_trans.is_null() { return Ok(cur.align()); }
+        let cur = cur.align();
+        shifter.shift_at(end_trans, &cur)?;
+        U8States::deserialize(cur, |q| Self::shift_ptr(q, shifter))
+    }
+
+    // Like reserve, but check the origin first, including that the successors are below
+    // `state_count`, so that serialize does not fail.
+    pub fn try_reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve, state_count: usize)
+        -> Result<usize, BuildError>
+    {
+        let check_qs = |qs: &Vec<usize>| check_indices("U8State", qs, state_count);
+        match origin {
+            U8StatePrepared::Sparse(sparse) => {
+                check_qs(&sparse.end_trans)?;
+                for (_, qs) in sparse.pattern_trans.iter() { check_qs(qs)?; }
+                U8ExplicitTrans::check(&sparse.explicit_trans)?;
+                for alist in sparse.explicit_trans.1.iter() {
+                    for (_, qs) in alist.iter() { check_qs(qs)?; }
+                }
+            },
+            U8StatePrepared::Dense(dense) => {
+                for qs in dense.trans.iter() { check_qs(qs)?; }
+            },
+            U8StatePrepared::Ranged(ranged) => {
+                U8RangeMap::check(&ranged.trans)?;
+                check_qs(&ranged.end_trans)?;
+                for qs in ranged.trans.1.iter() { check_qs(qs)?; }
+            },
         }
+        Ok(Self::reserve(origin, sz))
     }
 
     pub fn reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve) -> usize {
         sz.add::<U8State>(0);
         let result = sz.0;
-        sz.add::<bool>(1);
-        sz.add::<*const U8Tags>(1);
+        sz.add::<U8StateKind>(1);
+        sz.add::<BlobPtr<U8Tags>>(1);
         match origin {
             U8StatePrepared::Sparse(sparse) => {
-                sz.add::<*const U8ExplicitTrans>(1);
+                sz.add::<BlobPtr<U8States>>(1);
+                sz.add::<BlobPtr<U8ExplicitTrans>>(1);
                 U8PatternTrans::reserve(&sparse.pattern_trans, sz,
                     |qs, sz| { U8States::reserve(qs, sz); });
                 U8ExplicitTrans::reserve(&sparse.explicit_trans, sz, |alist, sz| {
                     U8AList::reserve(alist, sz, |qs, sz| { U8States::reserve(qs, sz); });
                 });
-                if !sparse.tags.is_empty() { U8Tags::reserve(&sparse.tags, sz); }
+                if !sparse.end_trans.is_empty() { U8States::reserve(&sparse.end_trans, sz); }
+            },
+            U8StatePrepared::Dense(dense) if dense.narrow => {
+                U8NarrowDenseTrans::reserve(&dense.trans, sz);
             },
             U8StatePrepared::Dense(dense) => {
-                U8ArrMap::reserve(&dense.trans, sz, |qs, sz| { U8States::reserve(qs, sz); });
-                if !dense.tags.is_empty() { U8Tags::reserve(&dense.tags, sz); }
+                U8DenseTrans::reserve(&dense.trans, sz);
+            },
+            U8StatePrepared::Ranged(ranged) => {
+                sz.add::<BlobPtr<U8States>>(1);
+                U8RangeMap::reserve(&ranged.trans, sz, |qs, sz| { U8States::reserve(qs, sz); });
+                if !ranged.end_trans.is_empty() { U8States::reserve(&ranged.end_trans, sz); }
             },
         }
 
         result
     }
 
-    pub unsafe fn serialize<After>
-    (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, qptrs: &Vec<usize>)
+    // The tags are looked up in `tagptrs`, the addresses of the tag sets in the pool.
+    pub unsafe fn serialize<After>(
+        origin: &<Self as Build>::Origin,
+        cur: BuildCursor<Self>,
+        qptrs: &[usize],
+        tagptrs: &HashMap<Vec<usize>, usize>,
+    ) -> BuildCursor<After>
+    {
+        Self::serialize_in(origin, cur, &CtxPair(U8StatePtrs(qptrs), TagSetPtrs(tagptrs)))
+    }
+
+    pub unsafe fn serialize_in<'p, After, I1, I2, C>
+    (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, ctx: &C)
     -> BuildCursor<After>
+        where C: Has<U8StatePtrs<'p>, I1> + Has<TagSetPtrs<'p>, I2>
     {
+        let qptrs = Has::<U8StatePtrs, I1>::get(ctx).0;
+        let tagptrs = Has::<TagSetPtrs, I2>::get(ctx).0;
+        let tagptr = |tags: &Vec<usize>| if tags.is_empty() { BlobPtr::NULL }
+            else { BlobPtr::offset(tagptrs[tags]) };
         let state = &mut *cur.get_mut();
-        let f_is_dense_cur = cur.transmute::<bool>();
-        let f_tags_cur = f_is_dense_cur.behind::<*const U8Tags>(1);
-        let setq = |q: &usize, qref: &mut *const U8State| { *qref = qptrs[*q] as *const U8State; };
+        let f_kind_cur = cur.transmute::<U8StateKind>();
+        let f_tags_cur = f_kind_cur.behind::<BlobPtr<U8Tags>>(1);
+        let setq = |q: &usize, qref: &mut BlobPtr<U8State>| { *qref = BlobPtr::offset(qptrs[*q]); };
 
         match origin {
             U8StatePrepared::Sparse(sparse_origin) => {
                 let sparse = &mut state.sparse;
-                sparse.is_dense = false;
-                let f_explicit_trans_cur = f_tags_cur.behind::<*const U8ExplicitTrans>(1);
+                sparse.kind = U8StateKind::Sparse;
+                let f_end_trans_cur = f_tags_cur.behind::<BlobPtr<U8States>>(1);
+                let f_explicit_trans_cur = f_end_trans_cur.behind::<BlobPtr<U8ExplicitTrans>>(1);
                 let f_pattern_trans_cur = f_explicit_trans_cur.behind::<U8PatternTrans>(1);
                 let exp_cur = U8PatternTrans::serialize(
                     &sparse_origin.pattern_trans, f_pattern_trans_cur,
                     |guard, guardref| { *guardref = *guard; },
                     |qs, qs_cur| { U8States::serialize(qs, qs_cur, setq) }
                 );
-                sparse.explicit_trans = exp_cur.cur as *const U8ExplicitTrans;
-                let tags_cur: BuildCursor<u8> = U8ExplicitTrans::serialize(
+                sparse.explicit_trans = BlobPtr::offset(exp_cur.cur);
+                let end_cur: BuildCursor<u8> = U8ExplicitTrans::serialize(
                     &sparse_origin.explicit_trans, exp_cur, |alist, alist_cur| {
                         U8AList::serialize(alist, alist_cur,
                             |c, c_cur| { *c_cur = *c; },
@@ -159,47 +327,153 @@ impl<'a> U8State<'a> {
                         )
                     }
                 );
-                if sparse_origin.tags.is_empty() {
-                    sparse.tags = std::ptr::null();
-                    tags_cur.align()
-                }
-                else {
-                    let tags_cur = tags_cur.align();
-                    sparse.tags = tags_cur.cur as *const U8Tags;
-                    U8Tags::serialize(&sparse_origin.tags, tags_cur, |t, tref| { *tref = *t; })
-                }
+                sparse.tags = tagptr(&sparse_origin.tags);
+                Self::serialize_end(&sparse_origin.end_trans, &mut sparse.end_trans, end_cur, setq)
+            },
+            U8StatePrepared::Dense(dense_origin) if dense_origin.narrow => {
+                let dense = &mut state.narrow_dense;
+                dense.kind = U8StateKind::NarrowDense;
+                let f_trans_cur = f_tags_cur.behind::<U8NarrowDenseTrans>(1);
+                dense.tags = tagptr(&dense_origin.tags);
+                U8NarrowDenseTrans::serialize(&dense_origin.trans, f_trans_cur, setq)
             },
             U8StatePrepared::Dense(dense_origin) => {
                 let dense = &mut state.dense;
-                dense.is_dense = true;
-                let f_trans_cur = f_tags_cur.behind::<U8ArrMap>(1);
-                let tags_cur: BuildCursor<u8> = U8ArrMap::serialize(
-                    &dense_origin.trans, f_trans_cur,
+                dense.kind = U8StateKind::Dense;
+                let f_trans_cur = f_tags_cur.behind::<U8DenseTrans>(1);
+                dense.tags = tagptr(&dense_origin.tags);
+                U8DenseTrans::serialize(&dense_origin.trans, f_trans_cur, setq)
+            },
+            U8StatePrepared::Ranged(ranged_origin) => {
+                let ranged = &mut state.ranged;
+                ranged.kind = U8StateKind::Ranged;
+                let f_end_trans_cur = f_tags_cur.behind::<BlobPtr<U8States>>(1);
+                let f_trans_cur = f_end_trans_cur.behind::<U8RangeMap>(1);
+                let end_cur: BuildCursor<u8> = U8RangeMap::serialize(
+                    &ranged_origin.trans, f_trans_cur,
                     |qs, qs_cur| U8States::serialize(qs, qs_cur, setq));
-                if dense_origin.tags.is_empty() {
-                    dense.tags = std::ptr::null();
-                    tags_cur.align()
-                } else {
-                    let tags_cur = tags_cur.align();
-                    dense.tags = tags_cur.cur as *const U8Tags;
-                    U8Tags::serialize(&dense_origin.tags, tags_cur, |t, tref| { *tref = *t; })
-                }
+                ranged.tags = tagptr(&ranged_origin.tags);
+                Self::serialize_end(&ranged_origin.end_trans, &mut ranged.end_trans, end_cur, setq)
             },
         }
     }
+
+    unsafe fn serialize_end<After, F: FnMut(&usize, &mut BlobPtr<U8State>)>
+    (origin: &<U8States<'a> as Build>::Origin, end_trans: &mut BlobPtr<U8States<'a>>,
+     cur: BuildCursor<u8>, setq: F)
+    -> BuildCursor<After>
+    {
+        if origin.is_empty() {
+            *end_trans = BlobPtr::NULL;
+            return cur.align();
+        }
+        let cur = cur.align();
+        *end_trans = BlobPtr::offset(cur.cur);
+        U8States::serialize(origin, cur, setq)
+    }
+}
+
+// The distinct non-empty tag sets of the states, in the order of their first occurrence.
+pub fn tag_sets<'p, I: IntoIterator<Item = &'p U8StatePrepared>>(states: I) -> Vec<Vec<usize>> {
+    let mut seen = hashbrown::HashSet::new();
+    states.into_iter().map(U8StatePrepared::tags)
+        .filter(|tags| !tags.is_empty() && seen.insert(*tags))
+        .map(<[usize]>::to_vec)
+        .collect()
+}
+
+// Reserve the pool, storing the address of each tag set into `addrs` (the `tagptrs` of
+// `U8State::serialize`).
+pub fn reserve_tag_pool(
+    sets: &Vec<Vec<usize>>, sz: &mut Reserve, addrs: &mut HashMap<Vec<usize>, usize>,
+) -> usize {
+    U8TagPool::reserve(sets, sz, |set, sz| { addrs.insert(set.clone(), U8Tags::reserve(set, sz)); })
+}
+
+pub unsafe fn serialize_tag_pool<After>(sets: &Vec<Vec<usize>>, cur: BuildCursor<U8TagPool>)
+    -> BuildCursor<After>
+{
+    U8TagPool::serialize(sets, cur,
+        |set, cur| U8Tags::serialize(set, cur, |x, y| { *y = *x as u64; }))
+}
+
+pub unsafe fn deserialize_tag_pool<After>(cur: BuildCursor<U8TagPool>) -> CursorResult<After> {
+    let shifter = Shifter::of(&cur);
+    U8TagPool::deserialize(cur, |cur| {
+        cur.mark(Target::U8Tags);
+        U8Tags::deserialize(cur, |tag| shifter.scalar(tag))
+    })
 }
 
 pub struct U8SparseStateIterator<'a, 'b> {
-    states_iter: Option<BlobVecIter<'a, *const U8State<'a>>>,
+    states_iter: Option<BlobVecIter<'a, BlobPtr<U8State<'a>>>>,
     pattern_iter: VecMapIter<'a, 'b, u8, Guard, U8States<'a>>,
     explicit_trans: *const U8ExplicitTrans<'a>,
+    guard: Option<&'a Guard>,
+}
+
+impl<'a, 'b> U8SparseStateIterator<'a, 'b> {
+    // The guard of the pattern transition which yielded the last successor, None if the successor
+    // came from the explicit (per-byte) transitions.
+    pub fn guard(&self) -> Option<&'a Guard> {
+        self.guard
+    }
+}
+
+pub type U8DenseStateIterator<'a> = BlobVecIter<'a, BlobPtr<U8State<'a>>>;
+
+// How a transition was selected: dense states index their successors by the byte directly, ranged
+// states by the range containing the byte, sparse states either by a pattern guard or by an
+// explicit byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransitionGuard<'a> {
+    Dense,
+    Ranged,
+    Pattern(&'a Guard),
+    Explicit,
+}
+
+// The successors of a state via a byte, see `U8State::successors`.
+pub struct Successors<'a, 'b> {
+    iter: U8StateIterator<'a, 'b>,
+    guard: TransitionGuard<'a>,
+}
+
+impl<'a, 'b> Successors<'a, 'b> {
+    // The guard of the transition which yielded the last successor.
+    pub fn guard(&self) -> TransitionGuard<'a> {
+        self.guard
+    }
 }
 
-pub type U8DenseStateIterator<'a> = BlobVecIter<'a, *const U8State<'a>>;
+impl<'a, 'b> Iterator for Successors<'a, 'b> where 'a: 'b {
+    type Item = &'a U8State<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // SAFETY: the iterator was created from a state of a deserialized blob living for 'a, see
+        // `U8State::successors`, and the successors are in the same blob.
+        unsafe {
+            let next = match &mut self.iter {
+                U8StateIterator::Sparse(iter) => {
+                    let next = iter.next();
+                    self.guard = match iter.guard() {
+                        Some(guard) => TransitionGuard::Pattern(guard),
+                        None => TransitionGuard::Explicit,
+                    };
+                    next
+                },
+                U8StateIterator::Dense(iter) | U8StateIterator::Ranged(iter) =>
+                    iter.next().map(BlobPtr::get),
+            };
+            next.map(|state| &*state)
+        }
+    }
+}
 
 pub enum U8StateIterator<'a, 'b> {
     Sparse(U8SparseStateIterator<'a, 'b>),
     Dense(U8DenseStateIterator<'a>),
+    Ranged(U8DenseStateIterator<'a>),
 }
 
 impl<'a, 'b> UnsafeIterator for U8SparseStateIterator<'a, 'b> where 'a: 'b {
@@ -208,26 +482,28 @@ impl<'a, 'b> UnsafeIterator for U8SparseStateIterator<'a, 'b> where 'a: 'b {
     unsafe fn next(&mut self) -> Option<Self::Item> {
         if let Some(states_iter) = self.states_iter.as_mut() {
             if let Some(state) = states_iter.next() {
-                return Some(&**state);
+                return Some(state.get());
             }
         }
         loop {
-            if let Some((_, states)) = self.pattern_iter.next() {
+            if let Some((guard, states)) = self.pattern_iter.next() {
                 let mut states_iter = states.iter();
                 if let Some(state) = states_iter.next() {
                     self.states_iter = Some(states_iter);
-                    return Some(*state);
+                    self.guard = Some(guard);
+                    return Some(state.get());
                 }
             } else {
                 if self.explicit_trans.is_null() { return None; }
                 else {
                     let explicit_trans = &*self.explicit_trans;
                     self.explicit_trans = std::ptr::null();
+                    self.guard = None;
                     if let Some(states) = explicit_trans.get(self.pattern_iter.x) {
                         let mut states_iter = states.iter();
                         if let Some(state) = states_iter.next() {
                             self.states_iter = Some(states_iter);
-                            return Some(*state);
+                            return Some(state.get());
                         } else { return None; }
                     } else { return None; }
                 }
@@ -239,25 +515,92 @@ impl<'a, 'b> UnsafeIterator for U8SparseStateIterator<'a, 'b> where 'a: 'b {
 #[derive(Debug)]
 pub struct U8DenseStatePrepared {
     tags: Vec<usize>,
-    trans: [Vec<usize>; 256],
+    // 257 vectors, see `U8DenseTrans`.
+    trans: Vec<Vec<usize>>,
+    // Stored as `U8NarrowDenseTrans`.
+    narrow: bool,
+}
+
+#[derive(Debug)]
+pub struct U8RangedStatePrepared {
+    tags: Vec<usize>,
+    trans: <U8RangeMap<'static> as Build>::Origin,
+    end_trans: Vec<usize>,
 }
 
 #[derive(Debug)]
 pub struct U8SparseStatePrepared {
     tags: Vec<usize>,
+    end_trans: Vec<usize>,
     pattern_trans: Vec<(Guard, Vec<usize>)>,
-    explicit_trans: Vec<Vec<(u8, Vec<usize>)>>,  // has size of 2**hashmap_cap
+    explicit_trans: <U8ExplicitTrans<'static> as Build>::Origin,  // has 2**hashmap_cap buckets
 }
 
 #[derive(Debug)]
 pub enum U8StatePrepared {
     Sparse(U8SparseStatePrepared),
     Dense(U8DenseStatePrepared),
+    Ranged(U8RangedStatePrepared),
+}
+
+impl U8StatePrepared {
+    pub fn tags(&self) -> &[usize] {
+        match self {
+            U8StatePrepared::Sparse(sparse) => &sparse.tags,
+            U8StatePrepared::Dense(dense) => &dense.tags,
+            U8StatePrepared::Ranged(ranged) => &ranged.tags,
+        }
+    }
+
+    pub fn kind(&self) -> U8StateKind {
+        match self {
+            U8StatePrepared::Sparse(_) => U8StateKind::Sparse,
+            U8StatePrepared::Dense(dense) if dense.narrow => U8StateKind::NarrowDense,
+            U8StatePrepared::Dense(_) => U8StateKind::Dense,
+            U8StatePrepared::Ranged(_) => U8StateKind::Ranged,
+        }
+    }
+
+    // The bytes having a successor.
+    pub fn covered(&self) -> Guard {
+        let mut covered = Guard::empty();
+        match self {
+            U8StatePrepared::Sparse(sparse) => {
+                for (guard, _) in sparse.pattern_trans.iter() { covered.union_update(guard); }
+                for (c, _) in sparse.explicit_trans.1.iter().flatten() {
+                    covered.add_range((*c, *c));
+                }
+            }
+            U8StatePrepared::Dense(dense) => {
+                for (c, qs) in dense.trans[..256].iter().enumerate() {
+                    if !qs.is_empty() { covered.add_range((c as u8, c as u8)); }
+                }
+            }
+            U8StatePrepared::Ranged(ranged) => {
+                let (ranges, values) = &ranged.trans;
+                let ends = ranges.iter().skip(1).map(|(start, _)| start - 1).chain([255]);
+                for ((start, vix), end) in ranges.iter().zip(ends) {
+                    if !values[*vix].is_empty() { covered.add_range((*start, end)); }
+                }
+            }
+        }
+        covered
+    }
+
+    // Whether the value may end in the state, i.e. it has successors after the end.
+    pub fn can_end(&self) -> bool {
+        match self {
+            U8StatePrepared::Sparse(sparse) => !sparse.end_trans.is_empty(),
+            U8StatePrepared::Dense(dense) => !dense.trans[256].is_empty(),
+            U8StatePrepared::Ranged(ranged) => !ranged.end_trans.is_empty(),
+        }
+    }
 }
 
 
 pub mod build {
-    use std::array;
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
 
     use crate::char_nfa;
     use hashbrown::HashMap;
@@ -267,6 +610,47 @@ pub mod build {
         fn guard_size_keep(&self) -> u32;
         fn hashmap_cap_power_fn(&self, len: usize) -> usize;
         fn dense_guard_count(&self) -> usize;
+        // States that would be dense are stored as range maps instead, if their transitions split
+        // the alphabet into at most this many ranges of equal successors.
+        fn max_ranged_ranges(&self) -> usize { 0 }
+        // Seed of the explicit transition hashmaps. Random by default, against hash flooding, so
+        // that the same config gives different blobs; fix it to get reproducible ones.
+        fn hash_seed(&self) -> u64 {
+            RandomState::new().build_hasher().finish()
+        }
+        // Store the offsets of the dense states as u16 instead of u64 if they fit, which shrinks
+        // each dense state by about 1.5 KiB. Such blobs are read by this version onwards only.
+        fn narrow_dense_offsets(&self) -> bool { false }
+    }
+
+    // The settings of the CLI, of the server and of the configs compiled by `embed`.
+    pub struct DefaultBuildConfig;
+    impl U8BuildConfig for DefaultBuildConfig {
+        fn guard_size_keep(&self) -> u32 { 10 }
+        fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 3 }
+        fn dense_guard_count(&self) -> usize { 15 }
+    }
+
+    // Prepared from an arbitrary NFA state by an arbitrary config, so that it is consistent. The
+    // successors are kept small, so that they mostly refer to existing states.
+    #[cfg(feature = "arbitrary")]
+    impl<'a> arbitrary::Arbitrary<'a> for U8StatePrepared {
+        fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+            let small = |qs: Vec<u8>| qs.into_iter().map(|q| q as usize % 16).collect::<Vec<_>>();
+            let end_transitions = small(u.arbitrary()?);
+            let tags = small(u.arbitrary()?);
+            let transitions = u.arbitrary::<Vec<(Guard, u8)>>()?.into_iter()
+                .map(|(guard, q)| (guard, q as usize % 16))
+                .collect();
+            let state = char_nfa::State {
+                transitions,
+                end_transitions,
+                tags: crate::char_enfa::OrderedIxs(tags),
+                is_deterministic: false,
+            };
+            let cfg: crate::fuzz::FuzzBuildConfig = u.arbitrary()?;
+            Ok(Self::prepare(&state, &cfg))
+        }
     }
 
     impl U8StatePrepared {
@@ -281,33 +665,36 @@ pub mod build {
                         explicitized_guard_trans.push((guard, target));
                     }
                 }
-                let mut explicit_trans0 = HashMap::<u8, Vec<usize>>::new();
+                // Ascending, so that the keys of each bucket are sorted and the blob does not
+                // depend on the iteration order of a hashmap.
+                let mut explicit_trans0 = Vec::<(u8, Vec<usize>)>::new();
                 let mut c = 0;
                 loop {
-                    for (guard, target) in explicitized_guard_trans.iter() {
-                        if guard.contains(c) {
-                            explicit_trans0.entry(c).or_insert(Vec::new()).push(*target);
-                        }
-                    }
+                    let targets = explicitized_guard_trans.iter()
+                        .filter(|(guard, _)| guard.contains(c))
+                        .map(|(_, target)| *target)
+                        .collect::<Vec<_>>();
+                    if !targets.is_empty() { explicit_trans0.push((c, targets)); }
                     if c == 255 { break; }
                     c += 1;
                 }
                 let hashmap_cap_power = cfg.hashmap_cap_power_fn(explicit_trans0.len());
-                let hashmap_cap = 1 << hashmap_cap_power;
-                let hashmap_mask = hashmap_cap - 1;
-                let mut hashmap_alists = Vec::<Vec<(u8, Vec<usize>)>>::with_capacity(hashmap_cap);
-                for _ in 0..hashmap_cap { hashmap_alists.push(Vec::new()) }
-                for (c, targets) in explicit_trans0 {
-                    hashmap_alists[c as usize & hashmap_mask].push((c, targets));
-                }
+                let seed = cfg.hash_seed();
+                let hashmap_alists = bucketize::<XxHashStrategy, _, _>(
+                    seed, hashmap_cap_power, explicit_trans0, |(c, _)| c);
+
+                // Sorted, so that the pattern transitions support range queries.
+                let mut pattern_trans = pattern_trans0.into_iter().collect::<Vec<_>>();
+                pattern_trans.sort_unstable_by_key(|(guard, _)| *guard);
 
                 Self::Sparse(U8SparseStatePrepared {
                     tags: old.tags.0.clone(),
-                    pattern_trans: pattern_trans0.into_iter().collect(),
-                    explicit_trans: hashmap_alists
+                    end_trans: old.end_transitions.clone(),
+                    pattern_trans,
+                    explicit_trans: (seed, hashmap_alists)
                 })
             } else {
-                let mut trans = array::from_fn(|_| Vec::new());
+                let mut trans = vec![Vec::new(); 257];
                 let mut c = 0;
                 loop {
                     for (guard, target) in old.transitions.iter() {
@@ -316,8 +703,37 @@ pub mod build {
                     if c == 255 { break; }
                     c += 1;
                 }
-                Self::Dense(U8DenseStatePrepared { tags: old.tags.0.clone(), trans })
+                let bytes = trans[..256].try_into().unwrap();
+                if let Some(ranged) = Self::compress_ranges(bytes, cfg.max_ranged_ranges()) {
+                    return Self::Ranged(U8RangedStatePrepared {
+                        tags: old.tags.0.clone(),
+                        trans: ranged,
+                        end_trans: old.end_transitions.clone(),
+                    });
+                }
+                trans[256] = old.end_transitions.clone();
+                let total = trans.iter().map(Vec::len).sum::<usize>();
+                let narrow = cfg.narrow_dense_offsets() && total <= u16::MAX as usize;
+                Self::Dense(U8DenseStatePrepared { tags: old.tags.0.clone(), trans, narrow })
+            }
+        }
+
+        fn compress_ranges(trans: &[Vec<usize>; 256], max_ranges: usize)
+            -> Option<<U8RangeMap<'static> as Build>::Origin>
+        {
+            let mut ranges = Vec::<(u8, usize)>::new();
+            let mut values = Vec::<Vec<usize>>::new();
+            let mut value_ixs = HashMap::<&Vec<usize>, usize>::new();
+            for (c, targets) in trans.iter().enumerate() {
+                if c > 0 && trans[c - 1] == *targets { continue; }
+                if ranges.len() == max_ranges { return None; }
+                let vix = *value_ixs.entry(targets).or_insert_with(|| {
+                    values.push(targets.clone());
+                    values.len() - 1
+                });
+                ranges.push((c as u8, vix));
             }
+            Some((ranges, values))
         }
     }
 }
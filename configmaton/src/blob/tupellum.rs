@@ -1,6 +1,6 @@
 use std::marker::PhantomData;
 
-use super::{Build, BuildCursor, Reserve};
+use super::{Build, BuildCursor, BuildError, CursorResult, Reserve};
 
 #[repr(C)]
 pub struct Tupellum<'a, A, B> {
@@ -12,12 +12,12 @@ impl<'a, A, B> Tupellum<'a, A, B> {
     pub unsafe fn deserialize
     <
         After,
-        FK: FnMut(BuildCursor<A>) -> BuildCursor<B>,
-        FV: FnMut(BuildCursor<B>) -> BuildCursor<After>,
+        FK: FnMut(BuildCursor<A>) -> CursorResult<B>,
+        FV: FnMut(BuildCursor<B>) -> CursorResult<After>,
     >
-    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> BuildCursor<After>
+    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> CursorResult<After>
     {
-        let vcur = fk(cur.transmute());
+        let vcur = fk(cur.transmute())?;
         fv(vcur)
     }
 }
@@ -42,6 +42,22 @@ impl<'a, A: Build, B: Build> Build for Tupellum<'a, A, B> {
 }
 
 impl<'a, A, B> Tupellum<'a, A, B> {
+    pub fn try_reserve<
+        BldA,
+        BldB,
+        Bld: TupellumBuild<BldA, BldB>,
+        FK: FnMut(&BldA, &mut Reserve) -> Result<(), BuildError>,
+        FV: FnMut(&BldB, &mut Reserve) -> Result<(), BuildError>,
+    >
+    (origin: &Bld, sz: &mut Reserve, mut fk: FK, mut fv: FV) -> Result<usize, BuildError>
+    {
+        sz.add::<Self>(0);
+        let my_addr = sz.0;
+        fk(origin.left(), sz)?;
+        fv(origin.right(), sz)?;
+        Ok(my_addr)
+    }
+
     pub fn reserve<
         BldA,
         BldB,
@@ -74,3 +90,102 @@ impl<'a, A, B> Tupellum<'a, A, B> {
         fv(origin.right(), vcur)
     }
 }
+
+// Flat variants of nested Tupellums, with the same layout. Each element is followed by the next
+// one, so only the first one is accessible directly, the rest via the `behind` of the previous.
+macro_rules! tupellum_n {
+    (
+        $name:ident<$first:ident, $($rest:ident),+>;
+        $($t:ident $fty:ident $f:ident $o:ident $ix:tt -> $next:ident),+
+    ) => {
+        #[repr(C)]
+        pub struct $name<'a, $first, $($rest),+> {
+            pub a: $first,
+            _phantom: PhantomData<&'a ($($rest,)+)>
+        }
+
+        impl<'a, $first, $($rest),+> $name<'a, $first, $($rest),+> {
+            #[allow(clippy::too_many_arguments)]  // one closure per element
+            pub unsafe fn deserialize
+            <After, $($fty: FnMut(BuildCursor<$t>) -> CursorResult<$next>),+>
+            (cur: BuildCursor<Self>, $(mut $f: $fty),+) -> CursorResult<After>
+            {
+                let cur = cur.transmute();
+                $(let cur = $f(cur)?;)+
+                Ok(cur)
+            }
+        }
+
+        impl<'a, $first: Build, $($rest: Build),+> Build for $name<'a, $first, $($rest),+> {
+            type Origin = ($first::Origin, $($rest::Origin),+);
+        }
+
+        impl<'a, $first, $($rest),+> $name<'a, $first, $($rest),+> {
+            #[allow(clippy::too_many_arguments)]  // one closure per element
+            pub fn try_reserve
+            <$($o,)+ $($fty: FnMut(&$o, &mut Reserve) -> Result<(), BuildError>),+>
+            (origin: &($($o,)+), sz: &mut Reserve, $(mut $f: $fty),+) -> Result<usize, BuildError>
+            {
+                sz.add::<Self>(0);
+                let my_addr = sz.0;
+                $($f(&origin.$ix, sz)?;)+
+                Ok(my_addr)
+            }
+
+            #[allow(clippy::too_many_arguments)]  // one closure per element
+            pub fn reserve<$($o,)+ $($fty: FnMut(&$o, &mut Reserve)),+>
+            (origin: &($($o,)+), sz: &mut Reserve, $(mut $f: $fty),+) -> usize
+            {
+                sz.add::<Self>(0);
+                let my_addr = sz.0;
+                $($f(&origin.$ix, sz);)+
+                my_addr
+            }
+
+            #[allow(clippy::too_many_arguments)]  // one closure per element
+            pub unsafe fn serialize
+            <After, $($o,)+ $($fty: FnMut(&$o, BuildCursor<$t>) -> BuildCursor<$next>),+>
+            (origin: &($($o,)+), cur: BuildCursor<Self>, $(mut $f: $fty),+) -> BuildCursor<After>
+            {
+                let cur = cur.transmute();
+                $(let cur = $f(&origin.$ix, cur);)+
+                cur
+            }
+        }
+    };
+}
+
+tupellum_n!(Tupellum3<A, B, C>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> After);
+tupellum_n!(Tupellum4<A, B, C, D>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> After);
+tupellum_n!(Tupellum5<A, B, C, D, E>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> After);
+tupellum_n!(Tupellum6<A, B, C, D, E, F>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> After);
+tupellum_n!(Tupellum7<A, B, C, D, E, F, G>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> G, G FG fg OG 6 -> After);
+tupellum_n!(Tupellum8<A, B, C, D, E, F, G, H>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> G, G FG fg OG 6 -> H, H FH fh OH 7 -> After);
+tupellum_n!(Tupellum9<A, B, C, D, E, F, G, H, I>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> G, G FG fg OG 6 -> H, H FH fh OH 7 -> I,
+    I FI fi OI 8 -> After);
+tupellum_n!(Tupellum12<A, B, C, D, E, F, G, H, I, J, K, L>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> G, G FG fg OG 6 -> H, H FH fh OH 7 -> I,
+    I FI fi OI 8 -> J, J FJ fj OJ 9 -> K, K FK fk OK 10 -> L, L FL fl OL 11 -> After);
+tupellum_n!(Tupellum13<A, B, C, D, E, F, G, H, I, J, K, L, M>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> G, G FG fg OG 6 -> H, H FH fh OH 7 -> I,
+    I FI fi OI 8 -> J, J FJ fj OJ 9 -> K, K FK fk OK 10 -> L, L FL fl OL 11 -> M,
+    M FM fm OM 12 -> After);
+tupellum_n!(Tupellum15<A, B, C, D, E, F, G, H, I, J, K, L, M, N, O>;
+    A FA fa OA 0 -> B, B FB fb OB 1 -> C, C FC fc OC 2 -> D, D FD fd OD 3 -> E,
+    E FE fe OE 4 -> F, F FF ff OF 5 -> G, G FG fg OG 6 -> H, H FH fh OH 7 -> I,
+    I FI fi OI 8 -> J, J FJ fj OJ 9 -> K, K FK fk OK 10 -> L, L FL fl OL 11 -> M,
+    M FM fm OM 12 -> N, N FN fn_ ON 13 -> O, O FO fo OO 14 -> After);
@@ -1,16 +1,19 @@
 use std::marker::PhantomData;
 
 use super::{
-    Build, BuildCursor, Reserve, UnsafeIterator, get_behind_struct, align_up, align_up_ptr,
+    Build, BuildCursor, BuildError, CursorResult, Reserve, Stride, UnsafeIterator, BlobLen,
+    get_behind_struct, align_up, align_up_ptr, root::BlobError,
 };
 
+// The length is stored as `L`, a narrower type makes small vectors smaller (the C header describes
+// the default).
 #[repr(C)]
-pub struct BlobVec<'a, X> {
-    pub(super) len: usize,
+pub struct BlobVec<'a, X, L = u64> {
+    pub(super) len: L,
     _phantom: PhantomData<&'a X>,
 }
 
-impl<'a, X: Build> Build for BlobVec<'a, X> {
+impl<'a, X: Build, L> Build for BlobVec<'a, X, L> {
     type Origin = Vec<X::Origin>;
 }
 
@@ -20,36 +23,55 @@ pub struct BlobVecIter<'a, X> {
     _phantom: PhantomData<&'a X>,
 }
 
-impl<'a, X> BlobVec<'a, X> {
+impl<'a, X, L: BlobLen> BlobVec<'a, X, L> {
+    pub fn len(&self) -> usize {
+        self.len.to_usize()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
     pub unsafe fn iter(&self) -> BlobVecIter<'a, X> {
         let cur = get_behind_struct::<_, X>(self);
-        BlobVecIter { cur, end: cur.add(self.len), _phantom: PhantomData }
+        BlobVecIter { cur, end: cur.add(self.len()), _phantom: PhantomData }
     }
 
     pub unsafe fn behind<After>(&self) -> &'a After {
         let cur = get_behind_struct::<_, X>(self);
-        &*align_up_ptr(cur.add(self.len))
+        &*align_up_ptr(cur.add(self.len()))
     }
 
     pub unsafe fn get(&self, ix: usize) -> &X {
-        assert!(ix < self.len);
+        assert!(ix < self.len());
         &*get_behind_struct::<_, X>(self).add(ix)
     }
 
     pub unsafe fn as_ref(&self) -> &'a [X] {
-        std::slice::from_raw_parts(get_behind_struct::<_, X>(self), self.len)
+        std::slice::from_raw_parts(get_behind_struct::<_, X>(self), self.len())
     }
 
-    pub unsafe fn deserialize<F: FnMut(&mut X), After>
-    (cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
+    pub unsafe fn deserialize<F: FnMut(&mut X) -> Result<(), BlobError>, After>
+    (cur: BuildCursor<Self>, mut f: F) -> CursorResult<After>
     {
         let mut xcur = cur.behind(1);
-        for _ in 0..(*cur.get_mut()).len { f(&mut *xcur.get_mut()); xcur.inc(); }
-        xcur.align()
+        let len = cur.swap.fix(&mut (*cur.try_get_mut()?).len).to_usize();
+        for _ in 0..len { f(&mut *xcur.try_get_mut()?)?; xcur.inc(); }
+        Ok(xcur.align())
     }
 }
 
-impl<'a, X: Build> BlobVec<'a, X> {
+impl<'a, X: Build, L: BlobLen> BlobVec<'a, X, L> {
+    // Like reserve, but check that the length fits into `L`, so that serialize does not fail.
+    pub fn try_reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve)
+        -> Result<usize, BuildError>
+    {
+        if L::from_usize(origin.len()).is_none() {
+            return Err(BuildError::TooLong { len: origin.len(), max: L::MAX });
+        }
+        Ok(Self::reserve(origin, sz))
+    }
+
     pub fn reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve) -> usize {
         sz.add::<Self>(0);
         let my_addr = sz.0;
@@ -65,13 +87,27 @@ impl<'a, X: Build> BlobVec<'a, X> {
     pub unsafe fn serialize<F: FnMut(&X::Origin, &mut X), After>
     (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
     {
-        (*cur.get_mut()).len = origin.len();
+        (*cur.get_mut()).len = L::from_usize(origin.len()).expect("checked by try_reserve");
         let mut xcur = cur.behind(1);
         for x in origin.iter() { f(x, &mut *xcur.get_mut()); xcur.inc(); }
         xcur.align()
     }
 }
 
+impl<'a, X, L: BlobLen> Stride for BlobVec<'a, X, L> {
+    unsafe fn end(&self) -> *const u8 {
+        get_behind_struct::<_, X>(self).add(self.len()) as *const u8
+    }
+}
+
+// E.g. for the vectors of a `VecOfVecs`.
+impl<'a, X> From<&'a [X]> for BlobVecIter<'a, X> {
+    fn from(xs: &'a [X]) -> Self {
+        let range = xs.as_ptr_range();
+        BlobVecIter { cur: range.start, end: range.end, _phantom: PhantomData }
+    }
+}
+
 impl<'a, X> UnsafeIterator for BlobVecIter<'a, X> {
     type Item = &'a X;
 
@@ -0,0 +1,125 @@
+use std::marker::PhantomData;
+
+use super::{
+    align_up_ptr, get_behind_struct, root::BlobError, BlobLen, Build, BuildCursor, BuildError,
+    CursorResult, Reserve, Stride,
+};
+
+// A vector of vectors with O(1) access to each of them. Layout: header, [L; len + 1] offsets
+// (in items) to the starts of the inner vectors, all items packed one after another. Like in
+// `BlobVec`, a narrower `L` makes small vectors smaller.
+#[repr(C)]
+pub struct VecOfVecs<'a, X, L = u64> {
+    pub(super) len: L,
+    _phantom: PhantomData<&'a X>,
+}
+
+impl<'a, X: Build, L> Build for VecOfVecs<'a, X, L> {
+    type Origin = Vec<Vec<X::Origin>>;
+}
+
+impl<'a, X, L: BlobLen> VecOfVecs<'a, X, L> {
+    pub fn len(&self) -> usize {
+        self.len.to_usize()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    unsafe fn offsets(&self) -> &'a [L] {
+        std::slice::from_raw_parts(get_behind_struct::<_, L>(self), self.len() + 1)
+    }
+
+    unsafe fn items(&self) -> *const X {
+        align_up_ptr(self.offsets().as_ptr().add(self.len() + 1))
+    }
+
+    pub unsafe fn get(&self, ix: usize) -> &'a [X] {
+        assert!(ix < self.len());
+        let offsets = self.offsets();
+        let (start, end) = (offsets[ix].to_usize(), offsets[ix + 1].to_usize());
+        std::slice::from_raw_parts(self.items().add(start), end - start)
+    }
+
+    pub unsafe fn iter(&self) -> impl Iterator<Item = &'a [X]> + 'a {
+        let items = self.items();
+        self.offsets().windows(2).map(move |w| {
+            let (start, end) = (w[0].to_usize(), w[1].to_usize());
+            std::slice::from_raw_parts(items.add(start), end - start)
+        })
+    }
+
+    pub unsafe fn behind<After>(&self) -> &'a After {
+        &*align_up_ptr(self.end())
+    }
+
+    pub unsafe fn deserialize<F: FnMut(&mut X) -> Result<(), BlobError>, After>
+    (cur: BuildCursor<Self>, mut f: F) -> CursorResult<After>
+    {
+        let len = cur.swap.fix(&mut (*cur.try_get_mut()?).len).to_usize();
+        // The offsets are indices, they must ascend (from zero) to stay within the items.
+        let mut ocur = cur.behind::<L>(1);
+        let mut total = 0;
+        for ix in 0..=len {
+            let offset = cur.swap.fix(&mut *ocur.try_get_mut()?).to_usize();
+            if offset < total || (ix == 0 && offset != 0) {
+                return Err(BlobError::Corrupt { offset: ocur.cur });
+            }
+            total = offset;
+            ocur.inc();
+        }
+        let mut xcur = ocur.align::<X>();
+        for _ in 0..total { f(&mut *xcur.try_get_mut()?)?; xcur.inc(); }
+        Ok(xcur.align())
+    }
+}
+
+impl<'a, X, L: BlobLen> Stride for VecOfVecs<'a, X, L> {
+    unsafe fn end(&self) -> *const u8 {
+        self.items().add(self.offsets()[self.len()].to_usize()) as *const u8
+    }
+}
+
+impl<'a, X: Build, L: BlobLen> VecOfVecs<'a, X, L> {
+    // Like reserve, but check that the length and the offsets fit into `L`, so that serialize
+    // does not fail.
+    pub fn try_reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve)
+        -> Result<usize, BuildError>
+    {
+        let total = origin.iter().map(|xs| xs.len()).sum::<usize>();
+        for len in [origin.len(), total] {
+            if L::from_usize(len).is_none() {
+                return Err(BuildError::TooLong { len, max: L::MAX });
+            }
+        }
+        Ok(Self::reserve(origin, sz))
+    }
+
+    pub fn reserve(origin: &<Self as Build>::Origin, sz: &mut Reserve) -> usize {
+        sz.add::<Self>(0);
+        let my_addr = sz.0;
+        sz.add::<Self>(1);
+        sz.add::<L>(origin.len() + 1);
+        sz.add::<X>(origin.iter().map(|xs| xs.len()).sum());
+        my_addr
+    }
+
+    pub unsafe fn serialize<F: FnMut(&X::Origin, &mut X), After>
+    (origin: &<Self as Build>::Origin, cur: BuildCursor<Self>, mut f: F) -> BuildCursor<After>
+    {
+        let checked = |len: usize| L::from_usize(len).expect("checked by try_reserve");
+        (*cur.get_mut()).len = checked(origin.len());
+        let mut ocur = cur.behind::<L>(1);
+        let mut xcur = ocur.behind::<X>(origin.len() + 1);
+        let mut offset = 0;
+        for xs in origin.iter() {
+            *ocur.get_mut() = checked(offset);
+            ocur.inc();
+            offset += xs.len();
+            for x in xs.iter() { f(x, &mut *xcur.get_mut()); xcur.inc(); }
+        }
+        *ocur.get_mut() = checked(offset);
+        xcur.align()
+    }
+}
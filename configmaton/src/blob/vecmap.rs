@@ -1,11 +1,16 @@
 use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
 
-use super::{vec::{BlobVec, BlobVecIter}, Assocs, AssocsSuper, Build, BuildCursor, Matches, Reserve, Shifter, UnsafeIterator};
+use super::{
+    vec::{BlobVec, BlobVecIter}, Assocs, AssocsSuper, Build, BuildCursor, BuildError, FirstError,
+    Matches, Reserve, Shifter, UnsafeIterator, CursorResult, BlobPtr, root::BlobError,
+    check_starts,
+};
 
 #[repr(C)]
 pub struct VecMapItem<K, V> {
     key: K,
-    val: *const V,
+    val: BlobPtr<V>,
 }
 
 impl<K: Build, V: Build> Build for VecMapItem<K, V> {
@@ -24,6 +29,14 @@ impl<'a, K: Build, V: Build> Build for VecMap<'a, K, V> {
 }
 
 impl<'a, K: Build, V: Build> VecMap<'a, K, V> {
+    pub fn try_reserve<FV: FnMut(&V::Origin, &mut Reserve) -> Result<(), BuildError>>
+    (origin: &<Self as Build>::Origin, sz: &mut Reserve, fv: FV) -> Result<usize, BuildError>
+    {
+        let errors = FirstError::default();
+        let my_addr = Self::reserve(origin, sz, errors.wrap(fv));
+        errors.into_result(my_addr)
+    }
+
     pub fn reserve<FV: FnMut(&V::Origin, &mut Reserve)>
     (origin: &<Self as Build>::Origin, sz: &mut Reserve, mut fv: FV) -> usize
     {
@@ -51,7 +64,7 @@ impl<'a, K: Build, V: Build> VecMap<'a, K, V> {
         let mut vcur = item_cur.behind::<V>(origin.len());
         <VecMapVec<'a, K, V>>::serialize::<_, V>(origin, kcur, |kv, bk| {
             fk(&kv.0, &mut bk.key);
-            bk.val = vcur.cur as *const V;
+            bk.val = BlobPtr::offset(vcur.cur);
             vcur = fv(&kv.1, vcur.clone());
         });
         vcur.align()
@@ -61,20 +74,28 @@ impl<'a, K: Build, V: Build> VecMap<'a, K, V> {
 impl<'a, K, V> VecMap<'a, K, V> {
     pub unsafe fn deserialize<
         After,
-        FK: FnMut(&mut K),
-        FV: FnMut(BuildCursor<V>) -> BuildCursor<V>,
+        FK: FnMut(&mut K) -> Result<(), BlobError>,
+        FV: FnMut(BuildCursor<V>) -> CursorResult<V>,
     >
-    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> BuildCursor<After>
+    (cur: BuildCursor<Self>, mut fk: FK, mut fv: FV) -> CursorResult<After>
     {
         let kcur = cur.behind::<VecMapVec<'a, K, V>>(0);
-        let len = (*kcur.get_mut()).len;
-        let shifter = Shifter(cur.buf);
+        let len = kcur.swap.peek(&(*kcur.try_get_mut()?).len);
+        let shifter = Shifter::of(&cur);
+        let mut offsets = vec![];
         let mut vcur = BlobVec::deserialize(kcur, |kv| {
-            fk(&mut kv.key);
-            shifter.shift(&mut kv.val);
-        });
-        for _ in 0..len { vcur = fv(vcur); }
-        vcur.align()
+            fk(&mut kv.key)?;
+            offsets.push(shifter.shift_offset(&mut kv.val, size_of::<V>())?);
+            Ok(())
+        })?;
+        let mut starts = Vec::with_capacity(offsets.len());
+        for _ in 0..len {
+            vcur = vcur.align();
+            starts.push(vcur.cur);
+            vcur = fv(vcur)?;
+        }
+        check_starts(&offsets, &starts)?;
+        Ok(vcur.align())
     }
 }
 
@@ -90,7 +111,7 @@ impl<'a, 'b, X: Matches<K>, K, V: 'b> UnsafeIterator for VecMapIter<'a, 'b, X, K
     unsafe fn next(&mut self) -> Option<Self::Item> {
         while let Some(VecMapItem{ key, val }) = self.vec_iter.next() {
             if self.x.matches(key) {
-                return Some((&key, &**val));
+                return Some((key, &*val.get()));
             }
         }
         None
@@ -108,3 +129,35 @@ impl<'a, K: 'a, V: 'a> Assocs<'a> for VecMap<'a, K, V> {
         where 'a: 'b + 'c
     { VecMapIter { x: key, vec_iter: self.keys.iter(), _phantom: PhantomData } }
 }
+
+pub struct VecMapRangeIter<'a, K, V> {
+    items: std::slice::Iter<'a, VecMapItem<K, V>>,
+}
+
+impl<'a, K, V: 'a> UnsafeIterator for VecMapRangeIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    unsafe fn next(&mut self) -> Option<Self::Item> {
+        self.items.next().map(|VecMapItem { key, val }| (key, &*val.get()))
+    }
+}
+
+impl<'a, K: Ord + 'a, V: 'a> VecMap<'a, K, V> {
+    // Iterate over the entries with keys within the range. Requires the map to be serialized from
+    // an origin sorted by key.
+    pub unsafe fn iter_range<R: RangeBounds<K>>(&self, range: R) -> VecMapRangeIter<'a, K, V> {
+        let items = self.keys.as_ref();
+        let start = match range.start_bound() {
+            Bound::Included(lo) => items.partition_point(|item| item.key < *lo),
+            Bound::Excluded(lo) => items.partition_point(|item| item.key <= *lo),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(hi) => items.partition_point(|item| item.key <= *hi),
+            Bound::Excluded(hi) => items.partition_point(|item| item.key < *hi),
+            Bound::Unbounded => items.len(),
+        };
+        let end = end.max(start);
+        VecMapRangeIter { items: items[start..end].iter() }
+    }
+}
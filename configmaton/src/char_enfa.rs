@@ -4,19 +4,25 @@ use super::ast::Ast;
 use super::guards::Monoid;
 
 #[derive(Debug)]
-pub struct State {
-    pub transitions: Vec<((u8, u8), usize)>,
+pub struct State<S = u8> {
+    pub transitions: Vec<((S, S), usize)>,
     pub epsilon_transitions: Vec<usize>
 }
 
-impl State {
-    fn new() -> Self {
+impl<S> Default for State<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> State<S> {
+    pub fn new() -> Self {
         Self { transitions: Vec::new(), epsilon_transitions: Vec::new() }
     }
 }
 
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct OrderedIxs(pub Vec<usize>);
 
 impl Monoid for OrderedIxs {
@@ -46,7 +52,7 @@ impl Monoid for OrderedIxs {
 }
 
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Cfg(pub OrderedIxs, pub bool);
 
 impl Monoid for Cfg {
@@ -61,8 +67,8 @@ impl Monoid for Cfg {
 }
 
 #[derive(Debug)]
-pub struct Nfa {
-    pub states: Vec<State>,
+pub struct Nfa<S = u8> {
+    pub states: Vec<State<S>>,
 }
 
 impl Nfa {
@@ -92,8 +98,39 @@ impl Nfa {
                 self.recur_ast(*right, qmid, qsuc);
             }
             Ast::Repetition(body) => {
-                self.states[qpre].epsilon_transitions.push(qsuc);
-                self.recur_ast(*body, qpre, qpre);
+                // A loop of its own, as a loop on qpre would be shared by its other successors,
+                // e.g. `.*a|^b` would not be anchored anymore.
+                let qloop = self.add_state();
+                self.states[qpre].epsilon_transitions.push(qloop);
+                self.recur_ast(*body, qloop, qloop);
+                self.states[qloop].epsilon_transitions.push(qsuc);
+            }
+            Ast::Counted(body, min, max) => {
+                // A chain of the mandatory copies, then of the optional ones, each of which can
+                // be skipped to the end, or a loop if the count is unbounded.
+                let mut q = qpre;
+                for _ in 0..min {
+                    let qmid = self.add_state();
+                    self.recur_ast((*body).clone(), q, qmid);
+                    q = qmid;
+                }
+                match max {
+                    Some(max) => {
+                        for _ in min..max {
+                            self.states[q].epsilon_transitions.push(qsuc);
+                            let qmid = self.add_state();
+                            self.recur_ast((*body).clone(), q, qmid);
+                            q = qmid;
+                        }
+                        self.states[q].epsilon_transitions.push(qsuc);
+                    }
+                    None => {
+                        let qloop = self.add_state();
+                        self.states[q].epsilon_transitions.push(qloop);
+                        self.recur_ast(*body, qloop, qloop);
+                        self.states[qloop].epsilon_transitions.push(qsuc);
+                    }
+                }
             }
             Ast::Epsilon => {
                 self.states[qpre].epsilon_transitions.push(qsuc);
@@ -101,6 +138,13 @@ impl Nfa {
         }
     }
 
+    fn add_state(&mut self) -> usize {
+        self.states.push(State::new());
+        self.states.len() - 1
+    }
+}
+
+impl<S> Nfa<S> {
     fn add_inherited(&self, q: usize, configuration: &mut HashSet<usize>) {
         if !configuration.insert(q) {
             return;
@@ -127,6 +171,23 @@ impl Nfa {
     }
 }
 
+impl<S: Copy + Ord> Nfa<S> {
+    // Run the automaton directly, without compiling it. Slow, but simple enough to serve as the
+    // reference semantics of the patterns, see `differential`.
+    pub fn is_match(&self, value: &[S]) -> bool {
+        let mut cfg = self.expand_config(vec![0]);
+        for c in value {
+            let next = cfg.0.0.iter()
+                .flat_map(|q| self.states[*q].transitions.iter())
+                .filter(|((from, to), _)| from <= c && c <= to)
+                .map(|(_, suc)| *suc)
+                .collect();
+            cfg = self.expand_config(next);
+        }
+        cfg.1
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -136,24 +197,46 @@ mod tests {
     fn test_nfa() {
         let ast = parse_regex("a([bA-D]|[cB-C])*d");
         let nfa = Nfa::from_ast(ast);
-        assert_eq!(nfa.states.len(), 4);
+        assert_eq!(nfa.states.len(), 5);
         assert_eq!(nfa.states[1].transitions, vec![]);
         assert_eq!(nfa.states[0].transitions, vec![((b'a', b'a'), 3)]);
+        assert_eq!(nfa.states[3].epsilon_transitions, vec![4]);
         assert_eq!(
-            nfa.states[3].transitions,
+            nfa.states[4].transitions,
             vec![
-                ((b'b', b'b'), 3),
-                ((b'A', b'D'), 3),
-                ((b'c', b'c'), 3),
-                ((b'B', b'C'), 3),
+                ((b'b', b'b'), 4),
+                ((b'A', b'D'), 4),
+                ((b'c', b'c'), 4),
+                ((b'B', b'C'), 4),
             ]
         );
-        assert_eq!(nfa.states[3].epsilon_transitions, vec![2]);
+        assert_eq!(nfa.states[4].epsilon_transitions, vec![2]);
         assert_eq!(nfa.states[2].transitions, vec![((b'd', b'd'), 1)]);
 
         assert_eq!(nfa.expand_config(vec![0]), Cfg(OrderedIxs(vec![0]), false));
         assert_eq!(nfa.expand_config(vec![1]), Cfg(OrderedIxs(vec![]), true));
         assert_eq!(nfa.expand_config(vec![2]), Cfg(OrderedIxs(vec![2]), false));
-        assert_eq!(nfa.expand_config(vec![3]), Cfg(OrderedIxs(vec![2, 3]), false));
+        assert_eq!(nfa.expand_config(vec![3]), Cfg(OrderedIxs(vec![2, 4]), false));
+
+        assert!(nfa.is_match(b"abCcd"));
+        assert!(nfa.is_match(b"ad"));
+        assert!(!nfa.is_match(b"aEd"));
+        assert!(!nfa.is_match(b"abc"));
+    }
+
+    #[test]
+    fn test_counted() {
+        let check = |regex: &str, matching: &[&[u8]], other: &[&[u8]]| {
+            let nfa = Nfa::from_ast(parse_regex(regex));
+            for value in matching { assert!(nfa.is_match(value), "{} {:?}", regex, value); }
+            for value in other { assert!(!nfa.is_match(value), "{} {:?}", regex, value); }
+        };
+        check("[0-9]{1,3}", &[b"0", b"12", b"123"], &[b"", b"1234", b"1a"]);
+        check("(ab){2}", &[b"abab"], &[b"ab", b"ababab"]);
+        check("a{2,}b?", &[b"aa", b"aaaab"], &[b"a", b"ab", b"aabb"]);
+        check("a+|b", &[b"a", b"aa", b"b"], &[b"", b"ab", b"ba"]);
+        check("ab?c", &[b"ac", b"abc"], &[b"abbc"]);
+        check("a*|b", &[b"", b"aa", b"b"], &[b"ab", b"ba"]);
+        check("(a{0,2}b){2}", &[b"bb", b"aabab"], &[b"aaabb", b"b"]);
     }
 }
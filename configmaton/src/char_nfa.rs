@@ -2,24 +2,34 @@ use std::collections::VecDeque;
 
 use hashbrown::{HashMap, hash_map::Entry};
 
-use super::guards;
-use super::guards::{Guard, Monoid};
+use super::guards::{Guard, Monoid, SymbolSet};
 use super::char_enfa::{Cfg, Nfa as Enfa, OrderedIxs};
+use super::partition;
 
 
-pub struct State {
-    pub transitions: Vec<(Guard, usize)>,
+// The automata are generic over the guards of their transitions, and so over the alphabet. The
+// blob is built from the byte ones only.
+pub struct State<G = Guard> {
+    pub transitions: Vec<(G, usize)>,
+    // Taken after the end of the value, so that the tags can depend on the value ending there.
+    pub end_transitions: Vec<usize>,
     pub tags: OrderedIxs,
     pub is_deterministic: bool,
 }
 
-pub struct Nfa {
-    pub states: Vec<State>,
+pub struct Nfa<G = Guard> {
+    pub states: Vec<State<G>>,
     pub configurations_to_states: HashMap<OrderedIxs, (usize, usize)>,
     pub visited_states: HashMap<usize, usize>,
 }
 
-impl Nfa {
+impl<G: SymbolSet> Default for Nfa<G> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<G: SymbolSet> Nfa<G> {
     pub fn new() -> Self {
         Nfa {
             states: vec![],
@@ -28,7 +38,27 @@ impl Nfa {
         }
     }
 
-    pub fn add_nfa(&mut self, enfa: Enfa, tag: usize) {
+    // Add the states of another NFA, shifting its state indices behind ours and its tags by
+    // `tag_offset`. Returns the shift of the state indices.
+    pub fn append(&mut self, other: Nfa<G>, tag_offset: usize) -> usize {
+        let offset = self.states.len();
+        let shift = |ixs: &OrderedIxs| OrderedIxs(ixs.0.iter().map(|ix| ix + offset).collect());
+        self.states.extend(other.states.into_iter().map(|state| State {
+            transitions: state.transitions.into_iter()
+                .map(|(guard, suc)| (guard, suc + offset))
+                .collect(),
+            end_transitions: state.end_transitions.into_iter().map(|suc| suc + offset).collect(),
+            tags: OrderedIxs(state.tags.0.into_iter().map(|tag| tag + tag_offset).collect()),
+            is_deterministic: state.is_deterministic,
+        }));
+        self.configurations_to_states.extend(other.configurations_to_states.iter()
+            .map(|(cfg, (ix, stop_size))| (shift(cfg), (ix + offset, stop_size + offset))));
+        self.visited_states.extend(other.visited_states.iter()
+            .map(|(ix, stop_size)| (ix + offset, stop_size + offset)));
+        offset
+    }
+
+    pub fn add_nfa(&mut self, enfa: Enfa<G::Symbol>, tag: usize) {
         let mut reachable_configurations: HashMap<Cfg, usize> = HashMap::new();
         let mut frontier: Vec<(OrderedIxs, usize)> = vec![];
 
@@ -40,6 +70,7 @@ impl Nfa {
         self.states.push(
             State {
                 transitions: vec![],
+                end_transitions: vec![],
                 tags: OrderedIxs(if is_final { vec![tag] } else { vec![] }),
                 is_deterministic: false,
             },
@@ -55,27 +86,31 @@ impl Nfa {
                 }
             }
 
-            let mut suc_to_guard: HashMap<usize, Guard> = HashMap::new();
+            let mut suc_to_guard: HashMap<usize, G> = HashMap::new();
             for (range, suc) in transitions {
-                suc_to_guard.entry(suc).or_insert(Guard::empty()).add_range(range);
+                suc_to_guard.entry(suc).or_insert(G::empty()).add_range(range);
             }
 
-            let mut cfgsuc_to_guard: HashMap<Cfg, Guard> = HashMap::new();
+            let mut cfgsuc_to_guard: HashMap<Cfg, G> = HashMap::new();
             for (suc, guard) in suc_to_guard {
                 let cfgsuc = enfa.expand_config(vec![suc]);
-                cfgsuc_to_guard.entry(cfgsuc).or_insert(Guard::empty()).union_update(&guard);
+                cfgsuc_to_guard.entry(cfgsuc).or_insert(G::empty()).union_update(&guard);
             }
 
             // 4. the DFA state transitions to the newly-created or reused states of the expanded
             //   configurations
             // 5. put the newly-created ones to the frontier, together with their state index.
 
+            // Sorted, so that the states are numbered independently of the hashing.
+            let mut cfgsuc_to_guard = cfgsuc_to_guard.into_iter().collect::<Vec<_>>();
+            cfgsuc_to_guard.sort_unstable_by(|(cfg1, g1), (cfg2, g2)| (g1, cfg1).cmp(&(g2, cfg2)));
             for (cfgsuc, guard) in cfgsuc_to_guard {
                 let new_state_ix = *reachable_configurations.entry(cfgsuc.clone()).or_insert_with(|| {
                     let is_final = cfgsuc.1;
                     let new_state_ix = self.states.len();
                     self.states.push(State {
                         transitions: vec![],
+                        end_transitions: vec![],
                         tags: OrderedIxs(if is_final { vec![tag] } else { vec![] }),
                         is_deterministic: false,
                     });
@@ -123,13 +158,16 @@ impl Nfa {
                 .or_insert_with(|| {
                 let mut tags = OrderedIxs(vec![]);
                 let mut transitions = vec![];
+                let mut end_transitions = vec![];
                 for suc_ix in cfg.0.iter() {
                     let state = &self.states[*suc_ix];
                     tags.append(&state.tags);
-                    transitions.extend(&state.transitions);
+                    transitions.extend(state.transitions.iter().cloned());
+                    end_transitions.extend(state.end_transitions.iter().copied());
                 }
                 let suc_ix = self.states.len();
-                self.states.push(State { transitions, tags, is_deterministic: false });
+                self.states.push(
+                    State { transitions, end_transitions, tags, is_deterministic: false });
                 if self.states.len() < stop_size { frontier.push_back(suc_ix); }
                 (suc_ix, stop_size)
             });
@@ -139,6 +177,220 @@ impl Nfa {
         }
     }
 
+    // Run the value from the given states and return the sorted tags of the states reached in the
+    // end, i.e. the patterns matching the whole value.
+    pub fn matching_tags<I: IntoIterator<Item = usize>>(&self, inits: I, value: &[G::Symbol])
+        -> Vec<usize>
+    {
+        let mut current = inits.into_iter().collect::<Vec<_>>();
+        for c in value {
+            let mut next = current.iter()
+                .flat_map(|ix| self.states[*ix].transitions.iter())
+                .filter(|(guard, _)| guard.contains(*c))
+                .map(|(_, suc)| *suc)
+                .collect::<Vec<_>>();
+            next.sort_unstable();
+            next.dedup();
+            current = next;
+        }
+        self.end_tags(&current)
+    }
+
+    // The tags of the states and of their successors after the end of the value.
+    fn end_tags(&self, cfg: &[usize]) -> Vec<usize> {
+        let mut tags = cfg.iter()
+            .flat_map(|ix| self.states[*ix].end_transitions.iter())
+            .chain(cfg)
+            .flat_map(|ix| self.states[*ix].tags.0.iter().copied())
+            .collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags.dedup();
+        tags
+    }
+
+    // Explore the product of the subset constructions of both automata, comparing the tags of the
+    // reached configurations. Returns a shortest word after which they differ, or None if the
+    // automata started in the given states match the same words with the same tags.
+    pub fn distinguishing_word(&self, inits: &[usize], other: &Nfa<G>, other_inits: &[usize])
+        -> Option<Vec<G::Symbol>>
+    {
+        fn normalize(mut ixs: Vec<usize>) -> Vec<usize> {
+            ixs.sort_unstable();
+            ixs.dedup();
+            ixs
+        }
+        fn step<G: SymbolSet>(nfa: &Nfa<G>, cfg: &[usize], c: G::Symbol) -> Vec<usize> {
+            normalize(cfg.iter()
+                .flat_map(|ix| nfa.states[*ix].transitions.iter())
+                .filter(|(guard, _)| guard.contains(c))
+                .map(|(_, suc)| *suc)
+                .collect())
+        }
+        // Each visited pair remembers its predecessor and the symbol leading from it.
+        type Pair = (Vec<usize>, Vec<usize>);
+        let mut visited: HashMap<Pair, Option<(usize, G::Symbol)>> = HashMap::new();
+        let mut pairs = vec![];
+        let init = (normalize(inits.to_vec()), normalize(other_inits.to_vec()));
+        visited.insert(init.clone(), None);
+        pairs.push(init);
+
+        let mut next = 0;
+        while next < pairs.len() {
+            let (cfg, other_cfg) = pairs[next].clone();
+            if self.end_tags(&cfg) != other.end_tags(&other_cfg) {
+                let mut word = vec![];
+                let mut cur = next;
+                while let Some((pre, c)) = visited[&pairs[cur]] {
+                    word.push(c);
+                    cur = pre;
+                }
+                word.reverse();
+                return Some(word);
+            }
+
+            // All symbols of a minterm of the outgoing guards lead to the same pair, so it
+            // suffices to try one of them.
+            let guards = cfg.iter().map(|ix| &self.states[*ix])
+                .chain(other_cfg.iter().map(|ix| &other.states[*ix]))
+                .flat_map(|state| state.transitions.iter())
+                .map(|(guard, _)| (OrderedIxs::empty(), guard.clone()));
+            let mut symbols = G::mintermize(guards).keys()
+                .filter_map(|minterm| minterm.first())
+                .collect::<Vec<_>>();
+            symbols.sort_unstable();
+
+            for c in symbols {
+                let suc = (step(self, &cfg, c), step(other, &other_cfg, c));
+                if let Entry::Vacant(entry) = visited.entry(suc.clone()) {
+                    entry.insert(Some((next, c)));
+                    pairs.push(suc);
+                }
+            }
+            next += 1;
+        }
+        None
+    }
+
+    pub fn equivalent(&self, inits: &[usize], other: &Nfa<G>, other_inits: &[usize]) -> bool {
+        self.distinguishing_word(inits, other, other_inits).is_none()
+    }
+
+    // The states reachable from `init`, renumbered so that `init` becomes 0.
+    pub fn sub_automaton(&self, init: usize) -> Nfa<G> {
+        let mut renumbered: HashMap<usize, usize> = HashMap::from_iter([(init, 0)]);
+        let mut order = vec![init];
+        let mut next = 0;
+        while next < order.len() {
+            let state = &self.states[order[next]];
+            let sucs = state.transitions.iter().map(|(_, suc)| suc).chain(&state.end_transitions);
+            for suc in sucs {
+                renumbered.entry(*suc).or_insert_with(|| { order.push(*suc); order.len() - 1 });
+            }
+            next += 1;
+        }
+
+        let mut result = Nfa::new();
+        result.states = order.iter().map(|ix| {
+            let state = &self.states[*ix];
+            State {
+                transitions: state.transitions.iter()
+                    .map(|(guard, suc)| (guard.clone(), renumbered[suc]))
+                    .collect(),
+                end_transitions: state.end_transitions.iter().map(|suc| renumbered[suc]).collect(),
+                tags: state.tags.clone(),
+                is_deterministic: state.is_deterministic,
+            }
+        }).collect();
+        result
+    }
+
+    // Determinize the automaton started in `init` (e.g. a single pattern) aside, leaving this one
+    // untouched. Returns the DFA, with the initial state 0, or None if it would have more than
+    // `max_states` states.
+    pub fn determinize_bounded(&self, init: usize, max_states: usize) -> Option<Nfa<G>> {
+        let mut nfa = self.sub_automaton(init);
+        // The construction stops expanding the configurations once there are this many states.
+        let stop_size = nfa.states.len() + max_states;
+        let dfa_init = nfa.determinize(OrderedIxs(vec![0]), stop_size);
+        if nfa.states.len() >= stop_size { return None; }
+        let dfa = nfa.sub_automaton(dfa_init);
+        if dfa.states.len() > max_states { return None; }
+        Some(dfa)
+    }
+
+    // Merge the states reachable from `inits` which match the same words with the same tags, found
+    // by refining the partition of the states by their tags until the states of each class move
+    // on the same symbols into the same classes (see `partition::refine`). The unreachable states
+    // are dropped. Returns the new index of each reachable state, the states being numbered in
+    // the order of discovery.
+    pub fn minimize(&mut self, inits: &[usize]) -> HashMap<usize, usize> {
+        let mut positions: HashMap<usize, usize> = HashMap::new();
+        let mut order = vec![];
+        for init in inits {
+            positions.entry(*init).or_insert_with(|| { order.push(*init); order.len() - 1 });
+        }
+        let mut next = 0;
+        while next < order.len() {
+            let state = &self.states[order[next]];
+            let sucs = state.transitions.iter().map(|(_, suc)| suc).chain(&state.end_transitions);
+            for suc in sucs {
+                positions.entry(*suc).or_insert_with(|| { order.push(*suc); order.len() - 1 });
+            }
+            next += 1;
+        }
+
+        // The transitions of the state into each class, the guards into the same class joined.
+        let into_classes = |state: &State<G>, classes: &[usize]| {
+            let mut guards: HashMap<usize, G> = HashMap::new();
+            for (guard, suc) in state.transitions.iter() {
+                guards.entry(classes[positions[suc]]).or_insert(G::empty()).union_update(guard);
+            }
+            let mut transitions = guards.into_iter().map(|(class, guard)| (guard, class))
+                .collect::<Vec<_>>();
+            transitions.sort_unstable();
+            let mut ends = state.end_transitions.iter()
+                .map(|suc| classes[positions[suc]])
+                .collect::<Vec<_>>();
+            ends.sort_unstable();
+            ends.dedup();
+            (transitions, ends)
+        };
+
+        let mut tag_classes = HashMap::new();
+        let initial = order.iter().map(|ix| {
+            let state = &self.states[*ix];
+            let len = tag_classes.len();
+            *tag_classes.entry((state.tags.clone(), state.is_deterministic)).or_insert(len)
+        }).collect::<Vec<_>>();
+        let mut preds = vec![vec![]; order.len()];
+        for (pos, ix) in order.iter().enumerate() {
+            let state = &self.states[*ix];
+            let sucs = state.transitions.iter().map(|(_, suc)| suc).chain(&state.end_transitions);
+            for suc in sucs { preds[positions[suc]].push(pos); }
+        }
+        let classes = partition::refine(&initial, &preds,
+            |pos, classes| into_classes(&self.states[order[pos]], classes));
+
+        // The first state of each class represents it.
+        let mut states = vec![];
+        for (ix, class) in order.iter().zip(classes.iter()) {
+            if *class < states.len() { continue; }
+            let state = &self.states[*ix];
+            let (transitions, end_transitions) = into_classes(state, &classes);
+            states.push(State {
+                transitions,
+                end_transitions,
+                tags: state.tags.clone(),
+                is_deterministic: state.is_deterministic,
+            });
+        }
+        self.states = states;
+        // The determinized configurations are of the old states.
+        self.configurations_to_states.clear();
+        self.visited_states.clear();
+        order.into_iter().zip(classes).collect()
+    }
+
     pub fn determinize(&mut self, init_states: OrderedIxs, stop_size: usize) -> usize {
         let mut frontier: VecDeque<usize> = VecDeque::new();
 
@@ -152,7 +404,8 @@ impl Nfa {
             // not singleton. In this case, it is always singleton.
             if pre.is_deterministic {
                 // Only continue the determinization with its successors.
-                for (_guard, suc) in pre.transitions.iter() {
+                let sucs = pre.transitions.iter().map(|(_, suc)| suc).chain(&pre.end_transitions);
+                for suc in sucs {
                     Self::continue_to_state(
                         *suc, &mut self.visited_states, states_len, &mut frontier, stop_size);
                 }
@@ -172,26 +425,26 @@ impl Nfa {
 
             // Let's go:
 
-            let mut suc_to_guard: HashMap<usize, Guard> = HashMap::new();
+            let mut suc_to_guard: HashMap<usize, G> = HashMap::new();
             for (range, suc) in pre.transitions.iter() {
-                suc_to_guard.entry(*suc).or_insert(Guard::empty()).union_update(range);
+                suc_to_guard.entry(*suc).or_insert(G::empty()).union_update(range);
             }
 
-            let mut cfgsuc_to_guard: HashMap<OrderedIxs, Guard> = HashMap::new();
+            let mut cfgsuc_to_guard: HashMap<OrderedIxs, G> = HashMap::new();
             for (suc, guard) in suc_to_guard {
                 cfgsuc_to_guard.entry(OrderedIxs(vec![suc]))
-                    .or_insert(Guard::empty()).union_update(&guard);
+                    .or_insert(G::empty()).union_update(&guard);
             }
 
             let mut len_before = cfgsuc_to_guard.len();
-            let mut guard_to_cfgsuc: HashMap<Guard, OrderedIxs> = HashMap::new();
+            let mut guard_to_cfgsuc: HashMap<G, OrderedIxs> = HashMap::new();
             loop {
                 for (cfgsuc, guard) in cfgsuc_to_guard.drain() {
                     guard_to_cfgsuc.entry(guard).or_insert(Monoid::empty()).append(&cfgsuc);
                 }
 
                 for (guard, cfgsuc) in guard_to_cfgsuc.drain() {
-                    cfgsuc_to_guard.entry(cfgsuc).or_insert(Guard::empty()).union_update(&guard);
+                    cfgsuc_to_guard.entry(cfgsuc).or_insert(G::empty()).union_update(&guard);
                 }
 
                 if cfgsuc_to_guard.len() == len_before { break; }
@@ -199,7 +452,7 @@ impl Nfa {
             }
 
             // 2. mintermize
-            guard_to_cfgsuc = guards::Guard::mintermize(cfgsuc_to_guard.drain());
+            guard_to_cfgsuc = G::mintermize(cfgsuc_to_guard.drain());
 
             // 3. join ranges that lead to the same state and states with the same ranges,
             //   cyclically.
@@ -207,7 +460,7 @@ impl Nfa {
             len_before = guard_to_cfgsuc.len();
             loop {
                 for (guard, cfgsuc) in guard_to_cfgsuc.drain() {
-                    cfgsuc_to_guard.entry(cfgsuc).or_insert(Guard::empty()).union_update(&guard);
+                    cfgsuc_to_guard.entry(cfgsuc).or_insert(G::empty()).union_update(&guard);
                 }
 
                 for (cfgsuc, guard) in cfgsuc_to_guard.drain() {
@@ -222,11 +475,27 @@ impl Nfa {
             //   configurations
             // 5. put the newly-created ones to the frontier, together with their state index.
 
-            pre.transitions.clear();
+            // The successor configurations may contain the state itself, so its transitions are
+            // replaced only after they have been created from them.
+            let mut end_cfg = pre.end_transitions.clone();
+            // The guards are disjoint, sorting by them numbers the states independently of the
+            // hashing.
+            let mut guard_to_cfgsuc = guard_to_cfgsuc.into_iter().collect::<Vec<_>>();
+            guard_to_cfgsuc.sort_unstable_by(|(g1, _), (g2, _)| g1.cmp(g2));
+            let mut transitions = vec![];
+            let mut end_transitions = vec![];
             for (guard, cfgsuc) in guard_to_cfgsuc {
                 let suc_ix = self.continue_to_cfg(&cfgsuc, &mut frontier, stop_size);
-                self.states[pre_ix].transitions.push((guard, suc_ix));
+                transitions.push((guard, suc_ix));
+            }
+            if !end_cfg.is_empty() {
+                end_cfg.sort_unstable();
+                end_cfg.dedup();
+                let suc_ix = self.continue_to_cfg(&OrderedIxs(end_cfg), &mut frontier, stop_size);
+                end_transitions.push(suc_ix);
             }
+            self.states[pre_ix].transitions = transitions;
+            self.states[pre_ix].end_transitions = end_transitions;
         }
         new_init
     }
@@ -254,27 +523,26 @@ mod tests {
 
         assert_eq!(dfa.states[0].transitions.len(), 2);
         assert_eq!(dfa.states[0].transitions[0].0,
-            Guard::from_ranges(vec![(b'a', b'a')]));
-        assert_eq!(dfa.states[0].transitions[1].0,
             Guard::from_ranges(vec![(0, b'a' - 1), (b'a' + 1, 255)]));
+        assert_eq!(dfa.states[0].transitions[1].0,
+            Guard::from_ranges(vec![(b'a', b'a')]));
 
-        let qsink = dfa.states[0].transitions[1].1;
-        let q2 = dfa.states[0].transitions[0].1;
-
+        let qsink = dfa.states[0].transitions[0].1;
+        let q2 = dfa.states[0].transitions[1].1;
         assert_eq!(dfa.states[qsink].tags, qnonfinal);
         assert_eq!(dfa.states[qsink].transitions, vec![(Guard::full(), qsink)]);
 
         assert_eq!(dfa.states[q2].tags, qnonfinal);
         assert_eq!(dfa.states[q2].transitions.len(), 3);
         assert_eq!(dfa.states[q2].transitions[0],
-            (Guard::from_ranges(vec![(b'A', b'D'), (b'b', b'c')]), q2));
-        assert_eq!(dfa.states[q2].transitions[1].0,
-            Guard::from_ranges(vec![(b'd', b'd')]));
-        assert_eq!(dfa.states[q2].transitions[2],
             (Guard::from_ranges(vec![(0, b'A' - 1), (b'D' + 1, b'b' - 1), (b'd' + 1, 255)]),
                 qsink));
+        assert_eq!(dfa.states[q2].transitions[1],
+            (Guard::from_ranges(vec![(b'A', b'D'), (b'b', b'c')]), q2));
+        assert_eq!(dfa.states[q2].transitions[2].0,
+            Guard::from_ranges(vec![(b'd', b'd')]));
 
-        let qf = dfa.states[q2].transitions[1].1;
+        let qf = dfa.states[q2].transitions[2].1;
         assert_eq!(dfa.states[qf].tags, qfinal);
         assert_eq!(dfa.states[qf].transitions, vec![(Guard::full(), qsink)]);
     }
@@ -297,11 +565,171 @@ mod tests {
     #[test]
     fn emptyword_nfa_works2() {
         let enfa = Enfa::from_ast(parse_regex(""));
-        let mut nfa = Nfa::new();
+        let mut nfa: Nfa = Nfa::new();
         nfa.add_nfa(enfa, 0);
 
         assert_eq!(nfa.states.len(), 1);
         assert_eq!(nfa.states[0].tags, OrderedIxs(vec![0]));
         assert_eq!(nfa.states[0].transitions, vec![]);
     }
+
+    #[test]
+    fn equivalence() {
+        let nfa = |regex| {
+            let mut nfa: Nfa = Nfa::new();
+            nfa.add_nfa(Enfa::from_ast(parse_regex(regex)), 0);
+            nfa
+        };
+
+        let a = nfa("a([bA-D]|[cB-C])*d");
+        let mut dfa = nfa("a([bA-D]|[cB-C])*d");
+        let init = dfa.determinize(OrderedIxs(vec![0]), 1000);
+        assert!(a.equivalent(&[0], &dfa, &[init]));
+        assert!(a.equivalent(&[0], &nfa("a[A-Db-c]*d"), &[0]));
+
+        assert_eq!(nfa("a|ab").distinguishing_word(&[0], &nfa("ab?c?"), &[0]),
+            Some(b"ac".to_vec()));
+        assert_eq!(nfa("x*").distinguishing_word(&[0], &nfa("xx*"), &[0]), Some(vec![]));
+
+        let mut retagged = Nfa::new();
+        retagged.add_nfa(Enfa::from_ast(parse_regex("a")), 1);
+        assert_eq!(nfa("a").distinguishing_word(&[0], &retagged, &[0]), Some(b"a".to_vec()));
+    }
+
+    #[test]
+    fn determinize_bounded() {
+        let mut nfa: Nfa = Nfa::new();
+        nfa.add_nfa(Enfa::from_ast(parse_regex("x*y")), 0);
+        let init = nfa.states.len();
+        nfa.add_nfa(Enfa::from_ast(parse_regex("(a|b)*a(a|b)(a|b)(a|b)(a|b)")), 1);
+
+        assert!(nfa.determinize_bounded(init, 16).is_none());
+        let dfa = nfa.determinize_bounded(init, 64).unwrap();
+        // The minimal DFA has 32 states, plus the sink.
+        assert!(dfa.states.len() > 32);
+        assert!(dfa.equivalent(&[0], &nfa, &[init]));
+        for state in dfa.states.iter() {
+            for (i, (guard, _)) in state.transitions.iter().enumerate() {
+                for (other, _) in state.transitions[i + 1..].iter() {
+                    assert!(guard.intersection(other).is_empty());
+                }
+            }
+        }
+        assert_eq!(nfa.sub_automaton(init).states.len(), nfa.states.len() - init);
+        assert!(nfa.states.iter().all(|state| !state.is_deterministic));
+    }
+
+    #[test]
+    fn minimize() {
+        let mut nfa: Nfa = Nfa::new();
+        nfa.add_nfa(Enfa::from_ast(parse_regex("x*y")), 0);
+        let init = nfa.states.len();
+        nfa.add_nfa(Enfa::from_ast(parse_regex("(a|b)*a(a|b)(a|b)(a|b)(a|b)")), 1);
+        let dfa = nfa.determinize_bounded(init, 64).unwrap();
+
+        let mut minimal = nfa.determinize_bounded(init, 64).unwrap();
+        let moved = minimal.minimize(&[0]);
+        assert_eq!(moved[&0], 0);
+        assert_eq!(moved.len(), dfa.states.len());
+        assert_eq!(minimal.states.len(), 33);
+        assert!(minimal.equivalent(&[0], &dfa, &[0]));
+
+        // The unreachable states are dropped.
+        let moved = nfa.minimize(&[init]);
+        assert_eq!(moved[&init], 0);
+        assert!(!moved.contains_key(&0));
+        assert!(nfa.equivalent(&[0], &dfa, &[0]));
+    }
+
+    #[test]
+    fn minimize_long_chains() {
+        // Two equal chains of `a`s, merged into one. Refining all the classes at once would take
+        // a round per state.
+        let n = 8000;
+        let mut nfa: Nfa = Nfa::new();
+        for chain in 0..2 {
+            for ix in 0..n {
+                let suc = chain * n + ix + 1;
+                nfa.states.push(State {
+                    transitions: if ix + 1 < n { vec![(Guard::from_range((b'a', b'a')), suc)] }
+                        else { vec![] },
+                    end_transitions: vec![],
+                    tags: OrderedIxs(if ix + 1 < n { vec![] } else { vec![0] }),
+                    is_deterministic: true,
+                });
+            }
+        }
+        let moved = nfa.minimize(&[0, n]);
+        assert_eq!(nfa.states.len(), n);
+        assert_eq!((moved[&0], moved[&n], moved[&(2 * n - 1)]), (0, 0, n - 1));
+    }
+
+    #[test]
+    fn determinize_self_loop() {
+        // The configurations reached from the loop of `(a|b)*a` contain the looping state itself.
+        let regex = "(a|b)*a(a|b)(a|b)(a|b)(a|b)";
+        let mut nfa: Nfa = Nfa::new();
+        nfa.add_nfa(Enfa::from_ast(parse_regex(regex)), 0);
+        let mut dfa: Nfa = Nfa::new();
+        dfa.add_nfa(Enfa::from_ast(parse_regex(regex)), 0);
+        let init = dfa.determinize(OrderedIxs(vec![0]), 1000);
+        assert_eq!(dfa.matching_tags([init], b"aaaaaa"), vec![0]);
+        assert!(nfa.equivalent(&[0], &dfa, &[init]));
+    }
+
+    #[test]
+    fn wide_alphabet() {
+        use crate::char_enfa::State as EState;
+        use crate::guards::RangeGuard;
+
+        // λ[α-ω]*
+        let enfa = |last| {
+            let mut states = (0..3).map(|_| EState::new()).collect::<Vec<_>>();
+            states[0].transitions.push((('λ', 'λ'), 2));
+            states[2].transitions.push((('α', last), 2));
+            states[2].epsilon_transitions.push(1);
+            Enfa { states }
+        };
+        let mut nfa: Nfa<RangeGuard<char>> = Nfa::new();
+        nfa.add_nfa(enfa('ω'), 0);
+        let second = nfa.states.len();
+        nfa.add_nfa(enfa('ψ'), 1);
+        let init = nfa.determinize(OrderedIxs(vec![0, second]), 1000);
+
+        let word = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(nfa.matching_tags([init], &word("λαβψ")), vec![0, 1]);
+        assert_eq!(nfa.matching_tags([0, second], &word("λαω")), vec![0]);
+        assert_eq!(nfa.matching_tags([init], &word("λa")), Vec::<usize>::new());
+        assert!(nfa.equivalent(&[0, second], &nfa, &[init]));
+        assert_eq!(nfa.distinguishing_word(&[0], &nfa, &[second]), Some(word("λ")));
+    }
+
+    #[test]
+    fn end_transitions() {
+        // Tag 0 is reached only after the end of a value matching "ab".
+        let mut nfa: Nfa = Nfa::new();
+        nfa.add_nfa(Enfa::from_ast(parse_regex("ab")), 2);
+        let second = nfa.states.len();
+        nfa.add_nfa(Enfa::from_ast(parse_regex(".*b")), 3);
+        nfa.states.push(State {
+            transitions: vec![],
+            end_transitions: vec![],
+            tags: OrderedIxs(vec![0]),
+            is_deterministic: false,
+        });
+        let end = nfa.states.len() - 1;
+        let ab = nfa.states.iter().position(|q| q.tags.0 == vec![2]).unwrap();
+        nfa.states[ab].end_transitions.push(end);
+
+        let init = nfa.determinize(OrderedIxs(vec![0, second]), 1000);
+        for q in nfa.states.iter().filter(|q| q.is_deterministic) {
+            assert!(q.end_transitions.len() <= 1);
+        }
+        assert_eq!(nfa.matching_tags([0, second], b"ab"), vec![0, 2, 3]);
+        assert_eq!(nfa.matching_tags([init], b"ab"), vec![0, 2, 3]);
+        assert_eq!(nfa.matching_tags([init], b"cb"), vec![3]);
+        assert_eq!(nfa.matching_tags([init], b"a"), Vec::<usize>::new());
+        assert!(nfa.equivalent(&[0, second], &nfa, &[init]));
+        assert_eq!(nfa.distinguishing_word(&[0], &nfa, &[ab]), Some(vec![]));
+    }
 }
@@ -1,11 +1,34 @@
+use hashbrown::HashSet;
 use indexmap::IndexSet;
 
-use crate::blob::{state::{U8State, U8StateIterator}, UnsafeIterator};
+use crate::blob::state::U8State;
+use crate::blob::BlobPtr;
+pub use crate::blob::state::TransitionGuard;
+
+// Called on each transition taken by the runner, e.g. for coverage reporting.
+pub trait Tracer<'a> {
+    fn on_transition(
+        &mut self,
+        source: *const U8State<'a>,
+        symbol: u8,
+        guard: TransitionGuard<'a>,
+        target: *const U8State<'a>,
+    );
+}
+
+pub struct NoTracer;
+
+impl<'a> Tracer<'a> for NoTracer {
+    fn on_transition(
+        &mut self, _: *const U8State<'a>, _: u8, _: TransitionGuard<'a>, _: *const U8State<'a>,
+    ) {}
+}
 
 pub struct Runner<'a> {
     pub states: IndexSet<*const U8State<'a>>,
 }
 
+#[allow(clippy::missing_safety_doc)]
 impl<'a> Runner<'a>
 {
     // Initialize the state of the automaton.
@@ -15,28 +38,38 @@ impl<'a> Runner<'a>
 
     // Read a symbol, perform transitions.
     pub unsafe fn read(&mut self, symbol: u8) {
+        self.read_traced(symbol, &mut NoTracer);
+    }
+
+    // Read a symbol, perform transitions, and report each of them to the tracer.
+    pub unsafe fn read_traced<T: Tracer<'a>>(&mut self, symbol: u8, tracer: &mut T) {
         let states = std::mem::take(&mut self.states);
 
-        // Finally, let's handle the self-handling states.
-        for state in states.into_iter() {
-            let state = &*state;
-            match state.iter_matches(&symbol) {
-                U8StateIterator::Sparse(mut iter) => {
-                    while let Some(right) = iter.next() {
-                        self.states.insert(right);
-                    }
-                },
-                U8StateIterator::Dense(mut iter) => {
-                    while let Some(right) = iter.next() {
-                        self.states.insert(*right);
-                    }
-                },
+        for left in states.into_iter() {
+            let state: &'a U8State<'a> = &*left;
+            let mut successors = state.successors(&symbol);
+            while let Some(right) = successors.next() {
+                tracer.on_transition(left, symbol, successors.guard(), right);
+                self.states.insert(right);
             }
         }
     }
 
+    // Read the end of the value: the successors after it join the current states, so that the
+    // tags of patterns observing the end of the value are reported, too.
+    pub unsafe fn finish(&mut self) {
+        let ends = self.states.iter()
+            .flat_map(|state| (**state).end_successors().iter().map(BlobPtr::get))
+            .collect::<Vec<_>>();
+        self.states.extend(ends);
+    }
+
+    // States sharing a tag set (compared by its pool pointer) report it once.
     pub unsafe fn get_tags<'b>(&'b self) -> impl Iterator<Item = usize> + 'b {
-        self.states.iter().flat_map(|state| (&**state).get_tags().iter().cloned())
+        let mut seen = HashSet::new();
+        self.states.iter()
+            .filter(move |state| seen.insert((***state).tag_set_id()))
+            .flat_map(|state| (&**state).get_tags().iter().map(|tag| *tag as usize))
     }
 }
 
@@ -57,6 +90,7 @@ mod tests {
             transitions: transitions.into_iter().map(|(a, b, q)|
                 (Guard::from_range((a, b)), q)
             ).collect(),
+            end_transitions: vec![],
             is_deterministic: false,
         }
     }
@@ -77,7 +111,7 @@ mod tests {
         let mut automaton = Runner::new([qs[0] as *const _]);
         let mut read_and_check_trans = |sym: u8, expected: Vec<usize>| {
             unsafe { automaton.read(sym) };
-            let real = unsafe { automaton.get_tags() }.into_iter().collect::<HashSet<_>>();
+            let real = unsafe { automaton.get_tags() }.collect::<HashSet<_>>();
             let expected = expected.into_iter().collect::<HashSet<_>>();
             assert_eq!(real, expected);
         };
@@ -105,4 +139,47 @@ mod tests {
         // -1-- 1--b-->2
         read_and_check_trans(b, vec![2]);
     }
+
+    struct Recorder<'a>(Vec<(*const U8State<'a>, u8, TransitionGuard<'a>, *const U8State<'a>)>);
+
+    impl<'a> Tracer<'a> for Recorder<'a> {
+        fn on_transition(
+            &mut self,
+            source: *const U8State<'a>,
+            symbol: u8,
+            guard: TransitionGuard<'a>,
+            target: *const U8State<'a>,
+        ) {
+            self.0.push((source, symbol, guard, target));
+        }
+    }
+
+    #[test]
+    fn tracer_sees_transitions() {
+        let qs = vec![
+            // Sparse: guard (b'a', b'z') is kept as a pattern, (b'0', b'0') is explicitized.
+            new_state(0, vec![(b'a', b'z', 1), (b'0', b'0', 0)]),
+            // Dense: three transitions reach the dense_guard_count of the test config.
+            new_state(1, vec![(b'a', b'a', 0), (b'b', b'b', 1), (b'c', b'c', 1)]),
+        ];
+        let mut buf = vec![];
+        let qs = unsafe { create_states(&mut buf, qs) };
+        let (q0, q1) = (qs[0] as *const U8State, qs[1] as *const U8State);
+        let guard = Guard::from_range((b'a', b'z'));
+
+        let mut runner = Runner::new([q0]);
+        let mut recorder = Recorder(vec![]);
+        unsafe {
+            runner.read_traced(b'0', &mut recorder);
+            runner.read_traced(b'x', &mut recorder);
+            runner.read_traced(b'a', &mut recorder);
+            runner.read_traced(b'!', &mut recorder);
+        }
+        assert_eq!(recorder.0, vec![
+            (q0, b'0', TransitionGuard::Explicit, q0),
+            (q0, b'x', TransitionGuard::Pattern(&guard), q1),
+            (q1, b'a', TransitionGuard::Dense, q0),
+        ]);
+        assert!(runner.states.is_empty());
+    }
 }
@@ -1,7 +1,7 @@
-use std::{fs::File, io::Read};
+use std::{fs::File, io::{Read, Write}};
 
-use configmaton::{blob::{keyval_state::LeafOrigin, state::build::U8BuildConfig}, keyval_nfa::{Cmd, Msg, Parser as AutParser}};
-use clap;
+use configmaton::{blob::{keyval_state::LeafOrigin, state::build::DefaultBuildConfig}, keyval_nfa::{Cmd, Msg, Parser as AutParser}};
+use configmaton::dot::{write_dot, DotOptions};
 use clap::Parser;
 
 #[derive(Parser)]
@@ -11,40 +11,69 @@ struct Args {
 
     #[clap(long)]
     dot: Option<String>,
-}
 
-pub struct BuildConfig;
-impl U8BuildConfig for BuildConfig {
-    fn guard_size_keep(&self) -> u32 { 10 }
-    fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 3 }
-    fn dense_guard_count(&self) -> usize { 15 }
+    // Limits of the dot export, the rest of the graph is left out.
+    #[clap(long)]
+    dot_max_nodes: Option<usize>,
+
+    #[clap(long)]
+    dot_max_edges: Option<usize>,
+
+    // Export only the states leading to the commands of the rule.
+    #[clap(long)]
+    dot_rule: Option<usize>,
+
+    #[clap(long)]
+    dot_cluster: bool,
+
+    // Store the regexes in the blob, so that they can be listed from it.
+    #[clap(long)]
+    embed_patterns: bool,
+
+    // Append a relocation table to the blob, so that it can be loaded faster.
+    #[clap(long)]
+    relocations: bool,
 }
 
-pub fn json_to_automaton_matchrun(json: &str)
+pub fn json_to_automaton_matchrun(json: &str, embed_patterns: bool)
     -> Result<(Msg, AutParser, LeafOrigin), serde_json::Error>
 {
     let config: Vec<Cmd> = serde_json::from_str(json)?;
-    let (parser, init) = AutParser::parse(config);
-    let msg = Msg::serialize(&parser, &init, &BuildConfig);
+    let (mut parser, init) = AutParser::parse(config);
+    parser.embed_patterns = embed_patterns;
+    let msg = Msg::serialize(&parser, &init, &DefaultBuildConfig);
     Ok((msg, parser, init))
 }
 
 fn main() {
     // take stdin, run json_to_automaton_matchrun, and, depending on arguments, store the msg
-    // or AutParser::to_dot
+    // or export the automaton to dot
 
     let args = Args::parse();
     let mut buf = String::new();
     std::io::stdin().read_to_string(&mut buf).unwrap();
-    let (msg, parser, init) = json_to_automaton_matchrun(&buf).unwrap();
+    let (msg, parser, init) = json_to_automaton_matchrun(&buf, args.embed_patterns).unwrap();
 
     if let Some(output) = args.output {
+        let msg = if args.relocations { msg.with_relocations().unwrap() } else { msg };
         let slice = unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) };
         std::fs::write(output, slice).unwrap();
     }
 
     if let Some(dot) = args.dot {
-        let file = File::create(dot).unwrap();
-        parser.to_dot(&init, file);
+        let mut file = std::io::BufWriter::new(File::create(dot).unwrap());
+        let opts = DotOptions {
+            max_nodes: args.dot_max_nodes.unwrap_or(usize::MAX),
+            max_edges: args.dot_max_edges.unwrap_or(usize::MAX),
+            rule: args.dot_rule,
+            cluster_by_rule: args.dot_cluster,
+            ..DotOptions::default()
+        };
+        let summary = write_dot(&parser, &init, &opts, &mut file).unwrap();
+        file.flush().unwrap();
+        if summary.truncated {
+            eprintln!("the dot export is truncated at {} nodes and {} edges",
+                summary.nodes, summary.edges);
+        }
     }
 }
@@ -0,0 +1,139 @@
+use hashbrown::HashMap;
+use serde_json::Value;
+
+use crate::configmaton::Configmaton;
+use crate::onion::Locker;
+
+// How the name of a command is found.
+pub enum CommandName {
+    // The command up to the first whitespace, the rest (trimmed) are the arguments.
+    FirstToken,
+    // The string field of a JSON object command, the arguments are the whole object.
+    JsonField(String),
+}
+
+pub type Handler<'a, L> = Box<dyn Fn(&mut Configmaton<'a, L>, &'a [u8]) + 'a>;
+
+// Handlers of the commands emitted by the rules, keyed by the command name.
+pub struct CommandRegistry<'a, L: Locker> {
+    name: CommandName,
+    handlers: HashMap<Vec<u8>, Handler<'a, L>>,
+}
+
+impl<'a, L: Locker> CommandRegistry<'a, L> {
+    pub fn new(name: CommandName) -> Self {
+        CommandRegistry { name, handlers: HashMap::new() }
+    }
+
+    // Replaces the handler registered under the same name, if any.
+    pub fn register<F: Fn(&mut Configmaton<'a, L>, &'a [u8]) + 'a>(&mut self, name: &str, f: F) {
+        self.handlers.insert(name.as_bytes().to_vec(), Box::new(f));
+    }
+
+    // The name and the arguments of the command, None if it has no name.
+    pub fn parse<'c>(&self, command: &'c [u8]) -> Option<(Vec<u8>, &'c [u8])> {
+        match &self.name {
+            CommandName::FirstToken => {
+                let command = command.trim_ascii();
+                let end = command.iter().position(u8::is_ascii_whitespace)
+                    .unwrap_or(command.len());
+                if end == 0 { return None; }
+                Some((command[..end].to_vec(), command[end..].trim_ascii_start()))
+            }
+            CommandName::JsonField(field) => {
+                let Ok(Value::Object(object)) = serde_json::from_slice(command) else {
+                    return None;
+                };
+                match object.get(field) {
+                    Some(Value::String(name)) => Some((name.clone().into_bytes(), command)),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    // Call the handler of the command. Returns false if there is none.
+    pub fn dispatch(&self, configmaton: &mut Configmaton<'a, L>, command: &'a [u8]) -> bool {
+        let Some((name, args)) = self.parse(command) else { return false; };
+        let Some(handler) = self.handlers.get(&name) else { return false; };
+        handler(configmaton, args);
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use crate::blob::tests::compile;
+    use crate::configmaton::CascadeLoop;
+    use crate::onion::ThreadUnsafeLocker;
+
+    use super::*;
+
+    #[test]
+    fn first_token() {
+        let log = RefCell::new(vec![]);
+        let msg = compile(r#"[
+            {"when": {"a": "1"}, "run": ["set b 1", "log  a is one ", "unknown"]},
+            {"when": {"b": "1"}, "run": ["log b is one"]}
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+
+        let mut registry = CommandRegistry::new(CommandName::FirstToken);
+        registry.register("set", |c, args| {
+            let mut args = args.splitn(2, |c| *c == b' ');
+            unsafe { c.set(args.next().unwrap(), args.next().unwrap()) };
+        });
+        registry.register("log", |_, args| log.borrow_mut().push(args));
+
+        assert_eq!(registry.parse(b"  log  x y"), Some((b"log".to_vec(), b"x y".as_ref())));
+        assert_eq!(registry.parse(b"  "), None);
+
+        unsafe { configmaton.set(b"a", b"1") };
+        let mut unhandled = vec![];
+        assert_eq!(configmaton.dispatch_commands(&registry, &mut unhandled), Ok(()));
+        assert_eq!(unhandled, vec![b"unknown".as_ref()]);
+        let mut log = log.take();
+        log.sort();
+        assert_eq!(log, vec![b"a is one".as_ref(), b"b is one"]);
+        assert_eq!(configmaton.get(b"b"), Some(b"1".as_ref()));
+    }
+
+    #[test]
+    fn dispatch_loop() {
+        let msg = compile(r#"[
+            {"when": {"a": "1"}, "run": ["set b 1"]},
+            {"when": {"b": "1"}, "run": ["c1", "c2", "c3", "c4", "c5", "c6"]}
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        let mut registry = CommandRegistry::new(CommandName::FirstToken);
+        registry.register("set", |c, args| {
+            let mut args = args.splitn(2, |c| *c == b' ');
+            unsafe { c.set(args.next().unwrap(), args.next().unwrap()) };
+        });
+
+        configmaton.set_cascade_limit(5);
+        unsafe { configmaton.set(b"a", b"1") };
+        let mut unhandled = vec![];
+        let result = configmaton.dispatch_commands(&registry, &mut unhandled);
+        assert_eq!(result, Err(CascadeLoop::TooDeep { limit: 5 }));
+        assert_eq!(unhandled.len(), 4);
+        assert_eq!(configmaton.get(b"b"), Some(b"1".as_ref()));
+
+        // The rest stays queued.
+        assert_eq!(configmaton.dispatch_commands(&registry, &mut unhandled), Ok(()));
+        assert_eq!(unhandled.len(), 6);
+    }
+
+    #[test]
+    fn json_field() {
+        let registry = CommandRegistry::<ThreadUnsafeLocker>::new(
+            CommandName::JsonField("cmd".to_owned()));
+        let command = br#"{"cmd": "notify", "to": "ops"}"#;
+        assert_eq!(registry.parse(command), Some((b"notify".to_vec(), command.as_ref())));
+        assert_eq!(registry.parse(br#"{"cmd": 1}"#), None);
+        assert_eq!(registry.parse(b"notify ops"), None);
+    }
+}
@@ -1,40 +1,513 @@
-use crate::blob::automaton::Automaton;
-use crate::keyval_simulator::Simulation;
-use crate::onion::{Onion, Locker};
+use std::{cell::RefCell, fmt, rc::Rc, time::SystemTime};
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::blob::automaton::{defaults, Automaton};
+use crate::blob::snapshot::{self, RestoreError, SnapshotContents};
+use crate::commands::CommandRegistry;
+use crate::holder::Handle;
+use crate::intern::KeyId;
+use crate::keyval_simulator::{Progress, Simulation};
+use crate::onion::{FrozenView, Locker, Meta, Onion, ThreadUnsafeLocker};
+#[cfg(feature = "coverage")]
+use crate::recorder::SharedRecorder;
 
 pub struct Configmaton<'a, L: Locker> {
     onion: Onion<'a, L, Self>,
     simulation: Simulation<'a>,
+    observer: Option<SharedObserver<'a>>,
+    subscriptions: Vec<Subscription<'a>>,
+    next_subscription: usize,
+    cascade_limit: usize,
+    // Bookkeeping of the running `set_and_handle`.
+    cascade: Option<Cascade<'a>>,
+    // Which sets of the parent reach the simulation of this child.
+    propagation: Propagation,
+    // The maximum number of transitions taken by the simulation per set, see `set_step_budget`.
+    step_budget: usize,
+    // Of the simulation, whose states are saved by their offsets in it, see `snapshot`.
+    automaton: *const Automaton<'a>,
+}
+
+// The sets of a parent are seen by the onions of all its children, but they are fed to the
+// simulation of a child (and further to its children) only if its policy accepts the key. The keys
+// set by the child itself are never propagated, the child's own value hides the parent's one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Propagation {
+    InheritAll,
+    InheritPrefix(Vec<u8>),
+    // The child reacts only to its own sets, e.g. a scoped session which does not care about the
+    // global updates.
+    Isolated,
+}
+
+impl Propagation {
+    pub fn accepts(&self, key: &[u8]) -> bool {
+        match self {
+            Propagation::InheritAll => true,
+            Propagation::InheritPrefix(prefix) => key.starts_with(prefix),
+            Propagation::Isolated => false,
+        }
+    }
+}
+
+pub const DEFAULT_CASCADE_LIMIT: usize = 1024;
+
+#[derive(Default)]
+struct Cascade<'a> {
+    // The commands emitted by each set of the cascade.
+    emitted: HashMap<KeyVal<'a>, Vec<&'a [u8]>>,
+    repeated: Option<KeyVal<'a>>,
+}
+
+type KeyVal<'a> = (&'a [u8], &'a [u8]);
+
+// Commands handled by `set_and_handle` keep setting keys which fire further commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CascadeLoop<'a> {
+    // More than `limit` commands have been handled.
+    TooDeep { limit: usize },
+    // Setting the key to the value has emitted the same commands for the second time.
+    Repeated { key: &'a [u8], value: &'a [u8], commands: Vec<&'a [u8]> },
+}
+
+impl fmt::Display for CascadeLoop<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CascadeLoop::TooDeep { limit } =>
+                write!(f, "more than {} commands handled in one cascade", limit),
+            CascadeLoop::Repeated { key, value, .. } =>
+                write!(f, "setting {}={} fires the same commands again",
+                    String::from_utf8_lossy(key), String::from_utf8_lossy(value)),
+        }
+    }
+}
+
+impl std::error::Error for CascadeLoop<'_> {}
+
+struct Subscription<'a> {
+    id: SubscriptionId,
+    filter: KeyFilter<'a>,
+    callback: KeyCallback<'a>,
+}
+
+type KeyFilter<'a> = Box<dyn Fn(&[u8]) -> bool + 'a>;
+// Called with the value set, or None for an unset.
+type KeyCallback<'a> = Box<dyn FnMut(&'a [u8], Option<&'a [u8]>) + 'a>;
+
+// A command, the ID of the rule emitting it and the key whose set fired the rule.
+pub type AttributedCommand<'a> = (&'a [u8], usize, Option<&'a [u8]>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(usize);
+
+// Runtime instrumentation hooks, e.g. for logging or metrics. All callbacks default to no-op.
+pub trait Observer<'a> {
+    fn on_set(&mut self, _key: &'a [u8], _value: &'a [u8]) {}
+    fn on_unset(&mut self, _key: &'a [u8]) {}
+    // A set reached a leaf of the automaton, which has the given commands.
+    fn on_rule_fired(&mut self, _commands: &[&'a [u8]]) {}
+    // A command got queued (it was not pending yet).
+    fn on_command_emitted(&mut self, _command: &'a [u8]) {}
+    fn on_child_created(&mut self) {}
+}
+
+// Children share the observer of their parent.
+pub type SharedObserver<'a> = Rc<RefCell<dyn Observer<'a> + 'a>>;
+
+// The estimated bytes held by a configmaton (not by its children), see `memory_usage`. The keys and
+// values are borrowed, so they are counted even if shared, e.g. with the other configmatons.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    // The keys not known to the automaton.
+    pub keys: usize,
+    // The values set, and those kept in the history.
+    pub values: usize,
+    // The table of the onion layer.
+    pub layers: usize,
+    // The current states of the simulation, and its suspended work.
+    pub states: usize,
+    // The queue of the commands, their bytes are a part of the automaton.
+    pub commands: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.keys + self.values + self.layers + self.states + self.commands
+    }
+}
+
+// The setup shared by the configmatons of one automaton, so that `Configmaton::with_handle` need
+// not walk the automaton for each of them: the interned keys of the transitions, the simulation
+// started from the initial states (with the commands of the rules without conditions queued) and
+// the defaults of the config.
+pub struct AutomatonHandle<'a> {
+    automaton: *const Automaton<'a>,
+    // Having read the defaults already.
+    simulation: Simulation<'a>,
+    defaults: Vec<KeyVal<'a>>,
 }
 
+impl<'a> AutomatonHandle<'a> {
+    pub fn new(automaton: &Automaton<'a>) -> Self {
+        let configmaton = Configmaton::<ThreadUnsafeLocker>::new(automaton);
+        AutomatonHandle {
+            automaton,
+            simulation: configmaton.simulation,
+            defaults: unsafe { defaults(automaton) }.collect(),
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
 impl<'a, L: Locker> Configmaton<'a, L> {
     pub fn new(automaton: &Automaton<'a>) -> Self {
+        Self::with_onion(automaton, Onion::new())
+    }
+
+    // Keep at most `capacity` values in each layer, forgetting the least recently read ones,
+    // except for those on which the automaton of the layer or of its descendants currently waits.
+    pub fn with_capacity(automaton: &Automaton<'a>, capacity: usize) -> Self {
+        Self::with_onion(automaton, Onion::with_capacity(capacity))
+    }
+
+    // The defaults of the config are set right away, the commands they fire get queued.
+    fn with_onion(automaton: &Automaton<'a>, onion: Onion<'a, L, Self>) -> Self {
+        let mut configmaton = Self::without_defaults(automaton, onion);
+        for (key, value) in unsafe { defaults(automaton) } {
+            // There are no children yet.
+            unsafe { configmaton.set(key, value) };
+        }
+        configmaton
+    }
+
+    // Like `new`, but the setup is copied from the handle.
+    pub fn with_handle(handle: &AutomatonHandle<'a>) -> Self {
+        let mut onion = Onion::new().with_keys(handle.simulation.keys().clone());
+        for (key, value) in handle.defaults.iter() { onion.set(key, value); }
+        Self::with_simulation(handle.automaton, onion, handle.simulation.clone())
+    }
+
+    fn without_defaults(automaton: &Automaton<'a>, onion: Onion<'a, L, Self>) -> Self {
+        let simulation = Simulation::new(automaton, |_| None);
+        Self::with_simulation(automaton, onion.with_keys(simulation.keys().clone()), simulation)
+    }
+
+    // The onion stores the keys interned by the simulation, see `Onion::with_keys`.
+    fn with_simulation(
+        automaton: *const Automaton<'a>, onion: Onion<'a, L, Self>, simulation: Simulation<'a>,
+    ) -> Self {
         Configmaton {
-            onion: Onion::new(),
-            simulation: Simulation::new(automaton, |_| None),
+            onion,
+            simulation,
+            observer: None,
+            subscriptions: vec![],
+            next_subscription: 0,
+            cascade_limit: DEFAULT_CASCADE_LIMIT,
+            cascade: None,
+            propagation: Propagation::InheritAll,
+            step_budget: usize::MAX,
+            automaton,
         }
     }
 
+    // Save the values and the state of the simulation (including the queued commands), so that
+    // e.g. a restarted process can continue by `restore` with the same automaton. The values of
+    // the parents are saved, too, but the children and the settings (the observer, the limits,
+    // ...) are not.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let contents = SnapshotContents {
+            entries: self.onion.entries(),
+            simulation: self.simulation.state(),
+        };
+        unsafe { snapshot::serialize(&*self.automaton, &contents) }
+    }
+
+    // A configmaton in the state saved by `snapshot`, with the default settings and no parent. The
+    // defaults of the config are not set again. The values and the commands borrow the snapshot.
+    pub fn restore(automaton: &Automaton<'a>, snapshot: &'a [u8]) -> Result<Self, RestoreError> {
+        let contents = unsafe { snapshot::restore(automaton, snapshot)? };
+        let mut configmaton = Self::without_defaults(automaton, Onion::new());
+        for (key, value, meta) in contents.entries {
+            configmaton.onion.set_with_meta(key, value, meta);
+        }
+        unsafe { configmaton.simulation.set_state(contents.simulation) };
+        Ok(configmaton)
+    }
+
+    // Mask the rules of the group (`"group"` in the config). The children created afterwards
+    // inherit the setting.
+    pub fn set_group_enabled(&mut self, group: &[u8], enabled: bool) {
+        self.simulation.set_group_enabled(group, enabled);
+    }
+
+    // The maximum number of commands handled by one `set_and_handle`.
+    pub fn set_cascade_limit(&mut self, limit: usize) {
+        self.cascade_limit = limit;
+    }
+
+    // Bound the work done by the simulation in a single set (or a set propagated from the parent),
+    // so that a pathological automaton cannot stall a latency-sensitive caller. The rest of the
+    // work is done by the next sets or by `poll`, until then the commands of the rules it would
+    // fire are not queued yet. The children created afterwards inherit the setting.
+    pub fn set_step_budget(&mut self, budget: usize) {
+        self.step_budget = budget;
+    }
+
+    // Continue the simulation suspended by the step budget, see `set_step_budget`. Unlike `set`,
+    // it does not poll the children.
+    pub fn poll(&mut self) -> Progress {
+        let queued = self.simulation.exts.len();
+        let budget = self.step_budget;
+        let progress = self.simulation.poll(|key| { self.onion.get(key) }, budget);
+        self.report(queued);
+        progress
+    }
+
+    // Whether nothing is left to do here and in the children: no commands are queued and no work
+    // is suspended by the step budget. E.g. a test or a batch pipeline can check the results then.
+    pub fn is_settled(&self) -> bool {
+        self.simulation.exts.is_empty() && !self.simulation.is_suspended()
+            && self.onion.children().all(|child| child.is_settled())
+    }
+
+    // Call `callback` on each set (with the value) and unset (with None) of a key accepted by
+    // `filter` on this instance. With `replay`, it is first called with the current values of the
+    // accepted keys (in the order of `entries`), so that no update is missed between reading the
+    // state and subscribing.
+    pub fn subscribe<F, C>(&mut self, filter: F, mut callback: C, replay: bool) -> SubscriptionId
+    where
+        F: Fn(&[u8]) -> bool + 'a,
+        C: FnMut(&'a [u8], Option<&'a [u8]>) + 'a,
+    {
+        if replay {
+            for (key, value, _) in self.onion.entries() {
+                if filter(key) { callback(key, Some(value)); }
+            }
+        }
+        let id = SubscriptionId(self.next_subscription);
+        self.next_subscription += 1;
+        self.subscriptions.push(Subscription {
+            id, filter: Box::new(filter), callback: Box::new(callback),
+        });
+        id
+    }
+
+    // Returns false if there is no such subscription.
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        let len = self.subscriptions.len();
+        self.subscriptions.retain(|sub| sub.id != id);
+        self.subscriptions.len() != len
+    }
+
+    // Applies to the children created afterwards, too.
+    pub fn set_observer(&mut self, observer: Option<SharedObserver<'a>>) {
+        self.simulation.record_fired(observer.is_some());
+        self.observer = observer;
+    }
+
+    // Record the coverage of the automaton by the sets, see `CoverageRecorder`. Like the observer,
+    // it applies to the children created afterwards, too.
+    #[cfg(feature = "coverage")]
+    pub fn set_coverage_recorder(&mut self, recorder: Option<SharedRecorder<'a>>) {
+        self.simulation.record_coverage(recorder);
+    }
+
+    // Not sharing anything thread-unsafe with other configmatons: no observer, subscriptions,
+    // children or coverage recorder.
+    pub(crate) fn is_detached(&self) -> bool {
+        self.observer.is_none() && self.subscriptions.is_empty() && !self.onion.has_children()
+            && !self.simulation.is_recording()
+    }
+
+    // The child lives until the handle is dropped, see `Onion::make_child`.
+    //
+    // UNSAFE: make sure you don't use children after the parent is dropped.
+    pub unsafe fn make_child(&mut self) -> Handle<Self> {
+        self.make_child_with(Propagation::InheritAll)
+    }
+
     // UNSAFE: make sure you don't use children after the parent is dropped.
-    pub unsafe fn make_child(&mut self) -> *mut Self {
+    pub unsafe fn make_child_with(&mut self, propagation: Propagation) -> Handle<Self> {
+        if let Some(observer) = &self.observer { observer.borrow_mut().on_child_created(); }
         self.onion.make_child(|onion| Configmaton {
             onion,
             simulation: self.simulation.clone(),
+            observer: self.observer.clone(),
+            subscriptions: vec![],
+            next_subscription: 0,
+            cascade_limit: self.cascade_limit,
+            cascade: None,
+            propagation,
+            step_budget: self.step_budget,
+            automaton: self.automaton,
         })
     }
 
-    // UNSAFE: children's simulation is untouched but the onion gets updated.
+    // UNSAFE: the children get updated, see `Propagation`.
     pub unsafe fn set(&mut self, key: &'a [u8], value: &'a [u8]) {
-        self.onion.set(key, value);
-        self.simulation.read(key, value, |key| { self.onion.get(key) });
+        self.set_with_meta(key, value, Meta::default());
+    }
+
+    // UNSAFE: the children get updated, see `Propagation`.
+    pub unsafe fn set_with_meta(&mut self, key: &'a [u8], value: &'a [u8], meta: Meta<'a>) {
+        self.onion.set_with_meta(key, value, meta);
+        for sub in self.subscriptions.iter_mut() {
+            if (sub.filter)(key) { (sub.callback)(key, Some(value)); }
+        }
+        if let Some(observer) = &self.observer { observer.borrow_mut().on_set(key, value); }
+        let queued = self.simulation.exts.len();
+        self.read(key, value);
+        if let Some(cascade) = &mut self.cascade {
+            let emitted = self.simulation.exts.iter().skip(queued).copied().collect::<Vec<_>>();
+            if !emitted.is_empty() {
+                if cascade.emitted.get(&(key, value)) == Some(&emitted) {
+                    cascade.repeated.get_or_insert((key, value));
+                }
+                cascade.emitted.insert((key, value), emitted);
+            }
+        }
+        self.propagate(key, value);
+    }
+
+    // Remove the value of the key, `get` returns None then (even if a parent has a value, see
+    // `Onion::unset`). The rules fetching the value of the key do not see the old one anymore,
+    // but the rules fired already are not taken back.
+    pub fn unset(&mut self, key: &'a [u8]) {
+        self.onion.unset(key);
+        for sub in self.subscriptions.iter_mut() {
+            if (sub.filter)(key) { (sub.callback)(key, None); }
+        }
+        if let Some(observer) = &self.observer { observer.borrow_mut().on_unset(key); }
+    }
+
+    // Feed a set (of this instance or of a parent) to the simulation.
+    fn read(&mut self, key: &'a [u8], value: &'a [u8]) {
+        let queued = self.simulation.exts.len();
+        let budget = self.step_budget;
+        self.simulation.read_budgeted(key, value, |key| { self.onion.get(key) }, budget);
+        self.report(queued);
+    }
+
+    // Notify the observer about the work of the simulation, evict the values it does not wait on.
+    fn report(&mut self, queued: usize) {
+        if let Some(observer) = &self.observer {
+            let mut observer = observer.borrow_mut();
+            for commands in self.simulation.take_fired() { observer.on_rule_fired(&commands); }
+            for command in self.simulation.exts.iter().skip(queued) {
+                observer.on_command_emitted(command);
+            }
+        }
+        if self.onion.capacity().is_some() {
+            let mut tracked = HashSet::new();
+            self.collect_tracked_keys(&mut tracked);
+            self.onion.evict(|key| tracked.contains(key));
+        }
+    }
+
+    // The keys on which the simulations of this configmaton and of its descendants wait. The
+    // descendants read the values of this one, too.
+    fn collect_tracked_keys(&self, keys: &mut HashSet<&'a [u8]>) {
+        keys.extend(self.simulation.tracked_keys());
+        for child in self.onion.children() { child.collect_tracked_keys(keys); }
+    }
+
+    unsafe fn propagate(&mut self, key: &'a [u8], value: &'a [u8]) {
+        for child in self.onion.iter_children() {
+            let child = &mut *child;
+            if !child.propagation.accepts(key) || child.onion.contains_here(key) { continue; }
+            child.read(key, value);
+            child.propagate(key, value);
+        }
+    }
+
+    // Unchanged by sets without any effect on the simulation, see `Simulation::state_hash`.
+    pub fn state_hash(&self) -> u64 {
+        self.simulation.state_hash()
+    }
+
+    // E.g. for monitoring the memory of the tenants, to evict their values or to drop them.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let (keys, values, layers) = self.onion.memory_usage();
+        let (states, commands) = self.simulation.memory_usage();
+        MemoryUsage { keys, values, layers, states, commands }
     }
 
     pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
         self.onion.get(key)
     }
 
+    pub fn get_with_meta(&self, key: &[u8]) -> Option<(&'a [u8], Meta<'a>)> {
+        self.onion.get_with_meta(key)
+    }
+
+    // Keep the last `depth` values of the keys starting with the prefix, see `Onion::history`. The
+    // children created afterwards inherit the setting.
+    pub fn set_history_depth(&mut self, prefix: &[u8], depth: usize) {
+        self.onion.set_history_depth(prefix, depth);
+    }
+
+    // The kept values of the key, the current one first, see `set_history_depth`.
+    pub fn history(&self, key: &[u8]) -> Vec<(&'a [u8], SystemTime)> {
+        self.onion.history(key)
+    }
+
+    // Whether the last set of the key has changed its kept value.
+    pub fn changed(&self, key: &[u8]) -> bool {
+        self.onion.changed(key)
+    }
+
+    // The ID of a key on which the automaton listens, for the repeated reads by `get_id`.
+    pub fn key_id(&self, key: &[u8]) -> Option<KeyId> {
+        self.onion.keys().id(key)
+    }
+
+    pub fn get_id(&self, id: KeyId) -> Option<&'a [u8]> {
+        self.onion.get_id(id)
+    }
+
+    // The values of the keys, in their order, all as of a single moment, see
+    // `Onion::read_snapshot`.
+    pub fn read_snapshot(&self, keys: &[&[u8]]) -> Vec<Option<&'a [u8]>> {
+        self.onion.read_snapshot(keys)
+    }
+
+    // See `Onion::entries` for the order.
+    pub fn entries(&self) -> Vec<(&'a [u8], &'a [u8], Meta<'a>)> {
+        self.onion.entries()
+    }
+
+    // The visible key-value pairs, each key once, with the value of the innermost layer setting
+    // it. Like `entries`, the pairs are collected first, so the configmaton can be set meanwhile.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        self.onion.entries().into_iter().map(|(key, value, _)| (key, value))
+    }
+
+    // A snapshot of the current values for read-only (possibly concurrent) use.
+    pub fn freeze_view(&self) -> FrozenView<'a> {
+        self.onion.freeze()
+    }
+
     pub fn pop_command(&mut self) -> Option<&'a [u8]> {
-        self.simulation.exts.pop()
+        self.simulation.pop_attributed().map(|(command, _)| command)
+    }
+
+    // Pop the last queued command starting with the prefix, leaving the other ones queued, e.g. for
+    // a handler of a single command family.
+    pub fn pop_command_matching(&mut self, prefix: &[u8]) -> Option<&'a [u8]> {
+        self.simulation.pop_matching_attributed(prefix).map(|(command, _)| command)
+    }
+
+    // Pop all the queued commands (in the order of `pop_command`), e.g. for an audit log. See
+    // `Parser` for the numbering of the rules, the key is None for the commands of the rules
+    // without conditions, emitted on start.
+    pub fn drain_commands_attributed(&mut self) -> Vec<AttributedCommand<'a>> {
+        let mut result = vec![];
+        while let Some((command, emitter)) = self.simulation.pop_attributed() {
+            let emitter = emitter.expect("commands are queued by the simulation");
+            result.push((command, emitter.rule, emitter.key));
+        }
+        result
     }
 
     pub fn handle_commands<F: FnMut(&mut Self, &'a [u8])>(&mut self, f: &mut F) {
@@ -43,12 +516,54 @@ impl<'a, L: Locker> Configmaton<'a, L> {
         }
     }
 
-    // UNSAFE: children's simulation is untouched but the onion gets updated.
+    // Route the queued commands (including those emitted meanwhile by the handlers) to the
+    // handlers of the registry, pushing the commands which have no handler to `unhandled`. Stops
+    // like `set_and_handle` if the handlers seem to loop.
+    pub fn dispatch_commands(
+        &mut self, registry: &CommandRegistry<'a, L>, unhandled: &mut Vec<&'a [u8]>
+    ) -> Result<(), CascadeLoop<'a>> {
+        self.cascade = Some(Cascade::default());
+        self.run_cascade(&mut |configmaton: &mut Self, command| {
+            if !registry.dispatch(configmaton, command) { unhandled.push(command); }
+        })
+    }
+
+    // Set the value and handle the commands, including those fired by the sets done by the
+    // handler. Stops with an error if the cascade seems to loop; the unhandled commands stay
+    // queued then.
+    //
+    // UNSAFE: the children get updated, see `Propagation`.
     pub unsafe fn set_and_handle<F: FnMut(&mut Self, &'a [u8])>
-        (&mut self, key: &'a [u8], value: &'a [u8], f: &mut F)
+        (&mut self, key: &'a [u8], value: &'a [u8], f: &mut F) -> Result<(), CascadeLoop<'a>>
     {
+        self.cascade = Some(Cascade::default());
         self.set(key, value);
-        self.handle_commands(f);
+        self.run_cascade(f)
+    }
+
+    // Handle the queued commands by `f` until none are left or the cascade seems to loop.
+    fn run_cascade<F: FnMut(&mut Self, &'a [u8])>(&mut self, f: &mut F)
+        -> Result<(), CascadeLoop<'a>>
+    {
+        let mut handled = 0;
+        let result = loop {
+            let cascade = self.cascade.as_mut().unwrap();
+            if let Some((key, value)) = cascade.repeated {
+                let commands = cascade.emitted.remove(&(key, value)).unwrap();
+                break Err(CascadeLoop::Repeated { key, value, commands });
+            }
+            let Some((command, emitter)) = self.simulation.pop_attributed() else {
+                break Ok(());
+            };
+            if handled == self.cascade_limit {
+                self.simulation.requeue(command, emitter);
+                break Err(CascadeLoop::TooDeep { limit: self.cascade_limit });
+            }
+            handled += 1;
+            f(self, command);
+        };
+        self.cascade = None;
+        result
     }
 
     // UNSAFE: make sure you don't use the children after calling this method.
@@ -59,7 +574,8 @@ impl<'a, L: Locker> Configmaton<'a, L> {
 
 #[cfg(test)]
 mod tests {
-    use crate::blob::tests::TestU8BuildConfig;
+    use crate::blob::root::BlobError;
+    use crate::blob::tests::{compile, TestU8BuildConfig};
     use crate::keyval_nfa::{Cmd, Msg, Parser};
 
     use crate::onion::ThreadUnsafeLocker;
@@ -80,6 +596,372 @@ mod tests {
         };
     }
 
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl<'a> Observer<'a> for Log {
+        fn on_set(&mut self, key: &'a [u8], value: &'a [u8]) {
+            self.0.push(format!("set {}={}",
+                String::from_utf8_lossy(key), String::from_utf8_lossy(value)));
+        }
+        fn on_rule_fired(&mut self, commands: &[&'a [u8]]) {
+            self.0.push(format!("fired {}", commands.len()));
+        }
+        fn on_command_emitted(&mut self, command: &'a [u8]) {
+            self.0.push(format!("cmd {}", String::from_utf8_lossy(command)));
+        }
+        fn on_child_created(&mut self) {
+            self.0.push("child".to_owned());
+        }
+    }
+
+    #[test]
+    fn observer() {
+        let msg = compile(r#"[
+            { "when": { "foo": "bar" }, "run": [ "m1", "m2" ] },
+            { "when": { "qux": "a.*" }, "run": [ "m3" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+
+        let log = Rc::new(RefCell::new(Log::default()));
+        configmaton.set_observer(Some(log.clone()));
+        unsafe { configmaton.set(b"foo", b"bar") };
+        let mut child = unsafe { configmaton.make_child() };
+        unsafe { child.set(b"qux", b"no") };
+        unsafe { child.set(b"qux", b"ahoy") };
+
+        assert_eq!(log.borrow().0, vec![
+            "set foo=bar", "fired 2", "cmd m1", "cmd m2", "child", "set qux=no", "set qux=ahoy",
+            "fired 1", "cmd m3",
+        ]);
+    }
+
+    #[test]
+    fn unset() {
+        let msg = compile(r#"[
+            { "when": { "a": "1", "b": "1" }, "run": [ "x" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        for order in [[b"a", b"b"], [b"b", b"a"]] {
+            let mut child = unsafe { configmaton.make_child() };
+            unsafe { child.set(order[0], b"1") };
+            child.unset(order[0]);
+            assert_eq!(child.get(order[0]), None);
+            unsafe { child.set(order[1], b"1") };
+            assert_eq!(child.pop_command(), None);
+            unsafe { child.set(order[0], b"1") };
+            assert_eq!(child.pop_command(), Some(b"x".as_ref()));
+        }
+
+        // The tombstone of the child hides the value of the parent, also from the rules.
+        unsafe { configmaton.set(b"a", b"1") };
+        let mut child = unsafe { configmaton.make_child() };
+        child.unset(b"a");
+        assert_eq!(child.get(b"a"), None);
+        assert!(child.entries().is_empty());
+        unsafe { child.set(b"b", b"1") };
+        assert_eq!(child.pop_command(), None);
+        unsafe { configmaton.set(b"a", b"2") };
+        assert_eq!(child.get(b"a"), None);
+        let id = configmaton.key_id(b"a").unwrap();
+        assert_eq!((configmaton.get_id(id), child.get_id(id)), (Some(b"2".as_ref()), None));
+        assert_eq!(configmaton.key_id(b"c"), None);
+
+        configmaton.unset(b"a");
+        assert_eq!(configmaton.get(b"a"), None);
+        assert!(configmaton.entries().is_empty());
+    }
+
+    #[test]
+    fn iter() {
+        let msg = compile("[]");
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        unsafe { configmaton.set(b"a", b"1") };
+        unsafe { configmaton.set(b"b", b"1") };
+        unsafe { configmaton.set(b"c", b"1") };
+        let mut child = unsafe { configmaton.make_child() };
+        unsafe { child.set(b"d", b"2") };
+        unsafe { child.set(b"a", b"2") };
+        child.unset(b"b");
+
+        let pairs = child.iter().collect::<Vec<_>>();
+        assert_eq!(pairs, vec![
+            (b"c".as_ref(), b"1".as_ref()), (b"d", b"2"), (b"a", b"2"),
+        ]);
+        assert_eq!(configmaton.iter().count(), 3);
+    }
+
+    #[test]
+    fn normalize() {
+        let msg = compile(r#"[
+            { "normalize": { "mode": [ "trim", "strip_quotes" ] } },
+            { "normalize": { "mode": [ "lowercase" ] } },
+            { "when": { "mode": "on" }, "run": [ "m" ] },
+            { "when": { "mode": "on", "user": "Bob" }, "run": [ "u" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        unsafe { configmaton.set(b"mode", b" \"ON\" ") };
+        assert_eq!(configmaton.get(b"mode"), Some(b" \"ON\" ".as_ref()));
+        assert_eq!(configmaton.pop_command(), Some(b"m".as_ref()));
+
+        // The old values fetched by the rules are normalized, too; other keys are not.
+        unsafe { configmaton.set(b"user", b"bob") };
+        assert_eq!(configmaton.pop_command(), None);
+        unsafe { configmaton.set(b"user", b"Bob") };
+        assert_eq!(configmaton.pop_command(), Some(b"u".as_ref()));
+    }
+
+    #[test]
+    fn match_mode() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            { "when": { "path": "usr" }, "match_mode": "substring", "run": [ "any" ] },
+            { "when": { "path": "^/usr|bin$" }, "match_mode": "substring", "run": [ "anchored" ] },
+            { "when": { "path": "^/usr/bin$" }, "run": [ "full" ] }
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let commands = |value: &'static [u8]| {
+            let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+            unsafe { configmaton.set(b"path", value) };
+            let mut commands = std::iter::from_fn(|| configmaton.pop_command().map(<[u8]>::to_vec))
+                .collect::<Vec<_>>();
+            commands.sort();
+            commands
+        };
+        assert_eq!(commands(b"/usr/bin"), vec![b"anchored".to_vec(), b"any".to_vec(),
+            b"full".to_vec()]);
+        assert_eq!(commands(b"/usr/local"), vec![b"anchored".to_vec(), b"any".to_vec()]);
+        assert_eq!(commands(b"/opt/usr/share"), vec![b"any".to_vec()]);
+        assert_eq!(commands(b"/opt/bin/x"), Vec::<Vec<u8>>::new());
+        let patterns = parser.patterns.iter().map(|(_, _, source)| source.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(patterns, vec!["usr", "^/usr|bin$", "^/usr/bin$"]);
+
+        let result = serde_json::from_str::<Vec<Cmd>>(
+            r#"[{ "when": { "a": "b" }, "match_mode": "prefix" }]"#);
+        assert!(result.unwrap_err().to_string().contains("unknown variant `prefix`"));
+    }
+
+    #[test]
+    fn ignore_case() {
+        let msg = compile(r#"[
+            { "when": { "level": "(?i)warn|error" }, "run": [ "alert" ] },
+            { "when": { "level": "debug", "user": "root" }, "ignore_case": true, "run": [ "d" ] }
+        ]"#);
+        let commands = |level: &'static [u8], user: &'static [u8]| {
+            let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+            unsafe { configmaton.set(b"user", user) };
+            unsafe { configmaton.set(b"level", level) };
+            std::iter::from_fn(|| configmaton.pop_command()).collect::<Vec<_>>()
+        };
+        assert_eq!(commands(b"WARN", b""), vec![b"alert"]);
+        assert_eq!(commands(b"Error", b""), vec![b"alert"]);
+        assert_eq!(commands(b"warning", b""), Vec::<&[u8]>::new());
+        assert_eq!(commands(b"DeBuG", b"Root"), vec![b"d"]);
+        assert_eq!(commands(b"DeBuG", b"rooted"), Vec::<&[u8]>::new());
+    }
+
+    #[test]
+    fn when_not() {
+        let msg = compile(r#"[
+            { "when": { "foo": "a" }, "when_not": { "bar": "b.*" }, "run": [ "x" ] },
+            { "when_not": { "env": "prod" }, "ignore_case": true, "run": [ "dev" ] }
+        ]"#);
+        let negated = unsafe { crate::blob::automaton::tag_rules(msg.get_automaton()) }.iter()
+            .map(|x| (x.rule, x.negated)).collect::<Vec<_>>();
+        assert_eq!(negated, vec![(0, 0), (0, 1), (1, 1)]);
+        let commands = |sets: &[(&'static [u8], &'static [u8])]| {
+            let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+            for (key, value) in sets { unsafe { configmaton.set(key, value) }; }
+            std::iter::from_fn(|| configmaton.pop_command()).collect::<Vec<_>>()
+        };
+        assert_eq!(commands(&[(b"foo", b"a"), (b"bar", b"c")]), vec![b"x"]);
+        assert_eq!(commands(&[(b"bar", b"b1"), (b"foo", b"a")]), Vec::<&[u8]>::new());
+        assert_eq!(commands(&[(b"bar", b"b1"), (b"foo", b"a"), (b"bar", b"c")]), vec![b"x"]);
+        // A key without a value satisfies no guard.
+        assert_eq!(commands(&[(b"foo", b"a")]), Vec::<&[u8]>::new());
+        assert_eq!(commands(&[(b"env", b"PROD")]), Vec::<&[u8]>::new());
+        assert_eq!(commands(&[(b"env", b"test")]), vec![b"dev"]);
+
+        let error = |config| serde_json::from_str::<Vec<Cmd>>(config).unwrap_err().to_string();
+        assert!(error(r#"[{ "when_not": "a" }]"#).contains("match is not an object"));
+        assert!(error(r#"[{ "run": [] }]"#).contains("missing field `when`"));
+    }
+
+    #[test]
+    fn num_range() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            { "when": { "port": { "range": [1024, 65535] }, "host": "a.*" }, "run": [ "user" ] },
+            { "when_not": { "port": { "range": [null, 1023] } }, "run": [ "unprivileged" ] },
+            { "normalize": { "port": [ "trim" ] } }
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let commands = |sets: &[(&'static [u8], &'static [u8])]| {
+            let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+            for (key, value) in sets { unsafe { configmaton.set(key, value) }; }
+            let mut commands = std::iter::from_fn(|| configmaton.pop_command()).collect::<Vec<_>>();
+            commands.sort();
+            commands
+        };
+        let both: Vec<&[u8]> = vec![b"unprivileged", b"user"];
+        assert_eq!(commands(&[(b"host", b"ab"), (b"port", b" 8080 ")]), both);
+        assert_eq!(commands(&[(b"port", b"1024.0"), (b"host", b"a")]), both);
+        assert_eq!(commands(&[(b"host", b"a"), (b"port", b"80")]), Vec::<&[u8]>::new());
+        assert_eq!(commands(&[(b"host", b"b"), (b"port", b"65535")]), vec![b"unprivileged"]);
+        // A value which is not a number is in no range.
+        assert_eq!(commands(&[(b"host", b"a"), (b"port", b"http")]), vec![b"unprivileged"]);
+        assert_eq!(commands(&[(b"port", b"80"), (b"port", b"1e5")]), vec![b"unprivileged"]);
+
+        let patterns = parser.patterns.iter().map(|(_, _, source)| source.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(patterns, vec![r#"{"range":[1024,65535]}"#, "a.*", r#"{"range":[null,1023]}"#]);
+
+        let error = |config| serde_json::from_str::<Vec<Cmd>>(config).unwrap_err().to_string();
+        assert!(error(r#"[{ "when": { "a": { "range": [2, 1] } } }]"#).contains("range is empty"));
+        assert!(error(r#"[{ "when": { "a": 1 } }]"#).contains("a string (regex) or a range"));
+    }
+
+    #[test]
+    fn subscribe() {
+        let msg = compile("[]");
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        unsafe { configmaton.set(b"a.x", b"1") };
+        unsafe { configmaton.set(b"b", b"2") };
+
+        let seen = Rc::new(RefCell::new(vec![]));
+        let seen2 = seen.clone();
+        let id = configmaton.subscribe(|key| key.starts_with(b"a."),
+            move |key, value| seen2.borrow_mut().push((key, value)), true);
+        assert_eq!(*seen.borrow(), vec![(b"a.x".as_ref(), Some(b"1".as_ref()))]);
+
+        unsafe { configmaton.set(b"a.y", b"3") };
+        unsafe { configmaton.set(b"b", b"4") };
+        assert_eq!(seen.borrow().len(), 2);
+        assert_eq!(seen.borrow()[1], (b"a.y".as_ref(), Some(b"3".as_ref())));
+
+        // The unsets are notified, too.
+        configmaton.unset(b"a.x");
+        configmaton.unset(b"b");
+        assert_eq!(seen.borrow().len(), 3);
+        assert_eq!(seen.borrow()[2], (b"a.x".as_ref(), None));
+
+        assert!(configmaton.unsubscribe(id));
+        assert!(!configmaton.unsubscribe(id));
+        unsafe { configmaton.set(b"a.z", b"5") };
+        configmaton.unset(b"a.y");
+        assert_eq!(seen.borrow().len(), 3);
+    }
+
+    #[test]
+    fn cascade_loop() {
+        let msg = compile(r#"[
+            { "when": { "a": "1" }, "run": [ "set b" ] },
+            { "when": { "b": "1" }, "run": [ "c1", "c2", "c3", "c4", "c5", "c6" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        configmaton.set_cascade_limit(5);
+
+        let mut handled = vec![];
+        let result = unsafe { configmaton.set_and_handle(b"a", b"1", &mut |c, command| {
+            if command == b"set b" { c.set(b"b", b"1"); }
+            handled.push(command);
+        }) };
+        assert_eq!(result, Err(CascadeLoop::TooDeep { limit: 5 }));
+        assert_eq!(handled.len(), 5);
+
+        let mut rest = vec![];
+        while let Some(command) = configmaton.pop_command() { rest.push(command); }
+        assert_eq!(rest.len(), 2);
+    }
+
+    #[test]
+    fn groups() {
+        let msg = compile(r#"[
+            { "when": { "a": "1" }, "run": [ "x" ], "group": "exp", "then": [
+                { "when": { "b": "1" }, "run": [ "x2" ] }
+            ] },
+            { "when": { "a": "1" }, "run": [ "y" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        fn commands<'a>(c: &mut Configmaton<'a, ThreadUnsafeLocker>) -> Vec<&'a [u8]> {
+            let mut result = vec![];
+            while let Some(command) = c.pop_command() { result.push(command); }
+            result.sort();
+            result
+        }
+
+        configmaton.set_group_enabled(b"exp", false);
+        let mut child = unsafe { configmaton.make_child() };
+        configmaton.set_group_enabled(b"exp", true);
+        unsafe { child.set(b"a", b"1") };
+        unsafe { child.set(b"b", b"1") };
+        assert_eq!(commands(&mut child), vec![b"y"]);
+
+        unsafe { configmaton.set(b"a", b"1") };
+        assert_eq!(commands(&mut configmaton), vec![b"x", b"y"]);
+        configmaton.set_group_enabled(b"exp", false);
+        unsafe { configmaton.set(b"b", b"1") };
+        assert!(commands(&mut configmaton).is_empty());
+    }
+
+    #[test]
+    fn propagation() {
+        let msg = compile(r#"[
+            { "when": { "global.mode": "on", "session.user": "root" }, "run": [ "x" ] },
+            { "when": { "news": "1" }, "run": [ "y" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        let mut all = unsafe { configmaton.make_child() };
+        let mut grandchild = unsafe { all.make_child() };
+        let mut prefixed = unsafe {
+            configmaton.make_child_with(Propagation::InheritPrefix(b"global.".to_vec()))
+        };
+        let mut isolated = unsafe { configmaton.make_child_with(Propagation::Isolated) };
+        for child in [&mut *all, &mut *prefixed, &mut *isolated] {
+            unsafe { child.set(b"session.user", b"root") };
+        }
+        unsafe { grandchild.set(b"session.user", b"root") };
+        unsafe { all.set(b"news", b"0") };
+
+        unsafe { configmaton.set(b"news", b"1") };
+        unsafe { configmaton.set(b"global.mode", b"on") };
+        assert_eq!(configmaton.pop_command(), Some(b"y".as_ref()));
+        assert_eq!(configmaton.pop_command(), None);
+        // The child hides the parent's news by its own value, for the grandchild, too.
+        assert_eq!(all.pop_command(), Some(b"x".as_ref()));
+        assert_eq!(all.pop_command(), None);
+        assert_eq!(grandchild.pop_command(), Some(b"x".as_ref()));
+        assert_eq!(grandchild.pop_command(), None);
+        assert_eq!(prefixed.pop_command(), Some(b"x".as_ref()));
+        assert_eq!(prefixed.pop_command(), None);
+        assert_eq!(isolated.pop_command(), None);
+        // The onion still shows the parent's values.
+        assert_eq!(isolated.get(b"global.mode"), Some(b"on".as_ref()));
+    }
+
+    #[test]
+    fn capacity_pins_children_keys() {
+        let msg = compile(r#"[
+            { "when": { "a": "1" }, "run": [ "x" ], "then": [
+                { "when": { "b": "1" }, "run": [ "y" ] }
+            ] }
+        ]"#);
+        let mut configmaton =
+            Configmaton::<ThreadUnsafeLocker>::with_capacity(msg.get_automaton(), 1);
+        let mut child = unsafe { configmaton.make_child_with(Propagation::Isolated) };
+        // Only the child waits on b.
+        unsafe { child.set(b"a", b"1") };
+        unsafe { configmaton.set(b"b", b"1") };
+        unsafe { configmaton.set(b"c", b"1") };
+        assert_eq!(configmaton.get(b"b"), Some(b"1".as_ref()));
+        assert_eq!(configmaton.get(b"c"), None);
+    }
+
     #[test]
     fn it_works() {
         // read and parse file tests/config.json
@@ -111,45 +993,287 @@ mod tests {
 
         // The output automaton is for now only for visual checking.
         let file = std::fs::File::create("/tmp/test_configmaton.dot").unwrap();
-        parser.to_dot(&init, std::io::BufWriter::new(file));
+        parser.to_dot(&init, std::io::BufWriter::new(file)).unwrap();
 
-        let outmsg = Msg::serialize(parser, init, &TestU8BuildConfig);
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
         let inmsg = unsafe {
             Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
         let aut = inmsg.get_automaton();
         let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(aut);
 
         let mut cmds: Vec<&[u8]> = Vec::new();
-        unsafe { configmaton.set_and_handle(b"qux", b"no!", &mut handle!(cmds, b"arrgh")) };
+        unsafe { configmaton.set_and_handle(b"qux", b"no!", &mut handle!(cmds, b"arrgh")) }
+            .unwrap();
         assert!(cmds.is_empty());
 
         {
-            let configmaton2 = unsafe { &mut *configmaton.make_child() };
-            let configmaton3 = unsafe { &mut *configmaton.make_child() };
-            let configmaton4 = unsafe { &mut *configmaton.make_child() };
-
-            unsafe { configmaton2.set_and_handle(b"foo", b"bar", &mut handle!(cmds, b"arrgh")) };
-            assert!(cmds.drain(..).collect::<Vec<_>>().is_empty());
-
-            unsafe { configmaton3.set_and_handle(b"foo", b"baz", &mut handle!(cmds, b"arrgh")) };
-            assert_eq!(cmds.drain(..).collect::<Vec<_>>(), vec![b"m2", b"m3"]);
-
-            unsafe { configmaton2.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) };
-            assert_eq!(cmds.drain(..).collect::<Vec<_>>(), vec![b"m1"]);
-            unsafe { configmaton2.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) };
-            assert!(cmds.drain(..).collect::<Vec<_>>().is_empty());
-
-            unsafe { configmaton3.set_and_handle(b"qux", b"arrgh", &mut handle!(cmds, b"arrgh")) };
-            assert!(cmds.drain(..).collect::<Vec<_>>().is_empty());
-            unsafe { configmaton3.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) };
-            assert_eq!(cmds.drain(..).collect::<Vec<_>>(), vec![b"m4"]);
-
-            unsafe { configmaton4.set_and_handle(b"foo", b"baz", &mut handle!(cmds, b"")) };
-            assert_eq!(cmds.drain(..).collect::<Vec<_>>(), vec![b"m2"]);
-            unsafe { configmaton4.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) };
-            let mut cmds_now = cmds.drain(..).collect::<Vec<_>>();
+            let mut configmaton2 = unsafe { configmaton.make_child() };
+            let mut configmaton3 = unsafe { configmaton.make_child() };
+            let mut configmaton4 = unsafe { configmaton.make_child() };
+
+            unsafe { configmaton2.set_and_handle(b"foo", b"bar", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            assert!(std::mem::take(&mut cmds).is_empty());
+
+            unsafe { configmaton3.set_and_handle(b"foo", b"baz", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            assert_eq!(std::mem::take(&mut cmds), vec![b"m2", b"m3"]);
+
+            unsafe { configmaton2.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            assert_eq!(std::mem::take(&mut cmds), vec![b"m1"]);
+            unsafe { configmaton2.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            assert!(std::mem::take(&mut cmds).is_empty());
+
+            unsafe { configmaton3.set_and_handle(b"qux", b"arrgh", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            assert!(std::mem::take(&mut cmds).is_empty());
+            unsafe { configmaton3.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            assert_eq!(std::mem::take(&mut cmds), vec![b"m4"]);
+
+            unsafe { configmaton4.set_and_handle(b"foo", b"baz", &mut handle!(cmds, b"")) }
+                .unwrap();
+            assert_eq!(std::mem::take(&mut cmds), vec![b"m2"]);
+            unsafe { configmaton4.set_and_handle(b"qux", b"ahoy", &mut handle!(cmds, b"arrgh")) }
+                .unwrap();
+            let mut cmds_now = std::mem::take(&mut cmds);
             cmds_now.sort();
             assert_eq!(cmds_now, vec![b"m3", b"m4"]);
         }
     }
+
+    #[test]
+    fn attributed() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            { "when": {}, "run": [ "boot" ] },
+            { "when": { "foo": "bar", "baz": "x" }, "run": [ "m1" ], "then": [
+                { "when": {}, "run": [ "m2" ] },
+                { "when": { "qux": "a.*" }, "run": [ "m1", "m3" ] }
+            ] }
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        assert_eq!(parser.rule_count, 4);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        assert_eq!(configmaton.drain_commands_attributed(), vec![(b"boot".as_ref(), 0, None)]);
+
+        unsafe { configmaton.set(b"baz", b"x") };
+        unsafe { configmaton.set(b"foo", b"bar") };
+        let mut commands = configmaton.drain_commands_attributed();
+        commands.sort();
+        assert_eq!(commands, vec![
+            (b"m1".as_ref(), 1, Some(b"foo".as_ref())),
+            (b"m2", 2, Some(b"foo")),
+        ]);
+
+        unsafe { configmaton.set(b"qux", b"ahoy") };
+        unsafe { configmaton.set(b"qux", b"ahoy") };
+        let mut commands = configmaton.drain_commands_attributed();
+        commands.sort();
+        assert_eq!(commands, vec![
+            (b"m1".as_ref(), 3, Some(b"qux".as_ref())),
+            (b"m3", 3, Some(b"qux")),
+        ]);
+        assert_eq!(configmaton.pop_command(), None);
+    }
+
+    #[test]
+    fn defaults() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            { "defaults": { "foo": "bar", "mode": "slow" } },
+            { "when": { "foo": "bar", "mode": "fast" }, "run": [ "m1" ] },
+            { "profiles": { "prod": [ { "defaults": { "mode": "fast" } } ] } }
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse_profile(config, Some("prod"));
+        assert_eq!(parser.defaults.len(), 3);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        assert_eq!(configmaton.get(b"foo"), Some(b"bar".as_ref()));
+        assert_eq!(configmaton.get(b"mode"), Some(b"fast".as_ref()));
+        assert_eq!(configmaton.pop_command(), Some(b"m1".as_ref()));
+
+        let config = r#"[{ "defaults": {}, "when": {} }]"#;
+        assert!(serde_json::from_str::<Vec<Cmd>>(config).is_err());
+        let config = r#"[{ "when": { "a": "1" }, "then": [ { "defaults": { "foo": "x" } } ] }]"#;
+        let err = serde_json::from_str::<Vec<Cmd>>(config).unwrap_err();
+        assert!(err.to_string().contains("defaults cannot be nested in a match"), "{}", err);
+    }
+
+    #[test]
+    fn pop_command_matching() {
+        let msg = compile(r#"[
+            { "when": { "a": "1" }, "run": [ "log x", "set b", "log y", "", "l" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        unsafe { configmaton.set(b"a", b"1") };
+        assert_eq!(configmaton.pop_command_matching(b"log "), Some(b"log y".as_ref()));
+        assert_eq!(configmaton.pop_command_matching(b"log "), Some(b"log x".as_ref()));
+        assert_eq!(configmaton.pop_command_matching(b"log "), None);
+        assert_eq!(configmaton.pop_command_matching(b"x"), None);
+        assert_eq!(configmaton.pop_command(), Some(b"l".as_ref()));
+        assert_eq!(configmaton.pop_command_matching(b""), Some(b"".as_ref()));
+        assert_eq!(configmaton.pop_command_matching(b"s"), Some(b"set b".as_ref()));
+        assert_eq!(configmaton.pop_command(), None);
+    }
+
+    #[test]
+    fn step_budget() {
+        let msg = compile(r#"[
+            { "when": { "a": "1", "b": "1" }, "run": [ "m1" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        configmaton.set_step_budget(1);
+        unsafe { configmaton.set(b"b", b"1") };
+        unsafe { configmaton.set(b"a", b"1") };
+        assert_eq!(configmaton.pop_command(), None);
+        while configmaton.poll() == Progress::Suspended {}
+        assert_eq!(configmaton.pop_command(), Some(b"m1".as_ref()));
+        assert_eq!(configmaton.poll(), Progress::Done);
+    }
+
+    #[test]
+    fn settled() {
+        let msg = compile(r#"[
+            { "when": { "a": "1", "b": "1" }, "run": [ "m1" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        assert!(configmaton.is_settled());
+        unsafe { configmaton.set(b"a", b"1") };
+        assert!(configmaton.is_settled());
+
+        let mut child = unsafe { configmaton.make_child() };
+        unsafe { child.set(b"b", b"1") };
+        assert!(!configmaton.is_settled());
+        assert_eq!(child.pop_command(), Some(b"m1".as_ref()));
+        assert!(configmaton.is_settled());
+
+        let mut child2 = unsafe { configmaton.make_child() };
+        child2.set_step_budget(1);
+        unsafe { child2.set(b"b", b"1") };
+        unsafe { child2.set(b"b", b"2") };
+        assert!(!configmaton.is_settled());
+        while child2.poll() == Progress::Suspended {}
+        assert_eq!(child2.pop_command(), Some(b"m1".as_ref()));
+        assert!(child2.is_settled());
+
+        // The dropped children do not count.
+        let mut child3 = unsafe { configmaton.make_child() };
+        unsafe { child3.set(b"b", b"1") };
+        assert!(!configmaton.is_settled());
+        drop(child3);
+        assert!(configmaton.is_settled());
+    }
+
+    #[test]
+    fn automaton_handle() {
+        let msg = compile(r#"[
+            { "defaults": { "foo": "bar" } },
+            { "when": {}, "run": [ "boot" ] },
+            { "when": { "foo": "bar" }, "run": [ "m1" ] },
+            { "when": { "a": "1" }, "then": [ { "when": { "b": "1" }, "run": [ "m2" ] } ] }
+        ]"#);
+        let handle = AutomatonHandle::new(msg.get_automaton());
+        let mut fresh = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        let mut first = Configmaton::<ThreadUnsafeLocker>::with_handle(&handle);
+        assert_eq!(first.state_hash(), fresh.state_hash());
+        assert_eq!(first.get(b"foo"), Some(b"bar".as_ref()));
+        let drain = |x: &mut Configmaton<ThreadUnsafeLocker>| {
+            x.drain_commands_attributed().into_iter().map(|x| x.0.to_vec()).collect::<Vec<_>>()
+        };
+        assert_eq!(drain(&mut first), drain(&mut fresh));
+
+        // The instances do not share their state.
+        unsafe { first.set(b"a", b"1") };
+        let mut second = Configmaton::<ThreadUnsafeLocker>::with_handle(&handle);
+        unsafe { second.set(b"b", b"1") };
+        assert_eq!(drain(&mut second), vec![b"m1".to_vec(), b"boot".to_vec()]);
+        unsafe { first.set(b"b", b"1") };
+        assert_eq!(first.pop_command(), Some(b"m2".as_ref()));
+        assert!(first.is_settled());
+    }
+
+    #[test]
+    fn snapshot() {
+        let msg = compile(r#"[
+            { "defaults": { "foo": "bar" } },
+            { "when": { "foo": "bar" }, "run": [ "boot" ] },
+            { "when": { "a": "1" }, "then": [ { "when": { "b": "1" }, "run": [ "m1" ] } ] },
+            { "when": { "c": "1" }, "run": [ "m2" ], "group": "exp" }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        unsafe { configmaton.set_with_meta(b"a", b"1", Meta::now().with_source(b"cli")) };
+        configmaton.set_group_enabled(b"exp", false);
+        let bytes = configmaton.snapshot();
+
+        let mut restored =
+            Configmaton::<ThreadUnsafeLocker>::restore(msg.get_automaton(), &bytes).unwrap();
+        assert_eq!(restored.entries(), configmaton.entries());
+        assert_eq!(restored.state_hash(), configmaton.state_hash());
+        unsafe { restored.set(b"c", b"1") };
+        unsafe { restored.set(b"b", b"1") };
+        assert_eq!(restored.pop_command(), Some(b"m1".as_ref()));
+        let (command, rule, key) = restored.drain_commands_attributed().pop().unwrap();
+        assert_eq!((command, rule, key), (b"boot".as_ref(), 0, Some(b"foo".as_ref())));
+        assert_eq!(configmaton.pop_command(), Some(b"boot".as_ref()));
+
+        let other = compile("[]");
+        let restore = |automaton, bytes| {
+            Configmaton::<ThreadUnsafeLocker>::restore(automaton, bytes).err().unwrap()
+        };
+        assert_eq!(restore(other.get_automaton(), &bytes), RestoreError::Automaton);
+
+        // Of the same shape, only the regexes differ.
+        let a = compile(r#"[{"when": {"foo": "a"}}]"#);
+        let b = compile(r#"[{"when": {"foo": "b"}}]"#);
+        let bytes_a = Configmaton::<ThreadUnsafeLocker>::new(a.get_automaton()).snapshot();
+        assert!(Configmaton::<ThreadUnsafeLocker>::restore(a.get_automaton(), &bytes_a).is_ok());
+        assert_eq!(restore(b.get_automaton(), &bytes_a), RestoreError::Automaton);
+
+        assert!(matches!(restore(msg.get_automaton(), &bytes[..bytes.len() - 1]),
+            RestoreError::Blob(BlobError::Truncated { .. })));
+        // The length of the last vector.
+        let mut broken = bytes.clone();
+        let last = broken.len() - size_of::<u64>();
+        broken[last..].copy_from_slice(&u64::MAX.to_ne_bytes());
+        assert!(matches!(restore(msg.get_automaton(), &broken),
+            RestoreError::Blob(BlobError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn memory_usage() {
+        let msg = compile(r#"[
+            { "when": { "a": "1" }, "run": [ "m1" ] }
+        ]"#);
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(msg.get_automaton());
+        let empty = configmaton.memory_usage();
+        assert_eq!((empty.keys, empty.values), (0, 0));
+        assert!(empty.states > 0);
+
+        configmaton.set_history_depth(b"x", 3);
+        unsafe { configmaton.set(b"a", b"1") };
+        unsafe { configmaton.set(b"xy", b"12345") };
+        unsafe { configmaton.set(b"xy", b"123") };
+        let usage = configmaton.memory_usage();
+        // The interned key "a" is not counted, "xy" keeps both of its values.
+        assert_eq!((usage.keys, usage.values), (2, 1 + 3 + 5));
+        assert!(usage.layers > empty.layers && usage.commands > empty.commands);
+        assert!(usage.total() > empty.total());
+
+        // The unset key and its kept values are dropped, the table keeps its capacity.
+        configmaton.unset(b"xy");
+        let unset = configmaton.memory_usage();
+        assert_eq!((unset.keys, unset.values), (0, 1));
+        assert!(unset.total() < usage.total());
+        unsafe { configmaton.set(b"xz", b"1234") };
+        assert!(configmaton.memory_usage().total() > unset.total());
+
+        let child = unsafe { configmaton.make_child() };
+        assert_eq!(child.memory_usage().values, 0);
+    }
 }
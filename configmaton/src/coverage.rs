@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+
+use crate::blob::state::build::U8BuildConfig;
+use crate::blob::state::{U8StateKind, U8StatePrepared};
+use crate::guards::Guard;
+use crate::keyval_nfa::Parser;
+
+// Which bytes each U8 state of a compiled config can read, to spot patterns that accidentally fail
+// on e.g. uppercase or non-ASCII bytes. The states are those of the parser's NFA, in its order and
+// prepared as they would be stored in the blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateCoverage {
+    pub state: usize,
+    pub kind: U8StateKind,
+    // The tags of the patterns accepted in the state.
+    pub tags: Vec<usize>,
+    // The regexes whose automaton contains the state, sorted.
+    pub patterns: Vec<String>,
+    pub covered: Guard,
+    // The bytes on which the state has no successor, i.e. the complement of `covered`.
+    pub dead_ends: Guard,
+    pub can_end: bool,
+}
+
+impl StateCoverage {
+    pub fn covered_ranges(&self) -> Vec<(u8, u8)> {
+        self.covered.ranges()
+    }
+
+    pub fn dead_end_ranges(&self) -> Vec<(u8, u8)> {
+        self.dead_ends.ranges()
+    }
+
+    // The bytes of `class` (e.g. `Guard::from_range((b'A', b'Z'))`) on which the state dead-ends.
+    pub fn dead_ends_in(&self, class: &Guard) -> Guard {
+        self.dead_ends.intersection(class)
+    }
+}
+
+pub fn coverage<Cfg: U8BuildConfig>(parser: &Parser, cfg: &Cfg) -> Vec<StateCoverage> {
+    let states = &parser.nfa.states;
+    let mut patterns = vec![vec![]; states.len()];
+    for (regex, (init, _)) in parser.regexes.iter() {
+        let mut visited = vec![false; states.len()];
+        let mut queue = VecDeque::from([init.0]);
+        visited[init.0] = true;
+        while let Some(q) = queue.pop_front() {
+            patterns[q].push(regex.clone());
+            for (_, suc) in states[q].transitions.iter() {
+                if !visited[*suc] {
+                    visited[*suc] = true;
+                    queue.push_back(*suc);
+                }
+            }
+        }
+    }
+
+    states.iter().zip(patterns).enumerate().map(|(ix, (state, mut patterns))| {
+        let prepared = U8StatePrepared::prepare(state, cfg);
+        let covered = prepared.covered();
+        patterns.sort();
+        StateCoverage {
+            state: ix,
+            kind: prepared.kind(),
+            tags: prepared.tags().to_vec(),
+            patterns,
+            covered,
+            dead_ends: Guard::full().subtract(&covered),
+            can_end: prepared.can_end(),
+        }
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::tests::TestU8BuildConfig;
+    use crate::keyval_nfa::Cmd;
+
+    use super::*;
+
+    #[test]
+    fn uppercase() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {"name": "[a-z]+", "os": "linux|mac"}, "run": ["m1"]}
+        ]"#).unwrap();
+        let (parser, _) = Parser::parse(config);
+        let report = coverage(&parser, &TestU8BuildConfig);
+        assert_eq!(report.len(), parser.nfa.states.len());
+
+        let name = report.iter().find(|q| q.patterns == vec!["[a-z]+".to_owned()]).unwrap();
+        let upper = Guard::from_range((b'A', b'Z'));
+        assert_eq!(name.dead_ends_in(&upper), upper);
+        assert_eq!(name.covered_ranges(), vec![(b'a', b'z')]);
+        assert_eq!(name.dead_end_ranges(), vec![(0, b'a' - 1), (b'z' + 1, 255)]);
+
+        let os = report.iter().filter(|q| q.patterns == vec!["linux|mac".to_owned()]);
+        assert!(os.clone().all(|q| q.kind == U8StateKind::Sparse && q.covered.size() <= 2));
+        assert!(os.clone().any(|q| q.covered == Guard::from_ranges(vec![(b'l', b'm')])));
+        assert!(os.clone().any(|q| q.covered.is_empty() && !q.tags.is_empty()));
+    }
+
+    // Dense (no ranges allowed) or ranged states.
+    struct DenseConfig(usize);
+    impl U8BuildConfig for DenseConfig {
+        fn guard_size_keep(&self) -> u32 { 2 }
+        fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+        fn dense_guard_count(&self) -> usize { 0 }
+        fn max_ranged_ranges(&self) -> usize { self.0 }
+    }
+
+    #[test]
+    fn kinds() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {"a": "[0-9a-f]+x|é"}, "run": ["m1"]}
+        ]"#).unwrap();
+        let (parser, _) = Parser::parse(config);
+        let sparse = coverage(&parser, &TestU8BuildConfig);
+        let dense = coverage(&parser, &DenseConfig(0));
+        let ranged = coverage(&parser, &DenseConfig(256));
+        assert!(dense.iter().all(|q| q.kind == U8StateKind::Dense));
+        assert!(ranged.iter().all(|q| q.kind == U8StateKind::Ranged));
+        for ((s, d), r) in sparse.iter().zip(dense.iter()).zip(ranged.iter()) {
+            assert_eq!((s.covered, s.can_end), (d.covered, d.can_end));
+            assert_eq!((s.covered, s.can_end), (r.covered, r.can_end));
+        }
+        let hex_digits = [(b'0', b'9'), (b'a', b'f')];
+        assert!(sparse.iter().any(|q| q.covered_ranges().starts_with(&hex_digits)));
+        assert!(sparse.iter().any(|q| q.covered.0 != 0));
+    }
+}
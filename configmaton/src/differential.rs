@@ -0,0 +1,70 @@
+use crate::ast::parse_regex;
+use crate::blob::state::build::U8BuildConfig;
+use crate::char_enfa;
+use crate::char_nfa;
+use crate::keyval_nfa::{Cmd, Msg, Parser};
+use crate::keyval_simulator::Simulation;
+
+// A value on which the compiled forms of a pattern disagree with the ε-NFA interpreter.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Discrepancy {
+    pub value: Vec<u8>,
+    // Whether the value matches according to the interpreter, the NFA, and the serialized
+    // automaton run by the simulator.
+    pub reference: bool,
+    pub nfa: bool,
+    pub blob: bool,
+}
+
+// Differential testing of the pattern compiler: match the values by `char_enfa::Nfa::is_match`,
+// by the NFA built from the ε-NFA and by a config with the single rule `{"k": regex}`, serialized
+// with `cfg`. Returns the values where the verdicts differ.
+pub fn check_regex<Cfg: U8BuildConfig>(regex: &str, values: &[&[u8]], cfg: &Cfg)
+    -> Vec<Discrepancy>
+{
+    let enfa = char_enfa::Nfa::from_ast(parse_regex(regex));
+    let mut nfa: char_nfa::Nfa = char_nfa::Nfa::new();
+    nfa.add_nfa(char_enfa::Nfa::from_ast(parse_regex(regex)), 0);
+
+    let config = serde_json::json!([{"when": {"k": regex}, "run": ["m"]}]);
+    let cmds: Vec<Cmd> = serde_json::from_str(&config.to_string()).unwrap();
+    let (parser, init) = Parser::parse(cmds);
+    let outmsg = Msg::serialize(&parser, &init, cfg);
+    let msg = unsafe {
+        Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len())
+    };
+    let automaton = msg.get_automaton();
+
+    values.iter().filter_map(|value| {
+        let mut simulation = Simulation::new(automaton, |_| None);
+        simulation.read(b"k", value, |_| None);
+        let discrepancy = Discrepancy {
+            value: value.to_vec(),
+            reference: enfa.is_match(value),
+            nfa: !nfa.matching_tags([0], value).is_empty(),
+            blob: !simulation.exts.is_empty(),
+        };
+        let agree = discrepancy.reference == discrepancy.nfa
+            && discrepancy.reference == discrepancy.blob;
+        (!agree).then_some(discrepancy)
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::tests::TestU8BuildConfig;
+
+    use super::*;
+
+    #[test]
+    fn agree() {
+        let values: Vec<&[u8]> = vec![
+            b"", b"a", b"b", b"ab", b"ba", b"abab", b"abc", b"aabbc", b"z9", b"\xff", b"a.c",
+        ];
+        for regex in ["", "a", "a*", "(ab)*", "a*b*c?", "[a-c]*", ".*b", "a|b|", "[[:alnum:]]*",
+            "a{2}", "(ab){1,2}c+", "a?b{0,}", "(?i)A[b-c]*|(?-i)Z", "(?i:A)b.[[:lower:]]"] {
+            assert_eq!(check_regex(regex, &values, &TestU8BuildConfig), vec![], "{}", regex);
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::blob::bdd::BddOrigin;
+use crate::blob::keyval_state::LeafOrigin;
+use crate::keyval_nfa::{bytes_as_string, fmte, Parser};
+
+// What `write_dot` exports. The graph is written as it is traversed (breadth-first from the
+// initial leaf, the DFAs last), and the traversal stops at the limits, so that even the graphs of
+// big configs stay viewable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DotOptions {
+    pub max_nodes: usize,
+    pub max_edges: usize,
+    // Only the keyval states from which the commands of the rule can be reached (the states of the
+    // rule and of its parents), see `Parser` for the numbering of the rules.
+    pub rule: Option<usize>,
+    // Put the keyval states of each rule (with their transitions) into a cluster.
+    pub cluster_by_rule: bool,
+    // Include the DFAs of the patterns.
+    pub dfas: bool,
+}
+
+impl Default for DotOptions {
+    fn default() -> Self {
+        DotOptions {
+            max_nodes: usize::MAX,
+            max_edges: usize::MAX,
+            rule: None,
+            cluster_by_rule: false,
+            dfas: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DotSummary {
+    pub nodes: usize,
+    pub edges: usize,
+    // Some nodes or edges were left out because of the limits.
+    pub truncated: bool,
+}
+
+struct Out<'o, W: Write> {
+    writer: W,
+    opts: &'o DotOptions,
+    nodes: HashSet<String>,
+    edges: usize,
+    truncated: bool,
+}
+
+impl<W: Write> Out<'_, W> {
+    // Declare the node, unless it is declared already. Returns false if it does not fit.
+    fn node(&mut self, id: &str, attrs: &str, cluster: Option<usize>) -> io::Result<bool> {
+        if self.nodes.contains(id) { return Ok(true); }
+        if self.nodes.len() == self.opts.max_nodes {
+            self.truncated = true;
+            return Ok(false);
+        }
+        self.nodes.insert(id.to_owned());
+        let attrs = if attrs.is_empty() { String::new() } else { format!(" [ {} ]", attrs) };
+        match cluster {
+            Some(rule) => writeln!(self.writer,
+                "  subgraph cluster_r{} {{ label=\"rule {}\"; {}{} }}", rule, rule, id, attrs),
+            None => writeln!(self.writer, "  {}{}", id, attrs),
+        }?;
+        Ok(true)
+    }
+
+    // The edge is left out if any of its nodes is.
+    fn edge(&mut self, from: &str, to: &str, attrs: &str) -> io::Result<()> {
+        if !self.nodes.contains(from) || !self.nodes.contains(to) { return Ok(()); }
+        if self.edges == self.opts.max_edges {
+            self.truncated = true;
+            return Ok(());
+        }
+        self.edges += 1;
+        let attrs = if attrs.is_empty() { String::new() } else { format!(" [ {} ]", attrs) };
+        writeln!(self.writer, "  {} -> {}{}", from, to, attrs)
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn leaves<'b>(bdd: &'b BddOrigin<usize, LeafOrigin>, result: &mut Vec<&'b LeafOrigin>) {
+    match bdd {
+        BddOrigin::Leaf(leaf) => result.push(leaf),
+        _ => unsafe {
+            leaves(bdd.get_pos(), result);
+            leaves(bdd.get_neg(), result);
+        }
+    }
+}
+
+// The keyval states from which a leaf emitting the rule is reachable.
+fn rule_states(parser: &Parser, rule: usize) -> HashSet<usize> {
+    let succs = parser.states.iter().map(|state| {
+        let mut state_leaves = vec![];
+        for tran in state.transitions.iter() { leaves(&tran.bdd, &mut state_leaves); }
+        state_leaves
+    }).collect::<Vec<_>>();
+    let mut selected = HashSet::new();
+    loop {
+        let len = selected.len();
+        for (ix, state_leaves) in succs.iter().enumerate() {
+            if state_leaves.iter().any(|leaf| leaf.rules.contains(&rule)
+                || leaf.states.iter().any(|q| selected.contains(q)))
+            {
+                selected.insert(ix);
+            }
+        }
+        if selected.len() == len { return selected; }
+    }
+}
+
+// Export the automaton of the parser in the dot format, see `DotOptions`.
+pub fn write_dot<W: Write>(parser: &Parser, init: &LeafOrigin, opts: &DotOptions, writer: W)
+    -> io::Result<DotSummary>
+{
+    let mut out = Out { writer, opts, nodes: HashSet::new(), edges: 0, truncated: false };
+    out.writer.write_all(b"digraph G {\n")?;
+
+    let selected = opts.rule.map(|rule| rule_states(parser, rule));
+    let is_selected = |q: &usize| selected.as_ref().is_none_or(|selected| selected.contains(q));
+    let cluster = |q: usize| if opts.cluster_by_rule { parser.state_rules.get(q).copied() }
+        else { None };
+
+    let mut queue = VecDeque::new();
+    let mut dfa_queue = VecDeque::new();
+    let mut leaf_ix = 0;
+    let mut bdd_ix = 0;
+    let mut tran_ix = 0;
+
+    // Returns the ID of the leaf node, None if it does not fit.
+    let mut leaf = |out: &mut Out<W>, leaf: &LeafOrigin, queue: &mut VecDeque<usize>,
+        cluster_of: Option<usize>| -> io::Result<Option<String>>
+    {
+        let t = format!("t{}", leaf_ix);
+        let e = format!("e{}", leaf_ix);
+        leaf_ix += 1;
+        if !out.node(&t, "shape=\"square\"", cluster_of)? { return Ok(None); }
+        if out.node(&e, "shape=\"diamond\"", cluster_of)? {
+            out.edge(&t, &e, &format!("label=\"{}\"", fmte(&leaf.exts, &leaf.get_olds)))?;
+        }
+        for q in leaf.states.iter().filter(|q| is_selected(q)) {
+            let id = format!("q{}", q);
+            let new = !out.nodes.contains(&id);
+            if out.node(&id, "", cluster(*q))? {
+                if new { queue.push_back(*q); }
+                out.edge(&e, &id, "")?;
+            }
+        }
+        Ok(Some(t))
+    };
+
+    leaf(&mut out, init, &mut queue, None)?;
+    while let Some(q) = queue.pop_front() {
+        let from = format!("q{}", q);
+        for tran in parser.states[q].transitions.iter() {
+            let g = format!("g{}", tran_ix);
+            tran_ix += 1;
+            if !out.node(&g, "shape=\"diamond\"", cluster(q))? { continue; }
+            out.edge(&from, &g, &format!("label=\"{}\"", escape(&bytes_as_string(&tran.key))))?;
+            if opts.dfas {
+                for d in tran.dfa_inits.iter() {
+                    let id = format!("d{}", d);
+                    let new = !out.nodes.contains(&id);
+                    if out.node(&id, "", None)? {
+                        if new { dfa_queue.push_back(*d); }
+                        out.edge(&g, &id, "color=\"blue\"")?;
+                    }
+                }
+            }
+
+            // The BDD nodes, each one once even if shared.
+            let mut visited: HashMap<*const BddOrigin<usize, LeafOrigin>, String> = HashMap::new();
+            let mut stack = vec![(&tran.bdd, g.clone(), "")];
+            while let Some((bdd, parent, attrs)) = stack.pop() {
+                if let Some(id) = visited.get(&(bdd as *const _)) {
+                    out.edge(&parent, id, attrs)?;
+                    continue;
+                }
+                let id = match bdd {
+                    BddOrigin::Leaf(target) => {
+                        let Some(id) = leaf(&mut out, target, &mut queue, cluster(q))?
+                            else { continue };
+                        id
+                    }
+                    _ => {
+                        let id = format!("b{}", bdd_ix);
+                        bdd_ix += 1;
+                        let label = format!("shape=\"diamond\", label=\"{}\"", bdd.get_var());
+                        if !out.node(&id, &label, cluster(q))? { continue; }
+                        let neg =
+                            if bdd.owns_neg() { "color=red, penwidth=2" } else { "color=red" };
+                        let pos =
+                            if bdd.owns_pos() { "color=green, penwidth=2" } else { "color=green" };
+                        unsafe {
+                            stack.push((bdd.get_neg(), id.clone(), neg));
+                            stack.push((bdd.get_pos(), id.clone(), pos));
+                        }
+                        id
+                    }
+                };
+                out.edge(&parent, &id, attrs)?;
+                visited.insert(bdd as *const _, id);
+            }
+        }
+    }
+
+    while let Some(d) = dfa_queue.pop_front() {
+        let state = &parser.nfa.states[d];
+        let from = format!("d{}", d);
+        let mut label = from.clone();
+        for tag in state.tags.0.iter() { label.push_str(&format!(" {}", tag)); }
+        writeln!(out.writer, "  {} [ label=\"{}\" ]", from, label)?;
+        for (guard, target) in state.transitions.iter() {
+            let id = format!("d{}", target);
+            let new = !out.nodes.contains(&id);
+            if out.node(&id, "", None)? {
+                if new { dfa_queue.push_back(*target); }
+                out.edge(&from, &id, &format!("label=\"{}\"", escape(&guard.to_string())))?;
+            }
+        }
+    }
+
+    let summary = DotSummary { nodes: out.nodes.len(), edges: out.edges, truncated: out.truncated };
+    if summary.truncated {
+        writeln!(out.writer, "  truncated [ shape=\"plaintext\", label=\"truncated at {} nodes, \
+            {} edges\" ]", summary.nodes, summary.edges)?;
+    }
+    out.writer.write_all(b"}\n")?;
+    Ok(summary)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::keyval_nfa::Cmd;
+
+    use super::*;
+
+    fn export(parser: &Parser, init: &LeafOrigin, opts: &DotOptions) -> (String, DotSummary) {
+        let mut dot = vec![];
+        let summary = write_dot(parser, init, opts, &mut dot).unwrap();
+        (String::from_utf8(dot).unwrap(), summary)
+    }
+
+    #[test]
+    fn limits_and_rules() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {"a": "x", "b": "[0-9]"}, "run": ["m0"], "then": [
+                {"when": {"c": "y"}, "run": ["m1"]}
+            ]},
+            {"when": {"d": "z"}, "run": ["m2"]}
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        assert_eq!(parser.state_rules.len(), parser.states.len());
+
+        let (dot, full) = export(&parser, &init, &DotOptions::default());
+        assert!(!full.truncated);
+        assert!(dot.starts_with("digraph G {\n") && dot.ends_with("}\n"));
+        assert!(dot.contains("[ label=\"0-9\" ]"));
+        assert_eq!(dot.lines().filter(|line| line.contains(" -> ")).count(), full.edges);
+        for q in 0..parser.states.len() { assert!(dot.contains(&format!("  q{}\n", q))); }
+
+        let opts = DotOptions { max_nodes: 5, max_edges: 3, ..DotOptions::default() };
+        let (dot, summary) = export(&parser, &init, &opts);
+        assert_eq!(summary, DotSummary { nodes: 5, edges: 3, truncated: true });
+        assert!(dot.contains("truncated at 5 nodes, 3 edges"));
+
+        // The state of rule 2 does not lead to the commands of rule 1, those of rule 0 do.
+        let opts = DotOptions { rule: Some(1), dfas: false, ..DotOptions::default() };
+        let (dot, _) = export(&parser, &init, &opts);
+        let rule_of = |q: usize| parser.state_rules[q];
+        for q in 0..parser.states.len() {
+            assert_eq!(dot.contains(&format!("  q{}\n", q)), rule_of(q) != 2);
+        }
+        assert!(!dot.contains("  d"));
+
+        let opts = DotOptions { cluster_by_rule: true, ..DotOptions::default() };
+        let (dot, summary) = export(&parser, &init, &opts);
+        assert_eq!(summary, full);
+        for rule in 0..parser.rule_count {
+            let cluster = format!("subgraph cluster_r{} {{ label=\"rule {}\"", rule, rule);
+            assert!(dot.contains(&cluster));
+        }
+    }
+
+    #[test]
+    fn write_errors() {
+        // Accepts a few bytes, then fails.
+        struct Short(usize);
+        impl Write for Short {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if self.0 == 0 { return Err(io::ErrorKind::WriteZero.into()); }
+                let len = buf.len().min(self.0);
+                self.0 -= len;
+                Ok(len)
+            }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let config: Vec<Cmd> = serde_json::from_str(r#"[{"when": {"a": "x"}, "run": ["m"]}]"#)
+            .unwrap();
+        let (parser, init) = Parser::parse(config);
+        let (dot, _) = export(&parser, &init, &DotOptions::default());
+        for len in [0, 20, dot.len() - 1] {
+            let error = write_dot(&parser, &init, &DotOptions::default(), Short(len)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+        }
+        assert!(write_dot(&parser, &init, &DotOptions::default(), Short(dot.len())).is_ok());
+        assert!(parser.to_dot(&init, Short(20)).is_err());
+    }
+}
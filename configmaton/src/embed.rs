@@ -0,0 +1,165 @@
+// Configs compiled at build time and embedded into the binary. The build script of the
+// application compiles the config by `compile_config`, which fails the build if the config is
+// broken, and the application includes the blob by `include_automaton!`:
+//
+//     // build.rs
+//     configmaton::embed::compile_config("rules.json").unwrap();
+//
+//     // main.rs
+//     let automaton = configmaton::include_automaton!("rules.json");
+//
+// The blob is built by the host, so a cross-compiled binary can use it only if the target has the
+// alignment of the words of the host (e.g. a wasm32 binary built on x86_64), see `TargetLayout`.
+// A blob of the other byte order is swapped when loaded.
+
+use std::{cell::UnsafeCell, fmt, io, path::{Path, PathBuf}, sync::OnceLock};
+
+use crate::ast::{self, RegexError};
+use crate::blob::{
+    automaton::Automaton, root::{BlobError, BlobHeader}, state::build::DefaultBuildConfig,
+    BuildError,
+};
+use crate::keyval_nfa::{Cmd, Msg, Parser};
+
+#[derive(Debug)]
+pub enum EmbedError {
+    Io { path: PathBuf, error: io::Error },
+    Json(serde_json::Error),
+    Regex { regex: String, error: RegexError },
+    Build(BuildError),
+    Blob(BlobError),
+    // `compile_config` has been called outside of a build script.
+    NoBuildEnv(&'static str),
+}
+
+impl fmt::Display for EmbedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmbedError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            EmbedError::Json(error) => write!(f, "invalid config: {}", error),
+            EmbedError::Regex { regex, error } => write!(f, "regex {:?}: {}", regex, error),
+            EmbedError::Build(error) => write!(f, "cannot build the blob: {}", error),
+            EmbedError::Blob(error) => write!(f, "invalid blob: {}", error),
+            EmbedError::NoBuildEnv(var) => write!(f, "{} is not set, not in a build script", var),
+        }
+    }
+}
+
+impl std::error::Error for EmbedError {}
+
+// Compile the JSON config into a serialized blob with a relocation table. Unlike `Parser::parse`,
+// an invalid regex is reported rather than panicked on.
+pub fn compile_json(json: &str) -> Result<Msg, EmbedError> {
+    let config: Vec<Cmd> = serde_json::from_str(json).map_err(EmbedError::Json)?;
+    for regex in config.iter().flat_map(Cmd::regexes) {
+        let (_, errors) = ast::parse_regex_recovering(regex);
+        if let Some(error) = errors.into_iter().next() {
+            return Err(EmbedError::Regex { regex: regex.to_owned(), error });
+        }
+    }
+    let (parser, init) = Parser::parse(config);
+    let msg = Msg::try_serialize(&parser, &init, &DefaultBuildConfig).map_err(EmbedError::Build)?;
+    msg.with_relocations().map_err(EmbedError::Blob)
+}
+
+// Write the blob of the config file, as raw bytes for `include_bytes!`.
+pub fn compile_file(config: &Path, blob: &Path) -> Result<(), EmbedError> {
+    let json = std::fs::read_to_string(config).map_err(io_error(config))?;
+    let msg = compile_json(&json)?;
+    if let Some(dir) = blob.parent() { std::fs::create_dir_all(dir).map_err(io_error(dir))?; }
+    let data = unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) };
+    std::fs::write(blob, data).map_err(io_error(blob))
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> EmbedError + '_ {
+    move |error| EmbedError::Io { path: path.to_owned(), error }
+}
+
+// For build scripts: compile the config (a path relative to the package) into the blob included
+// by `include_automaton!` with the same path, and rebuild when the config changes.
+pub fn compile_config(config: &str) -> Result<(), EmbedError> {
+    let env = |var| std::env::var_os(var).map(PathBuf::from).ok_or(EmbedError::NoBuildEnv(var));
+    let source = env("CARGO_MANIFEST_DIR")?.join(config);
+    println!("cargo:rerun-if-changed={}", source.display());
+    compile_file(&source, &env("OUT_DIR")?.join(format!("{}.blob", config)))
+}
+
+// A blob embedded in a static, deserialized in place (by its relocation table) on the first use,
+// so that neither a copy nor an allocation is needed. The array may be longer than the blob.
+#[repr(C, align(16))]
+pub struct EmbeddedAutomaton<const N: usize> {
+    data: UnsafeCell<[u8; N]>,
+    loaded: OnceLock<Result<(), BlobError>>,
+}
+
+// The data is changed only once, by the initializer of `loaded`, before it is shared.
+unsafe impl<const N: usize> Sync for EmbeddedAutomaton<N> {}
+
+impl<const N: usize> EmbeddedAutomaton<N> {
+    pub const fn new(data: [u8; N]) -> Self {
+        EmbeddedAutomaton { data: UnsafeCell::new(data), loaded: OnceLock::new() }
+    }
+
+    // Fails e.g. if the blob has been built by an incompatible version or target.
+    pub fn try_get(&'static self) -> Result<&'static Automaton<'static>, BlobError> {
+        let data = self.data.get() as *mut u8;
+        self.loaded.get_or_init(|| unsafe { Msg::deserialize_relocated(data, N) }).clone()?;
+        unsafe { Ok(&*(data.add(size_of::<BlobHeader>()) as *const Automaton)) }
+    }
+
+    pub fn get(&'static self) -> &'static Automaton<'static> {
+        self.try_get().expect("invalid embedded automaton")
+    }
+}
+
+// The automaton of the config compiled by `compile_config` with the same path, see the top of
+// the module.
+#[macro_export]
+macro_rules! include_automaton {
+    ($config:literal) => {{
+        static AUTOMATON: $crate::embed::EmbeddedAutomaton<
+            { include_bytes!(concat!(env!("OUT_DIR"), "/", $config, ".blob")).len() }
+        > = $crate::embed::EmbeddedAutomaton::new(
+            *include_bytes!(concat!(env!("OUT_DIR"), "/", $config, ".blob")));
+        AUTOMATON.get()
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configmaton::Configmaton;
+    use crate::onion::ThreadUnsafeLocker;
+
+    use super::*;
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no temp_dir on WASI")]
+    fn embedded() {
+        let dir = std::env::temp_dir().join(format!("configmaton-embed-{}", std::process::id()));
+        let config = dir.join("rules.json");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&config, r#"[
+            {"defaults": {"foo": "bar"}},
+            {"when": {"foo": "b.*"}, "run": ["m1"]}
+        ]"#).unwrap();
+        let blob = dir.join("out/rules.json.blob");
+        compile_file(&config, &blob).unwrap();
+        let data = std::fs::read(&blob).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut array = [0; 1 << 16];
+        array[..data.len()].copy_from_slice(&data);
+        let embedded: &'static _ = Box::leak(Box::new(EmbeddedAutomaton::new(array)));
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(embedded.get());
+        assert_eq!(configmaton.pop_command(), Some(b"m1".as_ref()));
+        assert!(std::ptr::eq(embedded.get(), embedded.try_get().unwrap()));
+
+        let broken: &'static _ = Box::leak(Box::new(EmbeddedAutomaton::new([0; 64])));
+        assert_eq!(broken.try_get().err(), Some(BlobError::Magic));
+
+        assert!(matches!(compile_json(r#"[{"when": {"foo": "a("}, "run": []}]"#),
+            Err(EmbedError::Regex { .. })));
+        assert!(matches!(compile_json("[{"), Err(EmbedError::Json(_))));
+        assert!(matches!(compile_config("rules.json"), Err(EmbedError::NoBuildEnv(_))));
+    }
+}
@@ -0,0 +1,172 @@
+use arbitrary::{Arbitrary, Unstructured};
+use hashbrown::HashMap;
+
+use crate::ast::parse_regex_recovering;
+use crate::blob::keyval_state::LeafOrigin;
+use crate::blob::sediment::Sediment;
+use crate::blob::state::build::U8BuildConfig;
+use crate::blob::state::{
+    deserialize_tag_pool, reserve_tag_pool, serialize_tag_pool, tag_sets, U8State, U8StatePrepared,
+    U8TagPool,
+};
+use crate::blob::{align_up_mut_ptr, BuildCursor, Reserve};
+use crate::char_runner;
+use crate::keyval_nfa::{join_leaves, Cmd, Msg, Parser};
+use crate::keyval_simulator::Simulation;
+
+// Entry points of fuzz targets, e.g. `fuzz_target!(|input: ConfigInput| fuzz::config(input))`.
+// Inputs rejected by design (invalid patterns, references to nonexistent states) are skipped,
+// anything else going wrong is a bug.
+
+// Small enough to exercise all the kinds of states and the hashmap collisions.
+#[derive(Debug, Clone)]
+pub struct FuzzBuildConfig {
+    guard_size_keep: u32,
+    hashmap_cap_power: usize,
+    dense_guard_count: usize,
+    max_ranged_ranges: usize,
+    hash_seed: u64,
+    narrow_dense_offsets: bool,
+}
+
+impl<'a> Arbitrary<'a> for FuzzBuildConfig {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(FuzzBuildConfig {
+            guard_size_keep: u.int_in_range(0..=256)?,
+            hashmap_cap_power: u.int_in_range(0..=4)?,
+            dense_guard_count: u.int_in_range(0..=8)?,
+            max_ranged_ranges: u.int_in_range(0..=16)?,
+            hash_seed: u.arbitrary()?,
+            narrow_dense_offsets: u.arbitrary()?,
+        })
+    }
+}
+
+impl U8BuildConfig for FuzzBuildConfig {
+    fn guard_size_keep(&self) -> u32 { self.guard_size_keep }
+    fn hashmap_cap_power_fn(&self, _len: usize) -> usize { self.hashmap_cap_power }
+    fn dense_guard_count(&self) -> usize { self.dense_guard_count }
+    fn max_ranged_ranges(&self) -> usize { self.max_ranged_ranges }
+    fn hash_seed(&self) -> u64 { self.hash_seed }
+    fn narrow_dense_offsets(&self) -> bool { self.narrow_dense_offsets }
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct ConfigInput {
+    pub cmds: Vec<Cmd>,
+    pub profile: Option<String>,
+    // Joined to the initial leaf of the parsed config.
+    pub init: LeafOrigin,
+    pub cfg: FuzzBuildConfig,
+    pub updates: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+// Parse the config, serialize it, read the blob back and simulate the updates on it.
+pub fn config(input: ConfigInput) {
+    let valid = |cmd: &Cmd| cmd.regexes().into_iter()
+        .all(|regex| parse_regex_recovering(regex).1.is_empty());
+    if !input.cmds.iter().all(valid) { return; }
+
+    let (parser, init) = Parser::parse_profile(input.cmds, input.profile.as_deref());
+    let mut extra = input.init;
+    // The rules are parallel to the commands.
+    extra.rules.resize(extra.exts.len(), 0);
+    let init = join_leaves([init, extra].into_iter());
+    let Ok(outmsg) = Msg::try_serialize(&parser, &init, &input.cfg) else { return };
+    let msg = unsafe {
+        Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len())
+    };
+
+    let mut db = HashMap::<&[u8], &[u8]>::new();
+    let mut simulation = Simulation::new(msg.get_automaton(), |_| None);
+    for (key, value) in input.updates.iter() {
+        db.insert(key, value);
+        simulation.read(key, value, |key| db.get(key).copied());
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+pub struct StatesInput {
+    pub states: Vec<U8StatePrepared>,
+    pub value: Vec<u8>,
+}
+
+// Serialize the states, read them back and run the value from the first one.
+pub fn states(input: StatesInput) {
+    let states = input.states;
+    if states.is_empty() { return; }
+    let mut sz = Reserve(0);
+    for state in states.iter() {
+        if U8State::try_reserve(state, &mut sz, states.len()).is_err() { return; }
+    }
+
+    let mut sz = Reserve(0);
+    let mut addrs = Vec::<usize>::new();
+    Sediment::<U8State>::reserve(&states, &mut sz, |state, sz| {
+        addrs.push(U8State::reserve(state, sz));
+    });
+    let sets = tag_sets(&states);
+    let mut tagptrs = HashMap::new();
+    reserve_tag_pool(&sets, &mut sz, &mut tagptrs);
+    let mut buf = vec![0u8; sz.0 + size_of::<u128>()];
+    let buf = align_up_mut_ptr::<u8, u128>(buf.as_mut_ptr()) as *mut u8;
+    unsafe {
+        let pool_cur: BuildCursor<U8TagPool> = Sediment::<U8State>::serialize(
+            &states, BuildCursor::new(buf),
+            |state, state_cur| U8State::serialize(state, state_cur, &addrs, &tagptrs));
+        let _: BuildCursor<()> = serialize_tag_pool(&sets, pool_cur);
+        let pool_cur = Sediment::<U8State>::deserialize(BuildCursor::new(buf),
+            |state_cur| U8State::deserialize(state_cur)).unwrap();
+        let _: BuildCursor<()> = deserialize_tag_pool(pool_cur).unwrap();
+
+        let mut runner = char_runner::Runner::new([buf.add(addrs[0]) as *const U8State]);
+        for c in input.value.iter() { runner.read(*c); }
+        runner.finish();
+        for state in runner.states.iter() { (**state).get_tags(); }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic pseudo-random inputs, the real fuzzing is done by a fuzzer.
+    fn inputs() -> impl Iterator<Item = Vec<u8>> {
+        let mut x = 0x2545f4914f6cdd1du64;
+        (0..200).map(move |len| (0..len * 8).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        }).collect())
+    }
+
+    #[test]
+    fn smoke() {
+        for data in inputs() {
+            let mut u = Unstructured::new(&data);
+            if let Ok(input) = ConfigInput::arbitrary(&mut u) { config(input); }
+            let mut u = Unstructured::new(&data);
+            if let Ok(input) = StatesInput::arbitrary(&mut u) { states(input); }
+        }
+
+        let cmds = serde_json::from_str(r#"[{"when": {"a": "x*"}, "run": ["m"]}]"#).unwrap();
+        config(ConfigInput {
+            cmds,
+            profile: None,
+            init: LeafOrigin {
+                states: vec![], get_olds: vec![], exts: vec![], rules: vec![], group: vec![]
+            },
+            cfg: FuzzBuildConfig {
+                guard_size_keep: 2,
+                hashmap_cap_power: 1,
+                dense_guard_count: 3,
+                max_ranged_ranges: 0,
+                hash_seed: 0,
+                narrow_dense_offsets: true,
+            },
+            updates: vec![(b"a".to_vec(), b"xx".to_vec())],
+        });
+    }
+}
@@ -1,3 +1,8 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::str::FromStr;
+
 use hashbrown::HashMap;
 
 pub trait Monoid {
@@ -5,52 +10,129 @@ pub trait Monoid {
     fn append(&mut self, other: &Self);
 }
 
-#[repr(C)]
-#[derive(Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
-pub struct Guard(pub u128, pub u128);
+// A symbol of an alphabet, numbered by `index` from `MIN` to `MAX` (possibly with gaps, like the
+// surrogates in `char`).
+pub trait Symbol: Copy + Ord + Hash + Debug {
+    const MIN: Self;
+    const MAX: Self;
+    fn index(self) -> u32;
+    fn from_index(ix: u32) -> Option<Self>;
 
-impl std::fmt::Debug for Guard {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Guard(")?;
+    fn next(self) -> Option<Self> {
+        (self.index() + 1..=Self::MAX.index()).find_map(Self::from_index)
+    }
+
+    fn prev(self) -> Option<Self> {
+        (Self::MIN.index()..self.index()).rev().find_map(Self::from_index)
+    }
+}
+
+impl Symbol for u8 {
+    const MIN: Self = 0;
+    const MAX: Self = u8::MAX;
+    fn index(self) -> u32 { self as u32 }
+    fn from_index(ix: u32) -> Option<Self> { ix.try_into().ok() }
+}
+
+impl Symbol for u16 {
+    const MIN: Self = 0;
+    const MAX: Self = u16::MAX;
+    fn index(self) -> u32 { self as u32 }
+    fn from_index(ix: u32) -> Option<Self> { ix.try_into().ok() }
+}
+
+impl Symbol for char {
+    const MIN: Self = '\0';
+    const MAX: Self = char::MAX;
+    fn index(self) -> u32 { self as u32 }
+    fn from_index(ix: u32) -> Option<Self> { char::from_u32(ix) }
+}
+
+// A set of symbols guarding a transition. The automata are generic over it, `Guard` is the
+// bitset over bytes used by the blob, `RangeGuard` serves wider alphabets.
+pub trait SymbolSet: Clone + Eq + Hash + Ord + Debug {
+    type Symbol: Symbol;
+
+    fn empty() -> Self;
+    fn full() -> Self;
+    fn is_empty(&self) -> bool;
+    fn from_range(range: (Self::Symbol, Self::Symbol)) -> Self;
+    fn contains(&self, c: Self::Symbol) -> bool;
+    // The smallest symbol of the set.
+    fn first(&self) -> Option<Self::Symbol>;
+    fn intersection(&self, right: &Self) -> Self;
+    fn subtract(&self, right: &Self) -> Self;
+    fn union(&self, right: &Self) -> Self;
+
+    fn add_range(&mut self, new_range: (Self::Symbol, Self::Symbol)) {
+        *self = self.union(&Self::from_range(new_range));
+    }
 
-        let mut in_range = false;
-        let mut range_start = 0u8;
-
-        for i in 0u8..=255u8 {
-            let contained = self.contains(i);
-            if contained && !in_range {
-                // Start of a new range
-                range_start = i;
-                in_range = true;
-            } else if !contained && in_range {
-                // End of the current range
-                if i - 1 != range_start {
-                    write_range(f, range_start, i - 1)?;
-                } else {
-                    write_byte(f, range_start)?;
+    fn union_update(&mut self, right: &Self) {
+        *self = self.union(right);
+    }
+
+    fn mintermize<Out: Monoid + Clone, I: Iterator<Item = (Out, Self)>>
+        (input_map: I) -> HashMap<Self, Out>
+    {
+        let mut leaves = HashMap::new();
+        leaves.insert(Self::full(), Out::empty()); // Global guard with no outputs
+
+        for (out, guard) in input_map {
+            let mut new_leaves = HashMap::new();
+            // Intersect each current leaf with the new guard
+            for (current_guard, current_out) in &leaves {
+                let intersection = current_guard.intersection(&guard);
+                if !intersection.is_empty() {
+                    let leaf_cfg = new_leaves.entry(intersection).or_insert(current_out.clone());
+                    leaf_cfg.append(&out);
                 }
-                in_range = false;
             }
-        }
 
-        // To handle the case where the last byte (255) is also included
-        if in_range {
-            if 255 != range_start {
-                write_range(f, range_start, 255)?;
-            } else {
-                write_byte(f, 255)?;
+            for (current_guard, current_out) in leaves.drain() {
+                let subtraction = current_guard.subtract(&guard);
+                if !subtraction.is_empty() {
+                    new_leaves.entry(subtraction).or_insert(current_out);
+                }
             }
+
+            leaves = new_leaves;
         }
 
+        leaves
+    }
+}
+
+// The bits of the bytes 0x80-0xff and 0x00-0x7f.
+#[repr(C)]
+#[derive(Hash, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Guard(pub u128, pub u128);
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Guard(")?;
+        for (start, end) in self.ranges() { write_range(f, start, end)?; }
         write!(f, ")")
     }
 }
 
-fn write_byte(f: &mut std::fmt::Formatter, byte: u8) -> std::fmt::Result {
+// The ranges separated by commas, e.g. `0-9,a-f,x`. The bytes other than the visible ASCII are
+// written as `\xx` (hex), and `\`, `-` and `,` are escaped by a backslash. See `FromStr`.
+impl fmt::Display for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (ix, (start, end)) in self.ranges().into_iter().enumerate() {
+            if ix > 0 { write!(f, ",")?; }
+            write_range(f, start, end)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_byte(f: &mut fmt::Formatter, byte: u8) -> fmt::Result {
     if byte.is_ascii_graphic() {
         match byte {
-            b'\\' => write!(f, "\\\\"),
-            b'-' => write!(f, "\\-"),
+            b'\\' | b'-' | b',' => write!(f, "\\{}", byte as char),
             _ => write!(f, "{}", byte as char),
         }
     } else {
@@ -58,10 +140,99 @@ fn write_byte(f: &mut std::fmt::Formatter, byte: u8) -> std::fmt::Result {
     }
 }
 
-fn write_range(f: &mut std::fmt::Formatter, start: u8, end: u8) -> std::fmt::Result {
+fn write_range(f: &mut fmt::Formatter, start: u8, end: u8) -> fmt::Result {
     write_byte(f, start)?;
-    write!(f, "-")?;
-    write_byte(f, end)
+    if start != end {
+        write!(f, "-")?;
+        write_byte(f, end)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseGuardError {
+    // The byte offset in the parsed string.
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseGuardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid guard at {}: {}", self.position, self.message)
+    }
+}
+
+impl std::error::Error for ParseGuardError {}
+
+impl FromStr for Guard {
+    type Err = ParseGuardError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let input = s.as_bytes();
+        let mut pos = 0;
+        let err = |position, message| ParseGuardError { position, message };
+        let byte = |pos: &mut usize| -> Result<u8, ParseGuardError> {
+            let c = *input.get(*pos).ok_or(err(*pos, "expected a byte"))?;
+            *pos += 1;
+            match c {
+                b'\\' => match input.get(*pos) {
+                    Some(c @ (b'\\' | b'-' | b',')) => { *pos += 1; Ok(*c) }
+                    _ => {
+                        let hex = input.get(*pos..*pos + 2)
+                            .and_then(|hex| std::str::from_utf8(hex).ok())
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                            .ok_or(err(*pos, "expected an escaped character or two hex digits"))?;
+                        *pos += 2;
+                        Ok(hex)
+                    }
+                },
+                b'-' | b',' => Err(err(*pos - 1, "unescaped separator")),
+                c if c.is_ascii() => Ok(c),
+                _ => Err(err(*pos - 1, "non-ASCII characters must be written in hex")),
+            }
+        };
+
+        let mut guard = Guard::empty();
+        while pos < input.len() {
+            if pos > 0 {
+                if input[pos] != b',' { return Err(err(pos, "expected a comma")); }
+                pos += 1;
+            }
+            let start = byte(&mut pos)?;
+            let mut end = start;
+            if input.get(pos) == Some(&b'-') {
+                pos += 1;
+                end = byte(&mut pos)?;
+                if end < start { return Err(err(pos, "the range ends before it starts")); }
+            }
+            guard.add_range((start, end));
+        }
+        Ok(guard)
+    }
+}
+
+// The canonical order: lexicographic by the ascending contained bytes, e.g. `a < a-b < b`.
+impl Ord for Guard {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // The sequences agree up to the lowest byte in which the guards differ. The one missing it
+        // continues by a greater byte (so it is greater), unless it ends there.
+        let Some(diff) = SymbolSet::first(&Guard(self.0 ^ other.0, self.1 ^ other.1)) else {
+            return Ordering::Equal;
+        };
+        let (missing, ordering) = if self.contains(diff) {
+            (other, Ordering::Less)
+        } else {
+            (self, Ordering::Greater)
+        };
+        let ends = diff == u8::MAX || missing.intersection(&BOTTOMS[diff as usize + 1]).is_empty();
+        if ends { ordering.reverse() } else { ordering }
+    }
+}
+
+impl PartialOrd for Guard {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
 pub const TOPS: [Guard; 256] = [
@@ -639,38 +810,121 @@ impl Guard {
         *self = Guard(self.0 | right.0, self.1 | right.1)
     }
 
-    pub fn mintermize<Out: Monoid + Clone, I: Iterator<Item = (Out, Self)>>
-        (input_map: I) -> HashMap<Self, Out>
-    {
-        let mut leaves = HashMap::new();
-        leaves.insert(Guard::full(), Out::empty()); // Global guard with no outputs
+    // The contained bytes, ascending.
+    pub fn bytes(&self) -> impl Iterator<Item = u8> {
+        let guard = *self;
+        (0u8..=255).filter(move |c| guard.contains(*c))
+    }
 
-        for (out, guard) in input_map {
-            let mut new_leaves = HashMap::new();
-            // Intersect each current leaf with the new guard
-            for (current_guard, current_out) in &leaves {
-                let intersection = current_guard.intersection(&guard);
-                if !intersection.is_empty() {
-                    let leaf_cfg = new_leaves.entry(intersection).or_insert(current_out.clone());
-                    leaf_cfg.append(&out);
-                }
+    // The maximal ranges of the contained bytes, ascending.
+    pub fn ranges(&self) -> Vec<(u8, u8)> {
+        let mut ranges: Vec<(u8, u8)> = vec![];
+        for c in self.bytes() {
+            match ranges.last_mut() {
+                Some((_, end)) if *end as usize + 1 == c as usize => *end = c,
+                _ => ranges.push((c, c)),
             }
+        }
+        ranges
+    }
 
-            for (current_guard, current_out) in leaves.drain() {
-                let subtraction = current_guard.subtract(&guard);
-                if !subtraction.is_empty() {
-                    new_leaves.entry(subtraction).or_insert(current_out);
-                }
-            }
+}
 
-            leaves = new_leaves;
-        }
+impl SymbolSet for Guard {
+    type Symbol = u8;
 
-        leaves
+    fn empty() -> Self { Guard::empty() }
+    fn full() -> Self { Guard::full() }
+    fn is_empty(&self) -> bool { Guard::is_empty(self) }
+    fn from_range(range: (u8, u8)) -> Self { Guard::from_range(range) }
+    fn contains(&self, c: u8) -> bool { Guard::contains(self, c) }
+    fn intersection(&self, right: &Self) -> Self { Guard::intersection(self, right) }
+    fn subtract(&self, right: &Self) -> Self { Guard::subtract(self, right) }
+    fn union(&self, right: &Self) -> Self { Guard::union(self, right) }
+    fn add_range(&mut self, new_range: (u8, u8)) { Guard::add_range(self, new_range) }
+    fn union_update(&mut self, right: &Self) { Guard::union_update(self, right) }
+
+    fn first(&self) -> Option<u8> {
+        if self.1 != 0 { Some(self.1.trailing_zeros() as u8) }
+        else if self.0 != 0 { Some(0x80 | self.0.trailing_zeros() as u8) }
+        else { None }
+    }
+}
+
+// A set of symbols as sorted, disjoint and non-adjacent inclusive ranges.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RangeGuard<S>(pub Vec<(S, S)>);
+
+impl<S: Symbol> RangeGuard<S> {
+    pub fn from_ranges(ranges: Vec<(S, S)>) -> Self {
+        let mut guard = Self::empty();
+        for range in ranges { guard.add_range(range); }
+        guard
+    }
+
+    fn complement(&self) -> Self {
+        let mut result = vec![];
+        let mut start = Some(S::MIN);
+        for (a, b) in self.0.iter() {
+            if let Some(start) = start {
+                if start < *a { result.push((start, a.prev().unwrap())); }
+            }
+            start = b.next();
+        }
+        if let Some(start) = start { result.push((start, S::MAX)); }
+        RangeGuard(result)
     }
 }
 
+impl<S: Symbol> SymbolSet for RangeGuard<S> {
+    type Symbol = S;
+
+    fn empty() -> Self { RangeGuard(vec![]) }
+    fn full() -> Self { RangeGuard(vec![(S::MIN, S::MAX)]) }
+    fn is_empty(&self) -> bool { self.0.is_empty() }
+    fn first(&self) -> Option<S> { self.0.first().map(|(a, _)| *a) }
+
+    fn from_range(range: (S, S)) -> Self {
+        RangeGuard(if range.0 <= range.1 { vec![range] } else { vec![] })
+    }
+
+    fn contains(&self, c: S) -> bool {
+        let i = self.0.partition_point(|(_, b)| *b < c);
+        i < self.0.len() && self.0[i].0 <= c
+    }
+
+    fn intersection(&self, right: &Self) -> Self {
+        let mut result = vec![];
+        let (mut i, mut j) = (0, 0);
+        while i < self.0.len() && j < right.0.len() {
+            let (a1, b1) = self.0[i];
+            let (a2, b2) = right.0[j];
+            let (a, b) = (a1.max(a2), b1.min(b2));
+            if a <= b { result.push((a, b)); }
+            if b1 < b2 { i += 1; } else { j += 1; }
+        }
+        RangeGuard(result)
+    }
 
+    fn subtract(&self, right: &Self) -> Self {
+        self.intersection(&right.complement())
+    }
+
+    fn union(&self, right: &Self) -> Self {
+        let mut ranges = self.0.iter().chain(right.0.iter()).copied().collect::<Vec<_>>();
+        ranges.sort_unstable();
+        let mut result: Vec<(S, S)> = vec![];
+        for (a, b) in ranges {
+            match result.last_mut() {
+                Some((_, last)) if last.next().is_none_or(|next| a <= next) => {
+                    *last = (*last).max(b);
+                }
+                _ => result.push((a, b)),
+            }
+        }
+        RangeGuard(result)
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -776,4 +1030,72 @@ mod tests {
         guard.add_range((67, 67));
         assert_eq!(guard, Guard::from_ranges(vec![(66, 67), (98, 99)]));
     }
+
+    #[test]
+    fn test_range_guard() {
+        let left = RangeGuard::from_ranges(vec![(0u16, 0), (3, 10), (20, 30), (1000, 2000)]);
+        let right = RangeGuard::from_ranges(vec![(5, 15), (25, 1500), (1, 2)]);
+        assert_eq!(left.union(&right), RangeGuard(vec![(0, 15), (20, 2000)]));
+        assert_eq!(left.intersection(&right), RangeGuard(vec![(5, 10), (25, 30), (1000, 1500)]));
+        assert_eq!(left.subtract(&right), RangeGuard(vec![(0, 0), (3, 4), (20, 24), (1501, 2000)]));
+        assert!(left.contains(1000) && !left.contains(11) && !left.contains(u16::MAX));
+        assert_eq!(RangeGuard::full().subtract(&left).first(), Some(1));
+
+        // The complement skips the surrogates, which are not chars.
+        let below = RangeGuard::from_range(('\0', '\u{d7ff}'));
+        let above = RangeGuard::full().subtract(&below);
+        assert_eq!(above, RangeGuard(vec![('\u{e000}', char::MAX)]));
+        assert_eq!(below.union(&above), RangeGuard::full());
+
+        let minterms = RangeGuard::mintermize(vec![
+            (vec![1].into_iter().collect::<HashSet<_>>(), RangeGuard::from_range(('a', 'z'))),
+            (vec![2].into_iter().collect(), RangeGuard::from_range(('m', 'ž'))),
+        ].into_iter());
+        assert_eq!(minterms.len(), 4);
+        assert_eq!(minterms[&RangeGuard(vec![('m', 'z')])], vec![1, 2].into_iter().collect());
+    }
+
+    #[test]
+    fn test_guard_display() {
+        let guard = Guard::from_ranges(vec![(b'x', b'x'), (b'a', b'f'), (b'0', b'9')]);
+        assert_eq!(guard.to_string(), "0-9,a-f,x");
+        assert_eq!("x,a-f,0-9".parse::<Guard>(), Ok(guard));
+        assert_eq!(format!("{:?}", guard), "Guard(0-9a-fx)");
+
+        let guard = Guard::from_ranges(vec![(0, b' '), (b',', b'-'), (b'\\', b'\\'), (255, 255)]);
+        assert_eq!(guard.to_string(), "\\00-\\20,\\,-\\-,\\\\,\\ff");
+        assert_eq!(guard.to_string().parse::<Guard>(), Ok(guard));
+        assert_eq!("".parse::<Guard>(), Ok(Guard::empty()));
+        assert_eq!(Guard::full().to_string(), "\\00-\\ff");
+
+        let err = |s: &str| s.parse::<Guard>().unwrap_err().position;
+        assert_eq!(err("a,"), 2);
+        assert_eq!(err("ab"), 1);
+        assert_eq!(err("z-a"), 3);
+        assert_eq!(err("\\g0"), 1);
+        assert_eq!(err("-"), 0);
+        assert_eq!(err("é"), 0);
+    }
+
+    #[test]
+    fn test_guard_order() {
+        let guard = |s: &str| s.parse::<Guard>().unwrap();
+        let mut guards = ["b", "a-b", "\\ff", "a", "a,c", "", "a,\\ff", "a-c"].map(guard);
+        guards.sort();
+        assert_eq!(guards.map(|g| g.to_string()),
+            ["", "a", "a-b", "a-c", "a,c", "a,\\ff", "b", "\\ff"]);
+        for left in guards.iter() {
+            for right in guards.iter() {
+                assert_eq!(left.cmp(right), left.bytes().cmp(right.bytes()));
+            }
+        }
+        assert_eq!(guard("0-2").bytes().collect::<Vec<_>>(), b"012");
+    }
+
+    #[test]
+    fn test_guard_first() {
+        assert_eq!(SymbolSet::first(&Guard::from_ranges(vec![(200, 210), (130, 140)])), Some(130));
+        assert_eq!(SymbolSet::first(&Guard::from_range((7, 7))), Some(7));
+        assert_eq!(SymbolSet::first(&Guard::empty()), None);
+    }
 }
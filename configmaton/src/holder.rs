@@ -1,33 +1,116 @@
+use std::cell::Cell;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
 struct Node<T> {
     value: T,
+    // Cleared when the handle of the value is dropped.
+    alive: Rc<Cell<bool>>,
     next: Option<Box<Node<T>>>,
 }
 
+// Owns the values, each one until its handle is dropped. The dead values are pruned by the next
+// `iter_mut`.
 pub struct Holder<T> {
     head: Option<Box<Node<T>>>,
 }
 
+// The owner of a value in a holder. The value is not moved or dropped while the handle exists,
+// unless the holder itself is dropped or cleared.
+pub struct Handle<T> {
+    value: *mut T,
+    alive: Rc<Cell<bool>>,
+}
+
+impl<T> Handle<T> {
+    // Give up the ownership, the value stays in the holder until it is dropped or cleared.
+    pub fn into_raw(self) -> *mut T {
+        let value = self.value;
+        std::mem::forget(self);
+        value
+    }
+}
+
+impl<T> Deref for Handle<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<T> DerefMut for Handle<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<T> Drop for Handle<T> {
+    fn drop(&mut self) {
+        self.alive.set(false);
+    }
+}
+
+impl<T> Default for Holder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Holder<T> {
     pub fn new() -> Self {
         Holder { head: None }
     }
 
-    pub fn add(&mut self, value: T) -> *mut T {
+    pub fn add(&mut self, value: T) -> Handle<T> {
         let old = self.head.take();
-        self.head = Some(Box::new(Node { value, next: old }));
-        &mut self.head.as_mut().unwrap().value
+        let alive = Rc::new(Cell::new(true));
+        self.head = Some(Box::new(Node { value, alive: alive.clone(), next: old }));
+        Handle { value: &mut self.head.as_mut().unwrap().value, alive }
+    }
+
+    // Drop the values whose handles are gone.
+    pub fn prune(&mut self) {
+        let mut link = &mut self.head;
+        while link.is_some() {
+            if link.as_ref().unwrap().alive.get() {
+                link = &mut link.as_mut().unwrap().next;
+            } else {
+                let next = link.as_mut().unwrap().next.take();
+                *link = next;
+            }
+        }
     }
 
     pub fn iter_mut(&mut self) -> Iter<T> {
+        self.prune();
         Iter { cur: self.head.as_mut().map(|node| &mut **node as *mut _) }
     }
 
+    // The live values, without pruning the dead ones.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut cur = self.head.as_deref();
+        std::iter::from_fn(move || {
+            while let Some(node) = cur {
+                cur = node.next.as_deref();
+                if node.alive.get() { return Some(&node.value); }
+            }
+            None
+        })
+    }
+
     pub fn clear(&mut self) {
         self.head = None;
     }
 
+    // Whether there are no live values.
     pub fn is_empty(&self) -> bool {
-        self.head.is_none()
+        let mut cur = self.head.as_ref();
+        while let Some(node) = cur {
+            if node.alive.get() { return false; }
+            cur = node.next.as_ref();
+        }
+        true
     }
 }
 
@@ -0,0 +1,142 @@
+use std::hash::BuildHasher;
+
+use hashbrown::{DefaultHashBuilder, HashSet, HashTable};
+
+use crate::blob::{keyval_state::KeyValState, UnsafeIterator};
+
+// A key numbered by an `Interner`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KeyId(u32);
+
+impl KeyId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// The keys on which an automaton listens, numbered in the order of their first appearance, so
+// that the onion and the simulation hash and compare small integers instead of byte strings. The
+// table is built once per loaded blob and then only read, which lets all the layers of an onion
+// (and the simulations of its children) share it; the other keys simply have no ID.
+//
+// A key is hashed once per lookup, see `hash` and `find`: the onion keeps the hash of a key without
+// an ID for its own tables.
+#[derive(Debug, Default, Clone)]
+pub struct Interner<'a> {
+    hasher: DefaultHashBuilder,
+    // The IDs, hashed by their keys.
+    ids: HashTable<KeyId>,
+    keys: Vec<&'a [u8]>,
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl<'a> Interner<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The keys of the transitions of all states reachable from the given ones.
+    pub unsafe fn of_states<'b, I: IntoIterator<Item = &'b KeyValState<'a>>>(states: I) -> Self
+        where 'a: 'b
+    {
+        let mut interner = Self::new();
+        let mut frontier = states.into_iter()
+            .map(|state| state as *const KeyValState<'a>)
+            .collect::<Vec<_>>();
+        let mut visited = frontier.iter().copied().collect::<HashSet<_>>();
+        while let Some(state) = frontier.pop() {
+            let mut keyvals = (*state).keyvals();
+            while let Some((key, tran)) = keyvals.next() {
+                interner.intern(key);
+                tran.finals().for_each_leaf(&mut |leaf| {
+                    for right in leaf.states() {
+                        if visited.insert(right.get()) { frontier.push(right.get()); }
+                    }
+                });
+            }
+        }
+        interner
+    }
+
+    pub fn intern(&mut self, key: &'a [u8]) -> KeyId {
+        let hash = self.hash(key);
+        if let Some(id) = self.find(hash, key) { return id; }
+        let id = KeyId(u32::try_from(self.keys.len()).expect("too many keys to intern"));
+        let (hasher, keys) = (&self.hasher, &self.keys);
+        self.ids.insert_unique(hash, id, |id| hasher.hash_one(keys[id.index()]));
+        self.keys.push(key);
+        id
+    }
+
+    pub fn id(&self, key: &[u8]) -> Option<KeyId> {
+        self.find(self.hash(key), key)
+    }
+
+    pub fn hash(&self, key: &[u8]) -> u64 {
+        self.hasher.hash_one(key)
+    }
+
+    // `id` with the hash of the key already computed by `hash`.
+    pub fn find(&self, hash: u64, key: &[u8]) -> Option<KeyId> {
+        self.ids.find(hash, |id| self.keys[id.index()] == key).copied()
+    }
+
+    // None if the ID comes from another interner with more keys. The ID of another interner with
+    // fewer keys gives some unrelated key, so the IDs must not be mixed.
+    pub fn key(&self, id: KeyId) -> Option<&'a [u8]> {
+        self.keys.get(id.index()).copied()
+    }
+
+    // The keys in the order of their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.keys.iter().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::{tests::TestU8BuildConfig, vec::BlobVec, vec_of_vecs::VecOfVecs, BlobPtr};
+    use crate::keyval_nfa::{Cmd, Msg, Parser};
+
+    use super::*;
+
+    #[test]
+    fn interner() {
+        let mut interner = Interner::new();
+        let a = interner.intern(b"a");
+        let b = interner.intern(b"b");
+        assert_eq!(interner.intern(b"a"), a);
+        assert_ne!(a, b);
+        assert_eq!((interner.id(b"b"), interner.id(b"c")), (Some(b), None));
+        assert_eq!(interner.key(b), Some(b"b".as_ref()));
+        assert_eq!(interner.key(KeyId(2)), None);
+        assert_eq!(interner.find(interner.hash(b"a"), b"a"), Some(a));
+        assert_eq!(interner.len(), 2);
+
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"defaults": {"d": "1"}},
+            {"when": {"foo": "a", "bar": "b"}, "run": ["ab"]},
+            {"when": {"qux": "c"}, "run": ["c"]}
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let aut = msg.get_automaton();
+        let inits: &BlobVec<BlobPtr<KeyValState>> = unsafe { aut.a.behind::<VecOfVecs<u8>>()
+            .behind::<BlobVec<u64>>().behind() };
+        let interner = unsafe { Interner::of_states(inits.as_ref().iter().map(|x| &*x.get())) };
+        let mut keys = (0..interner.len() as u32).map(|ix| interner.key(KeyId(ix)).unwrap())
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec![b"bar".as_ref(), b"foo", b"qux"]);
+    }
+}
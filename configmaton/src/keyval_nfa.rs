@@ -1,34 +1,54 @@
 use hashbrown::HashMap;
-use hashbrown::HashSet;
+use indexmap::{IndexMap, IndexSet};
+use std::cell::RefCell;
+use std::io;
 use std::io::Write;
+use std::path::Path;
 use std::fmt;
 
 use serde::de::{MapAccess, Visitor, Deserialize, Deserializer, Error, Unexpected};
 use serde_json;
 use serde_json::Value;
+use twox_hash::XxHash64;
 
 use crate::ast;
-use crate::blob::align_up_mut_ptr;
-use crate::blob::automaton::Automaton;
-use crate::blob::automaton::ExtsAndAut;
-use crate::blob::automaton::InitsAndStates;
-use crate::blob::automaton::States;
+use crate::partition;
+use crate::ast::{MatchMode, RegexOptions};
+use crate::blob::{align_up_mut_ptr, BlobPtr};
+use crate::blob::automaton::{Automaton, Section, TagRule};
 use crate::blob::bdd::BddOrigin;
 use crate::blob::keyval_state::KeyValState;
 use crate::blob::keyval_state::LeafOrigin;
+use crate::blob::keyval_state::NumGuard;
 use crate::blob::keyval_state::StateOrigin;
 use crate::blob::keyval_state::TranOrigin;
-use crate::blob::keyval_state::Bytes;
+use crate::blob::memmap::MemoryMap;
+use crate::blob::reloc;
+use crate::blob::root::{BlobError, BlobHeader, BlobRoot, TargetLayout};
 use crate::blob::sediment::Sediment;
+use crate::blob::vec_of_vecs::VecOfVecs;
 use crate::blob::state::build::U8BuildConfig;
 use crate::blob::state::U8State;
 use crate::blob::state::U8StatePrepared;
+use crate::blob::state::{deserialize_tag_pool, reserve_tag_pool, serialize_tag_pool, tag_sets};
 use crate::blob::vec::BlobVec;
 use crate::blob::BuildCursor;
+use crate::blob::CursorResult;
+use crate::blob::BuildError;
+use crate::blob::context::{CtxPair, KeyValStatePtrs, TagSetPtrs, U8StatePtrs};
+use crate::blob::check_indices;
 use crate::blob::Reserve;
 use crate::blob::Shifter;
+use crate::blob::{Target, Targets};
+use crate::blob::Swap;
 use crate::char_enfa;
 use crate::char_nfa;
+use crate::normalize::Normalizer;
+use crate::numeric::NumRange;
+use crate::rename::KeyRenames;
+use crate::pattern_cache::PatternCache;
+#[cfg(all(unix, feature = "shm"))]
+use crate::shm::SharedSegment;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct StateIx (pub usize);
@@ -37,23 +57,42 @@ pub struct DfaIx (pub usize);
 #[derive(Debug, Clone, Copy)]
 pub struct DfaStateIx (pub usize);
 
+// The states, getolds and (ext, rule) pairs are deduplicated in the order of their first
+// occurrence, so that the compiled leaves (and so the blob) are reproducible.
 pub fn join_leaves<I: Iterator<Item=LeafOrigin>>(targets: I) -> LeafOrigin {
-    let mut states = HashSet::new();
-    let mut get_olds = HashSet::new();
-    let mut exts = HashSet::new();
+    let mut states = IndexSet::new();
+    let mut get_olds = IndexSet::new();
+    let mut exts = IndexSet::new();
     for target in targets {
-        states.extend(target.states.into_iter());
-        get_olds.extend(target.get_olds.into_iter());
-        exts.extend(target.exts.into_iter());
+        states.extend(target.states);
+        get_olds.extend(target.get_olds);
+        exts.extend(target.exts.into_iter().zip(target.rules));
     }
+    // Only the leaves firing a single rule belong to its group, so joined leaves have none.
+    let (exts, rules) = exts.into_iter().unzip();
     LeafOrigin {
-        exts: exts.into_iter().collect(),
+        exts,
+        rules,
         get_olds: get_olds.into_iter().collect(),
         states: states.into_iter().collect(),
+        group: vec![],
     }
 }
 
-fn bytes_as_string(bytes: &[u8]) -> String {
+// The transition of a guard into `then` if the value matches its pattern (or does not match, if
+// it is negated), otherwise into `else_`.
+fn guard_bdd(var: usize, negated: bool, then: LeafOrigin, else_: LeafOrigin)
+    -> BddOrigin<usize, LeafOrigin>
+{
+    let (pos, neg) = if negated { (else_, then) } else { (then, else_) };
+    BddOrigin::NodeBothOwned {
+        var,
+        pos: Box::new(BddOrigin::Leaf(pos)),
+        neg: Box::new(BddOrigin::Leaf(neg)),
+    }
+}
+
+pub(crate) fn bytes_as_string(bytes: &[u8]) -> String {
     bytes.iter().map(|b|
         if b.is_ascii_graphic()
             { char::from(*b).to_string() }
@@ -62,211 +101,661 @@ fn bytes_as_string(bytes: &[u8]) -> String {
     ).collect()
 }
 
-fn fmte(exts: &Vec<Vec<u8>>, get_olds: &Vec<Vec<u8>>) -> String {
+pub(crate) fn fmte(exts: &[Vec<u8>], get_olds: &[Vec<u8>]) -> String {
     exts.iter().map(|ext| bytes_as_string(ext)).chain(
         get_olds.iter().map(|old| format!("GetOld({})", bytes_as_string(old)))
     ).collect::<Vec<_>>().join(", ").replace("\\", "\\\\").replace("\"", "\\\"")
 }
 
-pub fn to_dot
-    <F: FnMut(String)>
-    (bdd: &BddOrigin<usize, LeafOrigin>, bix: &mut usize, tix: &mut usize, write: &mut F)
-    -> String
-{
-    let mut visited = HashMap::new();
-    match bdd {
-        BddOrigin::Leaf(target) => {
-            let me = format!("t{}", tix);
-            write(format!("  t{} [ shape=\"square\" ]\n", tix));
-            write(format!("  e{} [ shape=\"diamond\" ]\n", tix));
-            write(format!("  t{} -> e{} [label=\"{}\"]\n",
-                tix, tix, fmte(&target.exts, &target.get_olds)));
-            for state in target.states.iter()
-                { write(format!("  e{} -> q{}\n", tix, state)); }
-            *tix += 1;
-            me
-        }
-        _ => {
-            let dtag = bdd.get_var();
-            let pos = unsafe { bdd.get_pos() };
-            let neg = unsafe { bdd.get_neg() };
-            let me = format!("b{}", bix);
-            write(format!("  {} [ shape=\"diamond\", label=\"{}\" ]\n", me, dtag));
-            *bix += 1;
-            let pos = visited.entry(pos as *const _)
-                .or_insert_with(|| to_dot(pos, bix, tix, write));
-            write(format!("  {} -> {} [ color=green{} ]\n", me, pos,
-                if bdd.owns_pos() { ", penwidth=2" } else { "" }));
-            let neg = visited.entry(neg as *const _)
-                .or_insert_with(|| to_dot(neg, bix, tix, write));
-            write(format!("  {} -> {} [ color=red{} ]\n", me, neg,
-                if bdd.owns_neg() { ", penwidth=2" } else { "" }));
-            me
+// Limits of the compilation, so that a hostile or accidental config cannot exhaust the memory of
+// the service compiling it. The default is unlimited.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileBudget {
+    pub max_ast_nodes: usize,
+    pub max_nfa_states: usize,
+    pub max_blob_size: usize,
+}
+
+impl Default for CompileBudget {
+    fn default() -> Self {
+        CompileBudget {
+            max_ast_nodes: usize::MAX,
+            max_nfa_states: usize::MAX,
+            max_blob_size: usize::MAX,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BudgetExceeded {
+    AstNodes { regex: String, nodes: usize, limit: usize },
+    NfaStates { regex: String, limit: usize },
+    BlobSize { size: usize, limit: usize },
+    // Not a budget as such, but the budgeted parsing does not panic on an invalid regex either.
+    InvalidRegex { regex: String, error: ast::RegexError },
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BudgetExceeded::AstNodes { regex, nodes, limit } =>
+                write!(f, "regex {:?} has {} nodes, the limit is {}", regex, nodes, limit),
+            BudgetExceeded::NfaStates { regex, limit } =>
+                write!(f, "regex {:?} exceeds the limit of {} NFA states", regex, limit),
+            BudgetExceeded::BlobSize { size, limit } =>
+                write!(f, "the blob has {} bytes, the limit is {}", size, limit),
+            BudgetExceeded::InvalidRegex { regex, error } =>
+                write!(f, "invalid regex {:?}: {}", regex, error),
+        }
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+fn parse_regex(regex: &str, options: RegexOptions) -> Result<ast::Ast, BudgetExceeded> {
+    let (ast, errors) = ast::parse_regex_recovering_in(regex, options);
+    match errors.into_iter().next() {
+        None => Ok(ast),
+        Some(error) => Err(BudgetExceeded::InvalidRegex { regex: regex.to_owned(), error }),
+    }
+}
+
+// The rules (matches) are numbered by `rule_count` in the order of the config, a rule before its
+// nested rules. The rules of the skipped profiles are not numbered.
 pub struct Parser {
     pub states: Vec<StateOrigin>,
+    // The rule whose conditions each of the states checks, in the order of `states`.
+    pub state_rules: Vec<usize>,
     pub nfa: char_nfa::Nfa,
     pub regexes: HashMap<String, (DfaStateIx, DfaIx)>,
+    // The number of DFA tags (DfaIx) used, not all of them have to be in `regexes` after merging.
+    tag_count: usize,
+    pub rule_count: usize,
+    // The key-values set initially, see `Cmd::Defaults`.
+    pub defaults: Vec<(Vec<u8>, Vec<u8>)>,
+    // The normalizers of the keys, see `Cmd::Normalize`.
+    pub normalizers: IndexMap<Vec<u8>, Normalizer>,
+    // The (key, pattern ID, regex) triples of the conditions, the pattern ID being the DfaIx. The
+    // regex of a numeric guard is its range as in the config, see `Condition`.
+    pub patterns: IndexSet<(Vec<u8>, usize, String)>,
+    // The rule and the index in `patterns` of each condition, with its pattern ID as the tag.
+    pub tag_rules: IndexSet<TagRule>,
+    // Store the regexes of the `patterns` in the blob, not only the keys and the IDs.
+    pub embed_patterns: bool,
+    // The `profiles` sections of this profile are included, the other ones are skipped.
+    profile: Option<String>,
+    // Compiled patterns are linked from here instead of being compiled again.
+    cache: Option<PatternCache>,
+    budget: CompileBudget,
 }
 
 impl Parser {
     pub fn parse(cmds: Vec<Cmd>) -> (Self, LeafOrigin) {
+        Self::parse_profile(cmds, None)
+    }
+
+    pub fn parse_profile(cmds: Vec<Cmd>, profile: Option<&str>) -> (Self, LeafOrigin) {
+        Self::parse_with(cmds, profile, None, CompileBudget::default())
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Parse with the patterns taken from (and added to) the cache, get it back by `take_cache`.
+    pub fn parse_cached(cmds: Vec<Cmd>, profile: Option<&str>, cache: PatternCache)
+        -> (Self, LeafOrigin)
+    {
+        Self::parse_with(cmds, profile, Some(cache), CompileBudget::default())
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Fail as soon as a pattern is invalid or exceeds the AST or the NFA limit of the budget.
+    pub fn parse_budgeted(cmds: Vec<Cmd>, profile: Option<&str>, budget: CompileBudget)
+        -> Result<(Self, LeafOrigin), BudgetExceeded>
+    {
+        Self::parse_with(cmds, profile, None, budget)
+    }
+
+    fn parse_with(
+        cmds: Vec<Cmd>,
+        profile: Option<&str>,
+        cache: Option<PatternCache>,
+        budget: CompileBudget,
+    ) -> Result<(Self, LeafOrigin), BudgetExceeded>
+    {
         let mut parser = Parser {
             states: vec![],
+            state_rules: vec![],
             nfa: char_nfa::Nfa::new(),
             regexes: HashMap::new(),
+            tag_count: 0,
+            rule_count: 0,
+            defaults: vec![],
+            normalizers: IndexMap::new(),
+            patterns: IndexSet::new(),
+            tag_rules: IndexSet::new(),
+            embed_patterns: false,
+            profile: profile.map(str::to_owned),
+            cache,
+            budget,
         };
-        let init = parser.parse_parallel(cmds);
+        let init = parser.parse_parallel(cmds, &None)?;
 
-        (parser, init)
+        Ok((parser, init))
     }
 
-    fn parse_parallel(&mut self, cmds: Vec<Cmd>) -> LeafOrigin {
+    pub fn take_cache(&mut self) -> Option<PatternCache> {
+        self.cache.take()
+    }
+
+    // Determinize the pattern automata one by one. A pattern whose DFA would have more than
+    // `max_states` states is kept as an NFA, the regexes of such patterns are returned (sorted) so
+    // that they can be rewritten. The states no longer reachable from the patterns are dropped.
+    pub fn determinize(&mut self, max_states: usize) -> Vec<String> {
+        let mut inits = self.regexes.values().map(|(dfa_state_ix, _)| dfa_state_ix.0)
+            .chain(self.states.iter()
+                .flat_map(|state| state.transitions.iter())
+                .flat_map(|tran| tran.dfa_inits.iter().copied()))
+            .collect::<Vec<_>>();
+        inits.sort_unstable();
+        inits.dedup();
+
+        let mut nfa = char_nfa::Nfa::new();
+        let mut moved = HashMap::new();
+        let mut blown_up = vec![];
+        for init in inits {
+            let pattern = self.nfa.determinize_bounded(init, max_states).unwrap_or_else(|| {
+                blown_up.extend(self.regexes.iter()
+                    .filter(|(_, (dfa_state_ix, _))| dfa_state_ix.0 == init)
+                    .map(|(regex, _)| regex.clone()));
+                self.nfa.sub_automaton(init)
+            });
+            moved.insert(init, nfa.append(pattern, 0));
+        }
+        self.nfa = nfa;
+
+        for (dfa_state_ix, _) in self.regexes.values_mut() {
+            dfa_state_ix.0 = moved[&dfa_state_ix.0];
+        }
+        for tran in self.states.iter_mut().flat_map(|state| state.transitions.iter_mut()) {
+            for init in tran.dfa_inits.iter_mut() { *init = moved[init]; }
+        }
+        blown_up.sort();
+        blown_up
+    }
+
+    // Link two independently parsed automata into one, which runs both of them side by side. The
+    // states of the second one get renumbered behind those of the first one.
+    pub fn merge((mut a, a_init): (Self, LeafOrigin), (b, mut b_init): (Self, LeafOrigin))
+        -> (Self, LeafOrigin)
+    {
+        let kv_offset = a.states.len();
+        let tag_offset = a.tag_count;
+        let dfa_offset = a.nfa.append(b.nfa, tag_offset);
+
+        let rule_offset = a.rule_count;
+        let mut shift_leaf = |leaf: &mut LeafOrigin| {
+            for state in leaf.states.iter_mut() { *state += kv_offset; }
+            for rule in leaf.rules.iter_mut() { *rule += rule_offset; }
+        };
+        for mut state in b.states {
+            for tran in state.transitions.iter_mut() {
+                for init in tran.dfa_inits.iter_mut() { *init += dfa_offset; }
+                for guard in tran.num_guards.iter_mut() { guard.tag += tag_offset as u64; }
+                tran.bdd.update(&mut |var| *var += tag_offset, &mut shift_leaf);
+            }
+            a.states.push(state);
+        }
+        a.state_rules.extend(b.state_rules.into_iter().map(|rule| rule + rule_offset));
+        shift_leaf(&mut b_init);
+
+        for (regex, (dfa_state_ix, dfa_ix)) in b.regexes {
+            a.regexes.entry(regex).or_insert(
+                (DfaStateIx(dfa_state_ix.0 + dfa_offset), DfaIx(dfa_ix.0 + tag_offset)));
+        }
+        a.tag_count += b.tag_count;
+        a.rule_count += b.rule_count;
+        a.defaults.extend(b.defaults);
+        for (key, normalizer) in b.normalizers {
+            let entry = a.normalizers.entry(key).or_default();
+            *entry = entry.union(normalizer);
+        }
+        let pattern_ixs = b.patterns.into_iter()
+            .map(|(key, id, regex)| a.patterns.insert_full((key, id + tag_offset, regex)).0)
+            .collect::<Vec<_>>();
+        a.tag_rules.extend(b.tag_rules.into_iter().map(|x| TagRule {
+            tag: x.tag + tag_offset as u64,
+            rule: x.rule + rule_offset as u64,
+            pattern: pattern_ixs[x.pattern as usize] as u64,
+            negated: x.negated,
+        }));
+        a.embed_patterns |= b.embed_patterns;
+
+        let init = join_leaves([a_init, b_init].into_iter());
+        (a, init)
+    }
+
+    // Rename the keys of the parsed config: those of the transitions, of the old values fetched by
+    // the leaves, of the defaults, of the normalizers and of the patterns. Unlike renaming them in
+    // the config (`Cmd::rename_keys`), the patterns need not be compiled again.
+    pub fn rename_keys(&mut self, init: &mut LeafOrigin, renames: &KeyRenames) {
+        let mut rename_leaf = |leaf: &mut LeafOrigin| {
+            for key in leaf.get_olds.iter_mut() { renames.rename(key); }
+        };
+        for tran in self.states.iter_mut().flat_map(|state| state.transitions.iter_mut()) {
+            renames.rename(&mut tran.key);
+            tran.bdd.update(&mut |_| {}, &mut rename_leaf);
+        }
+        rename_leaf(init);
+        for (key, _) in self.defaults.iter_mut() { renames.rename(key); }
+        // Keys may get merged by the renames.
+        for (mut key, normalizer) in std::mem::take(&mut self.normalizers) {
+            renames.rename(&mut key);
+            let entry = self.normalizers.entry(key).or_default();
+            *entry = entry.union(normalizer);
+        }
+        let pattern_ixs = std::mem::take(&mut self.patterns).into_iter()
+            .map(|(mut key, id, regex)| {
+                renames.rename(&mut key);
+                self.patterns.insert_full((key, id, regex)).0
+            })
+            .collect::<Vec<_>>();
+        self.tag_rules = std::mem::take(&mut self.tag_rules).into_iter()
+            .map(|x| TagRule { pattern: pattern_ixs[x.pattern as usize] as u64, ..x })
+            .collect();
+    }
+
+    fn parse_parallel(&mut self, cmds: Vec<Cmd>, group: &Option<String>)
+        -> Result<LeafOrigin, BudgetExceeded>
+    {
         let targets = cmds.into_iter().map(|cmd| match cmd {
-            Cmd::Match(match_) => self.parse_match(match_),
+            Cmd::Match(match_) => self.parse_match(match_, group),
+            Cmd::Profiles(mut profiles) => {
+                let cmds = self.profile.as_ref()
+                    .and_then(|profile| profiles.remove(profile))
+                    .unwrap_or_default();
+                self.parse_parallel(cmds, group)
+            }
+            Cmd::Defaults(defaults) => {
+                self.defaults.extend(defaults.into_iter()
+                    .map(|(key, value)| (key.into_bytes(), value.into_bytes())));
+                Ok(LeafOrigin {
+                    exts: vec![], rules: vec![], get_olds: vec![], states: vec![], group: vec![]
+                })
+            }
+            Cmd::Normalize(normalizers) => {
+                for (key, normalizer) in normalizers {
+                    let entry = self.normalizers.entry(key.into_bytes()).or_default();
+                    *entry = entry.union(normalizer);
+                }
+                Ok(LeafOrigin {
+                    exts: vec![], rules: vec![], get_olds: vec![], states: vec![], group: vec![]
+                })
+            }
             _ => unimplemented!(),
-        });
-        join_leaves(targets)
+        }).collect::<Result<Vec<_>, _>>()?;
+        Ok(join_leaves(targets.into_iter()))
     }
 
+    // The nested rules inherit the group. A rule without conditions fires as a part of its
+    // parent, so its group applies only to the nested rules.
     fn parse_match(
         &mut self,
         match_: Match,
-    ) -> LeafOrigin {
-        let mut then = self.parse_parallel(match_.then);
+        group: &Option<String>,
+    ) -> Result<LeafOrigin, BudgetExceeded> {
+        let group = match_.group.as_ref().or(group.as_ref()).cloned();
+        let rule = self.rule_count;
+        self.rule_count += 1;
+        let mut then = self.parse_parallel(match_.then, &group)?;
+        then.rules.extend(std::iter::repeat_n(rule, match_.run.len()));
         then.exts.extend(match_.run);
 
-        if match_.when.is_empty() { return then; }
+        // The negated guards are checked last, each one reaching its `then` if the value does not
+        // match. A key without a value satisfies neither kind of guard.
+        let mut guards = match_.when.into_iter().map(|(key, cond)| (key, cond, false))
+            .chain(match_.when_not.into_iter().map(|(key, cond)| (key, cond, true)))
+            .collect::<Vec<_>>();
+        if guards.is_empty() { return Ok(then); }
+        then.group = group.map(String::into_bytes).unwrap_or_default();
+
+        // A substring (or case insensitive) regex is rewritten to the equivalent plain one, e.g.
+        // `a|^b` to `.*a.*|b.*`, so that it shares the DFA (and the cached pattern) with the same
+        // plain regex. The patterns keep the regex as written.
+        let sources = guards.iter().map(|(_, cond, _)| cond.to_string()).collect::<Vec<_>>();
+        let options = RegexOptions { mode: match_.match_mode, ignore_case: match_.ignore_case };
+        if options != RegexOptions::default() {
+            for (_, cond, _) in guards.iter_mut() {
+                if let Condition::Regex(regex) = cond {
+                    *regex = parse_regex(regex, options)?.to_string();
+                }
+            }
+        }
 
-        let dfa_ixs = match_.when.iter().map(|(_, regex)| {
-            let dfa_ix = self.regexes.len();
-            *self.regexes.entry(regex.clone()).or_insert_with(|| {
-                let dfa_state_ix = self.nfa.states.len();
-                self.nfa.add_nfa(char_enfa::Nfa::from_ast(ast::parse_regex(&regex)), dfa_ix);
-                (DfaStateIx(dfa_state_ix), DfaIx(dfa_ix))
-            })
-        }).collect::<Vec<_>>();
+        // The DFA (none for a numeric guard) and the tag of each guard. Each numeric guard gets a
+        // tag of its own.
+        let mut dfa_ixs = vec![];
+        for (_, cond, _) in guards.iter() {
+            let regex = match cond {
+                Condition::Regex(regex) => regex,
+                Condition::Range(_) => {
+                    dfa_ixs.push((None, DfaIx(self.tag_count)));
+                    self.tag_count += 1;
+                    continue;
+                }
+            };
+            if let Some((dfa_state_ix, dfa_ix)) = self.regexes.get(regex) {
+                dfa_ixs.push((Some(*dfa_state_ix), *dfa_ix));
+                continue;
+            }
+            let dfa_ix = self.tag_count;
+            let ast = parse_regex(regex, RegexOptions::default())?;
+            let nodes = ast.node_count();
+            if nodes > self.budget.max_ast_nodes {
+                let limit = self.budget.max_ast_nodes;
+                return Err(BudgetExceeded::AstNodes { regex: regex.clone(), nodes, limit });
+            }
+            let dfa_state_ix = match self.cache.as_mut() {
+                Some(cache) => cache.get(regex).link(&mut self.nfa, dfa_ix),
+                None => {
+                    let dfa_state_ix = self.nfa.states.len();
+                    self.nfa.add_nfa(char_enfa::Nfa::from_ast(ast), dfa_ix);
+                    dfa_state_ix
+                }
+            };
+            if self.nfa.states.len() > self.budget.max_nfa_states {
+                let limit = self.budget.max_nfa_states;
+                return Err(BudgetExceeded::NfaStates { regex: regex.clone(), limit });
+            }
+            self.tag_count += 1;
+            let ixs = (DfaStateIx(dfa_state_ix), DfaIx(dfa_ix));
+            self.regexes.insert(regex.clone(), ixs);
+            dfa_ixs.push((Some(ixs.0), ixs.1));
+        }
+        for (((key, _, negated), source), (_, dfa_ix)) in
+            guards.iter().zip(sources).zip(dfa_ixs.iter())
+        {
+            let (pattern, _) =
+                self.patterns.insert_full((key.clone().into_bytes(), dfa_ix.0, source));
+            self.tag_rules.insert(TagRule {
+                tag: dfa_ix.0 as u64,
+                rule: rule as u64,
+                pattern: pattern as u64,
+                negated: *negated as u64,
+            });
+        }
 
-        let guard_count = match_.when.len();
-        for ((key, _), (dfa_state_ix, dfa_ix)) in
-            match_.when[..guard_count - 1].into_iter().zip(dfa_ixs.iter()).rev()
+        let guard_count = guards.len();
+        for ((key, cond, negated), (dfa_state_ix, dfa_ix)) in
+            guards[..guard_count - 1].iter().zip(dfa_ixs.iter()).rev()
         {
             let state_ix = self.states.len();
             let else_ = LeafOrigin {
-                exts: vec![], get_olds: vec![], states: vec![state_ix + guard_count]
+                exts: vec![],
+                rules: vec![],
+                get_olds: vec![],
+                states: vec![state_ix + guard_count],
+                group: vec![],
             };
             self.states.push(StateOrigin { transitions: vec![TranOrigin {
                 key: key.clone().into_bytes(),
-                dfa_inits: vec![dfa_state_ix.0],
-                bdd: BddOrigin::NodeBothOwned {
-                    var: dfa_ix.0,
-                    pos: Box::new(BddOrigin::Leaf(then)),
-                    neg: Box::new(BddOrigin::Leaf(else_)),
-                }
+                dfa_inits: dfa_state_ix.iter().map(|ix| ix.0).collect(),
+                num_guards: cond.num_guard(dfa_ix.0).into_iter().collect(),
+                bdd: guard_bdd(dfa_ix.0, *negated, then, else_),
             }]});
+            self.state_rules.push(rule);
             then = LeafOrigin {
                 exts: vec![],
+                rules: vec![],
                 get_olds: vec![key.clone().into_bytes()],
                 states: vec![state_ix],
+                group: vec![],
             };
         }
 
-        for ((key, _), (dfa_state_ix, dfa_ix)) in
-            match_.when[..guard_count].into_iter().zip(dfa_ixs.iter()).rev()
+        for ((key, cond, negated), (dfa_state_ix, dfa_ix)) in
+            guards[..guard_count].iter().zip(dfa_ixs.iter()).rev()
         {
             let state_ix = self.states.len();
-            let else_ = LeafOrigin
-                { exts: vec![], get_olds: vec![], states: vec![state_ix] };
+            let else_ = LeafOrigin {
+                exts: vec![], rules: vec![], get_olds: vec![], states: vec![state_ix], group: vec![]
+            };
             self.states.push(StateOrigin { transitions: vec![TranOrigin {
                 key: key.clone().into_bytes(),
-                dfa_inits: vec![dfa_state_ix.0],
-                bdd: BddOrigin::NodeBothOwned {
-                    var: dfa_ix.0,
-                    pos: Box::new(BddOrigin::Leaf(then)),
-                    neg: Box::new(BddOrigin::Leaf(else_))
-                }
+                dfa_inits: dfa_state_ix.iter().map(|ix| ix.0).collect(),
+                num_guards: cond.num_guard(dfa_ix.0).into_iter().collect(),
+                bdd: guard_bdd(dfa_ix.0, *negated, then, else_),
             }]});
+            self.state_rules.push(rule);
 
             then = LeafOrigin {
                 exts: vec![],
+                rules: vec![],
                 get_olds: vec![key.clone().into_bytes()],
                 states: vec![state_ix],
+                group: vec![],
             };
         }
 
-        then
+        Ok(then)
     }
 
-    pub fn to_dot<W: Write>(&self, init: &LeafOrigin, mut writer: W) {
-        writer.write_all(b"digraph G {\n").unwrap();
-
-        let mut write = |x: String| writer.write_all(x.as_bytes()).unwrap();
-
-        for i in 0..self.states.len() {
-            write(format!("  q{}\n", i));
-        }
-
-        // println!("~~~ {:?} ~~~> {:?}", init.exts, init.states);
-        write("  ti [ shape=\"square\" ]\n".to_owned());
-        write("  ei [ shape=\"diamond\" ]\n".to_owned());
-
-        write(format!("  ti -> ei [label=\"{}\"]\n", fmte(&init.exts, &init.get_olds)));
-        for state in init.states.iter() {
-            write(format!("  ei -> q{}\n", state));
-        }
-
-        {
-            let mut tix = 0;
-            let mut gix = 0;
-            let mut bix = 0;
-            for (qix, state) in self.states.iter().enumerate() {
-                for tran in state.transitions.iter() {
-                    write(format!("  g{} [ shape=\"diamond\" ]\n", gix));
-                    write(format!("  q{} -> g{} [label=\"{}\"]\n",
-                        qix, gix, bytes_as_string(&tran.key)));
-
-                    for dix in tran.dfa_inits.iter() {
-                        write(format!("  g{} -> d{} [color=\"blue\"]\n", gix, dix));
-                    }
-
-                    let root = to_dot(&tran.bdd, &mut bix, &mut tix, &mut write);
+    // Export the whole automaton in the dot format, see `write_dot` for the options.
+    pub fn to_dot<W: Write>(&self, init: &LeafOrigin, writer: W) -> std::io::Result<()> {
+        crate::dot::write_dot(self, init, &crate::dot::DotOptions::default(), writer).map(|_| ())
+    }
+}
 
-                    write(format!("  g{} -> {}\n", gix, root));
+// A BDD of a transition in preorder, with the states of the leaves replaced by their classes, so
+// that the transitions of the states are compared by `optimize`.
+#[derive(PartialEq, Eq, Hash)]
+enum BddToken<'a> {
+    Var(usize),
+    Leaf(Vec<usize>, &'a [Vec<u8>], &'a [Vec<u8>], &'a [usize], &'a [u8]),
+}
 
-                    gix += 1;
-                }
-            }
-        }
+fn bdd_tokens<'a>(
+    bdd: &'a BddOrigin<usize, LeafOrigin>, classes: &[usize], tokens: &mut Vec<BddToken<'a>>,
+) {
+    match bdd {
+        BddOrigin::Leaf(leaf) => {
+            let states = leaf.states.iter().map(|q| classes[*q]).collect::<IndexSet<_>>();
+            tokens.push(BddToken::Leaf(states.into_iter().collect(),
+                &leaf.get_olds, &leaf.exts, &leaf.rules, &leaf.group));
+        },
+        _ => unsafe {
+            tokens.push(BddToken::Var(*bdd.get_var()));
+            bdd_tokens(bdd.get_pos(), classes, tokens);
+            bdd_tokens(bdd.get_neg(), classes, tokens);
+        },
+    }
+}
 
-        for (dix, state) in self.nfa.states.iter().enumerate() {
-            write(format!("  d{} [label=\"d{}", dix, dix));
-            for tag in state.tags.0.iter() { write(format!(" {}", tag)); }
-            write("\"]\n".to_owned());
+// Shrink the parsed automaton before it is serialized. The equivalent pattern states are merged
+// (see `Nfa::minimize`) and so are the keyval states with the same transitions into the same
+// (merged) states, the latter ones being found by refining a single class the same way.
+pub fn optimize(parser: &mut Parser, init: &mut LeafOrigin) {
+    let mut inits = parser.regexes.values().map(|(dfa_state_ix, _)| dfa_state_ix.0)
+        .chain(parser.states.iter()
+            .flat_map(|state| state.transitions.iter())
+            .flat_map(|tran| tran.dfa_inits.iter().copied()))
+        .collect::<Vec<_>>();
+    inits.sort_unstable();
+    inits.dedup();
+    let moved = parser.nfa.minimize(&inits);
+    for (dfa_state_ix, _) in parser.regexes.values_mut() {
+        dfa_state_ix.0 = moved[&dfa_state_ix.0];
+    }
+    for tran in parser.states.iter_mut().flat_map(|state| state.transitions.iter_mut()) {
+        let dfa_inits = tran.dfa_inits.iter().map(|init| moved[init]).collect::<IndexSet<_>>();
+        tran.dfa_inits = dfa_inits.into_iter().collect();
+    }
 
-            for (guard, state) in state.transitions.iter() {
-                write(format!("  d{} -> d{} [label=\"{:?}\"]\n", dix, state, guard));
-            }
+    let mut preds = vec![vec![]; parser.states.len()];
+    for (ix, state) in parser.states.iter().enumerate() {
+        for tran in state.transitions.iter() {
+            let _ = unsafe { tran.bdd.for_each_leaf(&mut |leaf| {
+                for q in leaf.states.iter() { preds[*q].push(ix); }
+                Ok::<_, ()>(())
+            }) };
         }
-
-        writer.write_all(b"}\n").unwrap();
     }
+    let classes = partition::refine(&vec![0; parser.states.len()], &preds, |ix, classes| {
+        parser.states[ix].transitions.iter().map(|tran| {
+            let mut bdd = vec![];
+            bdd_tokens(&tran.bdd, classes, &mut bdd);
+            let num_guards = tran.num_guards.iter()
+                .map(|guard| (guard.tag, guard.range.min.to_bits(), guard.range.max.to_bits()))
+                .collect::<Vec<_>>();
+            (&tran.key, &tran.dfa_inits, num_guards, bdd)
+        }).collect::<Vec<_>>()
+    });
+
+    // The first state of each class represents it.
+    let mut states = std::mem::take(&mut parser.states).into_iter().map(Some).collect::<Vec<_>>();
+    let state_rules = std::mem::take(&mut parser.state_rules);
+    for (ix, class) in classes.iter().enumerate() {
+        if *class < parser.states.len() { continue; }
+        parser.states.push(states[ix].take().unwrap());
+        parser.state_rules.push(state_rules[ix]);
+    }
+    let mut merge_leaf = |leaf: &mut LeafOrigin| {
+        let states = leaf.states.iter().map(|q| classes[*q]).collect::<IndexSet<_>>();
+        leaf.states = states.into_iter().collect();
+    };
+    for tran in parser.states.iter_mut().flat_map(|state| state.transitions.iter_mut()) {
+        tran.bdd.update(&mut |_| {}, &mut merge_leaf);
+    }
+    merge_leaf(init);
 }
 
 #[derive(Debug)]
 pub enum Cmd {
     Match(Match),
+    // Commands included only when parsing for the given profile.
+    Profiles(std::collections::HashMap<String, Vec<Cmd>>),
+    // Key-values set when the automaton is instantiated, later ones overriding earlier ones. They
+    // are unconditional, so they are rejected in the `then` of a match (see `Cmd::unconditional`).
+    Defaults(Vec<(String, String)>),
+    // Normalizers of the values of the keys, applied before the values are matched. The
+    // normalizers declared for the same key accumulate. Like the defaults, they are unconditional.
+    Normalize(Vec<(String, Normalizer)>),
     Label(String, Vec<Cmd>),  // No support yet.
     Goto(String),  // No support yet.
 }
 
 #[derive(Debug, serde::Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub struct Match {
-    when: Vec<(String, String)>,
+    when: Vec<(String, Condition)>,
+    // Guards satisfied by the values which do not match their conditions. Like the guards of
+    // `when`, they wait for their keys: a key without a value (never set, or unset) satisfies no
+    // guard, so `{"when_not": {"env": "prod"}}` fires only once `env` is set to something else.
+    #[serde(default)]
+    when_not: Vec<(String, Condition)>,
     run: Vec<Vec<u8>>,
     then: Vec<Cmd>,
+    group: Option<String>,
+    // Of the regexes of `when`, see `MatchMode`.
+    #[serde(default)]
+    match_mode: MatchMode,
+    // Of the regexes of `when`, like a `(?i)` in front of each.
+    #[serde(default)]
+    ignore_case: bool,
+}
+
+// What the value of a guard is checked against: a regex (a string in the config), or a numeric
+// range, e.g. `{"range": [1024, 65535]}`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum Condition {
+    Regex(String),
+    Range(NumRange),
+}
+
+impl Condition {
+    fn num_guard(&self, tag: usize) -> Option<NumGuard> {
+        match self {
+            Condition::Regex(_) => None,
+            Condition::Range(range) => Some(NumGuard { tag: tag as u64, range: *range }),
+        }
+    }
+}
+
+// The regex, or the range as in the config, as the source of the pattern.
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Regex(regex) => f.write_str(regex),
+            Condition::Range(range) => range.fmt(f),
+        }
+    }
+}
+
+impl Cmd {
+    // The name of the unconditional command (possibly nested in profiles or labels), which would
+    // take effect even if nested in a match.
+    fn unconditional(&self) -> Option<&'static str> {
+        match self {
+            Cmd::Defaults(_) => Some("defaults"),
+            Cmd::Normalize(_) => Some("normalize"),
+            Cmd::Profiles(profiles) => profiles.values().flatten().find_map(Cmd::unconditional),
+            Cmd::Label(_, cmds) => cmds.iter().find_map(Cmd::unconditional),
+            Cmd::Match(_) | Cmd::Goto(_) => None,
+        }
+    }
+
+    // The regexes of the command and of its nested commands, in all profiles.
+    pub fn regexes(&self) -> Vec<&str> {
+        match self {
+            Cmd::Match(match_) => match_.when.iter().chain(match_.when_not.iter())
+                .filter_map(|(_, cond)| match cond {
+                    Condition::Regex(regex) => Some(regex.as_str()),
+                    Condition::Range(_) => None,
+                })
+                .chain(match_.then.iter().flat_map(Cmd::regexes))
+                .collect(),
+            Cmd::Profiles(profiles) => profiles.values().flatten().flat_map(Cmd::regexes).collect(),
+            Cmd::Label(_, cmds) => cmds.iter().flat_map(Cmd::regexes).collect(),
+            Cmd::Defaults(_) | Cmd::Normalize(_) | Cmd::Goto(_) => vec![],
+        }
+    }
+
+    // Rename the keys of the conditions, the defaults and the normalizers of the command and of
+    // its nested commands, in all profiles.
+    pub fn rename_keys(&mut self, renames: &KeyRenames) {
+        match self {
+            Cmd::Match(match_) => {
+                for (key, _) in match_.when.iter_mut().chain(match_.when_not.iter_mut()) {
+                    renames.rename_str(key);
+                }
+                for cmd in match_.then.iter_mut() { cmd.rename_keys(renames); }
+            }
+            Cmd::Profiles(profiles) => {
+                for cmd in profiles.values_mut().flatten() { cmd.rename_keys(renames); }
+            }
+            Cmd::Defaults(defaults) => {
+                for (key, _) in defaults.iter_mut() { renames.rename_str(key); }
+            }
+            Cmd::Normalize(normalizers) => {
+                for (key, _) in normalizers.iter_mut() { renames.rename_str(key); }
+            }
+            Cmd::Label(_, cmds) => { for cmd in cmds.iter_mut() { cmd.rename_keys(renames); } }
+            Cmd::Goto(_) => {}
+        }
+    }
+}
+
+// Only the supported commands, so that fuzzing explores the parser rather than `unimplemented!`.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Cmd {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=8)? {
+            0 => Cmd::Profiles(u.arbitrary()?),
+            1 => Cmd::Defaults(u.arbitrary()?),
+            2 => Cmd::Normalize(u.arbitrary()?),
+            _ => Cmd::Match(u.arbitrary()?),
+        })
+    }
 }
 
 struct CmdVisitor;
@@ -283,38 +772,28 @@ impl<'de> Visitor<'de> for CmdVisitor {
         V: MapAccess<'de>,
     {
         let mut when = None;
+        let mut when_not = None;
         let mut run: Option<Vec<String>> = None;
         let mut then = None;
+        let mut group = None;
+        let mut match_mode = None;
+        let mut ignore_case = None;
+        let mut profiles = None;
+        let mut defaults = None;
+        let mut normalize = None;
         while let Some(key) = map.next_key()? {
             match key {
                 "when" => {
                     if when.is_some() {
                         return Err(Error::duplicate_field("when"));
                     }
-                    let when_map: Value = map.next_value()?;
-                    match when_map {
-                        Value::Object(obj) => {
-                            let mut when_map = vec![];
-                            for (key, value) in obj {
-                                match value {
-                                    Value::String(value) => when_map.push((key, value)),
-                                    _ => return Err(
-                                        Error::invalid_type(
-                                            Unexpected::Other("match value is not a string"),
-                                            &"a string (regex)"
-                                        )
-                                    ),
-                                }
-                            }
-                            when = Some(when_map);
-                        },
-                        _ => return Err(
-                            Error::invalid_type(
-                                Unexpected::Other("match is not an object"),
-                                &"an object of key-regex pairs"
-                            )
-                        ),
+                    when = Some(guards(map.next_value()?)?);
+                }
+                "when_not" => {
+                    if when_not.is_some() {
+                        return Err(Error::duplicate_field("when_not"));
                     }
+                    when_not = Some(guards(map.next_value()?)?);
                 }
                 "run" => {
                     if run.is_some() {
@@ -326,20 +805,164 @@ impl<'de> Visitor<'de> for CmdVisitor {
                     if then.is_some() {
                         return Err(Error::duplicate_field("then"));
                     }
-                    then = Some(map.next_value()?);
+                    let cmds: Vec<Cmd> = map.next_value()?;
+                    if let Some(name) = cmds.iter().find_map(Cmd::unconditional) {
+                        return Err(Error::custom(format!("{} cannot be nested in a match", name)));
+                    }
+                    then = Some(cmds);
+                }
+                "group" => {
+                    if group.is_some() {
+                        return Err(Error::duplicate_field("group"));
+                    }
+                    group = Some(map.next_value()?);
+                }
+                "match_mode" => {
+                    if match_mode.is_some() {
+                        return Err(Error::duplicate_field("match_mode"));
+                    }
+                    let name: String = map.next_value()?;
+                    let Some(mode) = MatchMode::from_name(&name) else {
+                        return Err(Error::unknown_variant(&name, &["full", "substring"]));
+                    };
+                    match_mode = Some(mode);
+                }
+                "ignore_case" => {
+                    if ignore_case.is_some() {
+                        return Err(Error::duplicate_field("ignore_case"));
+                    }
+                    ignore_case = Some(map.next_value()?);
+                }
+                "profiles" => {
+                    if profiles.is_some() {
+                        return Err(Error::duplicate_field("profiles"));
+                    }
+                    profiles = Some(map.next_value()?);
+                }
+                "defaults" => {
+                    if defaults.is_some() {
+                        return Err(Error::duplicate_field("defaults"));
+                    }
+                    let defaults_map: Value = map.next_value()?;
+                    let Value::Object(obj) = defaults_map else {
+                        return Err(Error::invalid_type(
+                            Unexpected::Other("defaults are not an object"),
+                            &"an object of key-value pairs"
+                        ));
+                    };
+                    let mut defaults_map = vec![];
+                    for (key, value) in obj {
+                        let Value::String(value) = value else {
+                            return Err(Error::invalid_type(
+                                Unexpected::Other("default value is not a string"),
+                                &"a string"
+                            ));
+                        };
+                        defaults_map.push((key, value));
+                    }
+                    defaults = Some(defaults_map);
+                }
+                "normalize" => {
+                    if normalize.is_some() {
+                        return Err(Error::duplicate_field("normalize"));
+                    }
+                    let normalize_map: Value = map.next_value()?;
+                    let Value::Object(obj) = normalize_map else {
+                        return Err(Error::invalid_type(
+                            Unexpected::Other("normalize is not an object"),
+                            &"an object of key-normalizers pairs"
+                        ));
+                    };
+                    let mut normalize_map = vec![];
+                    for (key, names) in obj {
+                        let Value::Array(names) = names else {
+                            return Err(Error::invalid_type(
+                                Unexpected::Other("normalizers are not an array"),
+                                &"an array of normalizer names"
+                            ));
+                        };
+                        let mut normalizer = Normalizer::default();
+                        for name in names {
+                            let Some(name) = name.as_str() else {
+                                return Err(Error::invalid_type(
+                                    Unexpected::Other("normalizer is not a string"),
+                                    &"a normalizer name"
+                                ));
+                            };
+                            let Some(next) = Normalizer::from_name(name) else {
+                                return Err(Error::unknown_variant(
+                                    name, &["trim", "strip_quotes", "lowercase"]));
+                            };
+                            normalizer = normalizer.union(next);
+                        }
+                        normalize_map.push((key, normalizer));
+                    }
+                    normalize = Some(normalize_map);
                 }
                 _ => {
-                    return Err(Error::unknown_field(key, &["when", "run", "then"]));
+                    return Err(Error::unknown_field(key, &[
+                        "when", "when_not", "run", "then", "group", "match_mode", "ignore_case",
+                        "profiles", "defaults", "normalize",
+                    ]));
                 }
             }
         }
-        let when = when.ok_or_else(|| Error::missing_field("when"))?;
-        let run = run.unwrap_or_else(|| vec![]).into_iter().map(|s| s.into_bytes()).collect();
-        let then = then.unwrap_or_else(|| vec![]);
-        Ok(Cmd::Match(Match { when, run, then }))
+        let is_match = when.is_some() || when_not.is_some() || run.is_some() || then.is_some()
+            || group.is_some() || match_mode.is_some() || ignore_case.is_some();
+        if let Some(profiles) = profiles {
+            if is_match || defaults.is_some() || normalize.is_some() {
+                return Err(Error::custom("profiles cannot be combined with a match"));
+            }
+            return Ok(Cmd::Profiles(profiles));
+        }
+        if let Some(defaults) = defaults {
+            if is_match || normalize.is_some() {
+                return Err(Error::custom("defaults cannot be combined with a match"));
+            }
+            return Ok(Cmd::Defaults(defaults));
+        }
+        if let Some(normalize) = normalize {
+            if is_match {
+                return Err(Error::custom("normalize cannot be combined with a match"));
+            }
+            return Ok(Cmd::Normalize(normalize));
+        }
+        let when = match (when, &when_not) {
+            (None, None) => return Err(Error::missing_field("when")),
+            (when, _) => when.unwrap_or_default(),
+        };
+        let when_not = when_not.unwrap_or_default();
+        let run = run.unwrap_or_default().into_iter().map(|s| s.into_bytes()).collect();
+        let then = then.unwrap_or_else(std::vec::Vec::new);
+        let match_mode = match_mode.unwrap_or_default();
+        let ignore_case = ignore_case.unwrap_or_default();
+        Ok(Cmd::Match(Match { when, when_not, run, then, group, match_mode, ignore_case }))
     }
 }
 
+// The key-condition pairs of `when` or `when_not`.
+fn guards<E: Error>(value: Value) -> Result<Vec<(String, Condition)>, E> {
+    let Value::Object(obj) = value else {
+        return Err(Error::invalid_type(
+            Unexpected::Other("match is not an object"),
+            &"an object of key-regex pairs"
+        ));
+    };
+    let mut guards = vec![];
+    for (key, value) in obj {
+        let cond = match value {
+            Value::String(regex) => Condition::Regex(regex),
+            Value::Object(_) => Condition::Range(NumRange::from_json(&value).map_err(E::custom)?),
+            _ => return Err(Error::invalid_type(
+                Unexpected::Other("match value is neither a string nor an object"),
+                &"a string (regex) or a range"
+            )),
+        };
+        guards.push((key, cond));
+    }
+    Ok(guards)
+}
+
 impl<'de> Deserialize<'de> for Cmd {
     fn deserialize<D>(deserializer: D) -> Result<Cmd, D::Error>
     where
@@ -350,132 +973,694 @@ impl<'de> Deserialize<'de> for Cmd {
 }
 
 
+// A blob in a buffer owned by the caller (e.g. mmapped or in shared memory), deserialized in place
+// instead of being copied like by `Msg::read`.
+pub struct MsgRef<'a> {
+    data: &'a [u8],
+    // See `Msg::corrupt`.
+    corrupt: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MisalignedBuffer {
+    pub required: usize,
+}
+
+impl fmt::Display for MisalignedBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the blob buffer must be aligned to {} bytes", self.required)
+    }
+}
+
+impl std::error::Error for MisalignedBuffer {}
+
+#[allow(clippy::missing_safety_doc)]
+impl<'a> MsgRef<'a> {
+    // The buffer must hold a serialized (not yet deserialized) blob, as `Msg::read` expects.
+    pub unsafe fn from_mut_slice(buf: &'a mut [u8]) -> Result<Self, MisalignedBuffer> {
+        let required = align_of::<u128>();
+        if buf.as_ptr().align_offset(required) != 0 {
+            return Err(MisalignedBuffer { required });
+        }
+        let corrupt = corrupt_offset(Msg::deserialize(buf.as_mut_ptr(), buf.len()));
+        Ok(MsgRef { data: buf, corrupt })
+    }
+
+    pub fn data_len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn get_automaton(&self) -> &Automaton<'_> {
+        self.get_root().expect("not an automaton blob")
+    }
+
+    pub fn get_root<'b, T: BlobRoot + 'b>(&'b self) -> Result<&'b T, BlobError> {
+        unsafe { root(self.data.as_ptr(), self.data.len(), self.corrupt) }
+    }
+}
+
+// The root behind the header, if the header is valid for it, see `BlobHeader::check`.
+unsafe fn root<'a, T: BlobRoot + 'a>(data: *const u8, len: usize, corrupt: Option<usize>)
+    -> Result<&'a T, BlobError>
+{
+    if let Some(offset) = corrupt { return Err(BlobError::Corrupt { offset }); }
+    BlobHeader::check::<T>(data, len)?;
+    Ok(&*(data.add(size_of::<BlobHeader>()) as *const T))
+}
+
+// The blobs rejected by their headers are left intact, only the corrupted ones are unusable.
+fn corrupt_offset(deserialized: Result<(), BlobError>) -> Option<usize> {
+    match deserialized {
+        Err(BlobError::Corrupt { offset }) => Some(offset),
+        _ => None,
+    }
+}
+
+// The offsets of the sections of the blob (with a valid header in the native byte order), see
+// `BlobHeader::sections`. The directory is swapped by `swap`.
+unsafe fn read_directory(buf: *mut u8, swap: Swap) -> Result<Vec<usize>, BlobError> {
+    let header = &*(buf as *const BlobHeader);
+    let at = header.sections as usize;
+    if at < size_of::<BlobHeader>() || !at.is_multiple_of(align_of::<BlobVec<u64>>()) {
+        return Err(BlobError::Corrupt { offset: at });
+    }
+    let mut cur = BuildCursor::<BlobVec<u64>>::bounded(buf, header.length as usize);
+    cur.cur = at;
+    cur.swap = swap;
+    let mut directory = vec![];
+    let _: BuildCursor<()> = BlobVec::<u64>::deserialize(cur, |offset| {
+        let offset = usize::try_from(swap.fix(offset));
+        directory.push(offset.map_err(|_| BlobError::Corrupt { offset: at })?);
+        Ok(())
+    })?;
+    if directory.len() != Section::ALL.len() { return Err(BlobError::Corrupt { offset: at }); }
+    Ok(directory)
+}
+
+// Deserialize the section at the cursor, returning the cursor behind it. The pointers of the
+// section are shifted, but their targets in the other sections are not checked.
+unsafe fn deserialize_section(section: Section, cur: BuildCursor<()>, shifter: &Shifter)
+    -> CursorResult<()>
+{
+    match section {
+        Section::Getolds | Section::Exts | Section::DefaultKeys | Section::DefaultValues
+        | Section::NormalizedKeys | Section::PatternKeys | Section::PatternSources =>
+            VecOfVecs::<u8>::deserialize(cur.align(), |_| Ok(())),
+        Section::Rules | Section::PatternIds =>
+            BlobVec::<u64>::deserialize(cur.align(), |x| shifter.scalar(x)),
+        Section::Inits =>
+            BlobVec::<BlobPtr<KeyValState>>::deserialize(cur.align(),
+                |x| shifter.shift_to(x, size_of::<KeyValState>(), Target::KeyValState)),
+        Section::Normalizers => BlobVec::<Normalizer>::deserialize(cur.align(), |_| Ok(())),
+        Section::TagRules =>
+            BlobVec::<TagRule>::deserialize(cur.align(), |x| shifter.scalar(x)),
+        Section::KeyValStates => Sediment::<KeyValState>::deserialize(cur.align(),
+            |cur| KeyValState::deserialize(cur)),
+        Section::U8States => Sediment::<U8State>::deserialize(cur.align(),
+            |cur| U8State::deserialize(cur)),
+        Section::TagPool => deserialize_tag_pool(cur.align()),
+    }
+}
+
+enum MsgOwner {
+    Heap(Box<[u8]>),
+    #[cfg(all(unix, feature = "shm"))]
+    Shared(SharedSegment),
+}
+
 pub struct Msg {
-    owner: Box<[u8]>,
+    owner: MsgOwner,
     pub data: *const u8,
+    // The pointers of a deserialized blob are absolute, so it cannot be written out anymore.
+    deserialized: bool,
+    // Where `read` has found the blob corrupted, it is then partially deserialized.
+    corrupt: Option<usize>,
+}
+
+// A blob of which only some sections have been loaded, see `Msg::read_sections`. It has no
+// automaton to simulate.
+pub struct PartialMsg {
+    msg: Msg,
+    // The offsets of all the sections, by `Section`.
+    directory: Vec<usize>,
+    // Sorted.
+    loaded: Vec<Section>,
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl PartialMsg {
+    pub fn loaded(&self) -> &[Section] {
+        &self.loaded
+    }
+
+    // The section, if it has been loaded. T must be its type in `Automaton`. Its pointers into the
+    // other sections may be followed only if those have been loaded as well.
+    pub unsafe fn section<T>(&self, section: Section) -> Option<&T> {
+        self.loaded.binary_search(&section).ok()?;
+        Some(&*(self.msg.data.add(self.directory[section as usize]) as *const T))
+    }
 }
 
 // This is safe because we guarantee that `data` always points into `owner`.
 unsafe impl Send for Msg {}
 
+#[allow(clippy::missing_safety_doc)]
 impl Msg {
     pub fn data_len(&self) -> usize {
-        self.owner.len() - size_of::<usize>()
+        match &self.owner {
+            MsgOwner::Heap(buff) => buff.len() - align_of::<u128>(),
+            #[cfg(all(unix, feature = "shm"))]
+            MsgOwner::Shared(segment) => segment.data_len(),
+        }
     }
 
+    // An invalid blob is rejected by `get_root`.
     pub unsafe fn read<R: FnOnce(*mut u8)>(ext_read: R, len: usize) -> Msg {
-        let mut buff = vec![0; len + size_of::<usize>()].into_boxed_slice();
+        Self::read_checked(ext_read, len, Msg::deserialize).0
+    }
+
+    // Like `read`, but fail right away if the blob is invalid, e.g. if it has been truncated or
+    // corrupted on the way.
+    pub unsafe fn try_read<R: FnOnce(*mut u8)>(ext_read: R, len: usize) -> Result<Msg, BlobError> {
+        let (msg, deserialized) = Self::read_checked(ext_read, len, Msg::deserialize);
+        deserialized.map(|()| msg)
+    }
+
+    // Like `try_read`, but via the relocation table of the blob, see `deserialize_relocated`.
+    pub unsafe fn read_relocated<R: FnOnce(*mut u8)>(ext_read: R, len: usize)
+        -> Result<Msg, BlobError>
+    {
+        let (msg, deserialized) = Self::read_checked(ext_read, len, Msg::deserialize_relocated);
+        deserialized.map(|()| msg)
+    }
+
+    // Load only the given sections of the blob (e.g. the exts and the inits, for an inspection),
+    // found by the section directory of the blob, so that the others (e.g. the states) are not
+    // walked through.
+    pub unsafe fn read_sections<R: FnOnce(*mut u8)>(ext_read: R, len: usize, sections: &[Section])
+        -> Result<PartialMsg, BlobError>
+    {
+        let mut buff = vec![0; len + align_of::<u128>()].into_boxed_slice();
         let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
         ext_read(buf);
-        Msg::deserialize(buf);
-        Msg { owner: buff, data: buf }
+        let swap = BlobHeader::check_any_order::<Automaton>(buf, len)?;
+        BlobHeader::swap(buf as *mut BlobHeader, swap);
+        let directory = read_directory(buf, swap)?;
+        let mut loaded = sections.to_vec();
+        loaded.sort_unstable();
+        loaded.dedup();
+        let mut cur =
+            BuildCursor::<()>::bounded(buf, (*(buf as *const BlobHeader)).length as usize);
+        cur.swap = swap;
+        let shifter = Shifter::of(&cur);
+        for &section in loaded.iter() {
+            // Every section starts with a word.
+            let start = directory[section as usize];
+            if start < size_of::<BlobHeader>() || !start.is_multiple_of(align_of::<u64>()) {
+                return Err(BlobError::Corrupt { offset: start });
+            }
+            let mut cur = cur;
+            cur.cur = start;
+            deserialize_section(section, cur, &shifter)?;
+        }
+        let msg = Msg { owner: MsgOwner::Heap(buff), data: buf, deserialized: true, corrupt: None };
+        Ok(PartialMsg { msg, directory, loaded })
     }
 
+    unsafe fn read_checked<R: FnOnce(*mut u8)>(
+        ext_read: R,
+        len: usize,
+        deserialize: unsafe fn(*mut u8, usize) -> Result<(), BlobError>,
+    ) -> (Msg, Result<(), BlobError>)
+    {
+        let mut buff = vec![0; len + align_of::<u128>()].into_boxed_slice();
+        let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
+        ext_read(buf);
+        let deserialized = deserialize(buf, len);
+        let corrupt = corrupt_offset(deserialized.clone());
+        (Msg { owner: MsgOwner::Heap(buff), data: buf, deserialized: true, corrupt }, deserialized)
+    }
+
+    // Panics if the blob has a different root, see `get_root`.
     pub fn get_automaton<'a>(&'a self) -> &'a Automaton<'a> {
-        unsafe { &*(self.data as *const Automaton<'a>) }
+        self.get_root().expect("not an automaton blob")
     }
 
-    pub unsafe fn deserialize<'a>(buf: *mut u8) {
-        let cur = BuildCursor::new(buf);
-        let shifter = Shifter(cur.buf);
-        let _: BuildCursor<()> = unsafe {
-            Automaton::deserialize(cur,
-                |cur| Sediment::<Bytes>::deserialize(cur,
-                    |cur| Bytes::deserialize(cur, |_| ())),
-                |cur| ExtsAndAut::deserialize(cur,
-                    |cur| Sediment::<Bytes>::deserialize(cur,
-                        |cur| Bytes::deserialize(cur, |_| ())),
-                    |cur| InitsAndStates::deserialize(cur,
-                        |cur| BlobVec::<*const KeyValState>::deserialize(cur,
-                            |x| { shifter.shift(x); }),
-                        |cur| States::deserialize(cur,
-                            |cur| Sediment::<KeyValState>::deserialize(cur,
-                                |cur| KeyValState::deserialize(cur)),
-                            |cur| Sediment::<U8State>::deserialize(cur,
-                                |cur| U8State::deserialize(cur)),
-                        )
-                    )
-                )
-            )
-        };
+    // The root structure of the blob, checked against the header of the blob.
+    pub fn get_root<'a, T: BlobRoot + 'a>(&'a self) -> Result<&'a T, BlobError> {
+        unsafe { root(self.data, self.data_len(), self.corrupt) }
+    }
+
+    // An invalid blob (e.g. of an older version, another target or a different root) is left as
+    // is. Every structure of the blob is checked to lie within the blob, and every pointer to
+    // point to the start of a structure of its type (see `Targets`), so a corrupted blob is
+    // rejected (deserialized only partially) instead of being read out of bounds. A blob of the
+    // other byte order is swapped (see `Swap`).
+    pub unsafe fn deserialize(buf: *mut u8, len: usize) -> Result<(), BlobError> {
+        Self::deserialize_recording(buf, len, &Targets::default())
+    }
+
+    unsafe fn deserialize_recording(buf: *mut u8, len: usize, targets: &Targets)
+        -> Result<(), BlobError>
+    {
+        let swap = BlobHeader::check_any_order::<Automaton>(buf, len)?;
+        BlobHeader::swap(buf as *mut BlobHeader, swap);
+        Self::walk_recording(buf, swap, targets)
+    }
+
+    // Deserialize the sections of the blob (with a valid header in the native byte order).
+    unsafe fn walk(buf: *mut u8, swap: Swap) -> Result<(), BlobError> {
+        Self::walk_recording(buf, swap, &Targets::default())
+    }
+
+    unsafe fn walk_recording(buf: *mut u8, swap: Swap, targets: &Targets)
+        -> Result<(), BlobError>
+    {
+        let length = (*(buf as *const BlobHeader)).length as usize;
+        let mut header = BuildCursor::<BlobHeader>::bounded(buf, length);
+        header.swap = swap;
+        header.targets = targets;
+        let directory = read_directory(buf, swap)?;
+        let mut cur = header.behind::<()>(1);
+        let shifter = Shifter::of(&cur);
+        for section in Section::ALL {
+            let start = directory[section as usize];
+            if start != cur.align::<u64>().cur {
+                return Err(BlobError::Corrupt { offset: start });
+            }
+            cur = deserialize_section(section, cur, &shifter)?;
+        }
+        targets.check()
+    }
+
+    // Like `deserialize`, but a blob with a relocation table (see `with_relocations`) is loaded by
+    // shifting the pointers listed in the table, which is much faster for big automata. Only the
+    // pointers and their targets are checked to lie within the blob then, so use this only for
+    // the blobs which are known to be intact (e.g. checked by `read_from`).
+    pub unsafe fn deserialize_relocated(buf: *mut u8, len: usize) -> Result<(), BlobError> {
+        if BlobHeader::check_any_order::<Automaton>(buf, len)? == Swap::None
+            && reloc::relocate(buf)?
+        {
+            return Ok(());
+        }
+        Self::deserialize(buf, len)
     }
 
     pub fn serialize<Cfg: U8BuildConfig>(parser: &Parser, init: &LeafOrigin, cfg: &Cfg) -> Msg {
+        Self::serialize_with_map(parser, init, cfg).0
+    }
+
+    // A copy of the serialized (not yet read) blob with a relocation table appended (replacing
+    // its previous one), for `read_relocated`. The blob stays readable by `read`, which ignores
+    // the table. The table lists the pointer fields shifted by a walk through a copy of the blob,
+    // which fails if the blob is corrupted.
+    pub fn with_relocations(&self) -> Result<Msg, BlobError> {
+        assert!(!self.deserialized, "the blob is deserialized");
+        let header = unsafe { &*(self.data as *const BlobHeader) };
+        assert_eq!(header.target, TargetLayout::HOST, "the blob is of another target");
+        let len = match header.relocations {
+            0 => header.length as usize,
+            at => at as usize,
+        };
+        let mut copy = vec![0; len + align_of::<u128>()].into_boxed_slice();
+        let targets = Targets::recording_fields();
+        unsafe {
+            let buf = align_up_mut_ptr::<u8, u128>(copy.as_mut_ptr()) as *mut u8;
+            buf.copy_from(self.data, len);
+            let header = buf as *mut BlobHeader;
+            (*header).length = len as u64;
+            (*header).relocations = 0;
+            Self::deserialize_recording(buf, len, &targets)?;
+        }
+        let table = targets.into_fields();
+
+        let mut sz = Reserve(len);
+        let addr = reloc::reserve(&table, &mut sz);
+        let mut buff = vec![0; sz.0 + align_of::<u128>()].into_boxed_slice();
+        let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
+        unsafe {
+            buf.copy_from(self.data, len);
+            (*(buf as *mut BlobHeader)).length = sz.0 as u64;
+            reloc::serialize(&table, buf, addr);
+        }
+        Ok(Msg { owner: MsgOwner::Heap(buff), data: buf, deserialized: false, corrupt: None })
+    }
+
+    // A copy of the serialized (not yet read) blob in the other byte order, e.g. to ship it to the
+    // targets of that order, which then read it without swapping. The relocation table is dropped.
+    pub fn swap_byte_order(&self) -> Msg {
+        assert!(!self.deserialized, "the blob is deserialized");
+        let header = unsafe { &*(self.data as *const BlobHeader) };
+        assert_eq!(header.target, TargetLayout::HOST, "the blob is of another target");
+        let len = match header.relocations {
+            0 => header.length as usize,
+            at => at as usize,
+        };
+        let mut buff = vec![0; len + align_of::<u128>()].into_boxed_slice();
+        let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
+        unsafe {
+            buf.copy_from(self.data, len);
+            let header = buf as *mut BlobHeader;
+            (*header).length = len as u64;
+            (*header).relocations = 0;
+            Self::walk(buf, Swap::Store).expect("a serialized blob is intact");
+            BlobHeader::swap(header, Swap::Store);
+        }
+        Msg { owner: MsgOwner::Heap(buff), data: buf, deserialized: false, corrupt: None }
+    }
+
+    // Store a serialized (not yet read) blob in a file, followed by its checksum, so that
+    // `read_from` can reject corrupted files. The header of the blob tells truncated ones.
+    pub fn write_to<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if self.deserialized {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "the blob is deserialized"));
+        }
+        let data = unsafe { std::slice::from_raw_parts(self.data, self.data_len()) };
+        let mut file = io::BufWriter::new(std::fs::File::create(path)?);
+        file.write_all(data)?;
+        file.write_all(&XxHash64::oneshot(0, data).to_le_bytes())?;
+        file.flush()
+    }
+
+    pub fn read_from<P: AsRef<Path>>(path: P) -> io::Result<Msg> {
+        let file = std::fs::read(path)?;
+        let invalid = |error| io::Error::new(io::ErrorKind::InvalidData, error);
+        let (data, checksum) = file.split_last_chunk::<8>()
+            .ok_or_else(|| invalid(BlobError::Truncated {
+                length: size_of::<BlobHeader>() as u64, available: file.len() }))?;
+        unsafe { BlobHeader::check_any_order::<Automaton>(data.as_ptr(), data.len()) }
+            .map_err(invalid)?;
+        if u64::from_le_bytes(*checksum) != XxHash64::oneshot(0, data) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "checksum mismatch"));
+        }
+        unsafe { Msg::try_read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) }
+            .map_err(invalid)
+    }
+
+    // Like serialize, but fail instead of panicking if the parser refers to nonexistent states
+    // (e.g. if it has been assembled or modified by hand).
+    pub fn try_serialize<Cfg: U8BuildConfig>(parser: &Parser, init: &LeafOrigin, cfg: &Cfg)
+        -> Result<Msg, BuildError>
+    {
+        let result = Self::serialize_into(parser, init, cfg, |len| {
+            let mut buff = vec![0; len + align_of::<u128>()].into_boxed_slice();
+            let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
+            Ok::<_, std::convert::Infallible>((MsgOwner::Heap(buff), buf))
+        });
+        match result {
+            Ok((msg, _)) => Ok(msg),
+            Err(SerializeError::Build(error)) => Err(error),
+            Err(SerializeError::Alloc(never)) => match never {},
+        }
+    }
+
+    // Serialize, and report the byte ranges of the sections and states of the blob.
+    pub fn serialize_with_map<Cfg: U8BuildConfig>
+        (parser: &Parser, init: &LeafOrigin, cfg: &Cfg) -> (Msg, MemoryMap)
+    {
+        let result = Self::serialize_into(parser, init, cfg, |len| {
+            let mut buff = vec![0; len + align_of::<u128>()].into_boxed_slice();
+            let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
+            Ok::<_, std::convert::Infallible>((MsgOwner::Heap(buff), buf))
+        });
+        match result.map_err(SerializeError::alloc) { Ok(result) => result }
+    }
+
+    // Fail instead of allocating a blob larger than the budget allows.
+    pub fn serialize_budgeted<Cfg: U8BuildConfig>
+        (parser: &Parser, init: &LeafOrigin, cfg: &Cfg, budget: &CompileBudget)
+        -> Result<Msg, BudgetExceeded>
+    {
+        let (msg, _) = Self::serialize_into(parser, init, cfg, |len| {
+            if len > budget.max_blob_size {
+                return Err(BudgetExceeded::BlobSize { size: len, limit: budget.max_blob_size });
+            }
+            let mut buff = vec![0; len + align_of::<u128>()].into_boxed_slice();
+            let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
+            Ok((MsgOwner::Heap(buff), buf))
+        }).map_err(SerializeError::alloc)?;
+        Ok(msg)
+    }
+
+    // Build the blob directly in a new named shared-memory segment, and deserialize it there, so
+    // that other processes can use it via `attach_shared`. The name stays registered until
+    // `unlink_shared`.
+    #[cfg(all(unix, feature = "shm"))]
+    pub fn serialize_shared<Cfg: U8BuildConfig>
+        (name: &str, parser: &Parser, init: &LeafOrigin, cfg: &Cfg) -> std::io::Result<Msg>
+    {
+        let (mut msg, _) = Self::serialize_into(parser, init, cfg, |len| {
+            let segment = SharedSegment::create(name, len)?;
+            let buf = segment.data();
+            Ok::<_, std::io::Error>((MsgOwner::Shared(segment), buf))
+        }).map_err(SerializeError::alloc)?;
+        unsafe { Msg::deserialize(msg.data as *mut u8, msg.data_len()) }
+            .map_err(std::io::Error::other)?;
+        msg.deserialized = true;
+        if let MsgOwner::Shared(segment) = &mut msg.owner { segment.publish(); }
+        Ok(msg)
+    }
+
+    // Use a blob built by `serialize_shared` (possibly in another process). It is already
+    // deserialized, and must not be deserialized again.
+    #[cfg(all(unix, feature = "shm"))]
+    pub fn attach_shared(name: &str) -> std::io::Result<Msg> {
+        let segment = SharedSegment::attach(name)?;
+        let data = segment.data();
+        Ok(Msg { owner: MsgOwner::Shared(segment), data, deserialized: true, corrupt: None })
+    }
+
+    #[cfg(all(unix, feature = "shm"))]
+    pub fn unlink_shared(name: &str) -> std::io::Result<()> {
+        SharedSegment::unlink(name)
+    }
+
+    // Check the origins while reserving, so that they are walked only once before serializing.
+    fn serialize_into<Cfg: U8BuildConfig, E, A: FnOnce(usize) -> Result<(MsgOwner, *mut u8), E>>
+        (parser: &Parser, init: &LeafOrigin, cfg: &Cfg, alloc: A)
+        -> Result<(Msg, MemoryMap), SerializeError<E>>
+    {
+        let (kv_count, u8_count) = (parser.states.len(), parser.nfa.states.len());
+        check_indices("KeyValState", &init.states, kv_count).map_err(SerializeError::Build)?;
         let u8states = parser.nfa.states.iter()
             .map(|q| U8StatePrepared::prepare(q, cfg)).collect::<Vec<_>>();
+        let tag_sets = tag_sets(&u8states);
+        let mut tagqs = HashMap::new();
         let mut sz = Reserve(0);
+        sz.add::<BlobHeader>(1);
         let mut u8qs = Vec::<usize>::new();
         let mut kvqs = Vec::<usize>::new();
+        let (default_keys, default_values): (Vec<_>, Vec<_>) =
+            parser.defaults.iter().cloned().unzip();
+        let (normalized_keys, normalizers): (Vec<_>, Vec<_>) =
+            parser.normalizers.iter().map(|(key, normalizer)| (key.clone(), *normalizer)).unzip();
+        let pattern_keys =
+            parser.patterns.iter().map(|(key, _, _)| key.clone()).collect::<Vec<_>>();
+        let pattern_ids = parser.patterns.iter().map(|(_, id, _)| *id).collect::<Vec<_>>();
+        let pattern_sources = if parser.embed_patterns {
+            parser.patterns.iter().map(|(_, _, regex)| regex.clone().into_bytes()).collect()
+        } else {
+            vec![]
+        };
+        let mut tag_rules = parser.tag_rules.iter().copied().collect::<Vec<_>>();
+        tag_rules.sort_unstable();
         let mut origin = (
             &init.get_olds,
-            (
-                &init.exts,
-                (
-                    vec![0; init.states.len()],
-                    (
-                        &parser.states,
-                        &u8states,
-                    )
-                )
-            )
+            &init.exts,
+            &init.rules,
+            vec![0; init.states.len()],
+            &default_keys,
+            &default_values,
+            &normalized_keys,
+            &normalizers,
+            &pattern_keys,
+            &pattern_ids,
+            &pattern_sources,
+            &tag_rules,
+            &parser.states,
+            &u8states,
+            &tag_sets,
         );
 
-        Automaton::reserve(&origin, &mut sz,
-            |getolds, sz| {Sediment::<Bytes>::reserve(getolds, sz,
-                |getold, sz| {Bytes::reserve(getold, sz);} );},
-            |exts_and_aut, sz| {ExtsAndAut::reserve(exts_and_aut, sz,
-                |exts, sz| {Sediment::<Bytes>::reserve(exts, sz,
-                    |ext, sz| {Bytes::reserve(ext, sz);} );},
-                |inits_and_states, sz| {InitsAndStates::reserve(inits_and_states, sz,
-                    |inits, sz| { BlobVec::<*const KeyValState>::reserve(inits, sz); },
-                    |states, sz| {States::reserve(states, sz,
-                        |orig_kvqs, sz| {Sediment::<KeyValState>::reserve(orig_kvqs, sz,
-                            |kvq, sz| { kvqs.push(KeyValState::reserve(kvq, sz)) } );},
-                        |orig_u8qs, sz| {Sediment::<U8State>::reserve(orig_u8qs, sz,
-                            |u8q, sz| { u8qs.push(U8State::reserve(u8q, sz)) } );},
-                    );}
-                );}
-            );}
-        );
+        let map = RefCell::new(MemoryMap::new());
+        let directory = RefCell::new(vec![0; Section::ALL.len()]);
+        let section = |section: Section, sz: &mut Reserve,
+            f: &mut dyn FnMut(&mut Reserve) -> Result<usize, BuildError>|
+        {
+            let start = f(sz)?;
+            directory.borrow_mut()[section as usize] = start;
+            map.borrow_mut().add(section.name(), start, sz.0, 1);
+            Ok(())
+        };
+        let item = |name: &str, ix: usize, start: usize, end: usize| {
+            map.borrow_mut().add(format!("{} {}", name, ix), start, end, 2);
+        };
 
-        for (target, source) in origin.1.1.0.iter_mut().zip(init.states.iter()) {
+        let automaton_addr = Automaton::try_reserve(&origin, &mut sz,
+            |getolds, sz| section(Section::Getolds, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(getolds, sz)),
+            |exts, sz| section(Section::Exts, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(exts, sz)),
+            |rules, sz| section(Section::Rules, sz, &mut |sz|
+                BlobVec::<u64>::try_reserve(rules, sz)),
+            |inits, sz| section(Section::Inits, sz, &mut |sz|
+                BlobVec::<BlobPtr<KeyValState>>::try_reserve(inits, sz)),
+            |keys, sz| section(Section::DefaultKeys, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(keys, sz)),
+            |values, sz| section(Section::DefaultValues, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(values, sz)),
+            |keys, sz| section(Section::NormalizedKeys, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(keys, sz)),
+            |normalizers, sz| section(Section::Normalizers, sz, &mut |sz|
+                BlobVec::<Normalizer>::try_reserve(normalizers, sz)),
+            |keys, sz| section(Section::PatternKeys, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(keys, sz)),
+            |ids, sz| section(Section::PatternIds, sz, &mut |sz|
+                BlobVec::<u64>::try_reserve(ids, sz)),
+            |sources, sz| section(Section::PatternSources, sz, &mut |sz|
+                VecOfVecs::<u8>::try_reserve(sources, sz)),
+            |tag_rules, sz| section(Section::TagRules, sz, &mut |sz|
+                BlobVec::<TagRule>::try_reserve(tag_rules, sz)),
+            |orig_kvqs, sz| section(Section::KeyValStates, sz, &mut |sz|
+                Sediment::<KeyValState>::try_reserve(orig_kvqs, sz, |kvq, sz| {
+                    let start = KeyValState::try_reserve(kvq, sz, kv_count, u8_count)?;
+                    item("keyval_state", kvqs.len(), start, sz.0);
+                    kvqs.push(start);
+                    Ok(())
+                })),
+            |orig_u8qs, sz| section(Section::U8States, sz, &mut |sz|
+                Sediment::<U8State>::try_reserve(orig_u8qs, sz, |u8q, sz| {
+                    let start = U8State::try_reserve(u8q, sz, u8_count)?;
+                    item("u8_state", u8qs.len(), start, sz.0);
+                    u8qs.push(start);
+                    Ok(())
+                })),
+            |sets, sz| section(Section::TagPool, sz, &mut |sz|
+                Ok(reserve_tag_pool(sets, sz, &mut tagqs))),
+        ).map_err(SerializeError::Build)?;
+        let mut map = map.into_inner();
+        map.add("header", 0, size_of::<BlobHeader>(), 0);
+        map.add("automaton", automaton_addr, sz.0, 0);
+        let directory = directory.into_inner();
+        let directory_addr = BlobVec::<u64>::reserve(&directory, &mut sz);
+        map.add("sections", directory_addr, sz.0, 0);
+
+        for (target, source) in origin.3.iter_mut().zip(init.states.iter()) {
             *target = kvqs[*source];
         }
 
-        let mut buff = vec![0; sz.0 + size_of::<usize>()].into_boxed_slice();
-        let buf = align_up_mut_ptr::<u8, u128>(buff.as_mut_ptr()) as *mut u8;
-        let cur = BuildCursor::new(buf);
+        let (owner, buf) = alloc(sz.0).map_err(SerializeError::Alloc)?;
+        let header = BuildCursor::<BlobHeader>::new(buf);
+        unsafe {
+            BlobHeader::write::<Automaton>(header.get_mut(), sz.0);
+            (*header.get_mut()).sections = directory_addr as u64;
+            let mut cur = header.transmute::<BlobVec<u64>>();
+            cur.cur = directory_addr;
+            let _: BuildCursor<()> =
+                BlobVec::<u64>::serialize(&directory, cur, |x, y| { *y = *x as u64; });
+        }
+        let cur = header.behind(1);
+        let ctx = CtxPair(
+            CtxPair(U8StatePtrs(&u8qs), KeyValStatePtrs(&kvqs)), TagSetPtrs(&tagqs));
         let _: BuildCursor<()> = unsafe {
             Automaton::serialize(&origin, cur,
-                |getolds, cur| Sediment::<Bytes>::serialize(getolds, cur,
-                    |getold, cur| Bytes::serialize(getold, cur, |x, y| { *y = *x; })),
-                |exts_and_aut, cur| ExtsAndAut::serialize(exts_and_aut, cur,
-                    |exts, cur| Sediment::<Bytes>::serialize(exts, cur,
-                        |ext, cur| Bytes::serialize(ext, cur, |x, y| { *y = *x; })),
-                    |inits_and_states, cur| InitsAndStates::serialize(inits_and_states, cur,
-                        |inits, cur| BlobVec::<*const KeyValState>::serialize(inits, cur,
-                            |x, y| { *y = *x as *const KeyValState; }),
-                        |states, cur| States::serialize(states, cur,
-                            |orig_kvqs, cur| Sediment::<KeyValState>::serialize(orig_kvqs, cur,
-                                |kvq, cur| KeyValState::serialize(kvq, cur, &u8qs, &kvqs)),
-                            |orig_u8qs, cur| Sediment::<U8State>::serialize(orig_u8qs, cur,
-                                |u8q, cur| U8State::serialize(u8q, cur, &u8qs)),
-                        )
-                    )
-                )
+                |getolds, cur| VecOfVecs::<u8>::serialize(getolds, cur, |x, y| { *y = *x; }),
+                |exts, cur| VecOfVecs::<u8>::serialize(exts, cur, |x, y| { *y = *x; }),
+                |rules, cur| BlobVec::<u64>::serialize(rules, cur, |x, y| { *y = *x as u64; }),
+                |inits, cur| BlobVec::<BlobPtr<KeyValState>>::serialize(inits, cur,
+                    |x, y| { *y = BlobPtr::offset(*x); }),
+                |keys, cur| VecOfVecs::<u8>::serialize(keys, cur, |x, y| { *y = *x; }),
+                |values, cur| VecOfVecs::<u8>::serialize(values, cur, |x, y| { *y = *x; }),
+                |keys, cur| VecOfVecs::<u8>::serialize(keys, cur, |x, y| { *y = *x; }),
+                |normalizers, cur| BlobVec::<Normalizer>::serialize(normalizers, cur,
+                    |x, y| { *y = *x; }),
+                |keys, cur| VecOfVecs::<u8>::serialize(keys, cur, |x, y| { *y = *x; }),
+                |ids, cur| BlobVec::<u64>::serialize(ids, cur, |x, y| { *y = *x as u64; }),
+                |sources, cur| VecOfVecs::<u8>::serialize(sources, cur, |x, y| { *y = *x; }),
+                |tag_rules, cur| BlobVec::<TagRule>::serialize(tag_rules, cur, |x, y| { *y = *x; }),
+                |orig_kvqs, cur| Sediment::<KeyValState>::serialize(orig_kvqs, cur,
+                    |kvq, cur| KeyValState::serialize_in(kvq, cur, &ctx)),
+                |orig_u8qs, cur| Sediment::<U8State>::serialize(orig_u8qs, cur,
+                    |u8q, cur| U8State::serialize_in(u8q, cur, &ctx)),
+                |sets, cur| serialize_tag_pool(sets, cur),
             )
         };
+        unsafe {
+            let content = std::slice::from_raw_parts(
+                buf.add(size_of::<BlobHeader>()), sz.0 - size_of::<BlobHeader>());
+            (*(buf as *mut BlobHeader)).content_hash = XxHash64::oneshot(0, content);
+        }
+
+        Ok((Msg { owner, data: buf, deserialized: false, corrupt: None }, map))
+    }
+}
+
+// Why `Msg::serialize_into` failed.
+enum SerializeError<E> {
+    // The parser refers to nonexistent states.
+    Build(BuildError),
+    Alloc(E),
+}
 
-        Msg { owner: buff, data: buf }
+impl<E> SerializeError<E> {
+    // For the callers which do not check the parser, see `Msg::try_serialize`.
+    fn alloc(self) -> E {
+        match self {
+            SerializeError::Build(error) => panic!("cannot serialize the automaton: {}", error),
+            SerializeError::Alloc(error) => error,
+        }
+    }
+}
+
+// An automaton built right in the buffer it is run from, for the tests and the embedders compiling
+// their configs in the same process, where the copy made by `Msg::read` is pointless.
+pub struct OwnedAutomaton {
+    msg: Msg,
+}
+
+impl OwnedAutomaton {
+    pub fn new<Cfg: U8BuildConfig>(parser: &Parser, init: &LeafOrigin, cfg: &Cfg) -> Self {
+        Self::load(Msg::serialize(parser, init, cfg))
+    }
+
+    // Like `new`, but see `Msg::try_serialize`.
+    pub fn try_new<Cfg: U8BuildConfig>(parser: &Parser, init: &LeafOrigin, cfg: &Cfg)
+        -> Result<Self, BuildError>
+    {
+        Ok(Self::load(Msg::try_serialize(parser, init, cfg)?))
+    }
+
+    // Parse and build the config.
+    pub fn from_config<Cfg: U8BuildConfig>(cmds: Vec<Cmd>, cfg: &Cfg) -> Self {
+        let (parser, init) = Parser::parse(cmds);
+        Self::new(&parser, &init, cfg)
+    }
+
+    fn load(mut msg: Msg) -> Self {
+        unsafe { Msg::deserialize(msg.data as *mut u8, msg.data_len()) }
+            .expect("a freshly built blob is valid");
+        msg.deserialized = true;
+        OwnedAutomaton { msg }
+    }
+
+    pub fn as_automaton(&self) -> &Automaton<'_> {
+        self.msg.get_automaton()
+    }
+
+    // The loaded blob, e.g. for `Msg::get_root`. It cannot be written out anymore.
+    pub fn msg(&self) -> &Msg {
+        &self.msg
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use indexmap::IndexSet;
+    use std::mem::offset_of;
+
 
-    use crate::{blob::tests::TestU8BuildConfig, keyval_simulator::Simulation};
+    use crate::blob::root::{TargetLayout, BLOB_MAGIC, FORMAT_VERSION};
+    use crate::{blob::tests::TestU8BuildConfig, keyval_runner::Runner, keyval_simulator::Simulation};
 
     use super::*;
 
@@ -510,7 +1695,7 @@ mod tests {
 
         // The output automaton is for now only for visual checking.
         let file = std::fs::File::create("/tmp/test_complex.dot").unwrap();
-        parser.to_dot(&init, std::io::BufWriter::new(file));
+        parser.to_dot(&init, std::io::BufWriter::new(file)).unwrap();
     }
 
     #[test]
@@ -530,9 +1715,9 @@ mod tests {
 
         // The output automaton is for now only for visual checking.
         let file = std::fs::File::create("/tmp/test_simple.dot").unwrap();
-        parser.to_dot(&init, std::io::BufWriter::new(file));
+        parser.to_dot(&init, std::io::BufWriter::new(file)).unwrap();
 
-        let outmsg = Msg::serialize(parser, init, &TestU8BuildConfig);
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
         let inmsg = unsafe {
             Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
         let aut = inmsg.get_automaton();
@@ -548,10 +1733,7 @@ mod tests {
         assert!(sim.exts.is_empty());
         sim.read(b"foo", b"a",
             |x| match x { b"foo" => Some(b"a"), b"bar" => Some(b"b"), _ => None });
-        let ext = b"you win";
-        let mut exts = IndexSet::new();
-        exts.insert(ext.as_slice());
-        assert_eq!(&sim.exts, &exts);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"you win".as_slice()]);
     }
 
     #[test]
@@ -563,6 +1745,886 @@ mod tests {
 
         // The output automaton is for now only for visual checking.
         let file = std::fs::File::create("/tmp/test_simplest.dot").unwrap();
-        parser.to_dot(&init, std::io::BufWriter::new(file));
+        parser.to_dot(&init, std::io::BufWriter::new(file)).unwrap();
+    }
+
+    #[test]
+    fn root_tag() {
+        struct Other;
+        impl BlobRoot for Other {
+            const NAME: &'static str = "Other";
+            const SCHEMA: &'static str = "Other";
+        }
+
+        let config: Vec<Cmd> = serde_json::from_str(r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let mut data = unsafe { std::slice::from_raw_parts(outmsg.data, outmsg.data_len()) }
+            .to_vec();
+        let msg = unsafe { Msg::read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) };
+        assert!(msg.get_root::<Automaton>().is_ok());
+        let err = msg.get_root::<Other>().map(|_| ()).unwrap_err();
+        assert_eq!(err, BlobError::Root { expected: "Other", found: Automaton::tag() });
+        assert_eq!(err.to_string(), "the blob root is Automaton, not Other");
+
+        // A blob of another layout is neither deserialized nor used.
+        let tag_offset = offset_of!(BlobHeader, root_tag);
+        data[tag_offset] ^= 1;
+        let msg = unsafe { Msg::read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) };
+        assert!(matches!(msg.get_root::<Automaton>(), Err(BlobError::Root { .. })));
+
+        // Nor is a blob of another target (other than of the other byte order, see `swap`).
+        data[tag_offset] ^= 1;
+        data[offset_of!(BlobHeader, target) + offset_of!(TargetLayout, word_alignment)] ^= 2;
+        let msg = unsafe { Msg::read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) };
+        let err = msg.get_root::<Automaton>().map(|_| ()).unwrap_err();
+        let mut found = TargetLayout::HOST;
+        found.word_alignment ^= 2;
+        assert_eq!(err, BlobError::Target { found });
+        assert!(err.to_string().starts_with("the blob has been built for a "));
+    }
+
+    #[test]
+    fn try_read() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {"foo": "a|b.*", "bar": "[0-9]+"}, "run": ["m1"], "then": [
+                {"when": {"baz": "x"}, "run": ["m2"]}
+            ]}
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let data = unsafe { std::slice::from_raw_parts(outmsg.data, outmsg.data_len()) }.to_vec();
+        let try_read = |data: &[u8]| unsafe {
+            Msg::try_read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) };
+        assert!(try_read(&data).unwrap().get_root::<Automaton>().is_ok());
+
+        let mut truncated = data[..data.len() - 8].to_vec();
+        assert!(matches!(try_read(&truncated).map(|_| ()), Err(BlobError::Truncated { .. })));
+
+        // Claiming a shorter length, the last structures get out of bounds.
+        let (length, len) = (offset_of!(BlobHeader, length), truncated.len() as u64);
+        truncated[length..length + 8].copy_from_slice(&len.to_ne_bytes());
+        let err = try_read(&truncated).map(|_| ()).unwrap_err();
+        assert!(matches!(err, BlobError::Corrupt { offset } if offset <= truncated.len()));
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(truncated.as_ptr(), truncated.len()), truncated.len()) };
+        assert_eq!(msg.get_root::<Automaton>().map(|_| ()), Err(err));
+
+        // Whatever word gets corrupted, the blob is rejected or stays within its bounds: a pointer
+        // moved into the middle of its target (or anywhere else) is rejected too, so the accepted
+        // blobs can be simulated.
+        let (mut corrupted, mut accepted) = (0, 0);
+        for ix in (size_of::<BlobHeader>()..data.len()).step_by(size_of::<u64>()) {
+            let old = u64::from_ne_bytes(data[ix..ix + size_of::<u64>()].try_into().unwrap());
+            let moved = [old.wrapping_add(8), old.wrapping_sub(8)];
+            for word in [u64::MAX, data.len() as u64, 1, ix as u64].into_iter().chain(moved) {
+                let mut other = data.clone();
+                other[ix..ix + size_of::<u64>()].copy_from_slice(&word.to_ne_bytes());
+                match try_read(&other) {
+                    Err(err) => {
+                        assert!(matches!(err, BlobError::Corrupt { .. }));
+                        corrupted += 1;
+                    }
+                    Ok(msg) => {
+                        let db = |key: &[u8]| match key {
+                            b"foo" => Some(b"bx".as_ref()),
+                            _ => None,
+                        };
+                        let mut sim = Simulation::new(msg.get_automaton(), db);
+                        sim.read(b"bar", b"42", db);
+                        sim.read(b"baz", b"x", db);
+                        sim.read(b"foo", b"ay", db);
+                        accepted += 1;
+                    }
+                }
+            }
+        }
+        assert!(corrupted > 0 && accepted > 0);
+    }
+
+    #[test]
+    fn relocations() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {"foo": "a|b.*", "bar": "[0-9]+"}, "run": ["m1"], "then": [
+                {"when": {"baz": "x"}, "run": ["m2"]}
+            ]}
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig).with_relocations()
+            .unwrap();
+        let data = unsafe { std::slice::from_raw_parts(outmsg.data, outmsg.data_len()) }.to_vec();
+        let header = unsafe { &*(outmsg.data as *const BlobHeader) };
+        let at = header.relocations as usize;
+        assert_eq!(header.length, data.len() as u64);
+        assert!(at > 0);
+        let read = |data: &[u8]| unsafe {
+            Msg::read_relocated(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) };
+
+        // The same pointers as by the walk, which ignores the table.
+        let walked = unsafe {
+            Msg::try_read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) }.unwrap();
+        let relocated = read(&data).unwrap();
+        let word = |msg: &Msg, ix: usize| unsafe { *(msg.data.add(ix) as *const u64) };
+        for ix in (0..at).step_by(size_of::<u64>()) {
+            let (w, r) = (word(&walked, ix), word(&relocated, ix));
+            assert!(w == r || w - walked.data as u64 == r - relocated.data as u64);
+        }
+        let db = |key: &[u8]| match key { b"foo" => Some(b"bx".as_ref()), _ => None };
+        let mut sim = Simulation::new(relocated.get_automaton(), db);
+        sim.read(b"bar", b"42", db);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"m1".as_ref()]);
+
+        // A blob without the table is walked.
+        let plain = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        assert_eq!(plain.with_relocations().unwrap().data_len(), data.len());
+        let plain_data = unsafe { std::slice::from_raw_parts(plain.data, plain.data_len()) };
+        assert!(read(plain_data).is_ok());
+
+        // The table of a blob which has one already is replaced.
+        let again = outmsg.with_relocations().unwrap();
+        let again = unsafe { std::slice::from_raw_parts(again.data, again.data_len()) };
+        assert_eq!(again, &data[..]);
+        {
+            let relocated = read(again).unwrap();
+            let db = |key: &[u8]| match key { b"foo" => Some(b"bx".as_ref()), _ => None };
+            let mut sim = Simulation::new(relocated.get_automaton(), db);
+            sim.read(b"bar", b"42", db);
+            assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"m1".as_ref()]);
+        }
+
+        // A corrupted blob gets no table: the first init points to the middle of a word.
+        let corrupted = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        unsafe {
+            let sections = (*(corrupted.data as *const BlobHeader)).sections as usize;
+            let directory = corrupted.data.add(sections) as *const u64;
+            let inits = *directory.add(Section::Inits as usize + 1);
+            *(corrupted.data.add(inits as usize + size_of::<u64>()) as *mut u64) = 1;
+        }
+        assert_eq!(corrupted.with_relocations().map(|_| ()), Err(BlobError::Corrupt { offset: 1 }));
+
+        let field = |ix: usize| at + size_of::<u64>() * (ix + 1);
+        let set = |data: &mut Vec<u8>, at: usize, word: usize|
+            data[at..at + size_of::<u64>()].copy_from_slice(&(word as u64).to_ne_bytes());
+        let first = u64::from_ne_bytes(data[field(0)..field(1)].try_into().unwrap()) as usize;
+        let mut twice = data.clone();
+        set(&mut twice, field(1), first);
+        assert_eq!(read(&twice).map(|_| ()), Err(BlobError::Corrupt { offset: first }));
+        let mut outside = data.clone();
+        set(&mut outside, first, at);
+        assert_eq!(read(&outside).map(|_| ()), Err(BlobError::Corrupt { offset: at }));
+        let mut header = data.clone();
+        set(&mut header, field(0), 0);
+        assert_eq!(read(&header).map(|_| ()), Err(BlobError::Corrupt { offset: 0 }));
+    }
+
+    // All the kinds of states and sections, for the blobs of the other byte order.
+    const SWAP_CONFIG: &str = r#"[
+        {"defaults": {"mode": "slow"}},
+        {"normalize": {"mode": ["trim", "strip_quotes"]}},
+        {"when": {"foo": "a|b.*", "bar": "[0-9]+"}, "run": ["m1"], "then": [
+            {"when": {"baz": "x"}, "run": ["m2"]},
+            {"when": {"port": {"range": [1024, 65535]}}, "run": ["m3"], "group": "g"}
+        ]},
+        {"when": {"path": "usr"}, "match_mode": "substring", "run": ["m4"]},
+        {"when": {"mode": "[a-z]"}, "run": ["m5"]},
+        {"when": {"mode": "[a-c][e-g][i-k][m-o][q-s][u-w]"}, "run": ["m6"]}
+    ]"#;
+
+    struct SwapBuildConfig { narrow: bool }
+    impl U8BuildConfig for SwapBuildConfig {
+        fn guard_size_keep(&self) -> u32 { 2 }
+        fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+        fn dense_guard_count(&self) -> usize { 3 }
+        fn max_ranged_ranges(&self) -> usize { 6 }
+        fn hash_seed(&self) -> u64 { 0 }
+        fn narrow_dense_offsets(&self) -> bool { self.narrow }
+    }
+
+    fn swap_blob(narrow: bool) -> Msg {
+        let (mut parser, init) = Parser::parse(serde_json::from_str(SWAP_CONFIG).unwrap());
+        parser.embed_patterns = true;
+        Msg::serialize(&parser, &init, &SwapBuildConfig { narrow })
+    }
+
+    fn bytes(msg: &Msg) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) }
+    }
+
+    fn assert_swap_behaviour(msg: &Msg) {
+        let db = |key: &[u8]| match key {
+            b"foo" => Some(b"bx".as_ref()),
+            b"mode" => Some(b" 'bfjnrv' ".as_ref()),
+            _ => None,
+        };
+        let mut sim = Simulation::new(msg.get_automaton(), db);
+        sim.read(b"bar", b"42", db);
+        sim.read(b"port", b"8080", db);
+        sim.read(b"path", b"/usr/bin", db);
+        let mut exts = sim.exts.iter().copied().collect::<Vec<_>>();
+        exts.sort();
+        assert_eq!(exts, vec![b"m1".as_ref(), b"m3", b"m4", b"m6"]);
+    }
+
+    #[test]
+    fn swap_byte_order() {
+        for narrow in [false, true] {
+            let native = swap_blob(narrow);
+            let swapped = native.swap_byte_order();
+            let header = unsafe { &*(swapped.data as *const BlobHeader) };
+            assert_eq!(header.target, TargetLayout::SWAPPED);
+            assert_eq!(bytes(&swapped).len(), bytes(&native).len());
+            assert_ne!(bytes(&swapped), bytes(&native));
+
+            // The swapped blob is loaded into the same words as the native one, other than the
+            // pointers, which are shifted to the other buffer.
+            let read = |data: &[u8]| unsafe {
+                Msg::try_read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) }
+                .unwrap();
+            let (walked, loaded) = (read(bytes(&native)), read(bytes(&swapped)));
+            let word = |msg: &Msg, ix: usize| unsafe { *(msg.data.add(ix) as *const u64) };
+            let delta = (loaded.data as u64).wrapping_sub(walked.data as u64);
+            for ix in (0..native.data_len()).step_by(size_of::<u64>()) {
+                let (w, l) = (word(&walked, ix), word(&loaded, ix));
+                assert!(w == l || w.wrapping_add(delta) == l, "the word at {ix}");
+            }
+            assert_swap_behaviour(&loaded);
+
+            // The relocation table is dropped, the sections are found by the directory.
+            let relocated = native.with_relocations().unwrap().swap_byte_order();
+            assert_eq!(bytes(&relocated), bytes(&swapped));
+            let data = bytes(&relocated);
+            let partial = unsafe { Msg::read_sections(|buf| buf.copy_from(data.as_ptr(),
+                data.len()), data.len(), &[Section::PatternSources]) }.unwrap();
+            let sources = unsafe { partial.section::<VecOfVecs<u8>>(Section::PatternSources) }
+                .unwrap();
+            assert!(unsafe { sources.iter() }.any(|source| source == b"[0-9]+"));
+            assert!(unsafe { Msg::read_relocated(
+                |buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) }.is_ok());
+        }
+    }
+
+    // The blob of `SWAP_CONFIG` built on a little-endian host, which every host has to load
+    // alike. Regenerate it by running the tests with CONFIGMATON_BLESS=1 after a change of the
+    // format.
+    const LE_BLOB: &[u8] = include_bytes!("../testdata/le.blob");
+
+    #[test]
+    fn little_endian_fixture() {
+        let fresh = swap_blob(true);
+        let fresh = match TargetLayout::HOST.big_endian {
+            0 => fresh,
+            _ => fresh.swap_byte_order(),
+        };
+        if std::env::var_os("CONFIGMATON_BLESS").is_some() {
+            let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/le.blob");
+            std::fs::write(path, bytes(&fresh)).unwrap();
+            return;
+        }
+        assert_eq!(LE_BLOB, bytes(&fresh), "rerun the tests with CONFIGMATON_BLESS=1");
+        let msg = unsafe {
+            Msg::try_read(|buf| buf.copy_from(LE_BLOB.as_ptr(), LE_BLOB.len()), LE_BLOB.len()) }
+            .unwrap();
+        assert_swap_behaviour(&msg);
+    }
+
+    #[test]
+    fn blob_header() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let data = unsafe { std::slice::from_raw_parts(outmsg.data, outmsg.data_len()) }.to_vec();
+        let read = |data: &[u8], len: usize| unsafe {
+            Msg::read(|buf| buf.copy_from(data.as_ptr(), len), len) };
+        let check = |data: &[u8], len: usize|
+            read(data, len).get_root::<Automaton>().map(|_| ()).unwrap_err();
+
+        let header = unsafe { &*(outmsg.data as *const BlobHeader) };
+        assert_eq!(header.magic, BLOB_MAGIC);
+        assert_eq!(header.version, FORMAT_VERSION);
+        assert_eq!(header.length, data.len() as u64);
+
+        // Extra space behind the blob is fine.
+        let mut padded = data.clone();
+        padded.extend([0; 16]);
+        assert!(read(&padded, padded.len()).get_root::<Automaton>().is_ok());
+
+        let mut other = data.clone();
+        other[offset_of!(BlobHeader, magic)] ^= 1;
+        assert_eq!(check(&other, other.len()), BlobError::Magic);
+        assert_eq!(check(&other, other.len()).to_string(), "not a configmaton blob");
+
+        let mut other = data.clone();
+        other[offset_of!(BlobHeader, version)] += 1;
+        assert_eq!(check(&other, other.len()), BlobError::Version { found: FORMAT_VERSION + 1 });
+
+        let len = data.len() - 1;
+        let err = check(&data, len);
+        assert_eq!(err, BlobError::Truncated { length: data.len() as u64, available: len });
+        assert_eq!(err.to_string(),
+            format!("the blob has {} bytes, only {} are available", data.len(), len));
+        assert_eq!(check(&data, 4),
+            BlobError::Truncated { length: size_of::<BlobHeader>() as u64, available: 4 });
+    }
+
+    #[test]
+    fn try_serialize() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#).unwrap();
+        let (parser, mut init) = Parser::parse(config);
+        let msg = Msg::try_serialize(&parser, &init, &TestU8BuildConfig).unwrap();
+        assert_eq!(msg.data_len(), Msg::serialize(&parser, &init, &TestU8BuildConfig).data_len());
+
+        init.states.push(parser.states.len());
+        let err = Msg::try_serialize(&parser, &init, &TestU8BuildConfig).err().unwrap();
+        assert_eq!(err, BuildError::IndexOutOfRange {
+            what: "KeyValState", index: parser.states.len(), len: parser.states.len() });
+    }
+
+    #[test]
+    fn reproducible() {
+        // Sparse states with the bytes of the patterns in the explicit transitions.
+        struct ExplicitConfig;
+        impl U8BuildConfig for ExplicitConfig {
+            fn guard_size_keep(&self) -> u32 { 256 }
+            fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 1 }
+            fn dense_guard_count(&self) -> usize { 256 }
+            fn hash_seed(&self) -> u64 { 1234 }
+        }
+
+        let serialize = || {
+            let config: Vec<Cmd> = serde_json::from_str(r#"[
+                {"when": {"foo": "[a-f]x|[c-h]y", "bar": "[0-9a-z]"}, "run": ["m0"], "then": [
+                    {"when": {"baz": "a*b|[ab]c"}, "run": ["m1"]}
+                ]},
+                {"when": {"foo": "(x|y|z)+"}, "run": ["m2"]}
+            ]"#).unwrap();
+            let (parser, init) = Parser::parse(config);
+            let msg = Msg::serialize(&parser, &init, &ExplicitConfig);
+            unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) }.to_vec()
+        };
+        let blob = serialize();
+        for _ in 0..8 { assert_eq!(serialize(), blob); }
+    }
+
+    #[test]
+    fn profiles() {
+        let config = r#"[
+            {"when": {}, "run": ["always"]},
+            {"profiles": {
+                "prod": [{"when": {}, "run": ["prod"]}],
+                "dev": [{"when": {"foo": "a"}, "run": ["dev"]}]
+            }}
+        ]"#;
+        let parse = |profile| {
+            let (parser, init) = Parser::parse_profile(serde_json::from_str(config).unwrap(), profile);
+            let mut exts = init.exts.clone();
+            exts.sort();
+            (parser.states.len(), exts)
+        };
+        assert_eq!(parse(Some("prod")), (0, vec![b"always".to_vec(), b"prod".to_vec()]));
+        assert_eq!(parse(Some("dev")), (1, vec![b"always".to_vec()]));
+        assert_eq!(parse(None), (0, vec![b"always".to_vec()]));
+
+        let err = serde_json::from_str::<Vec<Cmd>>(
+            r#"[{"when": {}, "profiles": {"dev": []}}]"#).unwrap_err();
+        assert!(err.to_string().contains("profiles cannot be combined"));
+    }
+
+    #[test]
+    fn normalize() {
+        let parse = |config| Parser::parse(serde_json::from_str(config).unwrap());
+        let a = parse(r#"[
+            {"normalize": {"foo": ["trim"]}},
+            {"when": {"foo": "a"}, "run": ["m"]},
+            {"normalize": {"bar": ["lowercase"]}}
+        ]"#);
+        let b = parse(r#"[{"normalize": {"foo": ["strip_quotes"], "baz": []}}]"#);
+        let (parser, init) = Parser::merge(a, b);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let normalizers = unsafe { crate::blob::automaton::normalizers(msg.get_automaton()) }
+            .collect::<Vec<_>>();
+        assert_eq!(normalizers, vec![
+            (b"foo".as_ref(), Normalizer::TRIM.union(Normalizer::STRIP_QUOTES)),
+            (b"bar", Normalizer::LOWERCASE),
+            (b"baz", Normalizer::default()),
+        ]);
+
+        for (config, err) in [
+            (r#"[{"normalize": {"foo": ["upper"]}}]"#, "unknown variant `upper`"),
+            (r#"[{"normalize": {"foo": "trim"}}]"#, "not an array"),
+            (r#"[{"normalize": {"foo": ["trim"]}, "when": {}}]"#, "normalize cannot be combined"),
+            (r#"[{"when": {}, "then": [{"normalize": {"foo": ["trim"]}}]}]"#,
+                "normalize cannot be nested in a match"),
+            (r#"[{"when": {}, "then": [{"profiles": {"dev": [{"defaults": {"foo": "a"}}]}}]}]"#,
+                "defaults cannot be nested in a match"),
+        ] {
+            let parsed = serde_json::from_str::<Vec<Cmd>>(config).unwrap_err();
+            assert!(parsed.to_string().contains(err), "{}", parsed);
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let parse = |config| Parser::parse(serde_json::from_str(config).unwrap());
+        let (parser, init) = Parser::merge(
+            parse(r#"[{"when": {"foo": "a", "bar": "x+"}, "run": ["m1"]}, {"when": {}, "run": ["i1"]}]"#),
+            parse(r#"[{"when": {"foo": "a|b"}, "run": ["m2"]}, {"when": {"bar": "x+"}, "run": ["m3"]}]"#),
+        );
+        assert_eq!(parser.states.len(), 5);
+
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let inmsg = unsafe {
+            Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
+        let mut sim = Simulation::new(inmsg.get_automaton(), |_| None);
+        assert_eq!(sim.exts.pop(), Some(b"i1".as_ref()));
+
+        sim.read(b"foo", b"a", |x| match x { b"foo" => Some(b"a"), _ => None });
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"m2".as_ref()]);
+        sim.read(b"bar", b"xx",
+            |x| match x { b"foo" => Some(b"a"), b"bar" => Some(b"xx"), _ => None });
+        let mut exts = sim.exts.iter().copied().collect::<Vec<_>>();
+        exts.sort();
+        assert_eq!(exts, vec![b"m1".as_ref(), b"m2", b"m3"]);
+    }
+
+    #[test]
+    fn pattern_cache() {
+        let config = r#"[
+            {"when": {"foo": "a(b|c)*", "bar": "x+"}, "run": ["m1"]},
+            {"when": {"foo": "(a)(b|c)*"}, "run": ["m2"]}
+        ]"#;
+        let (parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+        let (mut cached, cached_init) =
+            Parser::parse_cached(serde_json::from_str(config).unwrap(), None, PatternCache::new());
+        let mut cache = cached.take_cache().unwrap();
+        assert_eq!((cache.hits, cache.misses), (1, 2));
+
+        let (mut relinked, relinked_init) =
+            Parser::parse_cached(serde_json::from_str(config).unwrap(), None, cache);
+        cache = relinked.take_cache().unwrap();
+        assert_eq!((cache.hits, cache.misses), (4, 2));
+
+        let run = |parser: &Parser, init: &LeafOrigin| {
+            let outmsg = Msg::serialize(parser, init, &TestU8BuildConfig);
+            let inmsg = unsafe {
+                Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
+            let mut sim = Simulation::new(inmsg.get_automaton(), |_| None);
+            sim.read(b"foo", b"abcb", |x| match x { b"foo" => Some(b"abcb"), _ => None });
+            sim.read(b"bar", b"xx",
+                |x| match x { b"foo" => Some(b"abcb"), b"bar" => Some(b"xx"), _ => None });
+            let mut exts = sim.exts.iter().map(|ext| ext.to_vec()).collect::<Vec<_>>();
+            exts.sort();
+            exts
+        };
+        assert_eq!(run(&parser, &init), vec![b"m1".to_vec(), b"m2".to_vec()]);
+        assert_eq!(run(&cached, &cached_init), run(&parser, &init));
+        assert_eq!(run(&relinked, &relinked_init), run(&parser, &init));
+    }
+
+    #[test]
+    fn classify() {
+        let config = r#"[
+            {"when": {"foo": "a.*"}, "then": [{"when": {"bar": "ab"}, "run": ["x"]}]},
+            {"when": {"qux": "b|ab"}, "run": ["y"]}
+        ]"#;
+        let (parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+        let tag = |regex: &str| parser.regexes[regex].1.0;
+        let inits = parser.regexes.values().map(|(dfa_state_ix, _)| dfa_state_ix.0);
+        assert_eq!(parser.nfa.matching_tags(inits.clone(), b"ab"),
+            { let mut tags = vec![tag("a.*"), tag("ab"), tag("b|ab")]; tags.sort(); tags });
+        assert_eq!(parser.nfa.matching_tags(inits.clone(), b"b"), vec![tag("b|ab")]);
+        assert_eq!(parser.nfa.matching_tags(inits, b"c"), Vec::<usize>::new());
+
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let inmsg = unsafe {
+            Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
+        let aut = inmsg.get_automaton();
+        let exts_section: &VecOfVecs<u8> = unsafe { aut.a.behind() };
+        let rules_section: &BlobVec<u64> = unsafe { exts_section.behind() };
+        let initial_states: &BlobVec<BlobPtr<KeyValState>> = unsafe { rules_section.behind() };
+        let dfa_inits = unsafe {
+            Runner::pattern_inits(initial_states.as_ref().iter().map(|state| &*state.get())) };
+        assert_eq!(dfa_inits.len(), 3);
+        for value in [b"ab".as_ref(), b"b", b"c", b"abc"] {
+            let inits = parser.regexes.values().map(|(dfa_state_ix, _)| dfa_state_ix.0);
+            assert_eq!(unsafe { Runner::classify(dfa_inits.iter().copied(), value) },
+                parser.nfa.matching_tags(inits, value));
+        }
+    }
+
+    #[test]
+    fn determinize() {
+        let config = r#"[
+            {"when": {"foo": "x*y", "bar": "(a|b)*a(a|b)(a|b)(a|b)(a|b)"}, "run": ["m1"]},
+            {"when": {"foo": "(ab)*"}, "then": [{"when": {"bar": "x*y"}, "run": ["m2"]}]}
+        ]"#;
+        let (mut parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+        let values = [b"".as_ref(), b"xxy", b"abab", b"aaaaaa", b"baabb", b"bbbbb"];
+        let matching = |parser: &Parser| values.iter().map(|value| {
+            let inits = parser.regexes.values().map(|(dfa_state_ix, _)| dfa_state_ix.0);
+            parser.nfa.matching_tags(inits, value)
+        }).collect::<Vec<_>>();
+        let before = matching(&parser);
+
+        assert_eq!(parser.determinize(16), vec!["(a|b)*a(a|b)(a|b)(a|b)(a|b)"]);
+        assert_eq!(matching(&parser), before);
+        assert_eq!(parser.determinize(64), Vec::<String>::new());
+        assert_eq!(matching(&parser), before);
+
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let inmsg = unsafe {
+            Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
+        let db = |key: &[u8]| match key {
+            b"foo" => Some(b"xxy".as_ref()),
+            b"bar" => Some(b"babbbb".as_ref()),
+            _ => None,
+        };
+        let mut simulation = Simulation::new(inmsg.get_automaton(), |_| None);
+        simulation.read(b"foo", b"xxy", db);
+        assert_eq!(simulation.exts.iter().collect::<Vec<_>>(), vec![b"m1"]);
+    }
+
+    #[test]
+    fn optimized() {
+        // The rules without commands check the same condition.
+        let config = r#"[
+            {"when": {"foo": "x*y", "bar": "(a|b)*a(a|b)(a|b)"}, "run": ["m1"]},
+            {"when": {"foo": "(ab)*"}, "then": [{"when": {"bar": "x*y"}, "run": ["m2"]}]},
+            {"when": {"qux": "a"}},
+            {"when": {"qux": "a"}}
+        ]"#;
+        let (mut parser, mut init) = Parser::parse(serde_json::from_str(config).unwrap());
+        parser.determinize(64);
+        let run = |parser: &Parser, init: &LeafOrigin| {
+            let outmsg = Msg::serialize(parser, init, &TestU8BuildConfig);
+            let inmsg = unsafe {
+                Msg::read(|buf| buf.copy_from(outmsg.data, outmsg.data_len()), outmsg.data_len()) };
+            let values = [(b"foo", b"ab".as_ref()), (b"bar", b"xy"), (b"foo", b"xy"),
+                (b"bar", b"abab"), (b"qux", b"a")];
+            let mut db = HashMap::new();
+            let mut sim = Simulation::new(inmsg.get_automaton(), |_| None);
+            let exts = values.into_iter().map(|(key, value)| {
+                db.insert(key.as_ref(), value);
+                sim.read(key, value, |x| db.get(x).copied());
+                sim.exts.iter().map(|ext| ext.to_vec()).collect::<Vec<_>>()
+            }).collect::<Vec<_>>();
+            (exts, outmsg.data_len())
+        };
+        let (u8_states, keyval_states) = (parser.nfa.states.len(), parser.states.len());
+        let (before, size) = run(&parser, &init);
+        assert_eq!(before[1], vec![b"m2".to_vec()]);
+
+        optimize(&mut parser, &mut init);
+        assert!(parser.nfa.states.len() < u8_states);
+        assert_eq!(parser.states.len(), keyval_states - 1);
+        assert_eq!(parser.state_rules.len(), parser.states.len());
+        let (after, optimized_size) = run(&parser, &init);
+        assert_eq!(after, before);
+        assert!(optimized_size < size);
+    }
+
+    #[test]
+    fn budget() {
+        let config = r#"[{"when": {"foo": "(a|b)*c", "bar": "abcdefgh"}, "run": ["m"]}]"#;
+        let parse = |budget| {
+            Parser::parse_budgeted(serde_json::from_str(config).unwrap(), None, budget)
+        };
+        let (parser, init) = parse(CompileBudget::default()).unwrap();
+        let nfa_states = parser.nfa.states.len();
+        let blob_size = Msg::serialize(&parser, &init, &TestU8BuildConfig).data_len();
+
+        let budget = CompileBudget { max_ast_nodes: 10, ..Default::default() };
+        assert_eq!(parse(budget).err(), Some(BudgetExceeded::AstNodes {
+            regex: "abcdefgh".to_owned(), nodes: 15, limit: 10 }));
+        let budget = CompileBudget { max_nfa_states: nfa_states - 1, ..Default::default() };
+        assert_eq!(parse(budget).err(), Some(BudgetExceeded::NfaStates {
+            regex: "abcdefgh".to_owned(), limit: nfa_states - 1 }));
+
+        let budget = CompileBudget {
+            max_ast_nodes: 15, max_nfa_states: nfa_states, max_blob_size: blob_size - 1 };
+        let (parser, init) = parse(budget.clone()).unwrap();
+        assert_eq!(Msg::serialize_budgeted(&parser, &init, &TestU8BuildConfig, &budget).err(),
+            Some(BudgetExceeded::BlobSize { size: blob_size, limit: blob_size - 1 }));
+        let budget = CompileBudget { max_blob_size: blob_size, ..budget };
+        let msg = Msg::serialize_budgeted(&parser, &init, &TestU8BuildConfig, &budget).unwrap();
+        assert_eq!(msg.data_len(), blob_size);
+
+        for (config, regex) in [
+            (r#"[{"when": {"foo": "a(b"}, "run": ["m"]}]"#, "a(b"),
+            (r#"[{"when": {"foo": "a(b"}, "run": ["m"], "match_mode": "substring"}]"#, "a(b"),
+        ] {
+            let error = Parser::parse_budgeted(
+                serde_json::from_str(config).unwrap(), None, CompileBudget::default()).err();
+            let Some(BudgetExceeded::InvalidRegex { regex: invalid, .. }) = error else {
+                panic!("{:?}", error);
+            };
+            assert_eq!(invalid, regex);
+        }
+    }
+
+    #[test]
+    fn msg_ref() {
+        let config = r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#;
+        let (parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let len = outmsg.data_len();
+
+        let mut storage = vec![0u128; len / 16 + 2];
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(storage.as_mut_ptr() as *mut u8, storage.len() * 16) };
+        unsafe { bytes.as_mut_ptr().copy_from(outmsg.data, len) };
+        assert_eq!(unsafe { MsgRef::from_mut_slice(&mut bytes[1..len + 1]) }.err(),
+            Some(MisalignedBuffer { required: align_of::<u128>() }));
+
+        let msg = unsafe { MsgRef::from_mut_slice(&mut bytes[..len]) }.unwrap();
+        assert_eq!(msg.data_len(), len);
+        let mut simulation = Simulation::new(msg.get_automaton(), |_| None);
+        simulation.read(b"foo", b"a", |_| None);
+        assert_eq!(simulation.exts.iter().collect::<Vec<_>>(), vec![b"bar"]);
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no temp_dir on WASI")]
+    fn file() {
+        let config = r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#;
+        let (parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+        let outmsg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let path = std::env::temp_dir()
+            .join(format!("configmaton-msg-{}.bin", std::process::id()));
+
+        outmsg.write_to(&path).unwrap();
+        let inmsg = Msg::read_from(&path).unwrap();
+        let mut simulation = Simulation::new(inmsg.get_automaton(), |_| None);
+        simulation.read(b"foo", b"a", |_| None);
+        assert_eq!(simulation.exts.iter().collect::<Vec<_>>(), vec![b"bar"]);
+        assert_eq!(inmsg.write_to(&path).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let read_error = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            Msg::read_from(&path).err().unwrap().to_string()
+        };
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert_eq!(read_error(&bytes), "checksum mismatch");
+        let len = outmsg.data_len();
+        assert_eq!(read_error(&bytes[..len - 1]), format!(
+            "the blob has {} bytes, only {} are available", len, len - 9));
+        assert_eq!(read_error(&[b'x'; 100]), "not a configmaton blob");
+        assert_eq!(read_error(b"x"), "the blob has 48 bytes, only 1 are available");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn join_leaves_order() {
+        let leaf = |states: Vec<usize>, exts: &[&str], rules: Vec<usize>| LeafOrigin {
+            states,
+            get_olds: exts.iter().map(|ext| ext.as_bytes().to_vec()).collect(),
+            exts: exts.iter().map(|ext| ext.as_bytes().to_vec()).collect(),
+            rules,
+            group: vec![],
+        };
+        let joined = join_leaves([
+            leaf(vec![5, 1], &["b", "a"], vec![0, 1]),
+            leaf(vec![1, 3, 0], &["a", "a", "c"], vec![1, 2, 0]),
+        ].into_iter());
+        assert_eq!(joined.states, vec![5, 1, 3, 0]);
+        assert_eq!(joined.get_olds, vec![b"b".to_vec(), b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(joined.exts, vec![b"b".to_vec(), b"a".to_vec(), b"a".to_vec(), b"c".to_vec()]);
+        assert_eq!(joined.rules, vec![0, 1, 2, 0]);
+
+        let config = r#"[
+            {"when": {}, "run": ["x", "y"]},
+            {"when": {}, "run": ["z", "x"]},
+            {"when": {"a": "1"}, "run": ["m"], "then": [{"when": {}, "run": ["n"]}]}
+        ]"#;
+        let compile = || {
+            let (parser, init) = Parser::parse(serde_json::from_str(config).unwrap());
+            let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+            let data = unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) }.to_vec();
+            (init.exts, data)
+        };
+        let (exts, data) = compile();
+        assert_eq!(exts, vec![b"x".to_vec(), b"y".to_vec(), b"z".to_vec(), b"x".to_vec()]);
+        assert_eq!(compile().1, data);
+    }
+
+    #[test]
+    fn patterns() {
+        let parse = |config: &str| Parser::parse(serde_json::from_str(config).unwrap());
+        let a = parse(r#"[
+            {"when": {"foo": "a.*", "bar": "b"}, "run": ["m1"]},
+            {"when": {"qux": "a.*"}, "run": ["m2"]}
+        ]"#);
+        let b = parse(r#"[{"when": {"foo": "c|d"}, "run": ["m3"]}]"#);
+        let (mut parser, init) = Parser::merge(a, b);
+        let read = |parser: &Parser| {
+            let msg = Msg::serialize(parser, &init, &TestU8BuildConfig);
+            unsafe { Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) }
+        };
+
+        let msg = read(&parser);
+        let patterns = unsafe { crate::blob::automaton::patterns(msg.get_automaton()) };
+        let keys_ids = patterns.iter().map(|p| (p.key, p.id)).collect::<Vec<_>>();
+        assert_eq!(keys_ids, vec![(b"foo".as_ref(), 0), (b"bar", 1), (b"qux", 0), (b"foo", 2)]);
+        assert!(patterns.iter().all(|p| p.regex.is_none()));
+        let uses = |tag| unsafe { crate::blob::automaton::uses_of_tag(msg.get_automaton(), tag) }
+            .iter().map(|x| (x.rule, x.pattern)).collect::<Vec<_>>();
+        assert_eq!(uses(0), vec![(0, 0), (1, 2)]);
+        assert_eq!(uses(1), vec![(0, 1)]);
+        assert_eq!(uses(2), vec![(2, 3)]);
+        assert!(uses(3).is_empty());
+        assert_eq!(unsafe { crate::blob::automaton::tag_rules(msg.get_automaton()) }.len(), 4);
+
+        parser.embed_patterns = true;
+        let msg = read(&parser);
+        let patterns = unsafe { crate::blob::automaton::patterns(msg.get_automaton()) };
+        let regexes = patterns.iter().map(|p| p.regex.unwrap()).collect::<Vec<_>>();
+        assert_eq!(regexes, vec![b"a.*".as_ref(), b"b", b"a.*", b"c|d"]);
+        assert_eq!(parser.regexes["c|d"].1.0, 2);
+    }
+
+    #[test]
+    fn read_sections() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {}, "run": ["m0"]},
+            {"when": {"foo": "a"}, "run": ["m1"], "then": [{"when": {"bar": "b"}, "run": ["m2"]}]}
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let (msg, map) = Msg::serialize_with_map(&parser, &init, &TestU8BuildConfig);
+        let mut data = unsafe { std::slice::from_raw_parts(msg.data, msg.data_len()) }.to_vec();
+        let read = |data: &[u8], sections: &[Section]| unsafe {
+            Msg::read_sections(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len(), sections)
+        };
+
+        // Corrupt the u8 states, which then cannot be read as a whole anymore.
+        let u8_states = map.sorted().into_iter().find(|r| r.name == "u8_states").unwrap();
+        data[u8_states.start..u8_states.end].fill(0xff);
+        let full = unsafe {
+            Msg::try_read(|buf| buf.copy_from(data.as_ptr(), data.len()), data.len()) };
+        assert!(matches!(full, Err(BlobError::Corrupt { .. })));
+
+        let partial = read(&data, &[Section::Inits, Section::Exts, Section::Inits]).unwrap();
+        assert_eq!(partial.loaded(), &[Section::Exts, Section::Inits]);
+        let exts = unsafe { partial.section::<VecOfVecs<u8>>(Section::Exts) }.unwrap();
+        let inits = unsafe {
+            partial.section::<BlobVec<BlobPtr<KeyValState>>>(Section::Inits) }.unwrap();
+        assert_eq!(unsafe { exts.iter() }.collect::<Vec<_>>(), vec![b"m0".as_ref()]);
+        assert_eq!(inits.len(), init.states.len());
+        let keyval_states = map.sorted().into_iter().find(|r| r.name == "keyval_states").unwrap();
+        for q in unsafe { inits.as_ref() } {
+            let offset = q.get() as usize - partial.msg.data as usize;
+            assert!(keyval_states.start < offset && offset < keyval_states.end);
+        }
+        assert!(unsafe { partial.section::<VecOfVecs<u8>>(Section::Getolds) }.is_none());
+
+        assert!(matches!(read(&data, &[Section::U8States]), Err(BlobError::Corrupt { .. })));
+        let offset = offset_of!(BlobHeader, sections);
+        data[offset..offset + 8].copy_from_slice(&1u64.to_ne_bytes());
+        assert_eq!(read(&data, &[]).map(|_| ()), Err(BlobError::Corrupt { offset: 1 }));
+    }
+
+    #[test]
+    fn memory_map() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let (msg, map) = Msg::serialize_with_map(&parser, &init, &TestU8BuildConfig);
+
+        let regions = map.sorted();
+        assert_eq!(regions[0].name, "header");
+        assert_eq!(regions[0].start, 0);
+        assert_eq!(regions[1].name, "automaton");
+        assert_eq!(regions[1].start, size_of::<BlobHeader>());
+        assert!(regions[1].end <= msg.data_len());
+
+        let sections = regions.iter().filter(|r| r.depth == 1).map(|r| r.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(sections,
+            vec![
+                "getolds", "exts", "rules", "inits", "default_keys", "default_values",
+                "normalized_keys", "normalizers", "pattern_keys", "pattern_ids", "pattern_sources",
+                "tag_rules", "keyval_states", "u8_states", "tag_pool",
+            ]);
+        for pair in regions.windows(2) {
+            if pair[0].depth == pair[1].depth { assert!(pair[0].end <= pair[1].start); }
+        }
+        let kvqs = regions.iter().filter(|r| r.name.starts_with("keyval_state ")).count();
+        assert_eq!(kvqs, parser.states.len());
+
+        let mut text = vec![];
+        map.to_text(&mut text);
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.lines().any(|line| line.ends_with("    u8_state 0")));
+        let mut dot = vec![];
+        map.to_dot(&mut dot);
+        assert!(String::from_utf8(dot).unwrap().contains("r1 -> r2"));
+    }
+
+    #[cfg(all(unix, feature = "shm"))]
+    #[test]
+    fn shared_blob() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let name = format!("configmaton-test-{}", std::process::id());
+
+        let created = Msg::serialize_shared(&name, &parser, &init, &TestU8BuildConfig).unwrap();
+        assert!(Msg::serialize_shared(&name, &parser, &init, &TestU8BuildConfig).is_err());
+        // The creator's mapping occupies the address, so this process cannot attach meanwhile.
+        assert!(Msg::attach_shared(&name).is_err());
+        drop(created);
+
+        let attached = Msg::attach_shared(&name).unwrap();
+        Msg::unlink_shared(&name).unwrap();
+        let mut sim = Simulation::new(attached.get_automaton(), |_| None);
+        sim.read(b"foo", b"a", |_| None);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"bar".as_ref()]);
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no temp_dir on WASI")]
+    fn owned_automaton() {
+        let config: Vec<Cmd> = serde_json::from_str(
+            r#"[{"when": {"foo": "a"}, "run": ["bar"]}]"#).unwrap();
+        let owned = OwnedAutomaton::from_config(config, &TestU8BuildConfig);
+        let mut sim = Simulation::new(owned.as_automaton(), |_| None);
+        sim.read(b"foo", b"a", |_| None);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"bar".as_ref()]);
+        assert!(owned.msg().write_to(std::env::temp_dir().join("unused.blob")).is_err());
+
+        let (parser, mut init) = Parser::parse(vec![]);
+        init.states.push(1);
+        assert!(OwnedAutomaton::try_new(&parser, &init, &TestU8BuildConfig).is_err());
+    }
+
+    #[test]
+    fn rename_keys() {
+        let config = r#"[
+            {"when": {"db.host": "h.*"}, "then": [{"when": {"user": "root"}, "run": ["alert"]}]},
+            {"defaults": {"db.port": "5432"}},
+            {"normalize": {"user": ["lowercase"]}}
+        ]"#;
+        let renames = KeyRenames::new().prefix("db.", "storage.").key("user", "login");
+        let run = |owned: &OwnedAutomaton| {
+            let automaton = owned.as_automaton();
+            let db = |key: &[u8]| if key == b"login" { Some(b"ROOT".as_ref()) } else { None };
+            let mut sim = Simulation::new(automaton, db);
+            sim.read(b"login", b"ROOT", db);
+            sim.read(b"storage.host", b"h1", db);
+            let defaults = unsafe { crate::blob::automaton::defaults(automaton) }
+                .map(|(key, _)| key.to_vec()).collect::<Vec<_>>();
+            (sim.exts.iter().map(|ext| ext.to_vec()).collect::<Vec<_>>(), defaults)
+        };
+        let expected = (vec![b"alert".to_vec()], vec![b"storage.port".to_vec()]);
+
+        let (mut parser, mut init) = Parser::parse(serde_json::from_str(config).unwrap());
+        parser.rename_keys(&mut init, &renames);
+        let keys = parser.patterns.iter().map(|(key, _, _)| key.as_slice()).collect::<Vec<_>>();
+        assert_eq!(keys, vec![b"login".as_ref(), b"storage.host"]);
+        assert_eq!(run(&OwnedAutomaton::new(&parser, &init, &TestU8BuildConfig)), expected);
+
+        let mut cmds: Vec<Cmd> = serde_json::from_str(config).unwrap();
+        for cmd in cmds.iter_mut() { cmd.rename_keys(&renames); }
+        assert_eq!(run(&OwnedAutomaton::from_config(cmds, &TestU8BuildConfig)), expected);
     }
 }
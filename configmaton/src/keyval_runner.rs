@@ -1,103 +1,423 @@
-use hashbrown::HashMap;
+use std::cell::Cell;
+use std::sync::Arc;
+
+use hashbrown::HashSet;
 use indexmap::IndexSet;  // we use IndexSet for faster worst-case iteration
+use twox_hash::XxHash64;
 
-use crate::blob::keyval_state::{Finals, KeyValState, LeafMeta};
+use crate::blob::bdd::VarProvider;
+use crate::blob::keyval_state::{InitsAndFinals, KeyValState};
 use crate::blob::sediment::Sediment;
+use crate::blob::state::U8State;
 use crate::blob::vec::BlobVec;
-use crate::blob::{align_up_ptr, get_behind_struct, FakeSafeIterator, UnsafeIterator};
+use crate::blob::{BlobPtr, UnsafeIterator};
 use crate::char_runner;
+use crate::intern::Interner;
+use crate::normalize::Normalizer;
+use crate::numeric;
+#[cfg(feature = "coverage")]
+use crate::recorder::SharedRecorder;
+
+pub type Exts<'a> = Sediment<'a, BlobVec<'a, u8>>;
+
+// The sorted tags matched by the DFAs, as the variables of the BDDs of the transitions. The
+// variables are looked up from the last one queried on, so a provider serves one evaluation.
+pub struct MatchedTags<'t> {
+    tags: &'t [usize],
+    cursor: Cell<usize>,
+}
+
+impl<'t> MatchedTags<'t> {
+    pub fn new(tags: &'t [usize]) -> Self {
+        MatchedTags { tags, cursor: Cell::new(0) }
+    }
+}
+
+impl VarProvider<u64> for MatchedTags<'_> {
+    fn holds(&self, var: &u64) -> bool {
+        let cursor = self.cursor.get();
+        match self.tags[cursor..].binary_search(&(*var as usize)) {
+            Ok(ix) => { self.cursor.set(cursor + ix + 1); true }
+            Err(ix) => { self.cursor.set(cursor + ix); false }
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Runner<'a> {
-    // Mapping from symbols to such current states from which a transition via the symbol exists.
-    pub sparse: HashMap<&'a [u8], IndexSet<*const KeyValState<'a>>>,
+    // Mapping from symbols (indexed by their IDs) to such current states from which a transition
+    // via the symbol exists.
+    pub sparse: Vec<IndexSet<*const KeyValState<'a>>>,
+    // The symbols of all the transitions, those of the states not reachable excepted.
+    keys: Arc<Interner<'a>>,
+    // Leaves of these rule groups are skipped, as if their rules did not exist.
+    disabled_groups: HashSet<Vec<u8>>,
+    #[cfg(feature = "coverage")]
+    coverage: Option<SharedRecorder<'a>>,
 }
 
+#[allow(clippy::missing_safety_doc)]
 impl<'a> Runner<'a>
 {
     // Initialize the state of the automaton.
     pub unsafe fn new<'b, I: IntoIterator<Item = &'b KeyValState<'a>>>(initial_states: I) -> Self
         where 'a: 'b
     {
-        let mut result = Runner{ sparse: HashMap::new() };
+        let initial_states = initial_states.into_iter().collect::<Vec<_>>();
+        let keys = Arc::new(Interner::of_states(initial_states.iter().copied()));
+        let mut result = Runner {
+            sparse: (0..keys.len()).map(|_| IndexSet::new()).collect(),
+            keys,
+            disabled_groups: HashSet::new(),
+            #[cfg(feature = "coverage")]
+            coverage: None,
+        };
         for any_state_lock in initial_states { result.add_right_state(any_state_lock); }
         result
     }
 
-    // Read a symbol, perform transitions.
-    pub unsafe fn read<GetOld: FnMut(&'a [u8]), RunExt: FnMut(&'a [u8])>(
-        &mut self, sym: &[u8], value: &[u8], mut get_old: GetOld, mut run_ext: RunExt
-    ) {
+    pub fn keys(&self) -> &Arc<Interner<'a>> {
+        &self.keys
+    }
+
+    // Mark the states visited from now on (starting with the current ones) in the recorder. Clones
+    // of the runner share it.
+    #[cfg(feature = "coverage")]
+    pub fn record_coverage(&mut self, recorder: Option<SharedRecorder<'a>>) {
+        if let Some(recorder) = &recorder {
+            let mut recorder = recorder.borrow_mut();
+            for state in self.sparse.iter().flatten() { recorder.visit_keyval_state(*state); }
+        }
+        self.coverage = recorder;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        #[cfg(feature = "coverage")]
+        if self.coverage.is_some() { return true; }
+        false
+    }
+
+    // Read a symbol, perform transitions. Returns the number of transitions taken.
+    pub unsafe fn read<GetOld: FnMut(&'a [u8]), RunExts: FnMut(&'a Exts<'a>, &'a [u64])>(
+        &mut self, sym: &[u8], value: &[u8], get_old: GetOld, run_exts: RunExts
+    ) -> usize {
+        let trans = self.take_transitions(sym);
+        if trans.is_empty() { return 0; }
+        let count = trans.len();
+        let tags = self.match_value_recorded(&trans, value);
+        self.apply_tags(trans, &tags, get_old, run_exts);
+        count
+    }
+
+    // Detach the current states listening on `sym` and return their transitions via `sym`.
+    // The states stop listening on all their other keys, too.
+    pub unsafe fn take_transitions(&mut self, sym: &[u8]) -> Vec<&'a InitsAndFinals<'a>> {
         let mut trans = vec![];
+        let Some(id) = self.keys.id(sym) else { return trans };
 
         // Prepare the results.
-        match self.sparse.get_mut(sym) {
-            None => return,
-            Some(states) => {
-                let old_sparse_states = std::mem::take(states);
-
-                // First, let's remove all listeners for transitions of the old states
-                for left_state in old_sparse_states.iter().cloned() {
-                    let mut keyvals = (*left_state).keyvals();
-                    while let Some((key, tran)) = keyvals.next() {
-                        if sym == key {
-                            // Register new listeners for transitions of the successors.
-                            trans.push(tran);
-                        } else {
-                            // Remove listeners for transitions of the left_state (other than the
-                            // one via `symbol` which is already removed).
-                            self.sparse.get_mut(key).unwrap().swap_remove(&left_state);
-                        }
-                    }
+        let old_sparse_states = std::mem::take(&mut self.sparse[id.index()]);
+
+        // First, let's remove all listeners for transitions of the old states
+        for left_state in old_sparse_states.iter().cloned() {
+            let mut keyvals = (*left_state).keyvals();
+            while let Some((key, tran)) = keyvals.next() {
+                if sym == key {
+                    // Register new listeners for transitions of the successors.
+                    trans.push(tran);
+                } else {
+                    // Remove listeners for transitions of the left_state (other than the one via
+                    // `symbol` which is already removed).
+                    let id = self.keys.id(key).unwrap();
+                    self.sparse[id.index()].swap_remove(&left_state);
                 }
-            },
+            }
         }
 
-        let mut crunner = char_runner::Runner::new(
-            trans.iter().flat_map(|tran| FakeSafeIterator(tran.a.iter())).copied()
-        );
+        trans
+    }
+
+    // Run the value through the DFAs and the numeric guards of the given transitions, returning
+    // the sorted matched tags. This does not touch the runner, so values of different keys can be
+    // matched in parallel.
+    pub unsafe fn match_value(trans: &[&'a InitsAndFinals<'a>], value: &[u8]) -> Vec<usize> {
+        let inits = trans.iter().flat_map(|tran| tran.inits()).map(BlobPtr::get);
+        let mut tags = Self::classify(inits, value);
+        Self::add_num_tags(trans, value, &mut tags);
+        tags
+    }
+
+    // Like `match_value`, marking the visited char states if coverage is recorded.
+    pub unsafe fn match_value_recorded(&self, trans: &[&'a InitsAndFinals<'a>], value: &[u8])
+        -> Vec<usize>
+    {
+        #[cfg(feature = "coverage")]
+        if let Some(recorder) = &self.coverage {
+            let mut recorder = recorder.borrow_mut();
+            let inits = trans.iter().flat_map(|tran| tran.inits()).map(BlobPtr::get);
+            let mut crunner = char_runner::Runner::new(inits);
+            recorder.visit_u8_states(crunner.states.iter());
+            for c in value { crunner.read_traced(*c, &mut *recorder); }
+            crunner.finish();
+            recorder.visit_u8_states(crunner.states.iter());
+            let mut tags = crunner.get_tags().collect::<Vec<_>>();
+            tags.sort_unstable();
+            tags.dedup();
+            Self::add_num_tags(trans, value, &mut tags);
+            return tags;
+        }
+        Self::match_value(trans, value)
+    }
+
+    // Add the tags of the numeric guards satisfied by the value to the sorted tags.
+    unsafe fn add_num_tags(trans: &[&'a InitsAndFinals<'a>], value: &[u8], tags: &mut Vec<usize>) {
+        let mut guards = trans.iter().flat_map(|tran| tran.num_guards()).peekable();
+        if guards.peek().is_none() { return; }
+        let Some(number) = numeric::parse(value) else { return };
+        let len = tags.len();
+        let matched = guards.filter(|guard| guard.range.contains_number(number));
+        tags.extend(matched.map(|x| x.tag as usize));
+        if tags.len() == len { return; }
+        tags.sort_unstable();
+        tags.dedup();
+    }
+
+    // Run the value through the given DFAs, returning the sorted tags of the matched patterns.
+    // Together with `pattern_inits`, this uses the automaton as a plain multi-pattern classifier,
+    // regardless of the rules and of the current states.
+    pub unsafe fn classify<I: IntoIterator<Item = *const U8State<'a>>>(inits: I, value: &[u8])
+        -> Vec<usize>
+    {
+        let mut crunner = char_runner::Runner::new(inits);
 
         for c in value { crunner.read(*c); }
+        crunner.finish();
 
         let mut tags = crunner.get_tags().collect::<Vec<_>>();
         tags.sort_unstable();
         tags.dedup();
-        let tags = tags;
+        tags
+    }
+
+    // Start matching a value of `sym` which arrives in chunks, see `ChunkedSet`. The states
+    // listening on `sym` are detached until the set is committed, like in `take_transitions`.
+    // The value is matched normalized by `normalizer`.
+    pub unsafe fn begin_set(&mut self, sym: &'a [u8], normalizer: Normalizer) -> ChunkedSet<'a> {
+        let left = self.keys.id(sym).map(|id| &self.sparse[id.index()])
+            .map_or_else(Vec::new, |states| states.iter().copied().collect());
+        let trans = self.take_transitions(sym);
+        let inits = trans.iter().flat_map(|tran| tran.inits()).map(BlobPtr::get);
+        let crunner = char_runner::Runner::new(inits);
+        // The numeric guards need the whole value, too, as well as the recording of coverage and
+        // the successors asking for the value as an old one (see `ChunkedSet::value`).
+        let numeric = trans.iter().any(|tran| !tran.num_guards().is_empty());
+        let asks_old = trans.iter().any(|tran| {
+            let mut asks = false;
+            tran.finals().for_each_leaf(
+                &mut |leaf| asks |= leaf.get_olds().iter().any(|old| old.as_ref() == sym));
+            asks
+        });
+        let buffer = (!normalizer.is_bytewise() || numeric || asks_old || self.is_recording())
+            .then(Vec::new);
+        ChunkedSet { key: sym, left, trans, crunner, normalizer, buffer }
+    }
+
+    // Abandon the value fed to the chunked set, reattaching the states detached by `begin_set`.
+    pub unsafe fn abandon_set(&mut self, set: ChunkedSet<'a>) {
+        for state in set.left { self.attach(&*state); }
+    }
 
+    // Finish the value fed to the chunked set and perform the transitions, like `read`. Returns
+    // the value if it has been kept, see `ChunkedSet::value`.
+    pub unsafe fn commit_set<GetOld: FnMut(&'a [u8]), RunExts: FnMut(&'a Exts<'a>, &'a [u64])>(
+        &mut self, set: ChunkedSet<'a>, get_old: GetOld, run_exts: RunExts
+    ) -> Option<Vec<u8>> {
+        let ChunkedSet { trans, mut crunner, normalizer, buffer, .. } = set;
+        if trans.is_empty() { return buffer; }
+        if let (true, Some(kept)) = (self.is_recording(), buffer.as_ref()) {
+            let tags = self.match_value_recorded(&trans, &normalizer.apply(kept));
+            self.apply_tags(trans, &tags, get_old, run_exts);
+            return buffer;
+        }
+        let value = buffer.as_ref().map(|buffer| normalizer.apply(buffer));
+        if let Some(value) = value.as_ref() {
+            for c in value.iter() { crunner.read(*c); }
+        }
+        crunner.finish();
+        let mut tags = crunner.get_tags().collect::<Vec<_>>();
+        tags.sort_unstable();
+        tags.dedup();
+        if let Some(value) = value { Self::add_num_tags(&trans, &value, &mut tags); }
+        self.apply_tags(trans, &tags, get_old, run_exts);
+        buffer
+    }
+
+    // Evaluate the BDDs of the transitions taken by `take_transitions`, given the tags matched by
+    // `match_value`. The commands of each reached leaf are passed to `run_exts` together, with the
+    // rules emitting them.
+    pub unsafe fn apply_tags<GetOld: FnMut(&'a [u8]), RunExts: FnMut(&'a Exts<'a>, &'a [u64])>(
+        &mut self,
+        trans: Vec<&'a InitsAndFinals<'a>>,
+        tags: &[usize],
+        mut get_old: GetOld,
+        mut run_exts: RunExts,
+    ) {
         for tran in trans {
-            let mut tag_i = 0;
-            let target = tran.a.behind::<Finals>().evaluate(|var| {
-                let var = *var;
-                if tag_i == tags.len() { return false; }
-                while tags[tag_i] < var {
-                    tag_i += 1;
-                    if tag_i == tags.len() { return false; }
-                }
-                if var == tags[tag_i] { tag_i += 1; return true; }
-                false
-            });
-            for right_state in target.0.a.as_ref() {
-                self.add_right_state(&**right_state);
+            let target = tran.finals().evaluate_with(&MatchedTags::new(tags));
+            if !self.disabled_groups.is_empty() && self.disabled_groups.contains(target.group()) {
+                continue;
+            }
+            #[cfg(feature = "coverage")]
+            if let Some(recorder) = &self.coverage { recorder.borrow_mut().visit_leaf(target); }
+            for right_state in target.states() {
+                self.add_right_state(&*right_state.get());
+            }
+            for x in target.get_olds().iter() { get_old(x.as_ref()); }
+            run_exts(target.exts(), target.rules());
+        }
+    }
+
+    pub fn set_group_enabled(&mut self, group: &[u8], enabled: bool) {
+        if enabled {
+            self.disabled_groups.remove(group);
+        } else {
+            self.disabled_groups.insert(group.to_vec());
+        }
+    }
+
+    pub fn disabled_groups(&self) -> impl Iterator<Item = &[u8]> {
+        self.disabled_groups.iter().map(Vec::as_slice)
+    }
+
+    // The bytes of the tables of the current states.
+    pub fn memory_usage(&self) -> usize {
+        let sets = self.sparse.iter()
+            .map(|states| states.capacity() * size_of::<(u64, *const KeyValState)>())
+            .sum::<usize>();
+        let groups = self.disabled_groups.iter().map(Vec::capacity).sum::<usize>();
+        self.sparse.capacity() * size_of::<IndexSet<*const KeyValState>>() + sets
+            + self.disabled_groups.capacity() * size_of::<Vec<u8>>() + groups
+    }
+
+    // The current states, each once. The states without transitions are not kept.
+    pub fn states(&self) -> IndexSet<*const KeyValState<'a>> {
+        self.sparse.iter().flatten().copied().collect()
+    }
+
+    // Replace the current states, e.g. by the ones of a snapshot. They must be states of the
+    // automaton of the runner.
+    pub unsafe fn set_states<I: IntoIterator<Item = *const KeyValState<'a>>>(&mut self, states: I) {
+        self.sparse.iter_mut().for_each(IndexSet::clear);
+        for state in states { self.add_right_state(&*state); }
+    }
+
+    // A fingerprint of the current states and the disabled groups, independent of the order in
+    // which they have been reached. The states are identified by their addresses, so only the
+    // fingerprints of the runners of the same loaded blob are comparable.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = 0u64;
+        for (key, states) in self.keys.iter().zip(self.sparse.iter()) {
+            let key = XxHash64::oneshot(0, key);
+            for state in states.iter() {
+                let state = (*state as usize as u64).to_le_bytes();
+                hash = hash.wrapping_add(XxHash64::oneshot(key, &state));
+            }
+        }
+        for group in self.disabled_groups.iter() {
+            hash = hash.wrapping_add(XxHash64::oneshot(1, group));
+        }
+        hash
+    }
+
+    // The initial DFA states of the transitions that are currently listening on `sym`.
+    pub unsafe fn dfa_inits(&self, sym: &[u8]) -> IndexSet<*const U8State<'a>> {
+        let mut result = IndexSet::new();
+        let Some(states) = self.keys.id(sym).map(|id| &self.sparse[id.index()]) else {
+            return result;
+        };
+        for state in states.iter() {
+            let mut keyvals = (**state).keyvals();
+            while let Some((key, tran)) = keyvals.next() {
+                if key == sym { result.extend(tran.inits().iter().map(BlobPtr::get)); }
+            }
+        }
+        result
+    }
+
+    // The initial DFA states of the transitions of all states reachable from the given ones, i.e.
+    // all the patterns of the automaton when started from its initial states.
+    pub unsafe fn pattern_inits<'b, I: IntoIterator<Item = &'b KeyValState<'a>>>(states: I)
+        -> IndexSet<*const U8State<'a>>
+        where 'a: 'b
+    {
+        let mut result = IndexSet::new();
+        let mut frontier = states.into_iter()
+            .map(|state| state as *const KeyValState<'a>)
+            .collect::<Vec<_>>();
+        let mut visited = frontier.iter().copied().collect::<HashSet<_>>();
+        while let Some(state) = frontier.pop() {
+            let mut keyvals = (*state).keyvals();
+            while let Some((_, tran)) = keyvals.next() {
+                result.extend(tran.inits().iter().map(BlobPtr::get));
+                tran.finals().for_each_leaf(&mut |leaf| {
+                    for right in leaf.states() {
+                        if visited.insert(right.get()) { frontier.push(right.get()); }
+                    }
+                });
             }
-            let meta: &LeafMeta = target.0.a.behind();
-            let mut behind = get_behind_struct(meta);
-            meta.a.each(|x| {
-                get_old(x.as_ref());
-                behind = x.behind();
-                behind
-            });
-            let exts: &Sediment<'a, BlobVec<'a, u8>> = &*align_up_ptr(behind);
-            exts.each(|x| {
-                run_ext(x.as_ref());
-                x.behind()
-            });
         }
+        result
     }
 
+    // The state is reachable, so its keys have been interned by `new`.
     unsafe fn add_right_state(&mut self, state: &KeyValState<'a>) {
+        #[cfg(feature = "coverage")]
+        if let Some(recorder) = &self.coverage { recorder.borrow_mut().visit_keyval_state(state); }
+        self.attach(state);
+    }
+
+    unsafe fn attach(&mut self, state: &KeyValState<'a>) {
         let mut keyvals = state.keyvals();
         while let Some((key, _)) = keyvals.next() {
-            self.sparse.entry(key).or_insert(IndexSet::new()).insert(state);
+            let id = self.keys.id(key).unwrap();
+            self.sparse[id.index()].insert(state);
         }
     }
 }
+
+// A value being matched chunk by chunk, carrying the state of the character DFAs between the
+// chunks, so that the value need not be concatenated first. Unless the normalizer works byte by
+// byte, the value is concatenated anyway, as its ends are needed to normalize it. The runner does
+// not advance until the set is committed (or abandoned, by `Runner::abandon_set`). Dropping it
+// instead loses the states listening on the key.
+#[must_use = "the value is not matched unless the set is committed"]
+pub struct ChunkedSet<'a> {
+    key: &'a [u8],
+    // The states detached by `begin_set`, for `abandon_set`.
+    left: Vec<*const KeyValState<'a>>,
+    trans: Vec<&'a InitsAndFinals<'a>>,
+    crunner: char_runner::Runner<'a>,
+    normalizer: Normalizer,
+    buffer: Option<Vec<u8>>,
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl<'a> ChunkedSet<'a> {
+    pub unsafe fn feed(&mut self, chunk: &[u8]) {
+        if self.trans.is_empty() { return; }
+        match self.buffer.as_mut() {
+            Some(buffer) => buffer.extend_from_slice(chunk),
+            None => for c in chunk { self.crunner.read(self.normalizer.apply_byte(*c)); },
+        }
+    }
+
+    pub fn key(&self) -> &'a [u8] {
+        self.key
+    }
+
+    // The value fed so far, if it is kept, e.g. for the successors of the set which fetch it as
+    // an old value.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.buffer.as_deref()
+    }
+}
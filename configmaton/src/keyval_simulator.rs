@@ -1,68 +1,830 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use hashbrown::{HashMap, HashSet};
 use indexmap::IndexSet;
+use twox_hash::XxHash64;
+
+#[cfg(feature = "coverage")]
+use crate::recorder::SharedRecorder;
+use crate::{blob::{automaton::{self, Automaton}, BlobPtr, keyval_state::{InitsAndFinals, KeyValState}, state::U8State, vec::BlobVec, vec_of_vecs::VecOfVecs}, char_runner, intern::Interner, keyval_runner::{ChunkedSet, Exts, Runner}, normalize::Normalizer};
 
-use crate::{blob::{align_up_ptr, automaton::{Automaton, InitsAndStates}, get_behind_struct, keyval_state::{Bytes, KeyValState}, sediment::Sediment, tupellum::Tupellum, vec::BlobVec}, keyval_runner::Runner};
+// Where a queued command comes from: the rule emitting it and the key whose set fired the rule
+// (None for the commands of the rules without conditions, queued from the start). Without a single
+// key being set (`new`, `read_many`), a rule completed by an old value is attributed to its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Emitter<'a> {
+    pub rule: usize,
+    pub key: Option<&'a [u8]>,
+}
 
 #[derive(Clone)]
 pub struct Simulation<'a> {
     keyval_runner: Runner<'a>,
-    pub exts: IndexSet<&'a [u8]>,
+    pub exts: CommandQueue<'a>,
+    // The emitter of each command at the time it was queued. A command emitted again while still
+    // queued keeps its first emitter.
+    emitters: HashMap<&'a [u8], Emitter<'a>>,
     getolds: IndexSet<&'a [u8]>,
+    // The commands of each leaf reached since the last `take_fired`, if recording is enabled.
+    fired: Option<Vec<Vec<&'a [u8]>>>,
+    // The sets waiting for the suspended work of the previous ones, see `read_budgeted`.
+    pending: VecDeque<(&'a [u8], &'a [u8])>,
+    // The key to which the rules fired by the `getolds` are attributed, see `finish_read`.
+    trigger: Option<&'a [u8]>,
+    // The values of these keys are matched normalized, see `Cmd::Normalize`.
+    normalizers: HashMap<&'a [u8], Normalizer>,
+}
+
+// The queued commands, popped from the last one. Indexed by the first byte, so that popping the
+// commands of a single family (`pop_matching`) does not scan the unrelated ones. The commands
+// popped out of order leave holes, compacted away once they outnumber the queued commands, so that
+// no pop shifts the rest of the queue.
+#[derive(Clone, Default)]
+pub struct CommandQueue<'a> {
+    // The commands in the order of queueing, None for the holes.
+    slots: Vec<Option<&'a [u8]>>,
+    // The slot of each queued command.
+    index: HashMap<&'a [u8], usize>,
+    // The slots of the nonempty commands by their first byte, in the order of queueing. Some of
+    // them may be stale: either holes or reused by later commands (which are queued again).
+    buckets: HashMap<u8, Vec<usize>>,
+    // The holes made since the last compaction.
+    holes: usize,
+}
+
+impl<'a> CommandQueue<'a> {
+    // Returns false if the command is queued already.
+    pub fn insert(&mut self, command: &'a [u8]) -> bool {
+        if self.index.contains_key(command) { return false; }
+        let slot = self.slots.len();
+        self.slots.push(Some(command));
+        self.index.insert(command, slot);
+        if let Some(first) = command.first() {
+            self.buckets.entry(*first).or_default().push(slot);
+        }
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<&'a [u8]> {
+        let command = loop {
+            match self.slots.pop()? {
+                Some(command) => break command,
+                None => self.holes -= 1,
+            }
+        };
+        let slot = self.index.remove(command).unwrap();
+        if let Some(first) = command.first() {
+            // The entries behind the slot of the last command are stale.
+            let bucket = self.buckets.get_mut(first).unwrap();
+            while bucket.pop().unwrap() != slot {}
+            if bucket.is_empty() { self.buckets.remove(first); }
+        }
+        Some(command)
+    }
+
+    // Pop the last queued command starting with the prefix.
+    pub fn pop_matching(&mut self, prefix: &[u8]) -> Option<&'a [u8]> {
+        let Some(first) = prefix.first() else { return self.pop() };
+        let bucket = self.buckets.get_mut(first)?;
+        let slots = &self.slots;
+        let live = |slot: &usize| slots.get(*slot).copied().flatten()
+            .filter(|command| command.first() == Some(first));
+        while bucket.last().is_some_and(|slot| live(slot).is_none()) { bucket.pop(); }
+        if bucket.is_empty() { self.buckets.remove(first); return None; }
+        // The last live entry of a slot is the one pushed by its command, the rest are left stale.
+        let slot = *bucket.iter().rev()
+            .find(|slot| live(slot).is_some_and(|command| command.starts_with(prefix)))?;
+        let command = self.slots[slot].take().unwrap();
+        self.index.remove(command);
+        self.holes += 1;
+        if self.holes > self.index.len() { self.compact(); }
+        Some(command)
+    }
+
+    fn compact(&mut self) {
+        let commands = self.iter().copied().collect::<Vec<_>>();
+        self.slots.clear();
+        self.index.clear();
+        self.buckets.clear();
+        self.holes = 0;
+        for command in commands { self.insert(command); }
+    }
+
+    // The queued commands from the first one.
+    pub fn iter(&self) -> impl Iterator<Item = &&'a [u8]> + '_ {
+        self.slots.iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    // The bytes of the tables, see `Simulation::memory_usage`.
+    fn memory_usage(&self) -> usize {
+        let buckets = self.buckets.values()
+            .map(|bucket| bucket.capacity() * size_of::<usize>())
+            .sum::<usize>();
+        self.slots.capacity() * size_of::<Option<&[u8]>>()
+            + self.index.capacity() * size_of::<(&[u8], usize)>()
+            + self.buckets.capacity() * size_of::<(u8, Vec<usize>)>() + buckets
+    }
+}
+
+// The dynamic state of a simulation, e.g. to be saved by `Configmaton::snapshot`. The settings
+// (e.g. the recording of the fired rules) are not a part of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SimulationState<'a> {
+    pub states: Vec<*const KeyValState<'a>>,
+    // The queued commands from the first one, with their emitters.
+    pub commands: Vec<(&'a [u8], Option<Emitter<'a>>)>,
+    pub disabled_groups: Vec<Vec<u8>>,
+    // The work suspended by `read_budgeted`.
+    pub pending: Vec<(&'a [u8], &'a [u8])>,
+    pub getolds: Vec<&'a [u8]>,
+    pub trigger: Option<&'a [u8]>,
 }
 
+// Whether a budgeted read has done all its work, or it has been suspended, see `read_budgeted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progress {
+    Done,
+    Suspended,
+}
+
+#[allow(clippy::missing_safety_doc)]
 impl<'a> Simulation<'a> {
     pub fn new<F: Fn(&'a [u8]) -> Option<&'a [u8]>>
         (aut1: &Automaton<'a>, db: F) -> Self
     {
-        let mut getolds = IndexSet::new();
-        let mut exts = IndexSet::new();
-        let mut behind = unsafe { get_behind_struct(aut1) };
-        unsafe { aut1.a.each(|getold| {
-            getolds.insert(getold.as_ref());
-            behind = getold.behind();
-            behind
-        }) };
-        let aut2: &Tupellum<'a, Sediment<'a, Bytes<'a>>, InitsAndStates<'a>> =
-            unsafe { &*align_up_ptr(behind) };
-        let mut behind = unsafe { get_behind_struct(aut2) };
-        unsafe { aut2.a.each(|ext| {
-            exts.insert(ext.as_ref());
-            behind = ext.behind();
-            behind
-        }) };
-        let initial_states: &BlobVec<*const KeyValState<'a>> = unsafe { &*align_up_ptr(behind) };
+        let getolds = unsafe { aut1.a.iter() }.collect();
+        let exts_section: &VecOfVecs<'a, u8> = unsafe { aut1.a.behind() };
+        let rules: &BlobVec<u64> = unsafe { exts_section.behind() };
+        let initial_states: &BlobVec<BlobPtr<KeyValState<'a>>> = unsafe { rules.behind() };
         let mut sim = Simulation {
-            keyval_runner: unsafe { Runner::new(initial_states.as_ref().iter().map(|x| &**x )) },
-            exts,
+            keyval_runner:
+                unsafe { Runner::new(initial_states.as_ref().iter().map(|x| &*x.get())) },
+            exts: CommandQueue::default(),
+            emitters: HashMap::new(),
             getolds,
+            fired: None,
+            pending: VecDeque::new(),
+            trigger: None,
+            normalizers: unsafe { automaton::normalizers(aut1) }.collect(),
         };
-        sim.finish_read(db);
+        for (ext, rule) in unsafe { exts_section.iter().zip(rules.as_ref()) } {
+            if sim.exts.insert(ext) {
+                sim.emitters.insert(ext, Emitter { rule: *rule as usize, key: None });
+            }
+        }
+        sim.finish_read(db, None);
         sim
     }
 
     pub fn read<F: Fn(&'a [u8]) -> Option<&'a [u8]>>
         (&mut self, key: &'a [u8], val: &'a [u8], db: F)
     {
-        unsafe {
-            self.keyval_runner.read(key, val,
-                |getold| { self.getolds.insert(getold); },
-                |ext| { self.exts.insert(ext); }
-            );
+        self.read_budgeted(key, val, db, usize::MAX);
+    }
+
+    // Like `read`, but suspend once `budget` transitions have been taken (reading the value and
+    // the old values it needs). The rest is done by the next `poll`s or reads, a read being done
+    // after the suspended work of the previous ones. A single step is never interrupted, so the
+    // budget may get exceeded by the transitions of the last step.
+    pub fn read_budgeted<F: Fn(&'a [u8]) -> Option<&'a [u8]>>
+        (&mut self, key: &'a [u8], val: &'a [u8], db: F, budget: usize) -> Progress
+    {
+        self.pending.push_back((key, val));
+        self.poll(db, budget)
+    }
+
+    // Continue the suspended work, see `read_budgeted`.
+    pub fn poll<F: Fn(&'a [u8]) -> Option<&'a [u8]>>(&mut self, db: F, budget: usize)
+        -> Progress
+    {
+        self.poll_fed(db, budget, None)
+    }
+
+    // Like `poll`, but the old value of the `fed` key is the given one rather than that of `db`.
+    fn poll_fed<F: Fn(&'a [u8]) -> Option<&'a [u8]>>
+        (&mut self, db: F, budget: usize, fed: Option<(&[u8], &[u8])>) -> Progress
+    {
+        let mut spent = 0;
+        loop {
+            if self.getolds.is_empty() && self.pending.is_empty() {
+                self.trigger = None;
+                return Progress::Done;
+            }
+            if spent >= budget { return Progress::Suspended; }
+            let (key, val, trigger) = match self.getolds.pop() {
+                Some(key) => {
+                    let val = match fed {
+                        Some((fed_key, val)) if fed_key == key => val,
+                        _ => match db(key) { Some(val) => val, None => continue },
+                    };
+                    (key, val, self.trigger.unwrap_or(key))
+                }
+                None => {
+                    let (key, val) = self.pending.pop_front().unwrap();
+                    self.trigger = Some(key);
+                    (key, val, key)
+                }
+            };
+            let val = self.normalize(key, val);
+            spent += unsafe {
+                self.keyval_runner.read(key, &val,
+                    |getold| { self.getolds.insert(getold); },
+                    |exts, rules| Self::queue(
+                        &mut self.exts, &mut self.emitters, &mut self.fired, exts, rules, trigger)
+                )
+            }.max(1);
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        !self.getolds.is_empty() || !self.pending.is_empty()
+    }
+
+    // Read a value of `key` chunk by chunk, without concatenating it, see `ValueFeeder` and
+    // `keyval_runner::ChunkedSet`. The simulation is borrowed until the value is finished. The work
+    // suspended by `read_budgeted` is done first (with `db` not yet containing the value), so that
+    // the value sees the states reached by the previous sets.
+    pub fn read_begin<F: Fn(&'a [u8]) -> Option<&'a [u8]>>(&mut self, key: &'a [u8], db: F)
+        -> ValueFeeder<'_, 'a>
+    {
+        self.poll(db, usize::MAX);
+        let normalizer = self.normalizers.get(key).copied().unwrap_or_default();
+        let set = unsafe { self.keyval_runner.begin_set(key, normalizer) };
+        ValueFeeder { simulation: self, set: Some(set) }
+    }
+
+    // Read a batch of updates. Values of distinct keys are matched against their DFAs
+    // independently (in parallel with the `parallel` feature), and the resulting transitions are
+    // applied in the batch order, so the outcome does not depend on thread scheduling.
+    //
+    // A key repeated in the batch starts a new round, so that its second value sees the states
+    // reached by its first one. `db` must already reflect the whole batch, as states entered
+    // during a round fetch the values of their keys from it (like in `read`).
+    pub fn read_many<F: Fn(&'a [u8]) -> Option<&'a [u8]>>
+        (&mut self, updates: &[(&'a [u8], &'a [u8])], db: F)
+    {
+        self.poll(&db, usize::MAX);
+        let mut start = 0;
+        while start < updates.len() {
+            let mut keys = HashSet::new();
+            let mut end = start;
+            while end < updates.len() && keys.insert(updates[end].0) { end += 1; }
+            self.read_round(&updates[start..end]);
+            start = end;
+        }
+        self.finish_read(db, None)
+    }
+
+    fn read_round(&mut self, updates: &[(&'a [u8], &'a [u8])]) {
+        let jobs = updates.iter().map(|(key, val)| MatchJob {
+            trans: unsafe { self.keyval_runner.take_transitions(key) },
+            key,
+            value: self.normalize(key, val),
+        }).collect::<Vec<_>>();
+
+        let tags = if self.keyval_runner.is_recording() {
+            // The recorder is not shared among threads.
+            jobs.iter().map(|job| {
+                if job.trans.is_empty() { return vec![]; }
+                unsafe { self.keyval_runner.match_value_recorded(&job.trans, &job.value) }
+            }).collect::<Vec<_>>()
+        } else {
+            #[cfg(feature = "parallel")]
+            {
+                use rayon::prelude::*;
+                jobs.par_iter().map(|job| job.run()).collect::<Vec<_>>()
+            }
+            #[cfg(not(feature = "parallel"))]
+            jobs.iter().map(|job| job.run()).collect::<Vec<_>>()
         };
-        self.finish_read(db)
+
+        for (job, tags) in jobs.into_iter().zip(tags) {
+            unsafe {
+                self.keyval_runner.apply_tags(job.trans, &tags,
+                    |getold| { self.getolds.insert(getold); },
+                    |exts, rules| Self::queue(
+                        &mut self.exts, &mut self.emitters, &mut self.fired, exts, rules, job.key)
+                );
+            }
+        }
+    }
+
+    fn queue(
+        queue: &mut CommandQueue<'a>,
+        emitters: &mut HashMap<&'a [u8], Emitter<'a>>,
+        fired: &mut Option<Vec<Vec<&'a [u8]>>>,
+        exts: &'a Exts<'a>,
+        rules: &'a [u64],
+        key: &'a [u8],
+    ) {
+        let exts = unsafe { exts.iter() }.map(|ext| unsafe { ext.as_ref() });
+        let mut enqueue = |ext: &'a [u8], rule: &u64| {
+            let emitter = Emitter { rule: *rule as usize, key: Some(key) };
+            if queue.insert(ext) { emitters.insert(ext, emitter); }
+        };
+        match fired {
+            None => exts.zip(rules).for_each(|(ext, rule)| enqueue(ext, rule)),
+            Some(fired) => {
+                let exts = exts.collect::<Vec<_>>();
+                exts.iter().zip(rules).for_each(|(ext, rule)| enqueue(ext, rule));
+                if !exts.is_empty() { fired.push(exts); }
+            }
+        }
+    }
+
+    // Pop the last queued command together with its emitter, which is None if the command was
+    // inserted into `exts` directly.
+    pub fn pop_attributed(&mut self) -> Option<(&'a [u8], Option<Emitter<'a>>)> {
+        let ext = self.exts.pop()?;
+        Some((ext, self.emitters.remove(ext)))
+    }
+
+    // Like `pop_attributed`, but pop the last command starting with the prefix.
+    pub fn pop_matching_attributed(&mut self, prefix: &[u8])
+        -> Option<(&'a [u8], Option<Emitter<'a>>)>
+    {
+        let ext = self.exts.pop_matching(prefix)?;
+        Some((ext, self.emitters.remove(ext)))
+    }
+
+    // Queue a popped command again, e.g. when it could not be handled.
+    pub fn requeue(&mut self, ext: &'a [u8], emitter: Option<Emitter<'a>>) {
+        if !self.exts.insert(ext) { return; }
+        match emitter {
+            Some(emitter) => { self.emitters.insert(ext, emitter); }
+            None => { self.emitters.remove(ext); }
+        }
+    }
+
+    // See `Runner::record_coverage`.
+    #[cfg(feature = "coverage")]
+    pub fn record_coverage(&mut self, recorder: Option<SharedRecorder<'a>>) {
+        self.keyval_runner.record_coverage(recorder);
+    }
+
+    // See `Runner::is_recording`.
+    pub fn is_recording(&self) -> bool {
+        self.keyval_runner.is_recording()
+    }
+
+    pub fn record_fired(&mut self, enable: bool) {
+        self.fired = if enable { Some(self.fired.take().unwrap_or_default()) } else { None };
+    }
+
+    // The commands of the leaves reached since the last call, one vector per leaf.
+    pub fn take_fired(&mut self) -> Vec<Vec<&'a [u8]>> {
+        self.fired.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    // Disabled rules stop firing (they are not reset when enabled again).
+    pub fn set_group_enabled(&mut self, group: &[u8], enabled: bool) {
+        self.keyval_runner.set_group_enabled(group, enabled);
+    }
+
+    pub fn state(&self) -> SimulationState<'a> {
+        SimulationState {
+            states: self.keyval_runner.states().into_iter().collect(),
+            commands: self.exts.iter().map(|ext| (*ext, self.emitters.get(ext).copied())).collect(),
+            disabled_groups: self.keyval_runner.disabled_groups().map(<[u8]>::to_vec).collect(),
+            pending: self.pending.iter().copied().collect(),
+            getolds: self.getolds.iter().copied().collect(),
+            trigger: self.trigger,
+        }
     }
 
+    // Continue from the state (e.g. restored from a snapshot) instead of the current one. The
+    // states must be of the automaton of the simulation.
+    pub unsafe fn set_state(&mut self, state: SimulationState<'a>) {
+        self.keyval_runner.set_states(state.states);
+        let enabled = self.keyval_runner.disabled_groups().map(<[u8]>::to_vec).collect::<Vec<_>>();
+        for group in enabled { self.keyval_runner.set_group_enabled(&group, true); }
+        for group in state.disabled_groups { self.keyval_runner.set_group_enabled(&group, false); }
+        self.exts = CommandQueue::default();
+        self.emitters.clear();
+        for (ext, emitter) in state.commands { self.requeue(ext, emitter); }
+        self.pending = state.pending.into();
+        self.getolds = state.getolds.into_iter().collect();
+        self.trigger = state.trigger;
+    }
+
+    // A cheap fingerprint of the behaviour of the simulation: equal fingerprints (of the
+    // simulations of the same loaded blob) mean equal reactions to further sets, up to hash
+    // collisions. See `Runner::state_hash`; the work suspended by `read_budgeted` is included,
+    // the queued commands are not.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = self.keyval_runner.state_hash();
+        for key in self.getolds.iter() { hash = hash.wrapping_add(XxHash64::oneshot(2, key)); }
+        for (ix, (key, val)) in self.pending.iter().enumerate() {
+            let key = XxHash64::oneshot(ix as u64, key);
+            hash = hash.wrapping_add(XxHash64::oneshot(key, val));
+        }
+        hash
+    }
+
+    // The bytes of the tables of the current states and of the suspended work, and of the tables of
+    // the queued commands (which are borrowed from the automaton), see `Configmaton::memory_usage`.
+    pub fn memory_usage(&self) -> (usize, usize) {
+        let states = self.keyval_runner.memory_usage()
+            + self.getolds.capacity() * size_of::<(u64, &[u8])>()
+            + self.pending.capacity() * size_of::<(&[u8], &[u8])>();
+        let commands = self.exts.memory_usage()
+            + self.emitters.capacity() * size_of::<(&[u8], Emitter)>();
+        (states, commands)
+    }
+
+    // Keys on which some of the current states wait.
+    pub fn tracked_keys(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        let keys = self.keyval_runner.keys();
+        keys.iter().zip(self.keyval_runner.sparse.iter())
+            .filter(|(_, states)| !states.is_empty())
+            .map(|(key, _)| key)
+    }
+
+    // The keys of the automaton, see `Interner`. The clones of the simulation share them.
+    pub fn keys(&self) -> &Arc<Interner<'a>> {
+        self.keyval_runner.keys()
+    }
+
+    // The character-DFA states in which a value of `key` would start to be matched.
+    pub fn active_dfa_states(&self, key: &[u8]) -> IndexSet<*const U8State<'a>> {
+        unsafe { self.keyval_runner.dfa_inits(key) }
+    }
+
+    // The character-DFA states reached after matching `prefix` as a (partial) value of `key`.
+    // The simulation itself is left untouched.
+    pub fn dfa_states_after(&self, key: &[u8], prefix: &[u8]) -> IndexSet<*const U8State<'a>> {
+        let mut crunner = char_runner::Runner::new(self.active_dfa_states(key));
+        for c in prefix { unsafe { crunner.read(*c) }; }
+        crunner.states
+    }
+
+    fn normalize<'v>(&self, key: &[u8], val: &'v [u8]) -> Cow<'v, [u8]> {
+        match self.normalizers.get(key) {
+            Some(normalizer) => normalizer.apply(val),
+            None => Cow::Borrowed(val),
+        }
+    }
+
+    // The rules fired by the fetched old values are attributed to `trigger`, the key being set, if
+    // it is known, otherwise to the fetched key.
     fn finish_read<F: Fn(&'a [u8]) -> Option<&'a [u8]>>
-        (&mut self, db: F)
+        (&mut self, db: F, trigger: Option<&'a [u8]>)
     {
-        while let Some(key) = self.getolds.pop() {
-            if let Some(val) = db(&key) {
-                unsafe {
-                    self.keyval_runner.read(key, val,
-                        |getold| { self.getolds.insert(getold); },
-                        |ext| { self.exts.insert(ext); }
-                    );
-                }
+        self.trigger = trigger;
+        self.poll(db, usize::MAX);
+    }
+}
+
+// A value being read by `read_begin`, the character automata advanced by each fed chunk. Dropped
+// unfinished, it abandons the value, as if it has never been read.
+#[must_use = "the value is not read unless it is finished"]
+pub struct ValueFeeder<'s, 'a> {
+    simulation: &'s mut Simulation<'a>,
+    // None once finished.
+    set: Option<ChunkedSet<'a>>,
+}
+
+impl<'a> ValueFeeder<'_, 'a> {
+    pub fn feed(&mut self, chunk: &[u8]) {
+        if let Some(set) = self.set.as_mut() { unsafe { set.feed(chunk) } }
+    }
+
+    // The same as `Simulation::read` with the whole value. The states reached by the value which
+    // fetch the old value of its key get the fed chunks (kept for them by the set), so `db` needs
+    // to contain the value only if the states reached later (by other old values) fetch it.
+    pub fn finish<F: Fn(&'a [u8]) -> Option<&'a [u8]>>(mut self, db: F) {
+        let set = self.set.take().unwrap();
+        let simulation = &mut *self.simulation;
+        let key = set.key();
+        let value = unsafe {
+            simulation.keyval_runner.commit_set(set,
+                |getold| { simulation.getolds.insert(getold); },
+                |exts, rules| Simulation::queue(&mut simulation.exts, &mut simulation.emitters,
+                    &mut simulation.fired, exts, rules, key)
+            )
+        };
+        simulation.trigger = Some(key);
+        simulation.poll_fed(db, usize::MAX, value.as_deref().map(|value| (key, value)));
+    }
+}
+
+impl Drop for ValueFeeder<'_, '_> {
+    fn drop(&mut self) {
+        if let Some(set) = self.set.take() {
+            unsafe { self.simulation.keyval_runner.abandon_set(set) }
+        }
+    }
+}
+
+struct MatchJob<'a> {
+    trans: Vec<&'a InitsAndFinals<'a>>,
+    key: &'a [u8],
+    value: Cow<'a, [u8]>,
+}
+
+// The blob is immutable once deserialized, so its states can be read from any thread.
+unsafe impl Send for MatchJob<'_> {}
+unsafe impl Sync for MatchJob<'_> {}
+
+impl MatchJob<'_> {
+    fn run(&self) -> Vec<usize> {
+        if self.trans.is_empty() { return vec![]; }
+        unsafe { Runner::match_value(&self.trans, &self.value) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::tests::compile;
+
+    use super::*;
+
+    #[test]
+    fn read_many_matches_sequential_reads() {
+        let msg = compile(r#"[
+            {"when": {"foo": "a", "bar": "b"}, "run": ["ab"]},
+            {"when": {"qux": "c.*"}, "run": ["c"]},
+            {"when": {"foo": "x"}, "run": ["x"]}
+        ]"#);
+        let aut = msg.get_automaton();
+        let db = |x: &[u8]| match x {
+            b"foo" => Some(b"a".as_slice()),
+            b"bar" => Some(b"b".as_slice()),
+            b"qux" => Some(b"cc".as_slice()),
+            _ => None,
+        };
+
+        let mut seq = Simulation::new(aut, |_| None);
+        seq.read(b"foo", b"a", db);
+        seq.read(b"bar", b"b", db);
+        seq.read(b"qux", b"cc", db);
+
+        let mut par = Simulation::new(aut, |_| None);
+        par.read_many(&[(b"foo", b"a"), (b"bar", b"b"), (b"qux", b"cc")], db);
+
+        let mut seq_exts = seq.exts.iter().copied().collect::<Vec<_>>();
+        let mut par_exts = par.exts.iter().copied().collect::<Vec<_>>();
+        seq_exts.sort();
+        par_exts.sort();
+        assert_eq!(par_exts, vec![b"ab".as_slice(), b"c"]);
+        assert_eq!(par_exts, seq_exts);
+    }
+
+    #[test]
+    fn chunked_set() {
+        let msg = compile(r#"[
+            {"when": {"foo": "ab*c", "bar": "x"}, "run": ["m1"]},
+            {"when": {"foo": "abb"}, "run": ["m2"]}
+        ]"#);
+        let aut = msg.get_automaton();
+        let db = |x: &[u8]| match x {
+            b"foo" => Some(b"abbbc".as_slice()),
+            b"bar" => Some(b"x".as_slice()),
+            _ => None,
+        };
+
+        let mut sim = Simulation::new(aut, |_| None);
+        let mut feeder = sim.read_begin(b"foo", |_| None);
+        for chunk in [b"ab".as_slice(), b"", b"bb", b"c"] { feeder.feed(chunk); }
+        feeder.finish(db);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"m1".as_slice()]);
+
+        let mut sim = Simulation::new(aut, |_| None);
+        let mut feeder = sim.read_begin(b"foo", |_| None);
+        feeder.feed(b"ab");
+        feeder.feed(b"b");
+        feeder.finish(|x| match x { b"foo" => Some(b"abb"), _ => None });
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"m2".as_slice()]);
+
+        // A key nobody listens on yields an empty set.
+        let mut feeder = sim.read_begin(b"qux", |_| None);
+        feeder.feed(b"whatever");
+        feeder.finish(|_| None);
+        assert_eq!(sim.exts.len(), 1);
+    }
+
+    #[test]
+    fn value_feeder() {
+        let msg = compile(r#"[
+            {"when": {"foo": "a.*"}, "run": ["a"], "then": [
+                {"when": {"foo": "ab"}, "run": ["ab"]}
+            ]}
+        ]"#);
+        let aut = msg.get_automaton();
+
+        // The nested rule fetches the old value of the key being fed, which `db` has not got.
+        let mut sim = Simulation::new(aut, |_| None);
+        let mut feeder = sim.read_begin(b"foo", |_| None);
+        feeder.feed(b"a");
+        feeder.feed(b"b");
+        feeder.finish(|_| None);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"a".as_slice(), b"ab"]);
+
+        // An unfinished value is abandoned, the states keep listening on the key.
+        let mut sim = Simulation::new(aut, |_| None);
+        let hash = sim.state_hash();
+        let mut feeder = sim.read_begin(b"foo", |_| None);
+        feeder.feed(b"a");
+        drop(feeder);
+        assert_eq!(sim.state_hash(), hash);
+        assert!(sim.exts.is_empty());
+        sim.read(b"foo", b"ab", |x| match x { b"foo" => Some(b"ab"), _ => None });
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"a".as_slice(), b"ab"]);
+    }
+
+    #[test]
+    fn read_begin_after_budgeted() {
+        let msg = compile(r#"[{"when": {"foo": "a", "bar": "b"}, "run": ["ab"]}]"#);
+        let aut = msg.get_automaton();
+        let mut sim = Simulation::new(aut, |_| None);
+        let db = |x: &[u8]| match x { b"foo" => Some(b"a".as_slice()), _ => None };
+        assert_eq!(sim.read_budgeted(b"foo", b"a", db, 0), Progress::Suspended);
+
+        // The chunked set sees the states reached by the suspended one.
+        let mut feeder = sim.read_begin(b"bar", db);
+        assert!(!feeder.simulation.is_suspended());
+        feeder.feed(b"b");
+        feeder.finish(|x| match x { b"bar" => Some(b"b"), x => db(x) });
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"ab".as_slice()]);
+    }
+
+    #[test]
+    fn read_begin() {
+        let msg = compile(r#"[
+            {"when": {"foo": "(ab)*c", "bar": "x"}, "run": ["m1"]},
+            {"when": {"foo": "ab"}, "run": ["m2"]}
+        ]"#);
+        let aut = msg.get_automaton();
+        let value = [b"ab".repeat(1000), b"c".to_vec()].concat();
+        let db = |x: &[u8]| match x {
+            b"foo" => Some(value.as_slice()),
+            b"bar" => Some(b"x".as_slice()),
+            _ => None,
+        };
+
+        let mut sim = Simulation::new(aut, |_| None);
+        let mut feeder = sim.read_begin(b"foo", |_| None);
+        for chunk in value.chunks(7) { feeder.feed(chunk); }
+        feeder.finish(db);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"m1".as_slice()]);
+
+        let mut read = Simulation::new(aut, |_| None);
+        read.read(b"foo", &value, db);
+        assert_eq!(sim.state_hash(), read.state_hash());
+    }
+
+    #[test]
+    fn normalize() {
+        let msg = compile(r#"[
+            {"normalize": {"foo": ["lowercase"], "bar": ["trim"]}},
+            {"when": {"foo": "ab*c"}, "run": ["foo"]},
+            {"when": {"bar": "x y"}, "run": ["bar"]}
+        ]"#);
+        let aut = msg.get_automaton();
+
+        let mut sim = Simulation::new(aut, |_| None);
+        let mut feeder = sim.read_begin(b"foo", |_| None);
+        for chunk in [b"A".as_slice(), b"Bb", b"C"] { feeder.feed(chunk); }
+        feeder.finish(|_| None);
+        let mut feeder = sim.read_begin(b"bar", |_| None);
+        for chunk in [b" x".as_slice(), b" ", b"y ", b"\n"] { feeder.feed(chunk); }
+        feeder.finish(|_| None);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"foo".as_slice(), b"bar"]);
+
+        let mut sim = Simulation::new(aut, |_| None);
+        sim.read_many(&[(b"foo", b"aBC"), (b"bar", b"x y")], |_| None);
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"foo".as_slice(), b"bar"]);
+    }
+
+    #[test]
+    fn read_many_repeated_key() {
+        let msg = compile(r#"[{"when": {"foo": "x"}, "run": ["x"]}]"#);
+        let aut = msg.get_automaton();
+        let mut sim = Simulation::new(aut, |_| None);
+        sim.read_many(&[(b"foo", b"y"), (b"foo", b"x")],
+            |x| match x { b"foo" => Some(b"x"), _ => None });
+        assert_eq!(sim.exts.iter().copied().collect::<Vec<_>>(), vec![b"x".as_slice()]);
+    }
+
+    #[test]
+    fn state_hash() {
+        let msg = compile(r#"[
+            {"when": {"a": "1", "b": "1"}, "run": ["ab"]},
+            {"when": {"c": "1"}, "run": ["c"], "group": "g"}
+        ]"#);
+        let aut = msg.get_automaton();
+        let mut sim = Simulation::new(aut, |_| None);
+        let init = sim.state_hash();
+        sim.read(b"x", b"1", |_| None);
+        sim.read(b"a", b"0", |_| None);
+        assert_eq!(sim.state_hash(), init);
+        sim.read(b"a", b"1", |_| None);
+        let after_a = sim.state_hash();
+        assert_ne!(after_a, init);
+
+        let mut other = Simulation::new(aut, |_| None);
+        other.read(b"a", b"1", |_| None);
+        assert_eq!(other.state_hash(), after_a);
+        other.set_group_enabled(b"g", false);
+        assert_ne!(other.state_hash(), after_a);
+        other.set_group_enabled(b"g", true);
+        assert_eq!(other.state_hash(), after_a);
+        assert_eq!(other.read_budgeted(b"b", b"1", |_| None, 0), Progress::Suspended);
+        assert_ne!(other.state_hash(), after_a);
+    }
+
+    #[test]
+    fn budgeted_read() {
+        let msg = compile(r#"[
+            {"when": {"a": "1", "b": "1", "c": "1"}, "run": ["abc"]},
+            {"when": {"d": "1"}, "run": ["d"]}
+        ]"#);
+        let aut = msg.get_automaton();
+        let db = |x: &[u8]| match x {
+            b"a" | b"b" | b"c" | b"d" => Some(b"1".as_slice()),
+            _ => None,
+        };
+
+        let mut sim = Simulation::new(aut, |_| None);
+        assert_eq!(sim.read_budgeted(b"a", b"1", db, 1), Progress::Suspended);
+        assert!(sim.is_suspended());
+        assert!(sim.exts.is_empty());
+        // Queued behind the suspended work of `a`.
+        assert_eq!(sim.read_budgeted(b"d", b"1", db, 0), Progress::Suspended);
+        assert!(sim.exts.is_empty());
+        while sim.poll(db, 1) == Progress::Suspended {}
+        assert!(!sim.is_suspended());
+        let exts = sim.exts.iter().copied().collect::<Vec<_>>();
+        assert_eq!(exts, vec![b"abc".as_slice(), b"d"]);
+        assert_eq!(sim.poll(db, 0), Progress::Done);
+
+        let mut unbounded = Simulation::new(aut, |_| None);
+        unbounded.read(b"a", b"1", db);
+        unbounded.read(b"d", b"1", db);
+        assert_eq!(unbounded.exts.iter().copied().collect::<Vec<_>>(), exts);
+    }
+
+    #[test]
+    fn dfa_state_introspection() {
+        let msg = compile(r#"[{"when": {"foo": "ab"}, "run": ["x"]}]"#);
+        let aut = msg.get_automaton();
+        let sim = Simulation::new(aut, |_| None);
+        assert_eq!(sim.tracked_keys().collect::<Vec<_>>(), vec![b"foo".as_slice()]);
+        assert_eq!(sim.active_dfa_states(b"foo").len(), 1);
+        assert!(sim.active_dfa_states(b"bar").is_empty());
+
+        let tags = |states: IndexSet<*const U8State>| unsafe {
+            char_runner::Runner::new(states).get_tags().collect::<Vec<_>>()
+        };
+        let partial = sim.dfa_states_after(b"foo", b"a");
+        assert_eq!(partial.len(), 1);
+        assert_ne!(partial, sim.active_dfa_states(b"foo"));
+        assert!(tags(partial).is_empty());
+        assert_eq!(tags(sim.dfa_states_after(b"foo", b"ab")), vec![0]);
+        assert!(tags(sim.dfa_states_after(b"foo", b"abc")).is_empty());
+    }
+
+    #[test]
+    fn command_queue() {
+        // Against a plain vector, with the pops of the families interleaved with the reinsertions
+        // into the holes and into the reused slots.
+        let commands = (0..40u8).map(|i| vec![b'a' + i % 3, i % 5, i]).collect::<Vec<_>>();
+        let mut queue = CommandQueue::default();
+        let mut model: Vec<&[u8]> = vec![];
+        for round in 0..20usize {
+            for command in commands.iter().skip(round).step_by(round % 3 + 1) {
+                let queued = model.contains(&command.as_slice());
+                assert_eq!(queue.insert(command), !queued);
+                if !queued { model.push(command); }
+            }
+            for prefix in [&b"b"[..], b"a\x01", b"", b"c\x04", b"a", b"b\x02"] {
+                let expected = model.iter().rposition(|command| command.starts_with(prefix))
+                    .map(|ix| model.remove(ix));
+                assert_eq!(queue.pop_matching(prefix), expected);
+                assert_eq!(queue.iter().copied().collect::<Vec<_>>(), model);
+                assert_eq!(queue.len(), model.len());
+                assert_eq!(queue.holes, queue.slots.len() - model.len());
             }
+            if round % 4 == 3 {
+                assert_eq!(queue.pop(), model.pop());
+                assert_eq!(queue.holes, queue.slots.len() - model.len());
+            }
+        }
+        while let Some(command) = queue.pop() {
+            assert_eq!(Some(command), model.pop());
+            assert_eq!(queue.holes, queue.slots.len() - model.len());
         }
+        assert!(model.is_empty() && queue.is_empty());
+        assert_eq!(queue.pop_matching(b"a"), None);
     }
 }
@@ -1,12 +1,30 @@
 pub mod configmaton;
+pub mod commands;
 pub mod keyval_runner;
 pub mod keyval_simulator;
 pub mod guards;
 pub mod char_nfa;
 pub mod char_enfa;
+pub mod partition;
 pub mod ast;
 pub mod keyval_nfa;
 pub mod char_runner;
 pub mod blob;
 pub mod holder;
 pub mod onion;
+pub mod intern;
+pub mod pattern_cache;
+pub mod differential;
+pub mod coverage;
+#[cfg(feature = "coverage")]
+pub mod recorder;
+pub mod dot;
+pub mod pool;
+pub mod normalize;
+pub mod numeric;
+pub mod rename;
+pub mod embed;
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+#[cfg(all(unix, feature = "shm"))]
+pub mod shm;
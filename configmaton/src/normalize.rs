@@ -0,0 +1,87 @@
+use std::borrow::Cow;
+
+// Rewriting of the values of a key before they are matched, declared in the config, e.g.
+// `{"normalize": {"user": ["trim", "lowercase"]}}`. The stored values stay as they have been set,
+// only the automaton sees them normalized. The steps are applied in the order of `NAMES`.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Normalizer(pub u8);
+
+impl Normalizer {
+    // Strip the ASCII whitespace at both ends.
+    pub const TRIM: Normalizer = Normalizer(1);
+    // Strip a pair of equal quotes (single or double) enclosing the value.
+    pub const STRIP_QUOTES: Normalizer = Normalizer(2);
+    pub const LOWERCASE: Normalizer = Normalizer(4);
+
+    pub const NAMES: [(&'static str, Normalizer); 3] = [
+        ("trim", Self::TRIM),
+        ("strip_quotes", Self::STRIP_QUOTES),
+        ("lowercase", Self::LOWERCASE),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|(n, _)| *n == name).map(|(_, normalizer)| *normalizer)
+    }
+
+    pub fn union(self, other: Normalizer) -> Self {
+        Normalizer(self.0 | other.0)
+    }
+
+    pub fn contains(self, other: Normalizer) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    // Whether the value can be normalized chunk by chunk, byte by byte in fact, see `apply_byte`.
+    // Otherwise, the ends of the value have to be known first.
+    pub fn is_bytewise(self) -> bool {
+        self.0 & !Self::LOWERCASE.0 == 0
+    }
+
+    pub fn apply(self, value: &[u8]) -> Cow<'_, [u8]> {
+        let mut value = value;
+        if self.contains(Self::TRIM) { value = value.trim_ascii(); }
+        if self.contains(Self::STRIP_QUOTES) {
+            if let [first @ (b'"' | b'\''), inner @ .., last] = value {
+                if first == last { value = inner; }
+            }
+        }
+        if self.contains(Self::LOWERCASE) && value.iter().any(u8::is_ascii_uppercase) {
+            return Cow::Owned(value.to_ascii_lowercase());
+        }
+        Cow::Borrowed(value)
+    }
+
+    // Only for the bytewise normalizers.
+    pub fn apply_byte(self, c: u8) -> u8 {
+        if self.contains(Self::LOWERCASE) { c.to_ascii_lowercase() } else { c }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        let all = Normalizer::NAMES.iter().fold(Normalizer::default(), |x, (_, y)| x.union(*y));
+        assert_eq!(all.apply(b" \t'On' \n").as_ref(), b"on");
+        assert_eq!(all.apply(b"\"on'").as_ref(), b"\"on'");
+        assert_eq!(all.apply(b" \" ").as_ref(), b"\"");
+        assert_eq!(all.apply(b"''").as_ref(), b"");
+        assert!(matches!(all.apply(b" on "), Cow::Borrowed(b"on")));
+
+        let quotes = Normalizer::from_name("strip_quotes").unwrap();
+        assert_eq!(quotes.apply(b" 'x' ").as_ref(), b" 'x' ");
+        assert!(!quotes.is_bytewise() && !all.is_bytewise());
+        assert!(Normalizer::LOWERCASE.is_bytewise());
+        assert_eq!(Normalizer::LOWERCASE.apply_byte(b'Q'), b'q');
+        assert_eq!(Normalizer::from_name("upper"), None);
+    }
+}
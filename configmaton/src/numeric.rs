@@ -0,0 +1,90 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+
+// An inclusive range of numbers, the condition of a numeric guard, e.g.
+// `{"when": {"port": {"range": [1024, 65535]}}}`. A null bound leaves the range open on its side.
+// The values are compared as decimal numbers, a value which is not a finite number is in no range.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct NumRange {
+    pub min: f64,
+    pub max: f64,
+}
+
+impl NumRange {
+    pub fn new(min: Option<f64>, max: Option<f64>) -> Self {
+        NumRange { min: min.unwrap_or(f64::NEG_INFINITY), max: max.unwrap_or(f64::INFINITY) }
+    }
+
+    pub fn contains(&self, value: &[u8]) -> bool {
+        parse(value).is_some_and(|x| self.contains_number(x))
+    }
+
+    pub fn contains_number(&self, x: f64) -> bool {
+        self.min <= x && x <= self.max
+    }
+
+    // The `{"range": [min, max]}` object of the config.
+    pub fn from_json(value: &Value) -> Result<Self, &'static str> {
+        let Some(Value::Array(bounds)) = value.as_object()
+            .filter(|obj| obj.len() == 1)
+            .and_then(|obj| obj.get("range"))
+        else {
+            return Err("a guard object must be {\"range\": [min, max]}");
+        };
+        let [min, max] = bounds.as_slice() else { return Err("a range must have two bounds") };
+        let bound = |bound: &Value| match bound {
+            Value::Null => Ok(None),
+            bound => bound.as_f64().map(Some).ok_or("a bound of a range must be a number or null"),
+        };
+        let range = NumRange::new(bound(min)?, bound(max)?);
+        if range.min > range.max { return Err("the range is empty"); }
+        Ok(range)
+    }
+}
+
+// The number in the value, surrounding whitespace not allowed.
+pub fn parse(value: &[u8]) -> Option<f64> {
+    std::str::from_utf8(value).ok()?.parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+// As in the config, e.g. to serve as the source of the pattern.
+impl fmt::Display for NumRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bound = |x: f64| if x.is_finite() { x.to_string() } else { "null".to_owned() };
+        write!(f, "{{\"range\":[{},{}]}}", bound(self.min), bound(self.max))
+    }
+}
+
+impl<'de> Deserialize<'de> for NumRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        NumRange::from_json(&Value::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn num_range() {
+        let range = NumRange::from_json(&serde_json::json!({"range": [1024, 65535]})).unwrap();
+        assert!(range.contains(b"1024") && range.contains(b"8080.5") && range.contains(b"65535"));
+        assert!(!range.contains(b"80") && !range.contains(b"65536"));
+        assert!(!range.contains(b"http") && !range.contains(b" 8080") && !range.contains(b""));
+        assert_eq!(range.to_string(), r#"{"range":[1024,65535]}"#);
+
+        let range = NumRange::from_json(&serde_json::json!({"range": [null, -1.5]})).unwrap();
+        assert!(range.contains(b"-1e9") && !range.contains(b"-1") && !range.contains(b"-inf"));
+        assert_eq!(range.to_string(), r#"{"range":[null,-1.5]}"#);
+
+        let error = |json| NumRange::from_json(&json).unwrap_err();
+        assert_eq!(error(serde_json::json!({"range": [2, 1]})), "the range is empty");
+        assert_eq!(error(serde_json::json!({"range": [1]})), "a range must have two bounds");
+        assert!(error(serde_json::json!({"range": ["1", 2]})).contains("number or null"));
+        assert!(error(serde_json::json!({"min": 1})).starts_with("a guard object"));
+    }
+}
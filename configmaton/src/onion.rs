@@ -1,12 +1,184 @@
-use std::{ops::{Deref, DerefMut}, sync::{RwLock, RwLockReadGuard, RwLockWriteGuard}};
+use std::{
+    collections::VecDeque,
+    hash::{BuildHasherDefault, Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering}, Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard,
+        RwLockWriteGuard,
+    },
+    time::SystemTime,
+};
 
-use hashbrown::HashMap;
-use crate::holder::Holder;
+use indexmap::{Equivalent, IndexMap};
+use crate::holder::{Handle, Holder};
+use crate::intern::{Interner, KeyId};
+
+// How many times `Onion::read_snapshot` tries to read without the locks.
+const SNAPSHOT_RETRIES: usize = 64;
 
 pub struct Onion<'a, L: Locker, Child> {
     parent: Option<*const Self>,
     children: Holder<Child>,
-    data: L::Lock<HashMap<&'a [u8], &'a [u8]>>,
+    data: L::Lock<Layer<'a>>,
+    // Maximum number of values in the layer, see `evict`.
+    capacity: Option<usize>,
+    // Source of the read timestamps for the LRU eviction.
+    clock: AtomicU64,
+    // Odd while the layer is being changed, see `read_snapshot`.
+    seq: L::Seq,
+    // Shared by all the layers, see `with_keys`.
+    keys: Arc<Interner<'a>>,
+    // Inherited by the children created afterwards, see `set_history_depth`.
+    history: Arc<HistoryDepths>,
+}
+
+struct Entry<'a> {
+    // None for a key unset in a child, hiding the values of the outer layers.
+    value: Option<&'a [u8]>,
+    meta: Meta<'a>,
+    last_read: AtomicU64,
+    // The last values set in this layer (the current one first) with the times of their sets, if
+    // the key keeps any.
+    history: VecDeque<(&'a [u8], SystemTime)>,
+}
+
+// How many values (including the current one) are kept per key, by the longest prefix of the key
+// configured, see `Onion::history`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HistoryDepths(Vec<(Vec<u8>, usize)>);
+
+impl HistoryDepths {
+    pub fn set(&mut self, prefix: &[u8], depth: usize) {
+        match self.0.iter_mut().find(|(known, _)| known == prefix) {
+            Some((_, known)) => *known = depth,
+            None => self.0.push((prefix.to_vec(), depth)),
+        }
+    }
+
+    pub fn depth(&self, key: &[u8]) -> usize {
+        self.0.iter()
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(0, |(_, depth)| *depth)
+    }
+}
+
+// The values set (or unset) in one onion, with their metadata, in the order in which the keys were
+// first set.
+type Layer<'a> = IndexMap<LayerKey<'a>, Entry<'a>, BuildHasherDefault<LayerHasher>>;
+
+// The ID of an interned key, compared as an integer, otherwise the key itself with its hash by the
+// interner. Either way, the key is hashed only once, by the interner, see `LayerHasher`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LayerKey<'a> {
+    Id(KeyId),
+    Bytes(u64, &'a [u8]),
+}
+
+impl<'a> LayerKey<'a> {
+    fn new(keys: &Interner, key: &'a [u8]) -> Self {
+        let hash = keys.hash(key);
+        keys.find(hash, key).map_or(LayerKey::Bytes(hash, key), LayerKey::Id)
+    }
+
+    fn bytes(self, keys: &Interner<'a>) -> &'a [u8] {
+        match self {
+            LayerKey::Id(id) => keys.key(id).expect("the ID of another interner"),
+            LayerKey::Bytes(_, key) => key,
+        }
+    }
+}
+
+impl Hash for LayerKey<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            // Spread over the high bits, which the tables use, too.
+            LayerKey::Id(id) => state.write_u64((id.index() as u64).wrapping_mul(FIBONACCI)),
+            LayerKey::Bytes(hash, _) => state.write_u64(*hash),
+        }
+    }
+}
+
+const FIBONACCI: u64 = 0x9e37_79b9_7f4a_7c15;
+
+// Takes the hash written by `LayerKey` as it is.
+#[derive(Default)]
+struct LayerHasher(u64);
+
+impl Hasher for LayerHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write_u64(&mut self, hash: u64) {
+        self.0 = hash;
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0.rotate_left(8) ^ *byte as u64).wrapping_mul(FIBONACCI);
+        }
+    }
+}
+
+// A layer key which need not outlive the layer, for the lookups.
+struct Lookup<'k>(LayerKey<'k>);
+
+impl Hash for Lookup<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<'a> Equivalent<LayerKey<'a>> for Lookup<'_> {
+    fn equivalent(&self, key: &LayerKey<'a>) -> bool {
+        self.0 == *key
+    }
+}
+
+// Optional information about where a value comes from, stored alongside it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Meta<'a> {
+    pub set_at: Option<SystemTime>,
+    pub source: Option<&'a [u8]>,
+}
+
+impl<'a> Meta<'a> {
+    pub fn now() -> Self {
+        Meta { set_at: Some(SystemTime::now()), source: None }
+    }
+
+    pub fn with_source(self, source: &'a [u8]) -> Self {
+        Meta { source: Some(source), ..self }
+    }
+}
+
+// An immutable copy of the values visible from an onion, which can be shared between threads.
+pub struct FrozenView<'a> {
+    data: IndexMap<&'a [u8], (&'a [u8], Meta<'a>)>,
+}
+
+impl<'a> FrozenView<'a> {
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.data.get(key).map(|(value, _)| *value)
+    }
+
+    pub fn get_with_meta(&self, key: &[u8]) -> Option<(&'a [u8], Meta<'a>)> {
+        self.data.get(key).copied()
+    }
+
+    // In the order of `Onion::entries`.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8], Meta<'a>)> + '_ {
+        self.data.iter().map(|(key, (value, meta))| (*key, *value, *meta))
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 }
 
 pub trait LockerSuper {
@@ -16,12 +188,36 @@ pub trait LockerSuper {
 
 pub trait Locker: LockerSuper {
     type Lock<T>;
+    type Seq: SeqCounter;
 
     fn new<T>(x: T) -> Self::Lock<T>;
     fn read<'a, T>(lock: &'a Self::Lock<T>) -> Self::Guard<'a, T>;
     fn write<'a, T>(lock: &'a mut Self::Lock<T>) -> Self::GuardMut<'a, T>;
 }
 
+// Counts the changes of a layer, so that a read may tell whether a write overlapped it, see
+// `Onion::read_snapshot`.
+pub trait SeqCounter: Default {
+    // None while a write is in progress.
+    fn current(&self) -> Option<u64>;
+    // Called before and after each write.
+    fn bump(&self);
+}
+
+// Nothing can overlap a read without threads.
+impl SeqCounter for () {
+    fn current(&self) -> Option<u64> { Some(0) }
+    fn bump(&self) {}
+}
+
+impl SeqCounter for AtomicU64 {
+    fn current(&self) -> Option<u64> {
+        let seq = self.load(Ordering::Acquire);
+        seq.is_multiple_of(2).then_some(seq)
+    }
+    fn bump(&self) { self.fetch_add(1, Ordering::AcqRel); }
+}
+
 pub struct ThreadUnsafeLocker;
 impl LockerSuper for ThreadUnsafeLocker {
     type Guard<'a, X: 'a> = &'a X;
@@ -29,12 +225,17 @@ impl LockerSuper for ThreadUnsafeLocker {
 }
 impl Locker for ThreadUnsafeLocker {
     type Lock<T> = T;
+    type Seq = ();
 
     fn new<T>(x: T) -> Self::Lock<T> { x }
     fn read<'a, T>(lock: &'a Self::Lock<T>) -> Self::Guard<'a, T> { lock }
     fn write<'a, T>(lock: &'a mut Self::Lock<T>) -> Self::GuardMut<'a, T> { lock }
 }
 
+// The layers guarded by locks. This does not make a configmaton Send or Sync: the sets of a parent
+// are fed to the simulations of its children and the children share the observer of the parent.
+// For concurrent use, see `ConfigmatonPool` (whose shards are independent), `FrozenView` and
+// `SharedOnion`.
 pub struct ThreadSafeLocker;
 impl LockerSuper for ThreadSafeLocker {
     type Guard<'a, X: 'a> = RwLockReadGuard<'a, X>;
@@ -42,57 +243,368 @@ impl LockerSuper for ThreadSafeLocker {
 }
 impl Locker for ThreadSafeLocker {
     type Lock<T> = RwLock<T>;
+    type Seq = AtomicU64;
 
     fn new<T>(x: T) -> Self::Lock<T> { RwLock::new(x) }
     fn read<'a, T>(lock: &'a Self::Lock<T>) -> Self::Guard<'a, T> { lock.read().unwrap() }
     fn write<'a, T>(lock: &'a mut Self::Lock<T>) -> Self::GuardMut<'a, T> { lock.write().unwrap() }
 }
 
+// The lockers whose locks may be shared by threads, so that the layers of a configmaton may be
+// moved to another thread, see `ConfigmatonPool`. Unsafe: the locks must be Send and Sync for
+// the Send and Sync contents.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe trait SyncLocker: Locker {
+    // The lock taken through a shared reference, see `Onion::set_shared`.
+    fn write_shared<'a, T>(lock: &'a Self::Lock<T>) -> Self::GuardMut<'a, T>;
+}
+
+unsafe impl SyncLocker for ThreadSafeLocker {
+    fn write_shared<'a, T>(lock: &'a Self::Lock<T>) -> Self::GuardMut<'a, T> {
+        lock.write().unwrap()
+    }
+}
+unsafe impl SyncLocker for MutexLocker {
+    fn write_shared<'a, T>(lock: &'a Self::Lock<T>) -> Self::GuardMut<'a, T> {
+        lock.lock().unwrap()
+    }
+}
+
+// Like `ThreadSafeLocker`, but the readers exclude each other, too. The writes take the lock as
+// well, since the children read their parents through raw pointers, not through the borrow of
+// the writer.
+pub struct MutexLocker;
+impl LockerSuper for MutexLocker {
+    type Guard<'a, X: 'a> = MutexGuard<'a, X>;
+    type GuardMut<'a, X: 'a> = MutexGuard<'a, X>;
+}
+impl Locker for MutexLocker {
+    type Lock<T> = Mutex<T>;
+    type Seq = AtomicU64;
+
+    fn new<T>(x: T) -> Self::Lock<T> { Mutex::new(x) }
+    fn read<'a, T>(lock: &'a Self::Lock<T>) -> Self::Guard<'a, T> { lock.lock().unwrap() }
+    // Not `get_mut`, see above.
+    #[allow(clippy::mut_mutex_lock)]
+    fn write<'a, T>(lock: &'a mut Self::Lock<T>) -> Self::GuardMut<'a, T> { lock.lock().unwrap() }
+}
+
+impl<'a, L: Locker, Child> Default for Onion<'a, L, Child> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
 impl<'a, L: Locker, Child> Onion<'a, L, Child>
 {
     pub fn new() -> Self {
+        Self::new_layer(None, None, Arc::default(), Arc::default())
+    }
+
+    // An onion whose layers (this one and those of the children) hold at most `capacity` values,
+    // evicting the least recently read ones.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::new_layer(None, Some(capacity), Arc::default(), Arc::default())
+    }
+
+    // Store the keys of the interner by their IDs in this onion and its children, e.g. the keys of
+    // the automaton, which get set repeatedly. Only for an onion in which nothing has been set.
+    pub fn with_keys(mut self, keys: Arc<Interner<'a>>) -> Self {
+        assert!(L::read(&self.data).is_empty() && self.parent.is_none());
+        self.keys = keys;
+        self
+    }
+
+    pub fn keys(&self) -> &Arc<Interner<'a>> {
+        &self.keys
+    }
+
+    pub fn capacity(&self) -> Option<usize> {
+        self.capacity
+    }
+
+    // Keep the last `depth` values of the keys starting with the prefix, e.g. to tell whether a
+    // value has changed. It applies to the values set afterwards, in this layer and in the children
+    // created afterwards; a longer prefix overrides a shorter one.
+    pub fn set_history_depth(&mut self, prefix: &[u8], depth: usize) {
+        Arc::make_mut(&mut self.history).set(prefix, depth);
+    }
+
+    fn new_layer(
+        parent: Option<*const Self>,
+        capacity: Option<usize>,
+        keys: Arc<Interner<'a>>,
+        history: Arc<HistoryDepths>,
+    ) -> Self {
         Onion {
-            parent: None,
+            parent,
             children: Holder::new(),
-            data: L::new(HashMap::new()),
+            data: L::new(IndexMap::default()),
+            capacity,
+            clock: AtomicU64::new(0),
+            seq: L::Seq::default(),
+            keys,
+            history,
         }
     }
 
     // Unfortunately, I did not find a way to express that the parent outlives child but both
-    // remain mutable.
-    pub fn make_child<NewChild: FnOnce(Self) -> Child>
-        (&mut self, new_child: NewChild) -> *mut Child
+    // remain mutable. The child lives until its handle is dropped (it is pruned then by the next
+    // `iter_children`, together with its own children), or until `clear_children`.
+    //
+    // UNSAFE: make sure you don't use the child after the parent is dropped or cleared.
+    pub unsafe fn make_child<NewChild: FnOnce(Self) -> Child>
+        (&mut self, new_child: NewChild) -> Handle<Child>
     {
-        self.children.add(new_child(Onion {
-            parent: Some(self),
-            children: Holder::new(),
-            data: L::new(HashMap::new()),
-        }))
+        let (capacity, keys, history) = (self.capacity, self.keys.clone(), self.history.clone());
+        self.children.add(new_child(Self::new_layer(Some(self), capacity, keys, history)))
     }
 
+    // A value is replaced as a whole, under the lock of its layer, so it is never seen torn. The
+    // values of several keys read one by one may come from different moments though, see
+    // `read_snapshot`.
     pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
-        if let Some(value) = L::read(&self.data).get(key) {
-            return Some(value);
+        self.get_with_meta(key).map(|(value, _)| value)
+    }
+
+    // The values of the keys as of a single moment, even if the layers are being changed by other
+    // threads meanwhile (with a thread-safe locker). The read is retried until no layer has
+    // changed during it, so that the writers never wait for the whole read. After
+    // `SNAPSHOT_RETRIES` failed tries (e.g. under a steady writer), the read takes the locks of
+    // all the layers instead.
+    pub fn read_snapshot(&self, keys: &[&[u8]]) -> Vec<Option<&'a [u8]>> {
+        let layers = self.layers();
+        for _ in 0..SNAPSHOT_RETRIES {
+            let Some(seqs) = layers.iter().map(|layer| layer.seq.current())
+                .collect::<Option<Vec<_>>>()
+            else {
+                std::hint::spin_loop();
+                continue;
+            };
+            let values = keys.iter().map(|key| self.get(key)).collect();
+            let unchanged = layers.iter().zip(seqs)
+                .all(|(layer, seq)| layer.seq.current() == Some(seq));
+            if unchanged { return values; }
+        }
+
+        let guards = layers.iter().map(|layer| L::read(&layer.data)).collect::<Vec<_>>();
+        keys.iter().map(|key| {
+            let key = Lookup(LayerKey::new(&self.keys, key));
+            layers.iter().zip(guards.iter())
+                .find_map(|(layer, data)| layer.get_in(data, &key))
+                .flatten()
+                .map(|(value, _)| value)
+        }).collect()
+    }
+
+    pub fn get_with_meta(&self, key: &[u8]) -> Option<(&'a [u8], Meta<'a>)> {
+        self.find(&Lookup(LayerKey::new(&self.keys, key)))
+    }
+
+    // The same as `get` with the key of the ID, without hashing the key.
+    pub fn get_id(&self, id: KeyId) -> Option<&'a [u8]> {
+        self.find(&Lookup(LayerKey::Id(id))).map(|(value, _)| value)
+    }
+
+    fn find(&self, key: &Lookup) -> Option<(&'a [u8], Meta<'a>)> {
+        if let Some(found) = self.get_here(key) {
+            return found;
         }
 
         let mut parent = self.parent?;
         loop {
             let parent_onion = unsafe { &*parent };
-            if let Some(value) = L::read(&parent_onion.data).get(key) {
-                return Some(value);
+            if let Some(found) = parent_onion.get_here(key) {
+                return found;
             }
             parent = parent_onion.parent?;
         }
     }
 
+    // Some(None) if the key is unset in this layer.
+    fn get_here(&self, key: &Lookup) -> Option<Option<(&'a [u8], Meta<'a>)>> {
+        self.get_in(&L::read(&self.data), key)
+    }
+
+    // `get_here` with the layer already locked.
+    fn get_in(&self, data: &Layer<'a>, key: &Lookup) -> Option<Option<(&'a [u8], Meta<'a>)>> {
+        let entry = data.get(key)?;
+        if self.capacity.is_some() { entry.last_read.store(self.tick(), Ordering::Relaxed); }
+        Some(entry.value.map(|value| (value, entry.meta)))
+    }
+
+    // The kept values of the key (see `set_history_depth`), the current one first, with the times
+    // of their sets (the ones of their metadata, if any). Only the values set in the layer of the
+    // current one are kept there, and an unset forgets them.
+    pub fn history(&self, key: &[u8]) -> Vec<(&'a [u8], SystemTime)> {
+        let key = Lookup(LayerKey::new(&self.keys, key));
+        for layer in self.layers() {
+            if let Some(entry) = L::read(&layer.data).get(&key) {
+                return entry.history.iter().copied().collect();
+            }
+        }
+        vec![]
+    }
+
+    // Whether the current value of the key differs from the previous one kept.
+    pub fn changed(&self, key: &[u8]) -> bool {
+        match self.history(key).as_slice() {
+            [(current, _), (previous, _), ..] => current != previous,
+            _ => false,
+        }
+    }
+
+    // Whether the key is set (or unset) in this layer, so that the outer ones do not matter.
+    pub fn contains_here(&self, key: &[u8]) -> bool {
+        L::read(&self.data).contains_key(&Lookup(LayerKey::new(&self.keys, key)))
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn write_layer<R, F: FnOnce(&mut Layer<'a>) -> R>(&mut self, f: F) -> R {
+        Self::write_locked(&self.seq, L::write(&mut self.data), f)
+    }
+
+    fn write_locked<R, F: FnOnce(&mut Layer<'a>) -> R>
+        (seq: &L::Seq, mut data: L::GuardMut<'_, Layer<'a>>, f: F) -> R
+    {
+        seq.bump();
+        let result = f(&mut data);
+        seq.bump();
+        result
+    }
+
+    // This one and the outer ones, from the inside out.
+    fn layers(&self) -> Vec<&Self> {
+        let mut layers = vec![self];
+        while let Some(parent) = layers.last().unwrap().parent {
+            layers.push(unsafe { &*parent });
+        }
+        layers
+    }
+
     pub fn set(&mut self, key: &'a [u8], value: &'a [u8]) {
-        L::write(&mut self.data).insert(key, value);
+        self.set_with_meta(key, value, Meta::default());
+    }
+
+    pub fn set_with_meta(&mut self, key: &'a [u8], value: &'a [u8], meta: Meta<'a>) {
+        let set = self.setter(key, value, meta);
+        self.write_layer(set);
+    }
+
+    fn setter(&self, key: &'a [u8], value: &'a [u8], meta: Meta<'a>)
+        -> impl FnOnce(&mut Layer<'a>) + 'a
+    {
+        let last_read = AtomicU64::new(self.tick());
+        let depth = self.history.depth(key);
+        let key = LayerKey::new(&self.keys, key);
+        move |data| {
+            let mut history = match data.get_mut(&key) {
+                Some(old) if depth > 0 => std::mem::take(&mut old.history),
+                _ => VecDeque::new(),
+            };
+            if depth > 0 {
+                history.push_front((value, meta.set_at.unwrap_or_else(SystemTime::now)));
+                history.truncate(depth);
+            }
+            data.insert(key, Entry { value: Some(value), meta, last_read, history });
+        }
+    }
+
+    // Remove the value of the key. A child keeps a tombstone instead, so that the values of the
+    // outer layers stay hidden, like by a set.
+    pub fn unset(&mut self, key: &'a [u8]) {
+        let key = LayerKey::new(&self.keys, key);
+        if self.parent.is_none() {
+            self.write_layer(|data| data.shift_remove(&key));
+        } else {
+            let last_read = AtomicU64::new(self.tick());
+            let tombstone =
+                Entry { value: None, meta: Meta::default(), last_read, history: VecDeque::new() };
+            self.write_layer(|data| data.insert(key, tombstone));
+        }
+    }
+
+    // Drop the least recently read (or set) values of this layer until it fits into its capacity.
+    // The values of pinned keys are never dropped, neither are the tombstones of the unset keys.
+    // Returns the dropped keys.
+    pub fn evict<P: Fn(&[u8]) -> bool>(&mut self, pinned: P) -> Vec<&'a [u8]> {
+        let Some(capacity) = self.capacity else { return vec![]; };
+        if L::read(&self.data).len() <= capacity { return vec![]; }
+        let keys = self.keys.clone();
+        self.write_layer(|data| {
+            let mut candidates = data.iter()
+                .map(|(key, entry)| (entry, key.bytes(&keys)))
+                .filter(|(entry, key)| entry.value.is_some() && !pinned(key))
+                .map(|(entry, key)| (entry.last_read.load(Ordering::Relaxed), key))
+                .collect::<Vec<_>>();
+            candidates.sort_unstable();
+            let evicted = candidates.into_iter()
+                .take(data.len() - capacity)
+                .map(|(_, key)| key)
+                .collect::<Vec<_>>();
+            for key in evicted.iter() { data.shift_remove(&Lookup(LayerKey::new(&keys, key))); }
+            evicted
+        })
     }
 
+    // The values visible from this onion, with their metadata. Keys of the outer layers come
+    // first, each layer in the order in which its keys were first set. A key overridden by an
+    // inner layer is listed in that layer. The layers are locked once each, from the inner one
+    // like in `read_snapshot`, so that the readers lock them in the same order.
+    pub fn entries(&self) -> Vec<(&'a [u8], &'a [u8], Meta<'a>)> {
+        let guards = self.layers().into_iter().map(|layer| L::read(&layer.data))
+            .collect::<Vec<_>>();
+        let mut result = vec![];
+        for (depth, data) in guards.iter().enumerate().rev() {
+            for (key, entry) in data.iter() {
+                let overridden = guards[..depth].iter().any(|inner| inner.contains_key(key));
+                let Some(value) = entry.value else { continue };
+                if !overridden { result.push((key.bytes(&self.keys), value, entry.meta)); }
+            }
+        }
+        result
+    }
+
+    // The bytes of the keys and of the values (the kept ones included) set in this layer, and of
+    // its table, see `Configmaton::memory_usage`. The interned keys are shared, so not counted.
+    pub fn memory_usage(&self) -> (usize, usize, usize) {
+        let data = L::read(&self.data);
+        let bucket = size_of::<(u64, LayerKey, Entry)>() + size_of::<usize>();
+        let mut table = data.capacity() * bucket;
+        let (mut keys, mut values) = (0, 0);
+        for (key, entry) in data.iter() {
+            if let LayerKey::Bytes(_, key) = key { keys += key.len(); }
+            // The kept values include the current one.
+            values += if entry.history.is_empty() {
+                entry.value.map_or(0, <[u8]>::len)
+            } else {
+                entry.history.iter().map(|(value, _)| value.len()).sum()
+            };
+            table += entry.history.capacity() * size_of::<(&[u8], SystemTime)>();
+        }
+        (keys, values, table)
+    }
+
+    pub fn freeze(&self) -> FrozenView<'a> {
+        FrozenView {
+            data: self.entries().into_iter().map(|(key, value, meta)| (key, (value, meta))).collect(),
+        }
+    }
+
+    // The live children, the dropped ones are pruned.
     pub fn iter_children(&mut self) -> impl Iterator<Item = *mut Child> {
         self.children.iter_mut()
     }
 
+    // Like `iter_children`, for reading.
+    pub fn children(&self) -> impl Iterator<Item = &Child> {
+        self.children.iter()
+    }
+
     pub fn clear_children(&mut self) {
         self.children.clear();
     }
@@ -102,6 +614,58 @@ impl<'a, L: Locker, Child> Onion<'a, L, Child>
     }
 }
 
+impl<'a, L: SyncLocker, Child> Onion<'a, L, Child> {
+    // `set_with_meta` through a shared reference, e.g. by one thread while others read the
+    // children. The layer is changed under its lock, like by any other write.
+    pub fn set_shared(&self, key: &'a [u8], value: &'a [u8], meta: Meta<'a>) {
+        Self::write_locked(&self.seq, L::write_shared(&self.data), self.setter(key, value, meta));
+    }
+
+    // A handle for other threads, see `SharedOnion`.
+    pub fn shared(&self) -> SharedOnion<'_, 'a, L, Child> {
+        SharedOnion(self)
+    }
+}
+
+// An onion shared between threads, e.g. a parent set by one thread while another one reads and
+// updates its children (created before sharing it). Only the calls which go through the locks of
+// the layers are exposed, the onion itself is not Sync: its layers point to the parents and to
+// the children without locks. The sets are those of `Onion`, the automaton of a configmaton does
+// not see them.
+pub struct SharedOnion<'o, 'a, L: SyncLocker, Child>(&'o Onion<'a, L, Child>);
+
+// The layers (of this onion and of its parents) are only reached through their locks, the
+// children not at all.
+unsafe impl<L: SyncLocker, Child> Sync for SharedOnion<'_, '_, L, Child> {}
+unsafe impl<L: SyncLocker, Child> Send for SharedOnion<'_, '_, L, Child> {}
+
+impl<L: SyncLocker, Child> Clone for SharedOnion<'_, '_, L, Child> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<L: SyncLocker, Child> Copy for SharedOnion<'_, '_, L, Child> {}
+
+impl<'a, L: SyncLocker, Child> SharedOnion<'_, 'a, L, Child> {
+    pub fn set_with_meta(&self, key: &'a [u8], value: &'a [u8], meta: Meta<'a>) {
+        self.0.set_shared(key, value, meta);
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.0.get(key)
+    }
+
+    pub fn get_with_meta(&self, key: &[u8]) -> Option<(&'a [u8], Meta<'a>)> {
+        self.0.get_with_meta(key)
+    }
+
+    // See `Onion::read_snapshot`.
+    pub fn read_snapshot(&self, keys: &[&[u8]]) -> Vec<Option<&'a [u8]>> {
+        self.0.read_snapshot(keys)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,8 +682,8 @@ mod tests {
         assert_eq!(onion1.0.get(b"b"), Some(b"2".as_ref()));
         assert_eq!(onion1.0.get(b"c"), None);
 
-        let onion2 = unsafe { &mut *onion1.0.make_child(|onion| JustOnion(onion)) };
-        let onion3 = unsafe { &mut *onion1.0.make_child(|onion| JustOnion(onion)) };
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        let mut onion3 = unsafe { onion1.0.make_child(JustOnion) };
         onion2.0.set(b"b", b"4");
         onion2.0.set(b"c", b"5");
         onion3.0.set(b"b", b"6");
@@ -157,4 +721,257 @@ mod tests {
         assert_eq!(onion3.0.get(b"c"), None);
         assert_eq!(onion3.0.get(b"d"), None);
     }
+
+    #[test]
+    fn lockers() {
+        struct LockedOnion<'a, L: Locker>(Onion<'a, L, Self>);
+
+        fn check<L: Locker>() {
+            let mut onion1 = LockedOnion(Onion::<L, _>::with_capacity(2));
+            onion1.0.set(b"a", b"1");
+            let mut onion2 = unsafe { onion1.0.make_child(LockedOnion) };
+            onion2.0.set(b"b", b"2");
+            onion2.0.unset(b"a");
+            onion1.0.set(b"c", b"3");
+            assert_eq!(onion1.0.get(b"a"), Some(b"1".as_ref()));
+            assert_eq!(onion2.0.get(b"a"), None);
+            assert_eq!(onion2.0.get(b"c"), Some(b"3".as_ref()));
+            assert_eq!(onion2.0.entries().len(), 2);
+            assert!(onion1.0.evict(|_| false).is_empty());
+        }
+        check::<ThreadUnsafeLocker>();
+        check::<ThreadSafeLocker>();
+        check::<MutexLocker>();
+    }
+
+    #[test]
+    fn onion_unset() {
+        let mut onion1 = JustOnion(Onion::with_capacity(1));
+        onion1.0.set(b"a", b"1");
+        onion1.0.set(b"b", b"2");
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        onion2.0.unset(b"a");
+        onion2.0.unset(b"c");
+        assert_eq!(onion2.0.get(b"a"), None);
+        assert!(onion2.0.contains_here(b"a"));
+        assert_eq!(onion2.0.entries().len(), 1);
+        assert!(onion2.0.evict(|_| false).is_empty());
+
+        onion1.0.unset(b"b");
+        assert!(!onion1.0.contains_here(b"b"));
+        assert_eq!(onion2.0.get(b"b"), None);
+        assert_eq!(onion2.0.get(b"a"), None);
+        assert_eq!(onion1.0.get(b"a"), Some(b"1".as_ref()));
+        onion2.0.set(b"a", b"3");
+        assert_eq!(onion2.0.get(b"a"), Some(b"3".as_ref()));
+    }
+
+    #[test]
+    fn onion_meta() {
+        let mut onion1 = JustOnion(Onion::new());
+        let meta = Meta::now().with_source(b"file");
+        onion1.0.set_with_meta(b"a", b"1", meta);
+        onion1.0.set(b"b", b"2");
+        assert_eq!(onion1.0.get_with_meta(b"a"), Some((b"1".as_ref(), meta)));
+        assert_eq!(onion1.0.get_with_meta(b"b"), Some((b"2".as_ref(), Meta::default())));
+        assert!(meta.set_at.is_some());
+
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        let meta2 = Meta::default().with_source(b"child");
+        onion2.0.set_with_meta(b"b", b"3", meta2);
+        assert_eq!(onion2.0.get_with_meta(b"a"), Some((b"1".as_ref(), meta)));
+        assert_eq!(onion2.0.get_with_meta(b"b"), Some((b"3".as_ref(), meta2)));
+        assert_eq!(onion2.0.get_with_meta(b"c"), None);
+    }
+
+    #[test]
+    fn onion_entries() {
+        let mut onion1 = JustOnion(Onion::new());
+        onion1.0.set(b"c", b"1");
+        onion1.0.set(b"a", b"2");
+        onion1.0.set(b"b", b"3");
+        onion1.0.set(b"c", b"4");
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        onion2.0.set(b"d", b"5");
+        onion2.0.set(b"a", b"6");
+
+        fn keyvals<'a>(onion: &JustOnion<'a>) -> Vec<(&'a [u8], &'a [u8])> {
+            onion.0.entries().into_iter().map(|(key, value, _)| (key, value)).collect()
+        }
+        assert_eq!(keyvals(&onion1),
+            vec![(b"c".as_ref(), b"4".as_ref()), (b"a", b"2"), (b"b", b"3")]);
+        assert_eq!(keyvals(&onion2),
+            vec![(b"c".as_ref(), b"4".as_ref()), (b"b", b"3"), (b"d", b"5"), (b"a", b"6")]);
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no threads on WASI")]
+    fn onion_freeze() {
+        let mut onion1 = JustOnion(Onion::new());
+        onion1.0.set(b"a", b"1");
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        onion2.0.set(b"b", b"2");
+        let view = onion2.0.freeze();
+        onion2.0.set(b"b", b"3");
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(view.get(b"a"), Some(b"1".as_ref()));
+                assert_eq!(view.get(b"b"), Some(b"2".as_ref()));
+                assert_eq!(view.get(b"c"), None);
+            });
+        });
+        assert_eq!(view.len(), 2);
+        assert_eq!(onion2.0.get(b"b"), Some(b"3".as_ref()));
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no threads on WASI")]
+    fn onion_snapshot() {
+        struct LockedOnion<'a, L: Locker>(Onion<'a, L, Self>);
+
+        // The writer sets a, then b, so that a is always b or one ahead of it. Read one by one,
+        // a child may see a new b with an old a.
+        fn check<L: SyncLocker>() {
+            let values = (0..2000).map(|i| i.to_string().into_bytes()).collect::<Vec<_>>();
+            let mut onion1 = LockedOnion::<L>(Onion::new());
+            onion1.0.set(b"a", &values[0]);
+            onion1.0.set(b"b", &values[0]);
+            let mut onion2 = unsafe { onion1.0.make_child(LockedOnion) };
+            let (shared, values) = (onion1.0.shared(), &values);
+            let number = |value: Option<&[u8]>| -> usize {
+                std::str::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+            };
+            std::thread::scope(|s| {
+                s.spawn(move || {
+                    for value in values.iter() {
+                        shared.set_with_meta(b"a", value, Meta::default());
+                        shared.set_with_meta(b"b", value, Meta::default());
+                    }
+                });
+                // Another reader of the parent.
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        let snapshot = shared.read_snapshot(&[b"a", b"b"]);
+                        let (a, b) = (number(snapshot[0]), number(snapshot[1]));
+                        assert!(a == b || a == b + 1, "{} {}", a, b);
+                    }
+                });
+                // The child is updated meanwhile.
+                onion2.0.set(b"c", &values[1]);
+                for _ in 0..1000 {
+                    let snapshot = onion2.0.read_snapshot(&[b"a", b"b"]);
+                    let (a, b) = (number(snapshot[0]), number(snapshot[1]));
+                    assert!(a == b || a == b + 1, "{} {}", a, b);
+                    assert_eq!(onion2.0.entries().len(), 3);
+                }
+            });
+            assert_eq!(onion2.0.read_snapshot(&[b"b", b"c"]),
+                vec![Some(b"1999".as_ref()), Some(b"1".as_ref())]);
+
+            // A writer which never seems to finish makes the read take the locks.
+            onion1.0.seq.bump();
+            assert_eq!(onion2.0.read_snapshot(&[b"a"]), vec![Some(b"1999".as_ref())]);
+        }
+
+        check::<ThreadSafeLocker>();
+        check::<MutexLocker>();
+    }
+
+    #[test]
+    fn onion_lru() {
+        let mut onion1 = JustOnion(Onion::with_capacity(2));
+        onion1.0.set(b"a", b"1");
+        onion1.0.set(b"b", b"2");
+        assert!(onion1.0.evict(|_| false).is_empty());
+        onion1.0.set(b"c", b"3");
+        assert_eq!(onion1.0.get(b"a"), Some(b"1".as_ref()));
+        assert_eq!(onion1.0.evict(|_| false), vec![b"b"]);
+        assert_eq!(onion1.0.get(b"b"), None);
+
+        onion1.0.set(b"d", b"4");
+        assert_eq!(onion1.0.evict(|key| key == b"c"), vec![b"a"]);
+        assert_eq!(onion1.0.get(b"c"), Some(b"3".as_ref()));
+        assert_eq!(onion1.0.get(b"d"), Some(b"4".as_ref()));
+
+        // Reading through a child refreshes the value in the parent.
+        let onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        assert_eq!(onion2.0.get(b"c"), Some(b"3".as_ref()));
+        onion1.0.set(b"e", b"5");
+        assert_eq!(onion1.0.evict(|_| false), vec![b"d"]);
+    }
+
+    #[test]
+    fn onion_interned() {
+        let mut keys = Interner::new();
+        let a = keys.intern(b"a");
+        keys.intern(b"b");
+        let mut onion1 = JustOnion(Onion::with_capacity(2).with_keys(Arc::new(keys)));
+        onion1.0.set(b"b", b"1");
+        onion1.0.set(b"x", b"2");
+        onion1.0.set(b"a", b"3");
+        assert_eq!(onion1.0.get_id(a), Some(b"3".as_ref()));
+        assert_eq!(onion1.0.get(b"x"), Some(b"2".as_ref()));
+
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        onion2.0.unset(b"a");
+        onion2.0.set(b"y", b"4");
+        assert_eq!(onion2.0.get_id(a), None);
+        assert!(onion2.0.contains_here(b"a") && !onion2.0.contains_here(b"b"));
+        let entries = onion2.0.entries().into_iter().map(|(key, value, _)| (key, value))
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec![(b"b".as_ref(), b"1".as_ref()), (b"x", b"2"), (b"y", b"4")]);
+
+        assert_eq!(onion1.0.evict(|key| key == b"b"), vec![b"a"]);
+        assert_eq!(onion1.0.get_id(a), None);
+    }
+
+    #[test]
+    fn onion_prune() {
+        let mut onion1 = JustOnion(Onion::new());
+        let onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        let mut onion3 = unsafe { onion1.0.make_child(JustOnion) };
+        let onion4 = unsafe { onion3.0.make_child(JustOnion) }.into_raw();
+        assert_eq!(onion1.0.iter_children().count(), 2);
+
+        drop(onion2);
+        assert!(onion1.0.has_children());
+        assert_eq!(onion1.0.iter_children().collect::<Vec<_>>(), vec![&mut *onion3 as *mut _]);
+        assert_eq!(onion3.0.iter_children().collect::<Vec<_>>(), vec![onion4]);
+        drop(onion3);
+        assert!(!onion1.0.has_children());
+        assert_eq!(onion1.0.iter_children().count(), 0);
+    }
+
+    #[test]
+    fn onion_history() {
+        let mut onion1 = JustOnion(Onion::new());
+        onion1.0.set_history_depth(b"net.", 3);
+        onion1.0.set_history_depth(b"net.port", 2);
+        onion1.0.set(b"other", b"1");
+        onion1.0.set(b"other", b"2");
+        assert!(onion1.0.history(b"other").is_empty() && !onion1.0.changed(b"other"));
+
+        let at = SystemTime::UNIX_EPOCH;
+        for value in [b"a", b"b", b"c", b"d"] { onion1.0.set(b"net.host", value); }
+        onion1.0.set_with_meta(b"net.port", b"80", Meta { set_at: Some(at), source: None });
+        onion1.0.set(b"net.port", b"80");
+        fn values<'a>(onion: &JustOnion<'a>, key: &[u8]) -> Vec<&'a [u8]> {
+            onion.0.history(key).into_iter().map(|(value, _)| value).collect()
+        }
+        assert_eq!(values(&onion1, b"net.host"), vec![b"d".as_ref(), b"c", b"b"]);
+        assert_eq!(values(&onion1, b"net.port"), vec![b"80".as_ref(), b"80"]);
+        assert_eq!(onion1.0.history(b"net.port")[1].1, at);
+        assert!(onion1.0.changed(b"net.host") && !onion1.0.changed(b"net.port"));
+
+        // The child keeps its own history, the parent's is visible until the child sets the key.
+        let mut onion2 = unsafe { onion1.0.make_child(JustOnion) };
+        assert_eq!(values(&onion2, b"net.host"), vec![b"d".as_ref(), b"c", b"b"]);
+        onion2.0.set(b"net.host", b"e");
+        assert_eq!(values(&onion2, b"net.host"), vec![b"e".as_ref()]);
+        assert!(!onion2.0.changed(b"net.host"));
+        onion2.0.unset(b"net.host");
+        assert!(onion2.0.history(b"net.host").is_empty());
+        assert_eq!(values(&onion1, b"net.host"), vec![b"d".as_ref(), b"c", b"b"]);
+    }
 }
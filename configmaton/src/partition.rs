@@ -0,0 +1,186 @@
+use std::hash::Hash;
+
+use hashbrown::HashMap;
+
+// Refine the partition of the states `0..initial.len()` (given by the class of each state) until
+// the states of each class have equal signatures. The signature of a state is computed from the
+// current class of each state, of which it must depend only on the classes of its successors,
+// `preds` being the inverse of the successor relation.
+//
+// This is Hopcroft's algorithm: a class split off is a splitter, whose predecessors are the only
+// states which can tell its parts apart, and a class already used as a splitter needs all but its
+// largest part to be used again. Each state is thus revisited O(log n) times for each successor.
+//
+// Returns the class of each state, the classes numbered in the order of their first state.
+pub fn refine<S, F>(initial: &[usize], preds: &[Vec<usize>], mut signature: F) -> Vec<usize>
+where
+    S: Hash + Eq,
+    F: FnMut(usize, &[usize]) -> S,
+{
+    // A splitter leaves the signatures of the states other than its predecessors as they are, so
+    // those have to be equal in each class to start with.
+    let mut numbering = HashMap::new();
+    let mut classes = initial.iter().enumerate().map(|(q, class)| {
+        let len = numbering.len();
+        *numbering.entry((*class, signature(q, initial))).or_insert(len)
+    }).collect::<Vec<_>>();
+    let count = numbering.len();
+    drop(numbering);
+
+    // The states sorted by their classes, each class being a range of them.
+    let mut elems = (0..classes.len()).collect::<Vec<_>>();
+    elems.sort_by_key(|q| classes[*q]);
+    let mut locs = vec![0; elems.len()];
+    for (loc, q) in elems.iter().enumerate() { locs[*q] = loc; }
+    let mut ranges = Vec::with_capacity(count);
+    let mut start = 0;
+    for class in 0..count {
+        let len = elems[start..].iter().take_while(|q| classes[**q] == class).count();
+        ranges.push((start, start + len));
+        start += len;
+    }
+
+    let mut pending = vec![true; count];
+    let mut splitters = (0..count).collect::<Vec<_>>();
+    let mut marked = vec![false; classes.len()];
+    while let Some(splitter) = splitters.pop() {
+        pending[splitter] = false;
+        let mut touched: HashMap<usize, Vec<usize>> = HashMap::new();
+        let (start, end) = ranges[splitter];
+        for q in elems[start..end].iter() {
+            for pred in preds[*q].iter() {
+                if !std::mem::replace(&mut marked[*pred], true) {
+                    touched.entry(classes[*pred]).or_default().push(*pred);
+                }
+            }
+        }
+
+        for (class, members) in touched {
+            let mut numbering = HashMap::new();
+            let mut groups: Vec<Vec<usize>> = vec![];
+            for q in members {
+                marked[q] = false;
+                let len = numbering.len();
+                let group = *numbering.entry(signature(q, &classes)).or_insert(len);
+                if group == groups.len() { groups.push(vec![]); }
+                groups[group].push(q);
+            }
+
+            // The states which are not predecessors differ from those which are. They stay in
+            // the class, or the first group does if there are none.
+            let (start, mut end) = ranges[class];
+            let rest = end - start - groups.iter().map(Vec::len).sum::<usize>();
+            let kept = usize::from(rest == 0);
+            if groups.len() == kept { continue; }
+            let mut parts = vec![class];
+            for group in groups[kept..].iter() {
+                let part = ranges.len();
+                for q in group {
+                    end -= 1;
+                    let (loc, other) = (locs[*q], elems[end]);
+                    elems.swap(loc, end);
+                    locs[other] = loc;
+                    locs[*q] = end;
+                    classes[*q] = part;
+                }
+                ranges.push((end, end + group.len()));
+                pending.push(false);
+                parts.push(part);
+            }
+            ranges[class] = (start, end);
+
+            let largest = match pending[class] {
+                true => class,
+                false => *parts.iter().max_by_key(|part| ranges[**part].1 - ranges[**part].0)
+                    .unwrap(),
+            };
+            for part in parts {
+                if part != largest && !pending[part] {
+                    pending[part] = true;
+                    splitters.push(part);
+                }
+            }
+        }
+    }
+
+    let mut numbering = vec![usize::MAX; ranges.len()];
+    let mut count = 0;
+    classes.into_iter().map(|class| {
+        if numbering[class] == usize::MAX { numbering[class] = count; count += 1; }
+        numbering[class]
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    // The fixpoint of refining all classes at once, by the class and the signature of each state.
+    fn refine_naive<S, F>(initial: &[usize], mut signature: F) -> Vec<usize>
+    where
+        S: Hash + Eq,
+        F: FnMut(usize, &[usize]) -> S,
+    {
+        let mut classes = initial.to_vec();
+        loop {
+            let mut numbering = HashMap::new();
+            let refined = (0..classes.len()).map(|q| {
+                let len = numbering.len();
+                *numbering.entry((classes[q], signature(q, &classes))).or_insert(len)
+            }).collect::<Vec<_>>();
+            if refined == classes { return classes; }
+            classes = refined;
+        }
+    }
+
+    #[test]
+    fn random_graphs() {
+        let mut seed = 0x2545f4914f6cdd1du64;
+        let mut random = |n: usize| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % n as u64) as usize
+        };
+        for _ in 0..200 {
+            let n = 1 + random(40);
+            let local = (0..n).map(|_| random(3) == 0).collect::<Vec<_>>();
+            // Labelled edges, the signature being the labels into each class joined, like the
+            // guards of `Nfa::minimize`.
+            let edges = (0..n).map(|_| (0..random(4)).map(|_| (random(2), random(n)))
+                .collect::<Vec<_>>()).collect::<Vec<_>>();
+            let initial = (0..n).map(|_| random(2)).collect::<Vec<_>>();
+            let mut preds = vec![vec![]; n];
+            for (q, edges) in edges.iter().enumerate() {
+                for (_, suc) in edges { preds[*suc].push(q); }
+            }
+            let signature = |q: usize, classes: &[usize]| {
+                let mut labels = BTreeMap::new();
+                for (label, suc) in edges[q].iter() {
+                    *labels.entry(classes[*suc]).or_insert(0) |= 1 << label;
+                }
+                (local[q], labels.into_iter().collect::<Vec<_>>())
+            };
+            assert_eq!(refine(&initial, &preds, signature), refine_naive(&initial, signature));
+        }
+    }
+
+    #[test]
+    fn scaling() {
+        // A chain of states, of which only the last one is final, loses one state from the class
+        // of the rest per split, so refining all the classes at once takes n rounds.
+        for n in [1000, 8000, 64000] {
+            let preds = (0..n).map(|q| if q == 0 { vec![] } else { vec![q - 1] })
+                .collect::<Vec<_>>();
+            let mut calls = 0;
+            let classes = refine(&vec![0; n], &preds, |q, classes| {
+                calls += 1;
+                (q == n - 1, classes.get(q + 1).copied())
+            });
+            assert_eq!(classes, (0..n).collect::<Vec<_>>());
+            assert!(calls <= 2 * n, "{calls} signatures for {n} states");
+        }
+    }
+}
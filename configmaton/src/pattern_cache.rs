@@ -0,0 +1,274 @@
+use std::path::PathBuf;
+
+use hashbrown::HashMap;
+use twox_hash::XxHash64;
+
+use super::ast;
+use super::char_enfa;
+use super::char_enfa::OrderedIxs;
+use super::char_nfa::{Nfa, State};
+use super::guards::Guard;
+
+// The NFA of a single pattern, with state indices relative to its initial state (0) and with a
+// final flag instead of a tag, so that it can be linked into any automaton under any tag.
+#[derive(Debug, PartialEq)]
+pub struct CompiledPattern {
+    pub states: Vec<PatternState>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PatternState {
+    pub transitions: Vec<(Guard, usize)>,
+    pub end_transitions: Vec<usize>,
+    pub is_final: bool,
+}
+
+// Bump on any change of the file layout (see `PatternCache::store`) or of
+// `CompiledPattern::compile`.
+const CACHE_FORMAT: u32 = 1;
+
+impl CompiledPattern {
+    pub fn compile(pattern: ast::Ast) -> Self {
+        let mut nfa = Nfa::new();
+        nfa.add_nfa(char_enfa::Nfa::from_ast(pattern), 0);
+        CompiledPattern {
+            states: nfa.states.into_iter()
+                .map(|state| PatternState {
+                    transitions: state.transitions,
+                    end_transitions: state.end_transitions,
+                    is_final: !state.tags.0.is_empty(),
+                })
+                .collect(),
+        }
+    }
+
+    // Append the states behind those of `nfa`, tagging the final ones by `tag`. The result is the
+    // same as if the pattern was added by `Nfa::add_nfa`. Returns the index of the initial state.
+    pub fn link(&self, nfa: &mut Nfa, tag: usize) -> usize {
+        let offset = nfa.states.len();
+        nfa.states.extend(self.states.iter().map(|state| State {
+            transitions: state.transitions.iter()
+                .map(|(guard, suc)| (*guard, suc + offset))
+                .collect(),
+            tags: OrderedIxs(if state.is_final { vec![tag] } else { vec![] }),
+            end_transitions: state.end_transitions.iter().map(|suc| suc + offset).collect(),
+            is_deterministic: false,
+        }));
+        offset
+    }
+
+    // Layout (little endian): u64 state count, then for each state a final flag byte, u64
+    // transition count, the transitions as (u128, u128, u64) triples, u64 end transition count
+    // and the end transitions as u64s.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend((self.states.len() as u64).to_le_bytes());
+        for state in self.states.iter() {
+            out.push(state.is_final as u8);
+            out.extend((state.transitions.len() as u64).to_le_bytes());
+            for (guard, suc) in state.transitions.iter() {
+                out.extend(guard.0.to_le_bytes());
+                out.extend(guard.1.to_le_bytes());
+                out.extend((*suc as u64).to_le_bytes());
+            }
+            out.extend((state.end_transitions.len() as u64).to_le_bytes());
+            for suc in state.end_transitions.iter() { out.extend((*suc as u64).to_le_bytes()); }
+        }
+        out
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<Self> {
+        fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+            let (head, tail) = bytes.split_first_chunk::<N>()?;
+            *bytes = tail;
+            Some(*head)
+        }
+        let take_usize = |bytes: &mut &[u8]| take(bytes).map(|b| u64::from_le_bytes(b) as usize);
+
+        let state_count = take_usize(&mut bytes)?;
+        let mut states = vec![];
+        for _ in 0..state_count {
+            let is_final = take::<1>(&mut bytes)?[0] != 0;
+            let transition_count = take_usize(&mut bytes)?;
+            let mut transitions = vec![];
+            for _ in 0..transition_count {
+                let guard = Guard(
+                    u128::from_le_bytes(take(&mut bytes)?), u128::from_le_bytes(take(&mut bytes)?));
+                let suc = take_usize(&mut bytes)?;
+                if suc >= state_count { return None; }
+                transitions.push((guard, suc));
+            }
+            let end_transition_count = take_usize(&mut bytes)?;
+            let mut end_transitions = vec![];
+            for _ in 0..end_transition_count {
+                let suc = take_usize(&mut bytes)?;
+                if suc >= state_count { return None; }
+                end_transitions.push(suc);
+            }
+            states.push(PatternState { transitions, end_transitions, is_final });
+        }
+        if !bytes.is_empty() { return None; }
+        Some(CompiledPattern { states })
+    }
+}
+
+// Compiled patterns, content-addressed by the canonical form of the pattern (its parsed AST), so
+// that differently written but equal patterns share an entry. With a directory, the patterns are
+// also stored there, one file per pattern, and reused by later runs, e.g. when iteratively editing
+// a large config.
+pub struct PatternCache {
+    patterns: HashMap<String, CompiledPattern>,
+    dir: Option<PathBuf>,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Default for PatternCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PatternCache {
+    pub fn new() -> Self {
+        PatternCache { patterns: HashMap::new(), dir: None, hits: 0, misses: 0 }
+    }
+
+    pub fn with_dir<P: Into<PathBuf>>(dir: P) -> Self {
+        PatternCache { dir: Some(dir.into()), ..Self::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    // The key of the file of the pattern. The canonical form, the compilation and the layout may
+    // change with the crate version, so the files of the other versions are not reused.
+    fn file_key(canonical: &str) -> String {
+        format!("{} {} {}", env!("CARGO_PKG_VERSION"), CACHE_FORMAT, canonical)
+    }
+
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let hash = XxHash64::oneshot(0, key.as_bytes());
+        self.dir.as_ref().map(|dir| dir.join(format!("{:016x}.nfa", hash)))
+    }
+
+    // A file holds its key in front of the NFA, to tell hash collisions apart.
+    fn load(&self, canonical: &str) -> Option<CompiledPattern> {
+        let key = Self::file_key(canonical);
+        let bytes = std::fs::read(self.path(&key)?).ok()?;
+        let (len, rest) = bytes.split_first_chunk::<8>()?;
+        let (stored_key, nfa) = rest.split_at_checked(u64::from_le_bytes(*len) as usize)?;
+        if stored_key != key.as_bytes() { return None; }
+        CompiledPattern::from_bytes(nfa)
+    }
+
+    fn store(&self, canonical: &str, pattern: &CompiledPattern) {
+        let key = Self::file_key(canonical);
+        let Some(path) = self.path(&key) else { return };
+        let mut bytes = (key.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(key.as_bytes());
+        bytes.extend(pattern.to_bytes());
+        // The cache only speeds up compilation, failing to fill it is not an error.
+        let _ = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| std::fs::write(path, bytes));
+    }
+
+    pub fn get(&mut self, regex: &str) -> &CompiledPattern {
+        let ast = ast::parse_regex(regex);
+        let canonical = format!("{:?}", ast);
+        if self.patterns.contains_key(&canonical) {
+            self.hits += 1;
+        } else {
+            let pattern = match self.load(&canonical) {
+                Some(pattern) => { self.hits += 1; pattern }
+                None => {
+                    self.misses += 1;
+                    let pattern = CompiledPattern::compile(ast);
+                    self.store(&canonical, &pattern);
+                    pattern
+                }
+            };
+            self.patterns.insert(canonical.clone(), pattern);
+        }
+        &self.patterns[&canonical]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link() {
+        let mut nfa = Nfa::new();
+        CompiledPattern::compile(ast::parse_regex("q")).link(&mut nfa, 0);
+        let pattern = CompiledPattern::compile(ast::parse_regex("(ab)*c"));
+        let init = pattern.link(&mut nfa, 3);
+        assert_eq!(init, 2);
+        assert_eq!(nfa.states.len(), init + pattern.states.len());
+
+        for (compiled, state) in pattern.states.iter().zip(&nfa.states[init..]) {
+            let shifted = compiled.transitions.iter()
+                .map(|(g, suc)| (*g, suc + init))
+                .collect::<Vec<_>>();
+            assert_eq!(state.transitions, shifted);
+            assert_eq!(state.tags.0, if compiled.is_final { vec![3] } else { vec![] });
+        }
+        assert_eq!(CompiledPattern::from_bytes(&pattern.to_bytes()), Some(pattern));
+    }
+
+    #[test]
+    fn end_transitions() {
+        let mut pattern = CompiledPattern::compile(ast::parse_regex("ab"));
+        let last = pattern.states.iter().position(|state| state.is_final).unwrap();
+        pattern.states[last].end_transitions.push(0);
+        let bytes = pattern.to_bytes();
+        assert_eq!(CompiledPattern::from_bytes(&bytes).as_ref(), Some(&pattern));
+
+        let mut nfa = Nfa::new();
+        CompiledPattern::compile(ast::parse_regex("q")).link(&mut nfa, 0);
+        let init = pattern.link(&mut nfa, 1);
+        assert_eq!(nfa.states[init + last].end_transitions, vec![init]);
+
+        // An end transition to a nonexistent state.
+        pattern.states[last].end_transitions[0] = pattern.states.len();
+        assert_eq!(CompiledPattern::from_bytes(&pattern.to_bytes()), None);
+    }
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no temp_dir on WASI")]
+    fn cache_dir() {
+        let dir = std::env::temp_dir()
+            .join(format!("configmaton-pattern-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut cache = PatternCache::with_dir(&dir);
+        let compiled = cache.get("a(b|c)*").to_bytes();
+        cache.get("(a)(b|c)*");
+        assert_eq!((cache.hits, cache.misses, cache.len()), (1, 1, 1));
+
+        let mut cache = PatternCache::with_dir(&dir);
+        assert_eq!(cache.get("a(b|c)*").to_bytes(), compiled);
+        assert_eq!((cache.hits, cache.misses), (1, 0));
+
+        assert_eq!(CompiledPattern::from_bytes(&compiled[..compiled.len() - 1]), None);
+
+        // The files of the other versions are not reused.
+        let key = PatternCache::file_key(&format!("{:?}", ast::parse_regex("a(b|c)*")));
+        let path = cache.path(&key).unwrap();
+        let stale = PatternCache::file_key("x").replace(" x", "");
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes.splice(8..8 + key.len(), key.replacen(&stale, "0.0.0 0", 1).bytes());
+        std::fs::write(&path, bytes).unwrap();
+        let mut cache = PatternCache::with_dir(&dir);
+        cache.get("a(b|c)*");
+        assert_eq!((cache.hits, cache.misses), (0, 1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+
+use indexmap::IndexSet;
+use twox_hash::XxHash64;
+
+use crate::blob::automaton::Automaton;
+use crate::configmaton::{AutomatonHandle, Configmaton};
+use crate::onion::SyncLocker;
+
+// The part of a key which selects its shard.
+pub type ShardKey = fn(&[u8]) -> &[u8];
+
+// Independent configmatons of one automaton, each holding the keys of one shard, so that sets of
+// keys of different shards run in parallel. A rule sees only the keys of its shard, so the keys
+// of each rule must get to the same shard, see `with_shard_key`. The defaults are set in each
+// shard, and a command queued by several shards is drained once.
+pub struct ConfigmatonPool<'a, L: SyncLocker> {
+    shards: Vec<Mutex<Shard<'a, L>>>,
+    shard_key: ShardKey,
+}
+
+struct Shard<'a, L: SyncLocker>(Configmaton<'a, L>);
+
+// Besides the layers, the configmatons are not Send only because of what they may share with
+// other ones (see `Configmaton::is_detached`), which the pool never sets, and the configmatons are
+// not reachable from outside the pool.
+unsafe impl<L: SyncLocker> Send for Shard<'_, L> {}
+
+impl<'a, L: SyncLocker> Shard<'a, L> {
+    fn new(handle: &AutomatonHandle<'a>) -> Self {
+        let configmaton = Configmaton::with_handle(handle);
+        assert!(configmaton.is_detached());
+        Shard(configmaton)
+    }
+}
+
+impl<'a, L: SyncLocker> ConfigmatonPool<'a, L> {
+    // Shard by the whole keys.
+    pub fn new(automaton: &Automaton<'a>, shards: usize) -> Self {
+        Self::with_shard_key(automaton, shards, |key| key)
+    }
+
+    // Shard by a part of the keys, e.g. a tenant prefix, which all the keys of a rule share.
+    pub fn with_shard_key(automaton: &Automaton<'a>, shards: usize, shard_key: ShardKey)
+        -> Self
+    {
+        assert!(shards > 0, "a pool needs a shard");
+        let handle = AutomatonHandle::new(automaton);
+        let shards = (0..shards).map(|_| Mutex::new(Shard::new(&handle))).collect();
+        ConfigmatonPool { shards, shard_key }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        (XxHash64::oneshot(0, (self.shard_key)(key)) % self.shards.len() as u64) as usize
+    }
+
+    pub fn set(&self, key: &'a [u8], value: &'a [u8]) {
+        let mut shard = self.shards[self.shard_of(key)].lock().unwrap();
+        // The shards have no children.
+        unsafe { shard.0.set(key, value) };
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.shards[self.shard_of(key)].lock().unwrap().0.get(key)
+    }
+
+    // Pop the queued commands of all the shards, shard by shard (each one in the order of
+    // `Configmaton::pop_command`). A command queued by several shards (e.g. emitted by a rule of
+    // the defaults, which are set in each shard) is returned once, at its first position.
+    pub fn drain_commands(&self) -> Vec<&'a [u8]> {
+        let mut commands = IndexSet::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            while let Some(command) = shard.0.pop_command() { commands.insert(command); }
+        }
+        commands.into_iter().collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::tests::TestU8BuildConfig;
+    use crate::keyval_nfa::{Cmd, Msg, Parser};
+    use crate::onion::ThreadSafeLocker;
+
+    use super::*;
+
+    #[test]
+    #[cfg_attr(target_os = "wasi", ignore = "no threads on WASI")]
+    fn sharded_sets() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            { "defaults": { "mode": "on" } },
+            { "when": {}, "run": [ "start" ] },
+            { "when": { "mode": "on" }, "run": [ "on" ] },
+            { "when": { "t1.a": "1", "t1.b": "1" }, "run": [ "t1" ] },
+            { "when": { "t2.a": "1", "t2.b": "1" }, "run": [ "t2" ] }
+        ]"#).unwrap();
+        let (parser, init) = Parser::parse(config);
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+
+        fn tenant(key: &[u8]) -> &[u8] { key.split(|c| *c == b'.').next().unwrap() }
+        let pool = ConfigmatonPool::<ThreadSafeLocker>::with_shard_key(
+            msg.get_automaton(), 4, tenant);
+        assert_eq!(pool.shard_of(b"t1.a"), pool.shard_of(b"t1.b"));
+        let mut commands = pool.drain_commands();
+        commands.sort();
+        assert_eq!(commands, vec![b"on".as_ref(), b"start"]);
+
+        std::thread::scope(|scope| {
+            for keys in [[b"t1.a", b"t1.b"], [b"t2.a", b"t2.b"]] {
+                let pool = &pool;
+                scope.spawn(move || for key in keys { pool.set(key, b"1") });
+            }
+        });
+        let mut commands = pool.drain_commands();
+        commands.sort();
+        assert_eq!(commands, vec![b"t1".as_ref(), b"t2"]);
+        assert_eq!(pool.get(b"t2.b"), Some(b"1".as_ref()));
+        assert_eq!(pool.get(b"mode"), Some(b"on".as_ref()));
+        assert!(pool.drain_commands().is_empty());
+    }
+}
@@ -0,0 +1,254 @@
+// Coverage of an automaton by its runs, e.g. to measure how well the integration tests of a config
+// exercise its rules. Unlike `coverage`, which inspects the compiled patterns, the recorder marks
+// the states and the leaves actually visited while the values were set, see
+// `Configmaton::set_coverage_recorder`.
+
+use std::{cell::RefCell, rc::Rc};
+
+use hashbrown::HashSet;
+use serde_json::{json, Value};
+
+use crate::blob::automaton::{self, Automaton, PatternInfo};
+use crate::blob::keyval_state::{KeyValState, Leaf};
+use crate::blob::state::U8State;
+use crate::char_runner::{Tracer, TransitionGuard};
+use crate::keyval_nfa::bytes_as_string;
+use crate::keyval_runner::Runner;
+
+#[derive(Debug, Default, Clone)]
+pub struct CoverageRecorder<'a> {
+    keyval_states: HashSet<*const KeyValState<'a>>,
+    u8_states: HashSet<*const U8State<'a>>,
+    leaves: HashSet<*const Leaf<'a>>,
+    // The rules which have emitted commands.
+    rules: HashSet<u64>,
+}
+
+// Children share the recorder of their parent, so that it covers the whole tree.
+pub type SharedRecorder<'a> = Rc<RefCell<CoverageRecorder<'a>>>;
+
+#[allow(clippy::missing_safety_doc)]
+impl<'a> CoverageRecorder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shared() -> SharedRecorder<'a> {
+        Rc::new(RefCell::new(Self::new()))
+    }
+
+    pub fn visit_keyval_state(&mut self, state: *const KeyValState<'a>) {
+        self.keyval_states.insert(state);
+    }
+
+    pub fn visit_u8_states<'b, I: IntoIterator<Item = &'b *const U8State<'a>>>(&mut self, states: I)
+        where 'a: 'b
+    {
+        self.u8_states.extend(states);
+    }
+
+    pub unsafe fn visit_leaf(&mut self, leaf: &'a Leaf<'a>) {
+        self.leaves.insert(leaf);
+        self.rules.extend(leaf.rules());
+    }
+
+    // Add the visits of another recorder of the same loaded blob, e.g. of another test.
+    pub fn merge(&mut self, other: &CoverageRecorder<'a>) {
+        self.keyval_states.extend(other.keyval_states.iter());
+        self.u8_states.extend(other.u8_states.iter());
+        self.leaves.extend(other.leaves.iter());
+        self.rules.extend(other.rules.iter());
+    }
+
+    // Compare the visits with everything reachable from the initial states of the automaton, by
+    // which the runs have been recorded.
+    pub fn report(&self, automaton: &Automaton<'a>) -> CoverageReport<'a> {
+        let inits = unsafe { automaton::inits(automaton) };
+        let (keyval_states, leaves) = unsafe { automaton::reachable_keyval_states(inits) };
+        let dfa_inits = unsafe { Runner::pattern_inits(inits.iter().map(|state| &*state.get())) };
+        let u8_states = unsafe { reachable_u8_states(dfa_inits) };
+
+        // The rules without conditions emit their commands whenever the automaton starts.
+        let initial_rules = unsafe { automaton::initial_rules(automaton) };
+        let mut rules = leaves.iter()
+            .flat_map(|leaf| unsafe { (**leaf).rules() })
+            .copied()
+            .filter(|rule| !self.rules.contains(rule) && !initial_rules.contains(rule))
+            .collect::<Vec<_>>();
+        rules.sort_unstable();
+        rules.dedup();
+        let patterns = unsafe { automaton::patterns(automaton) };
+        let uncovered_rules = rules.into_iter().map(|rule| UncoveredRule {
+            rule: rule as usize,
+            conditions: unsafe { automaton::tag_rules(automaton) }.iter()
+                .filter(|x| x.rule == rule)
+                .map(|x| UncoveredCondition {
+                    pattern: patterns[x.pattern as usize],
+                    negated: x.negated != 0,
+                })
+                .collect(),
+        }).collect();
+
+        let count = |visited: usize, total: usize| Counts { visited, total };
+        CoverageReport {
+            keyval_states: count(self.keyval_states.len(), keyval_states.len()),
+            u8_states: count(self.u8_states.len(), u8_states.len()),
+            leaves: count(self.leaves.len(), leaves.len()),
+            uncovered_rules,
+        }
+    }
+}
+
+unsafe fn reachable_u8_states<'a, I: IntoIterator<Item = *const U8State<'a>>>(inits: I)
+    -> HashSet<*const U8State<'a>>
+{
+    let mut frontier = inits.into_iter().collect::<Vec<_>>();
+    let mut states = frontier.iter().copied().collect::<HashSet<_>>();
+    while let Some(state) = frontier.pop() {
+        let state = &*state;
+        let mut push = |right: *const U8State<'a>| {
+            if states.insert(right) { frontier.push(right); }
+        };
+        for symbol in 0..=255u8 {
+            for right in state.successors(&symbol) { push(right); }
+        }
+        for right in state.end_successors() { push(right.get()); }
+    }
+    states
+}
+
+// The char states are marked as the values pass through them.
+impl<'a> Tracer<'a> for CoverageRecorder<'a> {
+    fn on_transition(
+        &mut self, _: *const U8State<'a>, _: u8, _: TransitionGuard<'a>, target: *const U8State<'a>,
+    ) {
+        self.u8_states.insert(target);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub visited: usize,
+    pub total: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageReport<'a> {
+    pub keyval_states: Counts,
+    pub u8_states: Counts,
+    pub leaves: Counts,
+    // The rules (see `Parser` for the numbering) whose commands have never been emitted, sorted.
+    // Only the rules emitting commands are considered.
+    pub uncovered_rules: Vec<UncoveredRule<'a>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UncoveredRule<'a> {
+    pub rule: usize,
+    // Of the rule itself, not of its parents.
+    pub conditions: Vec<UncoveredCondition<'a>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UncoveredCondition<'a> {
+    pub pattern: PatternInfo<'a>,
+    // Of `when_not`.
+    pub negated: bool,
+}
+
+impl CoverageReport<'_> {
+    // For the tools outside of Rust. The regexes are null unless embedded in the blob.
+    pub fn to_json(&self) -> Value {
+        let counts = |x: Counts| json!({"visited": x.visited, "total": x.total});
+        let rules = self.uncovered_rules.iter().map(|rule| json!({
+            "rule": rule.rule,
+            "conditions": rule.conditions.iter().map(|cond| json!({
+                "key": bytes_as_string(cond.pattern.key),
+                "regex": cond.pattern.regex.map(bytes_as_string),
+                "negated": cond.negated,
+            })).collect::<Vec<_>>(),
+        })).collect::<Vec<_>>();
+        json!({
+            "keyval_states": counts(self.keyval_states),
+            "u8_states": counts(self.u8_states),
+            "leaves": counts(self.leaves),
+            "uncovered_rules": rules,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blob::tests::TestU8BuildConfig;
+    use crate::configmaton::Configmaton;
+    use crate::keyval_nfa::{Cmd, Msg, Parser};
+    use crate::onion::ThreadUnsafeLocker;
+
+    use super::*;
+
+    #[test]
+    fn recorder() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {}, "run": ["boot"]},
+            {"when": {"foo": "a.*"}, "run": ["m1"], "then": [
+                {"when": {"bar": "x|y"}, "run": ["m2"]}
+            ]},
+            {"when": {"qux": "q"}, "run": ["m3"]}
+        ]"#).unwrap();
+        let (mut parser, init) = Parser::parse(config);
+        parser.embed_patterns = true;
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let automaton = msg.get_automaton();
+
+        let recorder = CoverageRecorder::shared();
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(automaton);
+        configmaton.set_coverage_recorder(Some(recorder.clone()));
+        unsafe { configmaton.set(b"foo", b"abc") };
+        let report = recorder.borrow().report(automaton);
+        let uncovered = report.uncovered_rules.iter().map(|x| x.rule).collect::<Vec<_>>();
+        assert_eq!(uncovered, vec![2, 3]);
+        let conditions = report.uncovered_rules[0].conditions.iter()
+            .map(|x| (x.pattern.key, x.pattern.regex.unwrap(), x.negated)).collect::<Vec<_>>();
+        assert_eq!(conditions, [(b"bar".as_ref(), b"x|y".as_ref(), false)]);
+        // The states of `foo` and `qux` are initial, the one of `bar` follows `foo`.
+        assert_eq!(report.keyval_states, Counts { visited: 3, total: 3 });
+        assert!(report.u8_states.visited < report.u8_states.total);
+        assert_eq!(report.leaves, Counts { visited: 1, total: 6 });
+
+        // The children record into the same recorder.
+        let mut child = unsafe { configmaton.make_child() };
+        unsafe { child.set(b"bar", b"y") };
+        let other = CoverageRecorder::shared();
+        let mut configmaton = Configmaton::<ThreadUnsafeLocker>::new(automaton);
+        configmaton.set_coverage_recorder(Some(other.clone()));
+        unsafe { configmaton.set(b"qux", b"q") };
+        unsafe { configmaton.set(b"foo", b"b") };
+        recorder.borrow_mut().merge(&other.borrow());
+        let report = recorder.borrow().report(automaton);
+        assert!(report.uncovered_rules.is_empty());
+        // Neither `bar` nor `qux` has been mismatched.
+        assert_eq!(report.leaves, Counts { visited: 4, total: 6 });
+        let json = report.to_json();
+        assert_eq!(json["uncovered_rules"], json!([]));
+        assert_eq!(json["leaves"]["total"], report.leaves.total);
+    }
+
+    #[test]
+    fn negated_conditions() {
+        let config: Vec<Cmd> = serde_json::from_str(r#"[
+            {"when": {"foo": "a"}, "when_not": {"bar": "a"}, "run": ["m1"]}
+        ]"#).unwrap();
+        let (mut parser, init) = Parser::parse(config);
+        parser.embed_patterns = true;
+        let msg = Msg::serialize(&parser, &init, &TestU8BuildConfig);
+        let msg = unsafe {
+            Msg::read(|buf| buf.copy_from(msg.data, msg.data_len()), msg.data_len()) };
+        let report = CoverageRecorder::shared().borrow().report(msg.get_automaton());
+        let conditions = report.uncovered_rules[0].conditions.iter()
+            .map(|x| (x.pattern.key, x.negated)).collect::<Vec<_>>();
+        assert_eq!(conditions, [(b"foo".as_ref(), false), (b"bar", true)]);
+        assert_eq!(report.to_json()["uncovered_rules"][0]["conditions"][1]["negated"], true);
+    }
+}
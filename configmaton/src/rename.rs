@@ -0,0 +1,74 @@
+use hashbrown::HashMap;
+
+// Renames of keys for the migrations of the key space, applied to the parsed configs by
+// `Cmd::rename_keys` and to the compiled ones by `Parser::rename_keys`. A key renamed exactly is
+// not rewritten by the prefixes, otherwise the longest matching prefix is replaced.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyRenames {
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+    prefixes: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl KeyRenames {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn key(mut self, from: &str, to: &str) -> Self {
+        self.keys.insert(from.as_bytes().to_vec(), to.as_bytes().to_vec());
+        self
+    }
+
+    pub fn prefix(mut self, from: &str, to: &str) -> Self {
+        self.prefixes.retain(|(known, _)| known != from.as_bytes());
+        self.prefixes.push((from.as_bytes().to_vec(), to.as_bytes().to_vec()));
+        self
+    }
+
+    // None if the key is kept.
+    pub fn renamed(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if let Some(to) = self.keys.get(key) { return Some(to.clone()); }
+        let (from, to) = self.prefixes.iter()
+            .filter(|(from, _)| key.starts_with(from))
+            .max_by_key(|(from, _)| from.len())?;
+        Some([to.as_slice(), &key[from.len()..]].concat())
+    }
+
+    pub fn rename(&self, key: &mut Vec<u8>) {
+        if let Some(renamed) = self.renamed(key) { *key = renamed; }
+    }
+
+    // The renames are given as strings, so a UTF-8 key stays UTF-8.
+    pub fn rename_str(&self, key: &mut String) {
+        if let Some(renamed) = self.renamed(key.as_bytes()) {
+            *key = String::from_utf8(renamed).expect("a renamed UTF-8 key is UTF-8");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_renames() {
+        let renames = KeyRenames::new()
+            .key("host", "net.host")
+            .prefix("db.", "storage.")
+            .prefix("db.primary.", "storage.main.")
+            .key("db.legacy", "legacy");
+        let renamed = |key: &str| {
+            renames.renamed(key.as_bytes()).map(|key| String::from_utf8(key).unwrap())
+        };
+        assert_eq!(renamed("host").as_deref(), Some("net.host"));
+        assert_eq!(renamed("hostname"), None);
+        assert_eq!(renamed("db.user").as_deref(), Some("storage.user"));
+        assert_eq!(renamed("db.primary.url").as_deref(), Some("storage.main.url"));
+        assert_eq!(renamed("db.legacy").as_deref(), Some("legacy"));
+        assert_eq!(renamed("db").as_deref(), None);
+
+        let mut key = "db.ž".to_owned();
+        renames.rename_str(&mut key);
+        assert_eq!(key, "storage.ž");
+    }
+}
@@ -1,6 +1,6 @@
 use std::{net::SocketAddr, sync::{Arc, RwLock}};
 
-use configmaton::{blob::state::build::U8BuildConfig, keyval_nfa::{Cmd, Msg, Parser}};
+use configmaton::{blob::state::build::DefaultBuildConfig, keyval_nfa::{Cmd, Msg, Parser}};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::{body::{Body, Bytes}, server::conn::http1, service::service_fn, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
@@ -89,19 +89,12 @@ struct App {
     db: Connection,
 }
 
-pub struct BuildConfig;
-impl U8BuildConfig for BuildConfig {
-    fn guard_size_keep(&self) -> u32 { 10 }
-    fn hashmap_cap_power_fn(&self, _len: usize) -> usize { 3 }
-    fn dense_guard_count(&self) -> usize { 15 }
-}
-
 pub fn json_to_automaton_matchrun(json: &str)
     -> Result<Msg, serde_json::Error>
 {
     let config: Vec<Cmd> = serde_json::from_str(json)?;
     let (parser, init) = Parser::parse(config);
-    Ok(Msg::serialize(&parser, &init, &BuildConfig))
+    Ok(Msg::serialize(&parser, &init, &DefaultBuildConfig))
 }
 
 async fn handle(app: Arc<RwLock<App>>, req: Request<hyper::body::Incoming>)
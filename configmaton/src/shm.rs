@@ -0,0 +1,118 @@
+// Named POSIX shared-memory segments holding a blob, so that several processes can evaluate one
+// copy of an automaton.
+//
+// Blobs contain absolute pointers once deserialized, so every process must map the segment at the
+// same address. The creator stores its mapping address in the segment header, and the attaching
+// processes request the same address (failing if it is already taken in their address space).
+
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::ptr;
+
+const MAGIC: u64 = u64::from_le_bytes(*b"cfgmshm1");
+
+#[repr(C)]
+struct Header {
+    magic: u64,
+    base: usize,
+    data_len: usize,
+}
+
+// The data starts behind the header, aligned like the blobs allocated on the heap.
+const DATA_OFFSET: usize = size_of::<Header>().next_multiple_of(size_of::<u128>());
+
+pub struct SharedSegment {
+    ptr: *mut u8,
+    len: usize,
+}
+
+fn shm_name(name: &str) -> io::Result<CString> {
+    CString::new(format!("/{}", name.trim_start_matches('/')))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a NUL byte"))
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret) }
+}
+
+impl SharedSegment {
+    // Create a new zero-filled segment able to hold `data_len` bytes of blob data.
+    pub fn create(name: &str, data_len: usize) -> io::Result<Self> {
+        let cname = shm_name(name)?;
+        let len = DATA_OFFSET + data_len;
+        unsafe {
+            let fd = check(libc::shm_open(
+                cname.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o600))?;
+            let mapped = check(libc::ftruncate(fd, len as libc::off_t))
+                .and_then(|_| Self::map(fd, len, ptr::null_mut()));
+            libc::close(fd);
+            let segment = match mapped {
+                Ok(segment) => segment,
+                Err(e) => { libc::shm_unlink(cname.as_ptr()); return Err(e); },
+            };
+            let header = &mut *(segment.ptr as *mut Header);
+            header.base = segment.ptr as usize;
+            header.data_len = data_len;
+            Ok(segment)
+        }
+    }
+
+    // Mark the data as complete, attaching fails before that.
+    pub fn publish(&mut self) {
+        unsafe { ptr::write_volatile(&mut (*(self.ptr as *mut Header)).magic, MAGIC); }
+    }
+
+    // Map an existing published segment, at the address where its creator has mapped it.
+    pub fn attach(name: &str) -> io::Result<Self> {
+        let cname = shm_name(name)?;
+        unsafe {
+            let fd = check(libc::shm_open(cname.as_ptr(), libc::O_RDWR, 0))?;
+            let result = Self::attach_fd(fd);
+            libc::close(fd);
+            result
+        }
+    }
+
+    unsafe fn attach_fd(fd: libc::c_int) -> io::Result<Self> {
+        let mut header = std::mem::zeroed::<Header>();
+        let read = libc::pread(fd, &mut header as *mut Header as *mut libc::c_void,
+            size_of::<Header>(), 0);
+        if read != size_of::<Header>() as isize || header.magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a published blob segment"));
+        }
+        let segment = Self::map(fd, DATA_OFFSET + header.data_len, header.base as *mut u8)?;
+        if segment.ptr as usize != header.base {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                "the address of the segment is already in use by this process"));
+        }
+        Ok(segment)
+    }
+
+    unsafe fn map(fd: libc::c_int, len: usize, hint: *mut u8) -> io::Result<Self> {
+        let ptr = libc::mmap(hint as *mut libc::c_void, len,
+            libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0);
+        if ptr == libc::MAP_FAILED { return Err(io::Error::last_os_error()); }
+        Ok(SharedSegment { ptr: ptr as *mut u8, len })
+    }
+
+    // Remove the name, the segment lives on until the last process unmaps it.
+    pub fn unlink(name: &str) -> io::Result<()> {
+        let cname = shm_name(name)?;
+        check(unsafe { libc::shm_unlink(cname.as_ptr()) }).map(|_| ())
+    }
+
+    pub fn data(&self) -> *mut u8 {
+        unsafe { self.ptr.add(DATA_OFFSET) }
+    }
+
+    pub fn data_len(&self) -> usize {
+        self.len - DATA_OFFSET
+    }
+}
+
+impl Drop for SharedSegment {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len); }
+    }
+}
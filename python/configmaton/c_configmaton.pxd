@@ -6,16 +6,28 @@ cdef extern from "configmaton.h":
     ctypedef struct FfiConfigmaton:
         pass
 
+    ctypedef struct ConfigmatonIter:
+        pass
+
     struct Bytestring:
         size_t len
         const unsigned char* data
 
     OwnedConfigmaton* new_configmaton_base(const unsigned char* buf, size_t len)
+    bint compile_config_json(
+            const unsigned char* buf, size_t len, Bytestring* out_blob, Bytestring* out_error)
+    void free_blob(Bytestring blob)
     void drop_configmaton_base(OwnedConfigmaton* base)
     FfiConfigmaton* base_get_configmaton(OwnedConfigmaton* base)
     FfiConfigmaton* configmaton_make_child(FfiConfigmaton* configmaton)
+    # The configmaton keeps pointing to the key and value of a set, and to the key of an unset, so
+    # they must outlive the whole tree of configmatons.
     void configmaton_set(FfiConfigmaton* configmaton, const unsigned char* key, size_t key_len,
              const unsigned char* value, size_t value_len)
+    void configmaton_unset(FfiConfigmaton* configmaton, const unsigned char* key, size_t key_len)
     Bytestring configmaton_get(
             const FfiConfigmaton* configmaton, const unsigned char* key, size_t key_len)
     Bytestring configmaton_pop_command(FfiConfigmaton* configmaton)
+    ConfigmatonIter* configmaton_iter_new(const FfiConfigmaton* configmaton)
+    bint configmaton_iter_next(ConfigmatonIter* iter, Bytestring* key, Bytestring* value)
+    void drop_configmaton_iter(ConfigmatonIter* iter)
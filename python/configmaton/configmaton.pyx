@@ -8,6 +8,12 @@ cdef size_t UNSIGNED_MAX = 2 * sys.maxsize + 1
 cdef class _Base:
     """Internal class for managing the lifetime of the Rust OwnedConfigmaton."""
     cdef c_configmaton.OwnedConfigmaton* _ptr
+    # The keys and values passed to the configmatons of the tree, which point into them until the
+    # tree is dropped.
+    cdef list _kept
+
+    def __cinit__(self):
+        self._kept = []
 
     def __dealloc__(self):
         c_configmaton.drop_configmaton_base(self._ptr)
@@ -60,6 +66,8 @@ cdef class Configmaton:
         """
         cdef c_configmaton.Bytestring cmd
 
+        self._base._kept.append(key)
+        self._base._kept.append(value)
         c_configmaton.configmaton_set(self._ptr, key, len(key), value, len(value))
         while True:
             cmd = c_configmaton.configmaton_pop_command(self._ptr)
@@ -67,6 +75,15 @@ cdef class Configmaton:
                 return
             self._handle_commands(bytes(cmd.data[:cmd.len]))
 
+    def unset(self, bytes key not None) -> None:
+        """Remove a configuration value, so that `get` returns None.
+
+        Args:
+            key: Configuration key
+        """
+        self._base._kept.append(key)
+        c_configmaton.configmaton_unset(self._ptr, key, len(key))
+
     def get(self, bytes key not None) -> Optional[bytes]:
         """Get a configuration value.
 
@@ -79,3 +96,21 @@ cdef class Configmaton:
         if result.len == UNSIGNED_MAX:
             return None
         return bytes(result.data[:result.len])
+
+    def items(self) -> List[Tuple[bytes, bytes]]:
+        """Get all configuration key-value pairs, the values of the child overriding those of
+        its ancestors.
+
+        Returns:
+            The (key, value) pairs, each key once.
+        """
+        cdef c_configmaton.ConfigmatonIter* it = c_configmaton.configmaton_iter_new(self._ptr)
+        cdef c_configmaton.Bytestring key
+        cdef c_configmaton.Bytestring value
+        result = []
+        try:
+            while c_configmaton.configmaton_iter_next(it, &key, &value):
+                result.append((bytes(key.data[:key.len]), bytes(value.data[:value.len])))
+        finally:
+            c_configmaton.drop_configmaton_iter(it)
+        return result